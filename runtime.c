@@ -1,3 +1,4 @@
+#include <math.h>
 #include <stdio.h>
 #include <stdlib.h>
 #include <string.h>
@@ -11,7 +12,8 @@ enum PLType {
     PL_INT,
     PL_FLOAT,
     PL_STR,
-    // PL_LIST,
+    PL_LIST,
+    PL_TUPLE,
     PL_FUNCPTR,
     PL_CLOS,
 };
@@ -30,10 +32,17 @@ union PLVal {
     i64 n;
     f64 x;
     char *s;
-    PLFuncptr funcptr; // Changed to use the function pointer type
+    struct PLCell *cell; // A non-empty list; the empty list is nil
+    struct PLTuple *tuple; // Multiple values that escaped from a struct return
+    // Functions and closures know their arity, -1 if they take any number of arguments
+    struct {
+        PLFuncptr funcptr;
+        int arity;
+    } func;
     struct {
         PLClosptr closptr;
         struct PLV *freevars;
+        int arity;
     } clos;
 };
 typedef union PLVal PLVal;
@@ -44,6 +53,20 @@ struct PLV {
 };
 typedef struct PLV PLV;
 
+// A cell of a list
+struct PLCell {
+    PLV head;
+    PLV tail;
+};
+typedef struct PLCell PLCell;
+
+// Multiple values, as returned by `values`
+struct PLTuple {
+    int len;
+    PLV items[];
+};
+typedef struct PLTuple PLTuple;
+
 // Function prototypes to create a new PLV
 PLV __new_NIL();
 PLV __new_BOOL(char b);
@@ -51,28 +74,28 @@ PLV __new_INT(i64 n);
 PLV __new_FLOAT(f64 x);
 PLV __new_STR(const char *s);
 PLV __new_LIST(PLV *elements, int len);
-PLV __new_FUNCPTR(PLFuncptr funcptr);
-PLV __new_CLOS(PLClosptr closptr, PLV *freevars);
+PLV __new_CONS(PLV head, PLV tail);
+PLV __new_TUPLE(PLV *items, int len);
+PLV __new_FUNCPTR(PLFuncptr funcptr, int arity);
+PLV __new_CLOS(PLClosptr closptr, PLV *freevars, int arity);
 
 // Function prototype to delete a PLV
 void __delete_PLV(PLV *v);
 
-// Function prototype for printing a PLV
+// Function prototypes for printing a PLV
+void __PL_print_float(f64 x);
 void __PLV_print(PLV *v);
 
 // Function prototype for funcall
-PLV __PL_funcall(PLV *args);
-
-// Built-in function prototypes
-PLV global_func_add(PLV *args);
-PLV global_func_sub(PLV *args);
-PLV global_func_mul(PLV *args);
-PLV global_func_div(PLV *args);
-PLV global_func_eq(PLV *args);
-PLV global_func_lt(PLV *args);
-PLV global_func_leq(PLV *args);
-PLV global_func_gt(PLV *args);
-PLV global_func_geq(PLV *args);
+PLV __PL_funcall(PLV *args, int nargs);
+
+// Function prototypes for checks used by statically typed code
+void __PL_check_type(PLV *v, PLType type, const char *what);
+i64 __PL_idiv(i64 x, i64 y);
+f64 __PL_fdiv(f64 x, f64 y);
+
+// Function prototype for the conversion of numeric arguments of the math builtins
+f64 __PL_to_float(PLV *v, const char *what);
 
 // Implementation of PLV creattion functions
 PLV __new_NIL() {
@@ -113,18 +136,56 @@ PLV __new_STR(const char *s) {
     return v;
 }
 
-PLV __new_FUNCPTR(PLFuncptr funcptr) {
+PLV __new_LIST(PLV *elements, int len) {
+    PLV v = __new_NIL();
+    for (int i = len - 1; i >= 0; i--) {
+        v = __new_CONS(elements[i], v);
+    }
+    return v;
+}
+
+PLV __new_CONS(PLV head, PLV tail) {
+    PLV v;
+    v.type = PL_LIST;
+    v.val.cell = malloc(sizeof(PLCell));
+    if (v.val.cell == NULL) {
+        fprintf(stderr, "Error: Memory allocation failed for list\n");
+        exit(1);
+    }
+    v.val.cell->head = head;
+    v.val.cell->tail = tail;
+    return v;
+}
+
+PLV __new_TUPLE(PLV *items, int len) {
+    PLV v;
+    v.type = PL_TUPLE;
+    v.val.tuple = malloc(sizeof(PLTuple) + sizeof(PLV) * len);
+    if (v.val.tuple == NULL) {
+        fprintf(stderr, "Error: Memory allocation failed for values\n");
+        exit(1);
+    }
+    v.val.tuple->len = len;
+    for (int i = 0; i < len; i++) {
+        v.val.tuple->items[i] = items[i];
+    }
+    return v;
+}
+
+PLV __new_FUNCPTR(PLFuncptr funcptr, int arity) {
     PLV v;
     v.type = PL_FUNCPTR;
-    v.val.funcptr = funcptr;
+    v.val.func.funcptr = funcptr;
+    v.val.func.arity = arity;
     return v;
 }
 
-PLV __new_CLOS(PLClosptr closptr, PLV *freevars) {
+PLV __new_CLOS(PLClosptr closptr, PLV *freevars, int arity) {
     PLV v;
     v.type = PL_CLOS;
     v.val.clos.closptr = closptr;
     v.val.clos.freevars = freevars; // Assume freevars is already allocated
+    v.val.clos.arity = arity;
     return v;
 }
 
@@ -139,6 +200,57 @@ void __delete_PLV(PLV *v) {
     // No need to free function pointers or other types
 }
 
+// Print a float as the interpreter does: the shortest digits that read back
+// as the same value, written out without an exponent
+void __PL_print_float(f64 x) {
+    if (isnan(x)) {
+        printf("NaN");
+        return;
+    }
+    if (isinf(x)) {
+        printf(x > 0 ? "inf" : "-inf");
+        return;
+    }
+    char buf[32];
+    for (int prec = 0; prec < 17; prec++) {
+        snprintf(buf, sizeof buf, "%.*e", prec, x);
+        if (strtod(buf, NULL) == x) {
+            break;
+        }
+    }
+    // buf is [-]d[.ddd]e[+-]XX: collect the digits and the exponent
+    char *p = buf;
+    if (*p == '-') {
+        putchar('-');
+        p++;
+    }
+    char digits[32];
+    int ndigits = 0;
+    for (; *p != 'e'; p++) {
+        if (*p != '.') {
+            digits[ndigits++] = *p;
+        }
+    }
+    while (ndigits > 1 && digits[ndigits - 1] == '0') {
+        ndigits--;
+    }
+    int point = atoi(p + 1) + 1; // number of digits before the decimal point
+    if (point <= 0) {
+        printf("0.");
+        for (int i = point; i < 0; i++) {
+            putchar('0');
+        }
+        printf("%.*s", ndigits, digits);
+    } else if (point >= ndigits) {
+        printf("%.*s", ndigits, digits);
+        for (int i = ndigits; i < point; i++) {
+            putchar('0');
+        }
+    } else {
+        printf("%.*s.%.*s", point, digits, ndigits - point, digits + point);
+    }
+}
+
 // Implementation of printing function
 void __PLV_print(PLV *v) {
     switch (v->type) {
@@ -152,13 +264,31 @@ void __PLV_print(PLV *v) {
         printf("%lld", v->val.n);
         break;
     case PL_FLOAT:
-        printf("%lf", v->val.x);
+        __PL_print_float(v->val.x);
         break;
     case PL_STR:
-        printf("\"%s\"", v->val.s);
+        printf("%s", v->val.s);
+        break;
+    case PL_LIST:
+        putchar('(');
+        for (PLV *l = v; l->type == PL_LIST; l = &l->val.cell->tail) {
+            if (l != v) {
+                putchar(' ');
+            }
+            __PLV_print(&l->val.cell->head);
+        }
+        putchar(')');
+        break;
+    case PL_TUPLE:
+        printf("(values");
+        for (int i = 0; i < v->val.tuple->len; i++) {
+            putchar(' ');
+            __PLV_print(&v->val.tuple->items[i]);
+        }
+        putchar(')');
         break;
     case PL_FUNCPTR:
-        printf("<function at %p>", (void *)v->val.funcptr);
+        printf("<function at %p>", (void *)v->val.func.funcptr);
         break;
     case PL_CLOS:
         printf("<closure %p>", v->val.clos.closptr);
@@ -166,222 +296,66 @@ void __PLV_print(PLV *v) {
     }
 }
 
-// Implementation of funcall
-PLV __PL_funcall(PLV *args) {
+// Implementation of funcall: `args` holds the function and its `nargs` arguments
+PLV __PL_funcall(PLV *args, int nargs) {
     if (args[0].type != PL_FUNCPTR && args[0].type != PL_CLOS) {
         fprintf(stderr,
                 "Error: First argument must be a function or closure\n");
         exit(1);
     }
 
-    PLV result;
-
-    if (args[0].type == PL_FUNCPTR) {
-        result = args[0].val.funcptr(args + 1);
-    } else {
-        result = args[0].val.clos.closptr(args[0].val.clos.freevars, args + 1);
-    }
-
-    return result;
-}
-
-// Implementation of Built-in functions
-PLV global_func_add(PLV *args) {
-    PLV result;
-
-    if (args[0].type == PL_INT && args[1].type == PL_INT) {
-        result.type = PL_INT;
-        result.val.n = args[0].val.n + args[1].val.n;
-    } else if (args[0].type == PL_FLOAT && args[1].type == PL_FLOAT) {
-        result.type = PL_FLOAT;
-        result.val.x = args[0].val.x + args[1].val.x;
-    } else {
-        fprintf(stderr, "Error: Type error in addition\n");
-        exit(1);
-    }
-
-    return result;
-}
-
-PLV global_func_sub(PLV *args) {
-    PLV result;
-
-    if (args[0].type == PL_INT && args[1].type == PL_INT) {
-        result.type = PL_INT;
-        result.val.n = args[0].val.n - args[1].val.n;
-    } else if (args[0].type == PL_FLOAT && args[1].type == PL_FLOAT) {
-        result.type = PL_FLOAT;
-        result.val.x = args[0].val.x - args[1].val.x;
-    } else {
-        fprintf(stderr, "Error: Type error in subtraction\n");
+    int arity = args[0].type == PL_FUNCPTR ? args[0].val.func.arity
+                                           : args[0].val.clos.arity;
+    if (arity >= 0 && arity != nargs) {
+        fprintf(stderr, "Error: Function expects %d argument(s), got %d\n", arity,
+                nargs);
         exit(1);
     }
 
-    return result;
-}
-
-PLV global_func_mul(PLV *args) {
     PLV result;
 
-    if (args[0].type == PL_INT && args[1].type == PL_INT) {
-        result.type = PL_INT;
-        result.val.n = args[0].val.n * args[1].val.n;
-    } else if (args[0].type == PL_FLOAT && args[1].type == PL_FLOAT) {
-        result.type = PL_FLOAT;
-        result.val.x = args[0].val.x * args[1].val.x;
-    } else {
-        fprintf(stderr, "Error: Type error in multiplication\n");
-        exit(1);
-    }
-
-    return result;
-}
-
-PLV global_func_div(PLV *args) {
-    PLV result;
-
-    if (args[0].type == PL_INT && args[1].type == PL_INT) {
-        if (args[1].val.n == 0) {
-            fprintf(stderr, "Error: Division by zero\n");
-            exit(1);
-        }
-        result.type = PL_INT;
-        result.val.n = args[0].val.n / args[1].val.n;
-    } else if (args[0].type == PL_FLOAT && args[1].type == PL_FLOAT) {
-        if (args[1].val.x == 0.0) {
-            fprintf(stderr, "Error: Division by zero\n");
-            exit(1);
-        }
-        result.type = PL_FLOAT;
-        result.val.x = args[0].val.x / args[1].val.x;
+    if (args[0].type == PL_FUNCPTR) {
+        result = args[0].val.func.funcptr(args + 1);
     } else {
-        fprintf(stderr, "Error: Type error in division\n");
-        exit(1);
-    }
-
-    return result;
-}
-
-PLV global_func_eq(PLV *args) {
-    PLV result;
-    result.type = PL_BOOL;
-
-    if (args[0].type != args[1].type) {
-        result.val.b = 0; // false
-        return result;
-    }
-
-    switch (args[0].type) {
-    case PL_NIL:
-        result.val.b = 1; // Both nil
-        break;
-    case PL_BOOL:
-        result.val.b = (args[0].val.b == args[1].val.b);
-        break;
-    case PL_INT:
-        result.val.b = (args[0].val.n == args[1].val.n);
-        break;
-    case PL_FLOAT:
-        result.val.b = (args[0].val.x == args[1].val.x);
-        break;
-    case PL_STR:
-        result.val.b = (strcmp(args[0].val.s, args[1].val.s) == 0);
-        break;
-    default:
-        result.val.b = 0; // Other types not comparable
-        break;
+        result = args[0].val.clos.closptr(args[0].val.clos.freevars, args + 1);
     }
 
     return result;
 }
 
-PLV global_func_lt(PLV *args) {
-    PLV result;
-    result.type = PL_BOOL;
-
-    if (args[0].type == PL_INT && args[1].type == PL_INT) {
-        result.val.b = (args[0].val.n < args[1].val.n);
-    } else if (args[0].type == PL_FLOAT && args[1].type == PL_FLOAT) {
-        result.val.b = (args[0].val.x < args[1].val.x);
-    } else {
-        fprintf(stderr, "Error: Type error in less than comparison\n");
+// Implementation of checks used by statically typed code
+void __PL_check_type(PLV *v, PLType type, const char *what) {
+    if (v->type != type) {
+        fprintf(stderr, "Error: Type error in %s\n", what);
         exit(1);
     }
-
-    return result;
 }
 
-PLV global_func_leq(PLV *args) {
-    PLV result;
-    result.type = PL_BOOL;
-
-    if (args[0].type == PL_INT && args[1].type == PL_INT) {
-        result.val.b = (args[0].val.n <= args[1].val.n);
-    } else if (args[0].type == PL_FLOAT && args[1].type == PL_FLOAT) {
-        result.val.b = (args[0].val.x <= args[1].val.x);
-    } else {
-        fprintf(stderr, "Error: Type error in less than or equal comparison\n");
+i64 __PL_idiv(i64 x, i64 y) {
+    if (y == 0) {
+        fprintf(stderr, "Error: Division by zero\n");
         exit(1);
     }
-
-    return result;
+    return x / y;
 }
 
-PLV global_func_gt(PLV *args) {
-    PLV result;
-    result.type = PL_BOOL;
-
-    if (args[0].type == PL_INT && args[1].type == PL_INT) {
-        result.val.b = (args[0].val.n > args[1].val.n);
-    } else if (args[0].type == PL_FLOAT && args[1].type == PL_FLOAT) {
-        result.val.b = (args[0].val.x > args[1].val.x);
-    } else {
-        fprintf(stderr, "Error: Type error in greater than comparison\n");
+f64 __PL_fdiv(f64 x, f64 y) {
+    if (y == 0.0) {
+        fprintf(stderr, "Error: Division by zero\n");
         exit(1);
     }
-
-    return result;
+    return x / y;
 }
 
-PLV global_func_geq(PLV *args) {
-    PLV result;
-    result.type = PL_BOOL;
-
-    if (args[0].type == PL_INT && args[1].type == PL_INT) {
-        result.val.b = (args[0].val.n >= args[1].val.n);
-    } else if (args[0].type == PL_FLOAT && args[1].type == PL_FLOAT) {
-        result.val.b = (args[0].val.x >= args[1].val.x);
-    } else {
-        fprintf(stderr,
-                "Error: Type error in greater than or equal comparison\n");
+// Implementation of the conversion of numeric arguments
+f64 __PL_to_float(PLV *v, const char *what) {
+    if (v->type == PL_INT) {
+        return (f64)v->val.n;
+    } else if (v->type != PL_FLOAT) {
+        fprintf(stderr, "Error: Type error in %s\n", what);
         exit(1);
     }
-
-    return result;
+    return v->val.x;
 }
 
-void print_PLV(PLV *v) {
-    switch (v->type) {
-    case PL_NIL:
-        printf("nil");
-        break;
-    case PL_BOOL:
-        printf(v->val.b ? "true" : "false");
-        break;
-    case PL_INT:
-        printf("%lld", v->val.n);
-        break;
-    case PL_FLOAT:
-        printf("%lf", v->val.x);
-        break;
-    case PL_STR:
-        printf("\"%s\"", v->val.s);
-        break;
-    case PL_FUNCPTR:
-        printf("<function at %p>", (void *)v->val.funcptr);
-        break;
-    case PL_CLOS:
-        printf("<closure %p>", v->val.clos.closptr);
-        break;
-    }
-}
+void print_PLV(PLV *v) { __PLV_print(v); }
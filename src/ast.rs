@@ -1,8 +1,12 @@
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
     fmt,
+    rc::Rc,
 };
 
+use crate::builtins::builtins;
+use crate::intpt::native::NativeFn;
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Expr {
     Nil,
@@ -36,6 +40,7 @@ pub enum Expr {
         name: String,
         args: Vec<String>,
         body: Box<Expr>,
+        sig: Signature,
     },
     LetFun {
         name: String,
@@ -57,24 +62,118 @@ pub enum Expr {
     },
 }
 
-// Primitive symbols (builtin functions)
-pub const PRIMITIVES: [&str; 9] = ["+", "-", "*", "/", "=", "<", "<=", ">", ">="];
+/// Type annotation on a defun parameter or return value
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TypeAnn {
+    Nil,
+    Bool,
+    Int,
+    Float,
+    Str,
+}
+
+/// Annotations of a defun; `None` means the parameter (or return value) is dynamically typed
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Signature {
+    pub params: Vec<Option<TypeAnn>>,
+    pub ret: Option<TypeAnn>,
+}
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone)]
 pub enum Value {
     Nil,
     Bool(bool),
     Int(i64),
     Float(f64),
     Str(String),
+    /// A non-empty list (the empty list is `Nil`). Cells are shared, so that consing
+    /// onto a list or taking its tail doesn't copy it.
+    List(Rc<Cons>),
+    /// A map from strings to values, passed in by the host application
+    Map(BTreeMap<String, Value>),
+    /// Multiple values, returned by `values`
+    Tuple(Vec<Value>),
     Func(fn(Vec<Value>) -> Value),
+    /// A function registered by the host application
+    Native(NativeFn),
     Closure {
         params: Vec<String>,
         body: Expr,
         mappings: HashMap<String, Value>,
+        sig: Signature,
     },
 }
 
+/// A cell of a list: a value consed onto a list, `Nil` or another cell
+#[derive(Debug, Clone)]
+pub struct Cons {
+    pub head: Value,
+    pub tail: Value,
+}
+
+// Dropping a long list cell by cell would overflow the stack
+impl Drop for Cons {
+    fn drop(&mut self) {
+        let mut tail = std::mem::replace(&mut self.tail, Value::Nil);
+        while let Value::List(cell) = tail {
+            match Rc::try_unwrap(cell) {
+                Ok(mut cell) => tail = std::mem::replace(&mut cell.tail, Value::Nil),
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+/// The elements of a list
+pub struct ListIter<'a>(&'a Value);
+
+impl<'a> Iterator for ListIter<'a> {
+    type Item = &'a Value;
+
+    fn next(&mut self) -> Option<&'a Value> {
+        match self.0 {
+            Value::List(cell) => {
+                self.0 = &cell.tail;
+                Some(&cell.head)
+            }
+            _ => None,
+        }
+    }
+}
+
+// Builtin functions compare by address, everything else structurally
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::Nil, Value::Nil) => true,
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::Int(a), Value::Int(b)) => a == b,
+            (Value::Float(a), Value::Float(b)) => a == b,
+            (Value::Str(a), Value::Str(b)) => a == b,
+            (Value::List(a), Value::List(b)) => Rc::ptr_eq(a, b) || self.items().eq(other.items()),
+            (Value::Map(a), Value::Map(b)) => a == b,
+            (Value::Tuple(a), Value::Tuple(b)) => a == b,
+            (Value::Func(a), Value::Func(b)) => std::ptr::fn_addr_eq(*a, *b),
+            (Value::Native(a), Value::Native(b)) => a == b,
+            (
+                Value::Closure {
+                    params: p1,
+                    body: b1,
+                    mappings: m1,
+                    sig: s1,
+                },
+                Value::Closure {
+                    params: p2,
+                    body: b2,
+                    mappings: m2,
+                    sig: s2,
+                },
+            ) => p1 == p2 && b1 == b2 && m1 == m2 && s1 == s2,
+            _ => false,
+        }
+    }
+}
+
 // Display implementation for Expr
 impl fmt::Display for Expr {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -82,8 +181,10 @@ impl fmt::Display for Expr {
             Expr::Nil => write!(f, "nil"),
             Expr::Bool(b) => write!(f, "{}", b),
             Expr::Int(i) => write!(f, "{}", i),
+            // Keep the decimal point so that the literal reads back as a float
+            Expr::Float(fl) if fl.fract() == 0.0 => write!(f, "{:.1}", fl),
             Expr::Float(fl) => write!(f, "{}", fl),
-            Expr::Str(s) => write!(f, "{}", s),
+            Expr::Str(s) => write!(f, "{:?}", s),
             Expr::Id(id) => write!(f, "{}", id),
             Expr::Form(list) => {
                 write!(f, "(")?;
@@ -138,15 +239,17 @@ impl fmt::Display for Expr {
             Expr::Def { x, y } => {
                 write!(f, "(def {} {})", x, y)
             }
-            Expr::Defun { name, args, body } => {
-                write!(f, "(defun {} (", name)?;
-                for (i, arg) in args.iter().enumerate() {
-                    if i > 0 {
-                        write!(f, " ")?;
-                    }
-                    write!(f, "{}", arg)?;
+            Expr::Defun {
+                name,
+                args,
+                body,
+                sig,
+            } => {
+                write!(f, "(defun {} ({})", name, sig.format_params(args))?;
+                if let Some(ret) = sig.ret {
+                    write!(f, " : {}", ret)?;
                 }
-                write!(f, ") {})", body)
+                write!(f, " {})", body)
             }
             Expr::LetFun {
                 name,
@@ -213,12 +316,138 @@ impl fmt::Display for Value {
             Value::Int(i) => write!(f, "{}", i),
             Value::Float(fl) => write!(f, "{}", fl),
             Value::Str(s) => write!(f, "{}", s),
-            Value::Func(_) => write!(f, "<function>"),
+            Value::List(_) => {
+                write!(f, "(")?;
+                for (i, item) in self.items().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, ")")
+            }
+            Value::Map(entries) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in entries.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}: {}", key, value)?;
+                }
+                write!(f, "}}")
+            }
+            Value::Tuple(items) => {
+                write!(f, "(values")?;
+                for item in items {
+                    write!(f, " {}", item)?;
+                }
+                write!(f, ")")
+            }
+            Value::Func(_) | Value::Native(_) => write!(f, "<function>"),
             Value::Closure { params, .. } => write!(f, "<closure:{}>", params.join(" ")),
         }
     }
 }
 
+impl fmt::Display for TypeAnn {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TypeAnn::Nil => write!(f, "nil"),
+            TypeAnn::Bool => write!(f, "bool"),
+            TypeAnn::Int => write!(f, "int"),
+            TypeAnn::Float => write!(f, "float"),
+            TypeAnn::Str => write!(f, "string"),
+        }
+    }
+}
+
+impl TypeAnn {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "nil" => Some(TypeAnn::Nil),
+            "bool" => Some(TypeAnn::Bool),
+            "int" => Some(TypeAnn::Int),
+            "float" => Some(TypeAnn::Float),
+            "string" => Some(TypeAnn::Str),
+            _ => None,
+        }
+    }
+
+    pub fn matches(&self, value: &Value) -> bool {
+        matches!(
+            (self, value),
+            (TypeAnn::Nil, Value::Nil)
+                | (TypeAnn::Bool, Value::Bool(_))
+                | (TypeAnn::Int, Value::Int(_))
+                | (TypeAnn::Float, Value::Float(_))
+                | (TypeAnn::Str, Value::Str(_))
+        )
+    }
+}
+
+impl Signature {
+    /// Annotation of the i-th parameter, if any
+    pub fn param(&self, i: usize) -> Option<TypeAnn> {
+        self.params.get(i).copied().flatten()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ret.is_none() && self.params.iter().all(|p| p.is_none())
+    }
+
+    /// The signature left after the first `n` parameters have been supplied
+    pub fn skip(&self, n: usize) -> Signature {
+        Signature {
+            params: self.params.iter().skip(n).copied().collect(),
+            ret: self.ret,
+        }
+    }
+
+    /// Render a parameter list with its annotations, e.g. `(x : int) y`
+    pub fn format_params(&self, args: &[String]) -> String {
+        args.iter()
+            .enumerate()
+            .map(|(i, arg)| match self.param(i) {
+                Some(ty) => format!("({} : {})", arg, ty),
+                None => arg.clone(),
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+impl Value {
+    /// The list of `items`, `Nil` if there are none
+    pub fn list<I>(items: I) -> Value
+    where
+        I: IntoIterator<Item = Value>,
+        I::IntoIter: DoubleEndedIterator,
+    {
+        items.into_iter().rev().fold(Value::Nil, |tail, head| {
+            Value::List(Rc::new(Cons { head, tail }))
+        })
+    }
+
+    /// The elements of a list; none if the value is not a list
+    pub fn items(&self) -> ListIter<'_> {
+        ListIter(self)
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Nil => "nil",
+            Value::Bool(_) => "bool",
+            Value::Int(_) => "int",
+            Value::Float(_) => "float",
+            Value::Str(_) => "string",
+            Value::List(_) => "list",
+            Value::Map(_) => "map",
+            Value::Tuple(_) => "values",
+            Value::Func(_) | Value::Native(_) | Value::Closure { .. } => "function",
+        }
+    }
+}
+
 impl Expr {
     pub fn is_atom(&self) -> bool {
         matches!(
@@ -228,16 +457,23 @@ impl Expr {
     }
 
     /// Collect free variables in this expression, excluding any variables in `bounded`
+    /// and the builtins
     pub fn free_vars(&self, bounded: &HashSet<String>) -> Vec<String> {
-        let mut free_vars = HashSet::new();
         let mut bounded = bounded.clone();
-        for id in PRIMITIVES.iter() {
-            bounded.insert(id.to_string());
+        for builtin in builtins() {
+            bounded.insert(builtin.name.to_string());
         }
-        self.collect_free_vars_helper(&bounded, &mut free_vars);
+        self.free_ids(&bounded)
+    }
+
+    /// Collect free identifiers in this expression, excluding any variables in `bounded`.
+    /// Unlike `free_vars`, references to builtins are kept, as a local variable may be
+    /// named after one.
+    pub fn free_ids(&self, bounded: &HashSet<String>) -> Vec<String> {
+        let mut free_vars = HashSet::new();
+        self.collect_free_vars_helper(bounded, &mut free_vars);
         let mut result: Vec<String> = free_vars.into_iter().collect();
         result.sort(); // For deterministic output
-        // println!("free_vars: {:?}", result);
         result
     }
 
@@ -313,9 +549,15 @@ impl Expr {
             Expr::LetClos {
                 name,
                 closid: _,
-                freevars: _,
+                freevars,
                 body,
             } => {
+                // The captured variables are used where the closure is made
+                for var in freevars {
+                    if !bounded.contains(var) {
+                        free_vars.insert(var.clone());
+                    }
+                }
                 let mut new_bounded = bounded.clone();
                 new_bounded.insert(name.clone());
                 body.collect_free_vars_helper(&new_bounded, free_vars);
@@ -0,0 +1,381 @@
+//! Arithmetic and comparison builtins
+
+use super::Builtin;
+use crate::ast::Value;
+
+fn add(args: Vec<Value>) -> Value {
+    if args.is_empty() {
+        panic!("No arguments for addition");
+    } else if args.len() != 2 {
+        panic!("Addition requires two arguments");
+    } else {
+        match (args[0].clone(), args[1].clone()) {
+            (Value::Int(x), Value::Int(y)) => Value::Int(x + y),
+            (Value::Float(x), Value::Float(y)) => Value::Float(x + y),
+            _ => panic!("Type error in addition"),
+        }
+    }
+}
+
+fn sub(args: Vec<Value>) -> Value {
+    if args.is_empty() {
+        panic!("No arguments for subtraction");
+    } else if args.len() != 2 {
+        panic!("Subtraction requires two arguments");
+    } else {
+        match (args[0].clone(), args[1].clone()) {
+            (Value::Int(x), Value::Int(y)) => Value::Int(x - y),
+            (Value::Float(x), Value::Float(y)) => Value::Float(x - y),
+            _ => panic!("Type error in subtraction"),
+        }
+    }
+}
+
+fn mul(args: Vec<Value>) -> Value {
+    if args.is_empty() {
+        panic!("No arguments for multiplication");
+    } else if args.len() != 2 {
+        panic!("Multiplication requires two arguments");
+    } else {
+        match (args[0].clone(), args[1].clone()) {
+            (Value::Int(x), Value::Int(y)) => Value::Int(x * y),
+            (Value::Float(x), Value::Float(y)) => Value::Float(x * y),
+            _ => panic!("Type error in multiplication"),
+        }
+    }
+}
+
+fn div(args: Vec<Value>) -> Value {
+    if args.is_empty() {
+        panic!("No arguments for division");
+    } else if args.len() != 2 {
+        panic!("Division requires two arguments");
+    } else {
+        match (args[0].clone(), args[1].clone()) {
+            (Value::Int(x), Value::Int(y)) => Value::Int(x / y),
+            (Value::Float(x), Value::Float(y)) => Value::Float(x / y),
+            _ => panic!("Type error in division"),
+        }
+    }
+}
+
+fn eq(args: Vec<Value>) -> Value {
+    if args.is_empty() {
+        panic!("No arguments for equality");
+    } else if args.len() != 2 {
+        panic!("Equality requires two arguments");
+    } else {
+        Value::Bool(args[0] == args[1])
+    }
+}
+
+fn leq(args: Vec<Value>) -> Value {
+    if args.is_empty() {
+        panic!("No arguments for less than or equal");
+    } else if args.len() != 2 {
+        panic!("Less than or equal requires two arguments");
+    } else {
+        match (args[0].clone(), args[1].clone()) {
+            (Value::Int(x), Value::Int(y)) => Value::Bool(x <= y),
+            (Value::Float(x), Value::Float(y)) => Value::Bool(x <= y),
+            _ => panic!("Type error in less than or equal"),
+        }
+    }
+}
+
+fn lt(args: Vec<Value>) -> Value {
+    if args.is_empty() {
+        panic!("No arguments for less than");
+    } else if args.len() != 2 {
+        panic!("Less than requires two arguments");
+    } else {
+        match (args[0].clone(), args[1].clone()) {
+            (Value::Int(x), Value::Int(y)) => Value::Bool(x < y),
+            (Value::Float(x), Value::Float(y)) => Value::Bool(x < y),
+            _ => panic!("Type error in less than"),
+        }
+    }
+}
+
+fn gt(args: Vec<Value>) -> Value {
+    if args.is_empty() {
+        panic!("No arguments for greater than");
+    } else if args.len() != 2 {
+        panic!("Greater than requires two arguments");
+    } else {
+        match (args[0].clone(), args[1].clone()) {
+            (Value::Int(x), Value::Int(y)) => Value::Bool(x > y),
+            (Value::Float(x), Value::Float(y)) => Value::Bool(x > y),
+            _ => panic!("Type error in greater than"),
+        }
+    }
+}
+
+fn geq(args: Vec<Value>) -> Value {
+    if args.is_empty() {
+        panic!("No arguments for greater than or equal");
+    } else if args.len() != 2 {
+        panic!("Greater than or equal requires two arguments");
+    } else {
+        match (args[0].clone(), args[1].clone()) {
+            (Value::Int(x), Value::Int(y)) => Value::Bool(x >= y),
+            (Value::Float(x), Value::Float(y)) => Value::Bool(x >= y),
+            _ => panic!("Type error in greater than or equal"),
+        }
+    }
+}
+
+pub(super) const BUILTINS: &[Builtin] = &[
+    Builtin {
+        name: "+",
+        arity: 2,
+        func: add,
+        c_name: "global_func_add",
+        c_code: r#"
+PLV global_func_add(PLV *args) {
+    PLV result;
+
+    if (args[0].type == PL_INT && args[1].type == PL_INT) {
+        result.type = PL_INT;
+        result.val.n = args[0].val.n + args[1].val.n;
+    } else if (args[0].type == PL_FLOAT && args[1].type == PL_FLOAT) {
+        result.type = PL_FLOAT;
+        result.val.x = args[0].val.x + args[1].val.x;
+    } else {
+        fprintf(stderr, "Error: Type error in addition\n");
+        exit(1);
+    }
+
+    return result;
+}
+"#,
+    },
+    Builtin {
+        name: "-",
+        arity: 2,
+        func: sub,
+        c_name: "global_func_sub",
+        c_code: r#"
+PLV global_func_sub(PLV *args) {
+    PLV result;
+
+    if (args[0].type == PL_INT && args[1].type == PL_INT) {
+        result.type = PL_INT;
+        result.val.n = args[0].val.n - args[1].val.n;
+    } else if (args[0].type == PL_FLOAT && args[1].type == PL_FLOAT) {
+        result.type = PL_FLOAT;
+        result.val.x = args[0].val.x - args[1].val.x;
+    } else {
+        fprintf(stderr, "Error: Type error in subtraction\n");
+        exit(1);
+    }
+
+    return result;
+}
+"#,
+    },
+    Builtin {
+        name: "*",
+        arity: 2,
+        func: mul,
+        c_name: "global_func_mul",
+        c_code: r#"
+PLV global_func_mul(PLV *args) {
+    PLV result;
+
+    if (args[0].type == PL_INT && args[1].type == PL_INT) {
+        result.type = PL_INT;
+        result.val.n = args[0].val.n * args[1].val.n;
+    } else if (args[0].type == PL_FLOAT && args[1].type == PL_FLOAT) {
+        result.type = PL_FLOAT;
+        result.val.x = args[0].val.x * args[1].val.x;
+    } else {
+        fprintf(stderr, "Error: Type error in multiplication\n");
+        exit(1);
+    }
+
+    return result;
+}
+"#,
+    },
+    Builtin {
+        name: "/",
+        arity: 2,
+        func: div,
+        c_name: "global_func_div",
+        c_code: r#"
+PLV global_func_div(PLV *args) {
+    PLV result;
+
+    if (args[0].type == PL_INT && args[1].type == PL_INT) {
+        if (args[1].val.n == 0) {
+            fprintf(stderr, "Error: Division by zero\n");
+            exit(1);
+        }
+        result.type = PL_INT;
+        result.val.n = args[0].val.n / args[1].val.n;
+    } else if (args[0].type == PL_FLOAT && args[1].type == PL_FLOAT) {
+        if (args[1].val.x == 0.0) {
+            fprintf(stderr, "Error: Division by zero\n");
+            exit(1);
+        }
+        result.type = PL_FLOAT;
+        result.val.x = args[0].val.x / args[1].val.x;
+    } else {
+        fprintf(stderr, "Error: Type error in division\n");
+        exit(1);
+    }
+
+    return result;
+}
+"#,
+    },
+    Builtin {
+        name: "=",
+        arity: 2,
+        func: eq,
+        c_name: "global_func_eq",
+        c_code: r#"
+PLV global_func_eq(PLV *args) {
+    PLV result;
+    result.type = PL_BOOL;
+
+    if (args[0].type != args[1].type) {
+        result.val.b = 0; // false
+        return result;
+    }
+
+    switch (args[0].type) {
+    case PL_NIL:
+        result.val.b = 1; // Both nil
+        break;
+    case PL_BOOL:
+        result.val.b = (args[0].val.b == args[1].val.b);
+        break;
+    case PL_INT:
+        result.val.b = (args[0].val.n == args[1].val.n);
+        break;
+    case PL_FLOAT:
+        result.val.b = (args[0].val.x == args[1].val.x);
+        break;
+    case PL_STR:
+        result.val.b = (strcmp(args[0].val.s, args[1].val.s) == 0);
+        break;
+    case PL_LIST: {
+        PLV heads[2] = {args[0].val.cell->head, args[1].val.cell->head};
+        PLV tails[2] = {args[0].val.cell->tail, args[1].val.cell->tail};
+        result.val.b = global_func_eq(heads).val.b && global_func_eq(tails).val.b;
+        break;
+    }
+    case PL_TUPLE: {
+        PLTuple *a = args[0].val.tuple, *b = args[1].val.tuple;
+        result.val.b = (a->len == b->len);
+        for (int i = 0; i < a->len && result.val.b; i++) {
+            PLV items[2] = {a->items[i], b->items[i]};
+            result.val.b = global_func_eq(items).val.b;
+        }
+        break;
+    }
+    default:
+        result.val.b = 0; // Other types not comparable
+        break;
+    }
+
+    return result;
+}
+"#,
+    },
+    Builtin {
+        name: "<=",
+        arity: 2,
+        func: leq,
+        c_name: "global_func_leq",
+        c_code: r#"
+PLV global_func_leq(PLV *args) {
+    PLV result;
+    result.type = PL_BOOL;
+
+    if (args[0].type == PL_INT && args[1].type == PL_INT) {
+        result.val.b = (args[0].val.n <= args[1].val.n);
+    } else if (args[0].type == PL_FLOAT && args[1].type == PL_FLOAT) {
+        result.val.b = (args[0].val.x <= args[1].val.x);
+    } else {
+        fprintf(stderr, "Error: Type error in less than or equal comparison\n");
+        exit(1);
+    }
+
+    return result;
+}
+"#,
+    },
+    Builtin {
+        name: "<",
+        arity: 2,
+        func: lt,
+        c_name: "global_func_lt",
+        c_code: r#"
+PLV global_func_lt(PLV *args) {
+    PLV result;
+    result.type = PL_BOOL;
+
+    if (args[0].type == PL_INT && args[1].type == PL_INT) {
+        result.val.b = (args[0].val.n < args[1].val.n);
+    } else if (args[0].type == PL_FLOAT && args[1].type == PL_FLOAT) {
+        result.val.b = (args[0].val.x < args[1].val.x);
+    } else {
+        fprintf(stderr, "Error: Type error in less than comparison\n");
+        exit(1);
+    }
+
+    return result;
+}
+"#,
+    },
+    Builtin {
+        name: ">",
+        arity: 2,
+        func: gt,
+        c_name: "global_func_gt",
+        c_code: r#"
+PLV global_func_gt(PLV *args) {
+    PLV result;
+    result.type = PL_BOOL;
+
+    if (args[0].type == PL_INT && args[1].type == PL_INT) {
+        result.val.b = (args[0].val.n > args[1].val.n);
+    } else if (args[0].type == PL_FLOAT && args[1].type == PL_FLOAT) {
+        result.val.b = (args[0].val.x > args[1].val.x);
+    } else {
+        fprintf(stderr, "Error: Type error in greater than comparison\n");
+        exit(1);
+    }
+
+    return result;
+}
+"#,
+    },
+    Builtin {
+        name: ">=",
+        arity: 2,
+        func: geq,
+        c_name: "global_func_geq",
+        c_code: r#"
+PLV global_func_geq(PLV *args) {
+    PLV result;
+    result.type = PL_BOOL;
+
+    if (args[0].type == PL_INT && args[1].type == PL_INT) {
+        result.val.b = (args[0].val.n >= args[1].val.n);
+    } else if (args[0].type == PL_FLOAT && args[1].type == PL_FLOAT) {
+        result.val.b = (args[0].val.x >= args[1].val.x);
+    } else {
+        fprintf(stderr,
+                "Error: Type error in greater than or equal comparison\n");
+        exit(1);
+    }
+
+    return result;
+}
+"#,
+    },
+];
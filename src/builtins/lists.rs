@@ -0,0 +1,115 @@
+//! List builtins. A list is `nil` (the empty list) or a value consed onto a list, stored
+//! in a cell shared by every list it is the tail of, by the interpreter as by the C
+//! runtime.
+
+use std::rc::Rc;
+
+use super::{Builtin, check_arity};
+use crate::ast::{Cons, Value};
+
+fn cons(args: Vec<Value>) -> Value {
+    check_arity("Cons", 2, &args);
+    let mut args = args.into_iter();
+    let (head, tail) = (args.next().unwrap(), args.next().unwrap());
+    match tail {
+        Value::Nil | Value::List(_) => Value::List(Rc::new(Cons { head, tail })),
+        _ => panic!("Type error in cons"),
+    }
+}
+
+/// The first cell of a non-empty list
+fn cell<'a>(value: &'a Value, what: &str) -> &'a Cons {
+    match value {
+        Value::List(cell) => cell,
+        Value::Nil => panic!("{} of an empty list", what),
+        _ => panic!("Type error in {}", what.to_lowercase()),
+    }
+}
+
+fn head(args: Vec<Value>) -> Value {
+    check_arity("Head", 1, &args);
+    cell(&args[0], "Head").head.clone()
+}
+
+fn tail(args: Vec<Value>) -> Value {
+    check_arity("Tail", 1, &args);
+    cell(&args[0], "Tail").tail.clone()
+}
+
+fn is_empty(args: Vec<Value>) -> Value {
+    check_arity("Empty", 1, &args);
+    match &args[0] {
+        Value::Nil => Value::Bool(true),
+        Value::List(_) => Value::Bool(false),
+        _ => panic!("Type error in empty"),
+    }
+}
+
+pub(super) const BUILTINS: &[Builtin] = &[
+    Builtin {
+        name: "cons",
+        arity: 2,
+        func: cons,
+        c_name: "global_func_cons",
+        c_code: r#"
+PLV global_func_cons(PLV *args) {
+    if (args[1].type != PL_NIL && args[1].type != PL_LIST) {
+        fprintf(stderr, "Error: Type error in cons\n");
+        exit(1);
+    }
+    return __new_CONS(args[0], args[1]);
+}
+"#,
+    },
+    Builtin {
+        name: "head",
+        arity: 1,
+        func: head,
+        c_name: "global_func_head",
+        c_code: r#"
+PLV global_func_head(PLV *args) {
+    if (args[0].type == PL_NIL) {
+        fprintf(stderr, "Error: Head of an empty list\n");
+        exit(1);
+    } else if (args[0].type != PL_LIST) {
+        fprintf(stderr, "Error: Type error in head\n");
+        exit(1);
+    }
+    return args[0].val.cell->head;
+}
+"#,
+    },
+    Builtin {
+        name: "tail",
+        arity: 1,
+        func: tail,
+        c_name: "global_func_tail",
+        c_code: r#"
+PLV global_func_tail(PLV *args) {
+    if (args[0].type == PL_NIL) {
+        fprintf(stderr, "Error: Tail of an empty list\n");
+        exit(1);
+    } else if (args[0].type != PL_LIST) {
+        fprintf(stderr, "Error: Type error in tail\n");
+        exit(1);
+    }
+    return args[0].val.cell->tail;
+}
+"#,
+    },
+    Builtin {
+        name: "empty?",
+        arity: 1,
+        func: is_empty,
+        c_name: "global_func_is_empty",
+        c_code: r#"
+PLV global_func_is_empty(PLV *args) {
+    if (args[0].type != PL_NIL && args[0].type != PL_LIST) {
+        fprintf(stderr, "Error: Type error in empty\n");
+        exit(1);
+    }
+    return __new_BOOL(args[0].type == PL_NIL);
+}
+"#,
+    },
+];
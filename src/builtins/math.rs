@@ -0,0 +1,435 @@
+//! Math library builtins
+
+use super::{Builtin, check_arity};
+use crate::ast::Value;
+
+/// A numeric argument converted to a float
+fn to_float(value: &Value, what: &str) -> f64 {
+    match value {
+        Value::Int(x) => *x as f64,
+        Value::Float(x) => *x,
+        _ => panic!("Type error in {}", what),
+    }
+}
+
+/// `base` to the power of `exp`, squaring `base` only while bits of `exp` are left, so
+/// that it overflows only if the result does
+fn int_pow(mut base: i64, mut exp: i64) -> i64 {
+    let mut result: i64 = 1;
+    while exp > 0 {
+        if exp & 1 == 1 {
+            result = result
+                .checked_mul(base)
+                .unwrap_or_else(|| panic!("Integer overflow"));
+        }
+        exp >>= 1;
+        if exp > 0 {
+            base = base
+                .checked_mul(base)
+                .unwrap_or_else(|| panic!("Integer overflow"));
+        }
+    }
+    result
+}
+
+/// Integer operands of `quotient`, `remainder` and `mod`, with a non-zero divisor
+fn int_division_args(args: &[Value], what: &str) -> (i64, i64) {
+    check_arity(what, 2, args);
+    match (&args[0], &args[1]) {
+        (Value::Int(_), Value::Int(0)) => panic!("Division by zero"),
+        (Value::Int(x), Value::Int(y)) => (*x, *y),
+        _ => panic!("Type error in {}", what.to_lowercase()),
+    }
+}
+
+fn sq(args: Vec<Value>) -> Value {
+    check_arity("Square", 1, &args);
+    match &args[0] {
+        Value::Int(x) => Value::Int(x * x),
+        Value::Float(x) => Value::Float(x * x),
+        _ => panic!("Type error in square function"),
+    }
+}
+
+fn sqrt(args: Vec<Value>) -> Value {
+    check_arity("Square root", 1, &args);
+    let x = to_float(&args[0], "square root function");
+    if x < 0.0 {
+        panic!("Cannot compute square root of negative number");
+    }
+    Value::Float(x.sqrt())
+}
+
+fn abs(args: Vec<Value>) -> Value {
+    check_arity("Absolute value", 1, &args);
+    match &args[0] {
+        Value::Int(x) => Value::Int(x.abs()),
+        Value::Float(x) => Value::Float(x.abs()),
+        _ => panic!("Type error in absolute value function"),
+    }
+}
+
+/// Rounding functions leave integers alone and round floats to integral floats
+fn rounding(args: Vec<Value>, what: &str, round: fn(f64) -> f64) -> Value {
+    check_arity(what, 1, &args);
+    match &args[0] {
+        Value::Int(x) => Value::Int(*x),
+        Value::Float(x) => Value::Float(round(*x)),
+        _ => panic!("Type error in {} function", what.to_lowercase()),
+    }
+}
+
+fn floor(args: Vec<Value>) -> Value {
+    rounding(args, "Floor", f64::floor)
+}
+
+fn ceil(args: Vec<Value>) -> Value {
+    rounding(args, "Ceiling", f64::ceil)
+}
+
+/// Rounds half-way cases away from zero, like C's `round`
+fn round(args: Vec<Value>) -> Value {
+    rounding(args, "Round", f64::round)
+}
+
+/// Transcendental functions take any number and return a float
+fn transcendental(args: Vec<Value>, what: &str, func: fn(f64) -> f64) -> Value {
+    check_arity(what, 1, &args);
+    let what = format!("{} function", what.to_lowercase());
+    Value::Float(func(to_float(&args[0], &what)))
+}
+
+fn exp(args: Vec<Value>) -> Value {
+    transcendental(args, "Exponential", f64::exp)
+}
+
+fn log(args: Vec<Value>) -> Value {
+    transcendental(args, "Logarithm", f64::ln)
+}
+
+fn sin(args: Vec<Value>) -> Value {
+    transcendental(args, "Sine", f64::sin)
+}
+
+fn cos(args: Vec<Value>) -> Value {
+    transcendental(args, "Cosine", f64::cos)
+}
+
+fn pow(args: Vec<Value>) -> Value {
+    check_arity("Power", 2, &args);
+    match (&args[0], &args[1]) {
+        (Value::Int(_), Value::Int(y)) if *y < 0 => panic!("Negative exponent in power"),
+        (Value::Int(x), Value::Int(y)) => Value::Int(int_pow(*x, *y)),
+        (Value::Float(x), Value::Float(y)) => Value::Float(x.powf(*y)),
+        _ => panic!("Type error in power function"),
+    }
+}
+
+fn min(args: Vec<Value>) -> Value {
+    check_arity("Minimum", 2, &args);
+    match (&args[0], &args[1]) {
+        (Value::Int(x), Value::Int(y)) => Value::Int(*x.min(y)),
+        (Value::Float(x), Value::Float(y)) => Value::Float(x.min(*y)),
+        _ => panic!("Type error in minimum function"),
+    }
+}
+
+fn max(args: Vec<Value>) -> Value {
+    check_arity("Maximum", 2, &args);
+    match (&args[0], &args[1]) {
+        (Value::Int(x), Value::Int(y)) => Value::Int(*x.max(y)),
+        (Value::Float(x), Value::Float(y)) => Value::Float(x.max(*y)),
+        _ => panic!("Type error in maximum function"),
+    }
+}
+
+/// Integer division truncating toward zero
+fn quotient(args: Vec<Value>) -> Value {
+    let (x, y) = int_division_args(&args, "Quotient");
+    Value::Int(x / y)
+}
+
+/// Remainder of `quotient`, with the sign of the dividend
+fn remainder(args: Vec<Value>) -> Value {
+    let (x, y) = int_division_args(&args, "Remainder");
+    Value::Int(x % y)
+}
+
+/// Modulo with the sign of the divisor
+fn modulo(args: Vec<Value>) -> Value {
+    let (x, y) = int_division_args(&args, "Modulo");
+    let r = x % y;
+    if r != 0 && (r < 0) != (y < 0) {
+        Value::Int(r + y)
+    } else {
+        Value::Int(r)
+    }
+}
+
+pub(super) const BUILTINS: &[Builtin] = &[
+    Builtin {
+        name: "sq",
+        arity: 1,
+        func: sq,
+        c_name: "global_func_sq",
+        c_code: r#"
+PLV global_func_sq(PLV *args) {
+    if (args[0].type == PL_INT) {
+        return __new_INT(args[0].val.n * args[0].val.n);
+    } else if (args[0].type == PL_FLOAT) {
+        return __new_FLOAT(args[0].val.x * args[0].val.x);
+    }
+    fprintf(stderr, "Error: Type error in square function\n");
+    exit(1);
+}
+"#,
+    },
+    Builtin {
+        name: "sqrt",
+        arity: 1,
+        func: sqrt,
+        c_name: "global_func_sqrt",
+        c_code: r#"
+PLV global_func_sqrt(PLV *args) {
+    f64 x = __PL_to_float(&args[0], "square root function");
+    if (x < 0.0) {
+        fprintf(stderr,
+                "Error: Cannot compute square root of negative number\n");
+        exit(1);
+    }
+    return __new_FLOAT(sqrt(x));
+}
+"#,
+    },
+    Builtin {
+        name: "abs",
+        arity: 1,
+        func: abs,
+        c_name: "global_func_abs",
+        c_code: r#"
+PLV global_func_abs(PLV *args) {
+    if (args[0].type == PL_INT) {
+        return __new_INT(args[0].val.n < 0 ? -args[0].val.n : args[0].val.n);
+    } else if (args[0].type == PL_FLOAT) {
+        return __new_FLOAT(fabs(args[0].val.x));
+    }
+    fprintf(stderr, "Error: Type error in absolute value function\n");
+    exit(1);
+}
+"#,
+    },
+    Builtin {
+        name: "floor",
+        arity: 1,
+        func: floor,
+        c_name: "global_func_floor",
+        c_code: r#"
+PLV global_func_floor(PLV *args) {
+    if (args[0].type == PL_INT) {
+        return args[0];
+    } else if (args[0].type == PL_FLOAT) {
+        return __new_FLOAT(floor(args[0].val.x));
+    }
+    fprintf(stderr, "Error: Type error in floor function\n");
+    exit(1);
+}
+"#,
+    },
+    Builtin {
+        name: "ceil",
+        arity: 1,
+        func: ceil,
+        c_name: "global_func_ceil",
+        c_code: r#"
+PLV global_func_ceil(PLV *args) {
+    if (args[0].type == PL_INT) {
+        return args[0];
+    } else if (args[0].type == PL_FLOAT) {
+        return __new_FLOAT(ceil(args[0].val.x));
+    }
+    fprintf(stderr, "Error: Type error in ceiling function\n");
+    exit(1);
+}
+"#,
+    },
+    Builtin {
+        name: "round",
+        arity: 1,
+        func: round,
+        c_name: "global_func_round",
+        c_code: r#"
+PLV global_func_round(PLV *args) {
+    if (args[0].type == PL_INT) {
+        return args[0];
+    } else if (args[0].type == PL_FLOAT) {
+        return __new_FLOAT(round(args[0].val.x));
+    }
+    fprintf(stderr, "Error: Type error in round function\n");
+    exit(1);
+}
+"#,
+    },
+    Builtin {
+        name: "exp",
+        arity: 1,
+        func: exp,
+        c_name: "global_func_exp",
+        c_code: r#"
+PLV global_func_exp(PLV *args) {
+    return __new_FLOAT(exp(__PL_to_float(&args[0], "exponential function")));
+}
+"#,
+    },
+    Builtin {
+        name: "log",
+        arity: 1,
+        func: log,
+        c_name: "global_func_log",
+        c_code: r#"
+PLV global_func_log(PLV *args) {
+    return __new_FLOAT(log(__PL_to_float(&args[0], "logarithm function")));
+}
+"#,
+    },
+    Builtin {
+        name: "sin",
+        arity: 1,
+        func: sin,
+        c_name: "global_func_sin",
+        c_code: r#"
+PLV global_func_sin(PLV *args) {
+    return __new_FLOAT(sin(__PL_to_float(&args[0], "sine function")));
+}
+"#,
+    },
+    Builtin {
+        name: "cos",
+        arity: 1,
+        func: cos,
+        c_name: "global_func_cos",
+        c_code: r#"
+PLV global_func_cos(PLV *args) {
+    return __new_FLOAT(cos(__PL_to_float(&args[0], "cosine function")));
+}
+"#,
+    },
+    Builtin {
+        name: "pow",
+        arity: 2,
+        func: pow,
+        c_name: "global_func_pow",
+        c_code: r#"
+PLV global_func_pow(PLV *args) {
+    if (args[0].type == PL_INT && args[1].type == PL_INT) {
+        if (args[1].val.n < 0) {
+            fprintf(stderr, "Error: Negative exponent in power\n");
+            exit(1);
+        }
+        // Exponentiation by squaring, `base` only while bits of the exponent are left
+        i64 base = args[0].val.n, result = 1;
+        for (i64 n = args[1].val.n; n > 0; n >>= 1) {
+            if (((n & 1) && __builtin_mul_overflow(result, base, &result)) ||
+                (n > 1 && __builtin_mul_overflow(base, base, &base))) {
+                fprintf(stderr, "Error: Integer overflow\n");
+                exit(1);
+            }
+        }
+        return __new_INT(result);
+    } else if (args[0].type == PL_FLOAT && args[1].type == PL_FLOAT) {
+        return __new_FLOAT(pow(args[0].val.x, args[1].val.x));
+    }
+    fprintf(stderr, "Error: Type error in power function\n");
+    exit(1);
+}
+"#,
+    },
+    Builtin {
+        name: "min",
+        arity: 2,
+        func: min,
+        c_name: "global_func_min",
+        c_code: r#"
+PLV global_func_min(PLV *args) {
+    if (args[0].type == PL_INT && args[1].type == PL_INT) {
+        return args[0].val.n <= args[1].val.n ? args[0] : args[1];
+    } else if (args[0].type == PL_FLOAT && args[1].type == PL_FLOAT) {
+        return __new_FLOAT(fmin(args[0].val.x, args[1].val.x));
+    }
+    fprintf(stderr, "Error: Type error in minimum function\n");
+    exit(1);
+}
+"#,
+    },
+    Builtin {
+        name: "max",
+        arity: 2,
+        func: max,
+        c_name: "global_func_max",
+        c_code: r#"
+PLV global_func_max(PLV *args) {
+    if (args[0].type == PL_INT && args[1].type == PL_INT) {
+        return args[0].val.n >= args[1].val.n ? args[0] : args[1];
+    } else if (args[0].type == PL_FLOAT && args[1].type == PL_FLOAT) {
+        return __new_FLOAT(fmax(args[0].val.x, args[1].val.x));
+    }
+    fprintf(stderr, "Error: Type error in maximum function\n");
+    exit(1);
+}
+"#,
+    },
+    Builtin {
+        name: "quotient",
+        arity: 2,
+        func: quotient,
+        c_name: "global_func_quotient",
+        c_code: r#"
+PLV global_func_quotient(PLV *args) {
+    if (args[0].type != PL_INT || args[1].type != PL_INT) {
+        fprintf(stderr, "Error: Type error in quotient\n");
+        exit(1);
+    }
+    return __new_INT(__PL_idiv(args[0].val.n, args[1].val.n));
+}
+"#,
+    },
+    Builtin {
+        name: "remainder",
+        arity: 2,
+        func: remainder,
+        c_name: "global_func_remainder",
+        c_code: r#"
+PLV global_func_remainder(PLV *args) {
+    if (args[0].type != PL_INT || args[1].type != PL_INT) {
+        fprintf(stderr, "Error: Type error in remainder\n");
+        exit(1);
+    }
+    if (args[1].val.n == 0) {
+        fprintf(stderr, "Error: Division by zero\n");
+        exit(1);
+    }
+    return __new_INT(args[0].val.n % args[1].val.n);
+}
+"#,
+    },
+    Builtin {
+        name: "mod",
+        arity: 2,
+        func: modulo,
+        c_name: "global_func_mod",
+        c_code: r#"
+PLV global_func_mod(PLV *args) {
+    if (args[0].type != PL_INT || args[1].type != PL_INT) {
+        fprintf(stderr, "Error: Type error in modulo\n");
+        exit(1);
+    }
+    i64 y = args[1].val.n;
+    if (y == 0) {
+        fprintf(stderr, "Error: Division by zero\n");
+        exit(1);
+    }
+    i64 r = args[0].val.n % y;
+    return __new_INT(r != 0 && (r < 0) != (y < 0) ? r + y : r);
+}
+"#,
+    },
+];
@@ -0,0 +1,191 @@
+//! Registry of the builtin functions. Each builtin is implemented once in Rust for the
+//! interpreter and once in C for the compiled runtime; the interpreter prelude, the
+//! free variable analysis and the code generator all read this registry.
+
+mod basics;
+mod lists;
+mod math;
+mod values;
+
+use crate::ast::Value;
+
+/// A builtin function of PureLisp
+#[derive(Debug)]
+pub struct Builtin {
+    /// Name of the builtin in PureLisp programs
+    pub name: &'static str,
+    /// Number of arguments, or `VARIADIC`
+    pub arity: usize,
+    /// Implementation used by the interpreter
+    pub func: fn(Vec<Value>) -> Value,
+    /// Name of the C function implementing the builtin in the runtime
+    pub c_name: &'static str,
+    /// Definition of the C function, taking its arguments as `PLV *args`
+    pub c_code: &'static str,
+}
+
+/// Arity of a builtin taking any number of arguments
+pub const VARIADIC: usize = usize::MAX;
+
+/// Every builtin, in the order they are loaded
+pub fn builtins() -> impl Iterator<Item = &'static Builtin> {
+    basics::BUILTINS
+        .iter()
+        .chain(math::BUILTINS)
+        .chain(lists::BUILTINS)
+        .chain(values::BUILTINS)
+}
+
+/// The builtin named `name`
+pub fn lookup(name: &str) -> Option<&'static Builtin> {
+    builtins().find(|builtin| builtin.name == name)
+}
+
+pub fn is_builtin(name: &str) -> bool {
+    lookup(name).is_some()
+}
+
+/// C prototypes followed by C definitions of every builtin
+pub fn c_code() -> String {
+    let prototypes: Vec<String> = builtins()
+        .map(|builtin| format!("PLV {}(PLV *args);", builtin.c_name))
+        .collect();
+    let definitions: Vec<&str> = builtins().map(|builtin| builtin.c_code.trim()).collect();
+    format!(
+        "// Built-in function prototypes\n{}\n\n// Implementation of Built-in functions\n{}\n",
+        prototypes.join("\n"),
+        definitions.join("\n\n")
+    )
+}
+
+/// Panic unless `args` holds exactly `arity` arguments
+fn check_arity(what: &str, arity: usize, args: &[Value]) {
+    if args.len() != arity {
+        let plural = if arity == 1 { "" } else { "s" };
+        panic!("{} requires exactly {} argument{}", what, arity, plural);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::create_environment;
+
+    #[test]
+    fn test_builtins_registry() {
+        let env = create_environment();
+        let mut names = HashSet::new();
+        let mut c_names = HashSet::new();
+        for builtin in builtins() {
+            assert!(names.insert(builtin.name), "{}", builtin.name);
+            assert!(c_names.insert(builtin.c_name), "{}", builtin.c_name);
+            // Builtins are C functions known to the code generator by their prefix
+            assert!(
+                builtin.c_name.starts_with("global_func_"),
+                "{}",
+                builtin.c_name
+            );
+            assert!(
+                builtin
+                    .c_code
+                    .contains(&format!("PLV {}(PLV *args)", builtin.c_name)),
+                "{}",
+                builtin.name
+            );
+            assert!(matches!(env.get(builtin.name), Some(Value::Func(_))));
+        }
+        assert!(is_builtin("sqrt") && is_builtin("remainder"));
+        assert!(!is_builtin("square"));
+    }
+
+    #[test]
+    fn test_math_builtins() {
+        let call = |name: &str, args: Vec<Value>| (lookup(name).unwrap().func)(args);
+        let (i, x) = (Value::Int, Value::Float);
+        assert_eq!(call("floor", vec![x(-2.5)]), x(-3.0));
+        assert_eq!(call("ceil", vec![x(2.25)]), x(3.0));
+        assert_eq!(call("round", vec![x(2.5)]), x(3.0));
+        assert_eq!(call("round", vec![i(7)]), i(7));
+        assert_eq!(call("exp", vec![i(0)]), x(1.0));
+        assert_eq!(call("log", vec![x(1.0)]), x(0.0));
+        assert_eq!(call("sin", vec![i(0)]), x(0.0));
+        assert_eq!(call("cos", vec![x(0.0)]), x(1.0));
+        assert_eq!(call("pow", vec![i(2), i(10)]), i(1024));
+        // Powers at the edge of the range do not overflow
+        assert_eq!(call("pow", vec![i(-2), i(63)]), i(i64::MIN));
+        assert_eq!(call("pow", vec![i(1), i(i64::MAX)]), i(1));
+        assert_eq!(call("pow", vec![x(4.0), x(0.5)]), x(2.0));
+        assert_eq!(call("min", vec![i(3), i(-1)]), i(-1));
+        assert_eq!(call("max", vec![x(3.0), x(-1.0)]), x(3.0));
+        // `quotient` truncates; `remainder` takes the sign of the dividend and `mod`
+        // the sign of the divisor
+        assert_eq!(call("quotient", vec![i(-7), i(2)]), i(-3));
+        assert_eq!(call("remainder", vec![i(-7), i(2)]), i(-1));
+        assert_eq!(call("mod", vec![i(-7), i(2)]), i(1));
+        assert_eq!(call("mod", vec![i(7), i(-2)]), i(-1));
+        assert_eq!(call("mod", vec![i(6), i(-2)]), i(0));
+    }
+
+    #[test]
+    fn test_list_builtins() {
+        let call = |name: &str, args: Vec<Value>| (lookup(name).unwrap().func)(args);
+        let one = call("cons", vec![Value::Int(1), Value::Nil]);
+        let list = call("cons", vec![Value::Int(0), one.clone()]);
+        assert_eq!(list.to_string(), "(0 1)");
+        assert_eq!(call("head", vec![list.clone()]), Value::Int(0));
+        let rest = call("tail", vec![list.clone()]);
+        assert_eq!(rest, Value::list([Value::Int(1)]));
+        // The tail is the list that was consed onto, not a copy of it
+        match (&rest, &one) {
+            (Value::List(a), Value::List(b)) => assert!(std::rc::Rc::ptr_eq(a, b)),
+            _ => unreachable!(),
+        }
+        // The empty list is nil
+        assert_eq!(call("tail", vec![rest]), Value::Nil);
+        assert_eq!(call("empty?", vec![Value::Nil]), Value::Bool(true));
+        assert_eq!(call("empty?", vec![list]), Value::Bool(false));
+    }
+
+    #[test]
+    #[should_panic(expected = "Head of an empty list")]
+    fn test_head_of_empty_list() {
+        (lookup("head").unwrap().func)(vec![Value::Nil]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Division by zero")]
+    fn test_mod_by_zero() {
+        (lookup("mod").unwrap().func)(vec![Value::Int(1), Value::Int(0)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Integer overflow")]
+    fn test_pow_overflow() {
+        (lookup("pow").unwrap().func)(vec![Value::Int(2), Value::Int(100)]);
+    }
+
+    #[test]
+    fn test_values_builtins() {
+        let call = |name: &str, args: Vec<Value>| (lookup(name).unwrap().func)(args);
+        let values = call("values", vec![Value::Int(1), Value::Str("two".into())]);
+        assert_eq!(values.to_string(), "(values 1 two)");
+        let values_ref =
+            |v: &Value, n, i| call("values-ref", vec![v.clone(), Value::Int(n), Value::Int(i)]);
+        assert_eq!(values_ref(&values, 2, 1), Value::Str("two".into()));
+    }
+
+    #[test]
+    #[should_panic(expected = "Expected 3 values, found 2")]
+    fn test_values_ref_count() {
+        let values = Value::Tuple(vec![Value::Int(1), Value::Int(2)]);
+        (lookup("values-ref").unwrap().func)(vec![values, Value::Int(3), Value::Int(0)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Power requires exactly 2 arguments")]
+    fn test_builtin_arity() {
+        (lookup("pow").unwrap().func)(vec![Value::Int(1)]);
+    }
+}
@@ -0,0 +1,67 @@
+//! Multiple values. `values` returns its arguments together, which `let-values`
+//! takes apart again with `values-ref`. The compiler returns them in a C struct where it
+//! can, and only allocates them on the heap when they are used as a single value.
+
+use super::{Builtin, VARIADIC, check_arity};
+use crate::ast::Value;
+
+fn values(args: Vec<Value>) -> Value {
+    Value::Tuple(args)
+}
+
+/// `(values-ref v n i)`: the value `i` of the `n` values `v`
+fn values_ref(args: Vec<Value>) -> Value {
+    check_arity("Values-ref", 3, &args);
+    match (&args[0], &args[1], &args[2]) {
+        (Value::Tuple(items), Value::Int(n), Value::Int(i)) => {
+            if items.len() as i64 != *n {
+                panic!("Expected {} values, found {}", n, items.len());
+            }
+            match items.get(*i as usize) {
+                Some(item) if *i >= 0 => item.clone(),
+                _ => panic!("Index out of range in values-ref"),
+            }
+        }
+        _ => panic!("Type error in values-ref"),
+    }
+}
+
+pub(super) const BUILTINS: &[Builtin] = &[
+    Builtin {
+        name: "values",
+        arity: VARIADIC,
+        func: values,
+        c_name: "global_func_values",
+        // Calls to `values` are generated inline, where the number of values is known
+        c_code: r#"
+PLV global_func_values(PLV *args) {
+    fprintf(stderr, "Error: values can only be called directly\n");
+    exit(1);
+}
+"#,
+    },
+    Builtin {
+        name: "values-ref",
+        arity: 3,
+        func: values_ref,
+        c_name: "global_func_values_ref",
+        c_code: r#"
+PLV global_func_values_ref(PLV *args) {
+    if (args[0].type != PL_TUPLE || args[1].type != PL_INT || args[2].type != PL_INT) {
+        fprintf(stderr, "Error: Type error in values-ref\n");
+        exit(1);
+    }
+    if (args[0].val.tuple->len != args[1].val.n) {
+        fprintf(stderr, "Error: Expected %lld values, found %d\n", args[1].val.n,
+                args[0].val.tuple->len);
+        exit(1);
+    }
+    if (args[2].val.n < 0 || args[2].val.n >= args[1].val.n) {
+        fprintf(stderr, "Error: Index out of range in values-ref\n");
+        exit(1);
+    }
+    return args[0].val.tuple->items[args[2].val.n];
+}
+"#,
+    },
+];
@@ -1,90 +1,293 @@
+use std::collections::HashSet;
+
+use super::util::NameGenerator;
 use crate::Expr;
 
-pub fn a_normal(expr: Expr, cont: Box<dyn FnOnce(Expr) -> Expr>) -> Expr {
+struct ANormalizer {
+    /// Every identifier of the program, so that renamed binders are fresh
+    used: HashSet<String>,
+    namer: NameGenerator,
+}
+
+impl ANormalizer {
+    /// Normalize `expr`, whose value is passed to `cont`. `cont_free` holds the free
+    /// variables of the code `cont` wraps around the value: binders that are moved
+    /// over that code are renamed if they would capture one of them.
+    fn a_normal(
+        &mut self,
+        expr: Expr,
+        cont: Box<dyn FnOnce(Expr) -> Expr>,
+        cont_free: &HashSet<String>,
+    ) -> Expr {
+        let no_free = HashSet::new();
+        match expr {
+            Expr::Nil
+            | Expr::Int(_)
+            | Expr::Float(_)
+            | Expr::Str(_)
+            | Expr::Bool(_)
+            | Expr::Id(_)
+            | Expr::Form(_) => cont(expr),
+            Expr::Let { bindings, body } => {
+                assert!(
+                    bindings.len() == 1,
+                    "A-normalization only supports single binding let (have you k-normalized it in advance?)"
+                );
+                let (x, e) = bindings.into_iter().next().unwrap();
+                let (x, body) = if cont_free.contains(&x) {
+                    let new_x = self.fresh();
+                    let body = rename_free(*body, &x, &new_x);
+                    (new_x, body)
+                } else {
+                    (x, *body)
+                };
+                let new_body = self.a_normal(body, cont, cont_free);
+                let mut body_free: HashSet<String> =
+                    new_body.free_ids(&no_free).into_iter().collect();
+                body_free.remove(&x);
+                self.a_normal(
+                    e,
+                    Box::new(move |c| Expr::Let {
+                        bindings: vec![(x, c)],
+                        body: Box::new(new_body),
+                    }),
+                    &body_free,
+                )
+            }
+            Expr::If { cond, then, else_ } => {
+                let new_then = Box::new(self.a_normal(*then, Box::new(|e| e), &no_free));
+                let new_else = Box::new(self.a_normal(*else_, Box::new(|e| e), &no_free));
+                cont(Expr::If {
+                    cond,
+                    then: new_then,
+                    else_: new_else,
+                })
+            }
+            Expr::LetFun {
+                name,
+                args,
+                fun_body,
+                expr_body,
+            } => {
+                let (name, fun_body, expr_body) = if cont_free.contains(&name) {
+                    let new_name = self.fresh();
+                    let fun_body = if args.contains(&name) {
+                        *fun_body
+                    } else {
+                        rename_free(*fun_body, &name, &new_name)
+                    };
+                    let expr_body = rename_free(*expr_body, &name, &new_name);
+                    (new_name, fun_body, expr_body)
+                } else {
+                    (name, *fun_body, *expr_body)
+                };
+                let new_fun_body = Box::new(self.a_normal(fun_body, Box::new(|e| e), &no_free));
+                let new_expr_body = Box::new(self.a_normal(expr_body, cont, cont_free));
+                Expr::LetFun {
+                    name,
+                    args,
+                    fun_body: new_fun_body,
+                    expr_body: new_expr_body,
+                }
+            }
+            Expr::Fn { args, body } => {
+                let new_body = Box::new(self.a_normal(*body, Box::new(|e| e), &no_free));
+                let new_expr = Expr::Fn {
+                    args,
+                    body: new_body,
+                };
+                cont(new_expr)
+            }
+            Expr::Def { x, y } => {
+                let new_y = Box::new(self.a_normal(*y, Box::new(|e| e), &no_free));
+                Expr::Def { x, y: new_y }
+            }
+            Expr::Defun {
+                name,
+                args,
+                body,
+                sig,
+            } => {
+                let new_body = Box::new(self.a_normal(*body, Box::new(|e| e), &no_free));
+                Expr::Defun {
+                    name,
+                    args,
+                    body: new_body,
+                    sig,
+                }
+            }
+            Expr::And(_)
+            | Expr::Or(_)
+            | Expr::Not(_)
+            | Expr::DefClos { .. }
+            | Expr::LetClos { .. } => {
+                panic!("Invalid Expr for A-normalization: {}", expr)
+            }
+        }
+    }
+
+    fn fresh(&mut self) -> String {
+        loop {
+            let name = self.namer.next("%a");
+            if self.used.insert(name.clone()) {
+                return name;
+            }
+        }
+    }
+}
+
+/// Replace the free occurrences of `from` in a K-normalized `expr` by `to`, which
+/// must not be bound in `expr`
+fn rename_free(expr: Expr, from: &str, to: &str) -> Expr {
     match expr {
-        Expr::Nil
-        | Expr::Int(_)
-        | Expr::Float(_)
-        | Expr::Str(_)
-        | Expr::Bool(_)
-        | Expr::Id(_)
-        | Expr::Form(_) => cont(expr),
-        Expr::Let { bindings, body } => {
-            assert!(
-                bindings.len() == 1,
-                "A-normalization only supports single binding let (have you k-normalized it in advance?)"
-            );
-            let (x, e) = &bindings[0];
-            let (x, e) = (x.clone(), e.clone());
-            let new_body = a_normal(*body, cont);
-            a_normal(
-                e.clone(),
-                Box::new(move |c| Expr::Let {
-                    bindings: vec![(x.clone(), c)],
-                    body: Box::new(new_body),
-                }),
-            )
+        Expr::Id(id) if id == from => Expr::Id(to.to_string()),
+        Expr::Nil | Expr::Bool(_) | Expr::Int(_) | Expr::Float(_) | Expr::Str(_) | Expr::Id(_) => {
+            expr
         }
-        Expr::If { cond, then, else_ } => {
-            let new_then = Box::new(a_normal(*then, Box::new(|e| e)));
-            let new_else = Box::new(a_normal(*else_, Box::new(|e| e)));
-            cont(Expr::If {
-                cond,
-                then: new_then,
-                else_: new_else,
-            })
+        Expr::Form(form) => {
+            Expr::Form(form.into_iter().map(|e| rename_free(e, from, to)).collect())
         }
+        Expr::Let { bindings, body } => {
+            let shadowed = bindings.iter().any(|(x, _)| x == from);
+            let bindings = bindings
+                .into_iter()
+                .map(|(x, e)| (x, rename_free(e, from, to)))
+                .collect();
+            let body = if shadowed {
+                *body
+            } else {
+                rename_free(*body, from, to)
+            };
+            Expr::Let {
+                bindings,
+                body: Box::new(body),
+            }
+        }
+        Expr::If { cond, then, else_ } => Expr::If {
+            cond: Box::new(rename_free(*cond, from, to)),
+            then: Box::new(rename_free(*then, from, to)),
+            else_: Box::new(rename_free(*else_, from, to)),
+        },
+        Expr::Fn { args, body } if !args.iter().any(|x| x == from) => Expr::Fn {
+            args,
+            body: Box::new(rename_free(*body, from, to)),
+        },
         Expr::LetFun {
             name,
             args,
             fun_body,
             expr_body,
-        } => {
-            let new_fun_body = Box::new(a_normal(*fun_body, Box::new(|e| e)));
-            let new_expr_body = Box::new(a_normal(*expr_body, cont));
+        } if name != from => {
+            let fun_body = if args.iter().any(|x| x == from) {
+                *fun_body
+            } else {
+                rename_free(*fun_body, from, to)
+            };
             Expr::LetFun {
                 name,
                 args,
-                fun_body: new_fun_body,
-                expr_body: new_expr_body,
+                fun_body: Box::new(fun_body),
+                expr_body: Box::new(rename_free(*expr_body, from, to)),
             }
         }
+        _ => expr,
+    }
+}
+
+/// Every identifier occurring in `expr`, bound or free
+fn collect_names(expr: &Expr, names: &mut HashSet<String>) {
+    match expr {
+        Expr::Id(id) => {
+            names.insert(id.clone());
+        }
+        Expr::Nil | Expr::Bool(_) | Expr::Int(_) | Expr::Float(_) | Expr::Str(_) => {}
+        Expr::Form(list) | Expr::And(list) | Expr::Or(list) => {
+            list.iter().for_each(|e| collect_names(e, names));
+        }
+        Expr::Not(e) => collect_names(e, names),
+        Expr::Let { bindings, body } => {
+            for (x, e) in bindings {
+                names.insert(x.clone());
+                collect_names(e, names);
+            }
+            collect_names(body, names);
+        }
+        Expr::If { cond, then, else_ } => {
+            collect_names(cond, names);
+            collect_names(then, names);
+            collect_names(else_, names);
+        }
         Expr::Fn { args, body } => {
-            let new_body = Box::new(a_normal(*body, Box::new(|e| e)));
-            let new_expr = Expr::Fn {
-                args,
-                body: new_body,
-            };
-            cont(new_expr)
+            names.extend(args.iter().cloned());
+            collect_names(body, names);
         }
         Expr::Def { x, y } => {
-            let new_y = Box::new(a_normal(*y, Box::new(|e| e)));
-            Expr::Def { x, y: new_y }
+            names.insert(x.clone());
+            collect_names(y, names);
         }
-        Expr::Defun { name, args, body } => {
-            let new_body = Box::new(a_normal(*body, Box::new(|e| e)));
-            Expr::Defun {
-                name,
-                args,
-                body: new_body,
-            }
+        Expr::Defun {
+            name, args, body, ..
+        } => {
+            names.insert(name.clone());
+            names.extend(args.iter().cloned());
+            collect_names(body, names);
+        }
+        Expr::LetFun {
+            name,
+            args,
+            fun_body,
+            expr_body,
+        } => {
+            names.insert(name.clone());
+            names.extend(args.iter().cloned());
+            collect_names(fun_body, names);
+            collect_names(expr_body, names);
         }
-        Expr::And(_) | Expr::Or(_) | Expr::Not(_) | Expr::DefClos { .. } | Expr::LetClos { .. } => {
-            panic!("Invalid Expr for A-normalization: {}", expr)
+        Expr::DefClos {
+            name,
+            freevars,
+            args,
+            body,
+        } => {
+            names.insert(name.clone());
+            names.extend(freevars.iter().chain(args).cloned());
+            collect_names(body, names);
+        }
+        Expr::LetClos {
+            name,
+            closid,
+            freevars,
+            body,
+        } => {
+            names.insert(name.clone());
+            names.insert(closid.clone());
+            names.extend(freevars.iter().cloned());
+            collect_names(body, names);
         }
     }
 }
 
 pub fn a_normalize(prog: Vec<Expr>) -> Vec<Expr> {
-    prog.iter()
-        .map(|expr| a_normal(expr.clone(), Box::new(|e| e)))
+    let mut used = HashSet::new();
+    for expr in &prog {
+        collect_names(expr, &mut used);
+    }
+    let mut normalizer = ANormalizer {
+        used,
+        namer: NameGenerator::new(),
+    };
+    prog.into_iter()
+        .map(|expr| normalizer.a_normal(expr, Box::new(|e| e), &HashSet::new()))
         .collect()
 }
 
 #[cfg(test)]
 mod test {
     use crate::{
+        Value,
         compl::{knormal::k_normalize, util::NameGenerator},
+        create_environment, format_prog,
+        intpt::file::process_string,
         pretty_format, read_string,
     };
 
@@ -120,6 +323,23 @@ mod test {
         println!("a-normalized: {}", pretty_format(&aprog[0]));
     }
 
+    #[test]
+    fn anormal_test_no_capture() {
+        // Flattening the inner `letfun`/`let` must not capture the outer `f`/`y`/`x`
+        let prog = read_string(
+            "(letfun (f (x) (+ x 1)) (f (letfun (f (x) (* x 2)) (f 5))))\n\
+             (let ((y 1)) (+ y (let ((y 10)) (* y 2))))\n\
+             (def x 0)\n\
+             (if (let ((x 1)) false) 1 x)",
+        )
+        .unwrap();
+        let kprog = k_normalize(prog, &mut NameGenerator::new());
+        let aprog = a_normalize(kprog);
+        let results = process_string(&format_prog(&aprog), &mut create_environment()).unwrap();
+        assert_eq!(results[..2], [Value::Int(11), Value::Int(21)]);
+        assert_eq!(results[3], Value::Int(0));
+    }
+
     #[test]
     fn anormal_test_fn() {
         let prog = read_string("(let ((f (fn (x) (+ (* x x) x)))) (f (+ 1 (* 2 3))))").unwrap();
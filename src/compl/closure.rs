@@ -2,10 +2,14 @@ use std::collections::HashSet;
 
 use super::util::NameGenerator;
 use crate::Expr;
+use crate::ast::Signature;
+use crate::builtins::is_builtin;
 
 struct ClosureConverter {
     global_defs: Vec<Expr>,
     freevars_exclude: HashSet<String>,
+    /// Local variables in scope, innermost last; they may shadow builtins
+    locals: Vec<String>,
 }
 
 impl ClosureConverter {
@@ -13,6 +17,7 @@ impl ClosureConverter {
         ClosureConverter {
             global_defs: Vec::new(),
             freevars_exclude: HashSet::new(),
+            locals: Vec::new(),
         }
     }
     fn id_map(expr: Expr, from: &str, to: &str) -> Expr {
@@ -55,8 +60,13 @@ impl ClosureConverter {
                 let new_bindings = bindings
                     .into_iter()
                     .map(|(x, e)| (x, Self::id_map(e, from, to)))
-                    .collect();
-                let new_body = Box::new(Self::id_map(*body, from, to));
+                    .collect::<Vec<_>>();
+                // A binder named `from` shadows it in the body
+                let new_body = if new_bindings.iter().any(|(x, _)| x == from) {
+                    body
+                } else {
+                    Box::new(Self::id_map(*body, from, to))
+                };
                 Expr::Let {
                     bindings: new_bindings,
                     body: new_body,
@@ -67,8 +77,12 @@ impl ClosureConverter {
                 args,
                 fun_body,
                 expr_body,
-            } => {
-                let new_fun_body = Box::new(Self::id_map(*fun_body, from, to));
+            } if name != from => {
+                let new_fun_body = if args.iter().any(|arg| arg == from) {
+                    fun_body
+                } else {
+                    Box::new(Self::id_map(*fun_body, from, to))
+                };
                 let new_expr_body = Box::new(Self::id_map(*expr_body, from, to));
                 Expr::LetFun {
                     name,
@@ -77,17 +91,26 @@ impl ClosureConverter {
                     expr_body: new_expr_body,
                 }
             }
+            Expr::LetFun { .. } => expr,
             Expr::LetClos {
                 name,
                 closid,
                 freevars,
                 body,
             } => {
-                let new_body = Box::new(Self::id_map(*body, from, to));
+                let new_freevars = freevars
+                    .into_iter()
+                    .map(|var| if var == from { to.to_string() } else { var })
+                    .collect();
+                let new_body = if name == from {
+                    body
+                } else {
+                    Box::new(Self::id_map(*body, from, to))
+                };
                 Expr::LetClos {
                     name,
                     closid,
-                    freevars,
+                    freevars: new_freevars,
                     body: new_body,
                 }
             }
@@ -134,7 +157,9 @@ impl ClosureConverter {
                 );
                 let (x, e) = bindings[0].clone();
                 let new_e = self.convert_helper(e, namer);
+                self.locals.push(x.clone());
                 let new_body = self.convert_helper(*body, namer);
+                self.locals.pop();
                 Expr::Let {
                     bindings: vec![(x, new_e)],
                     body: Box::new(new_body),
@@ -146,8 +171,13 @@ impl ClosureConverter {
                 fun_body,
                 expr_body,
             } => {
+                let mark = self.locals.len();
+                self.locals.push(name.clone());
+                self.locals.extend(args.iter().cloned());
                 let new_fun_body = self.convert_helper(*fun_body, namer);
+                self.locals.truncate(mark + 1);
                 let new_expr_body = self.convert_helper(*expr_body, namer);
+                self.locals.truncate(mark);
 
                 // Create bounded set for function body
                 // (includes the function name, args and the global labels generated so far)
@@ -157,17 +187,27 @@ impl ClosureConverter {
                     fun_bounded.insert(arg.clone());
                 }
 
-                let freevars: Vec<String> = new_fun_body.free_vars(&fun_bounded);
+                // Builtins are not captured, unless a local variable shadows them
+                let freevars: Vec<String> = new_fun_body
+                    .free_ids(&fun_bounded)
+                    .into_iter()
+                    .filter(|var| !is_builtin(var) || self.locals.contains(var))
+                    .collect();
                 let closid = namer.next("@f");
                 self.freevars_exclude.insert(closid.clone());
-                let new_fun_body = Self::id_map(new_fun_body, &name, &closid);
+                let new_fun_body = if args.contains(&name) {
+                    new_fun_body
+                } else {
+                    Self::id_map(new_fun_body, &name, &closid)
+                };
                 if freevars.is_empty() {
                     self.global_defs.push(Expr::Defun {
                         name: closid.clone(),
                         args,
                         body: Box::new(new_fun_body),
+                        sig: Signature::default(),
                     });
-                    return Self::id_map(new_expr_body, &name, &closid);
+                    Self::id_map(new_expr_body, &name, &closid)
                 } else {
                     self.global_defs.push(Expr::DefClos {
                         name: closid.clone(),
@@ -175,12 +215,12 @@ impl ClosureConverter {
                         args,
                         body: Box::new(new_fun_body),
                     });
-                    return Expr::LetClos {
+                    Expr::LetClos {
                         name,
                         closid,
                         freevars,
                         body: Box::new(new_expr_body),
-                    };
+                    }
                 }
             }
             Expr::Def { x, y } => {
@@ -190,12 +230,20 @@ impl ClosureConverter {
                     y: Box::new(new_y),
                 }
             }
-            Expr::Defun { name, args, body } => {
+            Expr::Defun {
+                name,
+                args,
+                body,
+                sig,
+            } => {
+                self.locals.extend(args.iter().cloned());
                 let new_body = self.convert_helper(*body, namer);
+                self.locals.clear();
                 Expr::Defun {
                     name,
                     args,
                     body: Box::new(new_body),
+                    sig,
                 }
             }
         }
@@ -221,11 +269,14 @@ pub fn closure_convert(prog: Vec<Expr>, namer: &mut NameGenerator) -> Vec<Expr>
 #[cfg(test)]
 mod test {
     use crate::{
+        Value,
         compl::{
             anormal::a_normalize, closure::closure_convert, copyprop::copy_prop,
-            knormal::k_normalize, util::NameGenerator,
+            knormal::k_normalize, util::NameGenerator, verify::verify_closure,
         },
-        format_prog, read_string,
+        create_environment, format_prog,
+        intpt::file::process_string,
+        read_string,
     };
 
     #[test]
@@ -249,4 +300,30 @@ mod test {
         println!("copy-propagated:\n{}", format_prog(&cprog));
         println!("closure-converted:\n{}", format_prog(&converted));
     }
+
+    #[test]
+    fn test_closure_captures_nested_captures() {
+        // `g` captures `b`, so `f`, in which `g`'s closure is made, must capture it too
+        let prog =
+            read_string("(defun h (b) (letfun (f (a) (letfun (g (y) (+ y b)) (g a))) f))").unwrap();
+        let mut namer = NameGenerator::new();
+        let aprog = a_normalize(k_normalize(prog, &mut namer));
+        let converted = closure_convert(aprog, &mut namer);
+        let result = verify_closure(&converted, &Default::default());
+        assert!(result.is_ok(), "{}", format_prog(&converted));
+    }
+
+    #[test]
+    fn test_closure_shadowed_function_name() {
+        // The inner `h` is a different function from the outer one it shadows
+        let prog = read_string(
+            "(def y 2)\n(letfun (h (a) (+ a 1)) (+ (h 1) (letfun (h (b) (+ b y)) (h 10))))",
+        )
+        .unwrap();
+        let mut namer = NameGenerator::new();
+        let aprog = a_normalize(k_normalize(prog, &mut namer));
+        let converted = closure_convert(aprog, &mut namer);
+        let results = process_string(&format_prog(&converted), &mut create_environment()).unwrap();
+        assert_eq!(results.last(), Some(&Value::Int(14)));
+    }
 }
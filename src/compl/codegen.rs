@@ -1,4 +1,9 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+
 use crate::Expr;
+use crate::ast::{Signature, TypeAnn};
+use crate::builtins::{VARIADIC, builtins, lookup};
+use crate::module::SEPARATOR;
 
 /// Generates C code from a PureLisp program that has been already processed through
 /// k-normalization, a-normalization, copy-propagation, and closure conversion.
@@ -43,6 +48,84 @@ struct CCodeGenerator {
     main_prog: Vec<String>,
     func_def: Vec<String>,
     clos_def: Vec<String>,
+    /// Statically known types of C values in the function being generated
+    known: HashMap<String, TypeAnn>,
+    /// C values held as raw `i64`/`f64`/`char` scalars instead of boxed `PLV`s
+    unboxed: HashSet<String>,
+    /// Annotated return types of top-level functions, by C symbol
+    returns: HashMap<String, TypeAnn>,
+    /// Arities of top-level functions and closures, by C symbol
+    arities: HashMap<String, usize>,
+    /// Arity and number of values of the top-level functions returning a `PLValuesN`
+    /// struct, by C symbol
+    values_returns: HashMap<String, (usize, usize)>,
+    /// C values held as `PLValuesN` structs, with their number of values
+    structs: HashMap<String, usize>,
+    /// Numbers of values for which a `PLValuesN` struct type is needed
+    values_types: BTreeSet<usize>,
+}
+
+/// How the tail positions of the function being generated return from it
+struct Tail {
+    /// C value the name of the function refers to in its body and its arity, when its
+    /// tail calls to itself jump back to the start of its body instead of growing the C stack
+    self_loop: Option<(String, usize)>,
+    /// Number of values the function returns in a `PLValuesN` struct, if it does
+    values: Option<usize>,
+    /// Annotated return type of the function, checked on return, and its name
+    ret: Option<(TypeAnn, String)>,
+}
+
+/// Whether `expr` calls the function `name` with `arity` arguments in tail position
+fn tail_calls(expr: &Expr, name: &str, arity: usize) -> bool {
+    match expr {
+        Expr::Form(form) => form.len() == arity + 1 && form[0] == Expr::Id(name.to_string()),
+        Expr::If { then, else_, .. } => {
+            tail_calls(then, name, arity) || tail_calls(else_, name, arity)
+        }
+        Expr::Let { bindings, body } => {
+            bindings.iter().all(|(x, _)| x != name) && tail_calls(body, name, arity)
+        }
+        Expr::LetClos { name: x, body, .. } => x != name && tail_calls(body, name, arity),
+        _ => false,
+    }
+}
+
+/// C type tag of an annotated type
+fn type_tag(ty: TypeAnn) -> &'static str {
+    match ty {
+        TypeAnn::Nil => "PL_NIL",
+        TypeAnn::Bool => "PL_BOOL",
+        TypeAnn::Int => "PL_INT",
+        TypeAnn::Float => "PL_FLOAT",
+        TypeAnn::Str => "PL_STR",
+    }
+}
+
+/// C scalar type, `PLV` field and boxing constructor of a type that can be kept unboxed
+fn scalar_repr(ty: TypeAnn) -> Option<(&'static str, &'static str, &'static str)> {
+    match ty {
+        TypeAnn::Int => Some(("i64", "n", "__new_INT")),
+        TypeAnn::Float => Some(("f64", "x", "__new_FLOAT")),
+        TypeAnn::Bool => Some(("char", "b", "__new_BOOL")),
+        TypeAnn::Nil | TypeAnn::Str => None,
+    }
+}
+
+/// C operator for a binary arithmetic or comparison builtin, and whether it yields a bool
+fn prim_op(func: &str) -> Option<(&'static str, bool)> {
+    match func {
+        "global_func_add" => Some(("+", false)),
+        "global_func_sub" => Some(("-", false)),
+        "global_func_mul" => Some(("*", false)),
+        "global_func_div" => Some(("/", false)),
+        "global_func_eq" => Some(("==", true)),
+        "global_func_lt" => Some(("<", true)),
+        "global_func_leq" => Some(("<=", true)),
+        "global_func_gt" => Some((">", true)),
+        "global_func_geq" => Some((">=", true)),
+        _ => None,
+    }
 }
 
 impl CCodeGenerator {
@@ -57,6 +140,13 @@ impl CCodeGenerator {
             main_prog: Vec::new(),
             func_def: Vec::new(),
             clos_def: Vec::new(),
+            known: HashMap::new(),
+            unboxed: HashSet::new(),
+            returns: HashMap::new(),
+            arities: HashMap::new(),
+            values_returns: HashMap::new(),
+            structs: HashMap::new(),
+            values_types: BTreeSet::new(),
         }
     }
 
@@ -66,22 +156,22 @@ impl CCodeGenerator {
         var_name
     }
 
+    /// Name of the C variable or function implementing the global `name`. The name of
+    /// the global is mangled into it, module separator included, for readability
+    fn global_name(&mut self, prefix: &str, name: &str) -> String {
+        let mangled: String = name
+            .replace(SEPARATOR, "__")
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        format!("{}_{}", self.fresh_var(prefix), mangled)
+    }
+
     fn load_builtin_env(&mut self) {
-        self.env
-            .push("+".to_string(), "global_func_add".to_string());
-        self.env
-            .push("-".to_string(), "global_func_sub".to_string());
-        self.env
-            .push("*".to_string(), "global_func_mul".to_string());
-        self.env
-            .push("/".to_string(), "global_func_div".to_string());
-        self.env.push("=".to_string(), "global_func_eq".to_string());
-        self.env.push(">".to_string(), "global_func_gt".to_string());
-        self.env.push("<".to_string(), "global_func_lt".to_string());
-        self.env
-            .push("<=".to_string(), "global_func_leq".to_string());
-        self.env
-            .push(">=".to_string(), "global_func_geq".to_string());
+        for builtin in builtins() {
+            self.env
+                .push(builtin.name.to_string(), builtin.c_name.to_string());
+        }
     }
 
     /// Generate C code from the PureLisp program
@@ -91,28 +181,41 @@ impl CCodeGenerator {
         for expr in prog.iter() {
             match expr {
                 Expr::Def { x, .. } => {
-                    let x_addr = self.fresh_var("global_var");
+                    let x_addr = self.global_name("global_var", x);
                     self.env.push(x.clone(), x_addr);
                 }
-                Expr::Defun { name, .. } => {
-                    let funcptr = self.fresh_var("global_func");
+                Expr::Defun {
+                    name, args, sig, ..
+                } => {
+                    let funcptr = self.global_name("global_func", name);
+                    self.arities.insert(funcptr.clone(), args.len());
+                    if let Some(ret) = sig.ret {
+                        self.returns.insert(funcptr.clone(), ret);
+                    }
                     self.env.push(name.clone(), funcptr);
                 }
-                Expr::DefClos { name, .. } => {
-                    let closptr = self.fresh_var("global_clos");
+                Expr::DefClos { name, args, .. } => {
+                    let closptr = self.global_name("global_clos", name);
+                    self.arities.insert(closptr.clone(), args.len());
                     self.env.push(name.clone(), closptr);
                 }
                 _ => {}
             }
         }
+        self.find_values_returns(&prog);
 
         for expr in prog {
             match expr {
                 Expr::Def { x, y } => {
                     self.gen_def(x, *y);
                 }
-                Expr::Defun { name, args, body } => {
-                    self.gen_defun(name, args, *body);
+                Expr::Defun {
+                    name,
+                    args,
+                    body,
+                    sig,
+                } => {
+                    self.gen_defun(name, args, sig, *body);
                 }
                 Expr::DefClos {
                     name,
@@ -134,6 +237,7 @@ impl CCodeGenerator {
 
     fn gen_def(&mut self, x: String, y: Expr) {
         let (y_code, y_addr) = self.gen_expr(y);
+        let y_addr = self.boxed(&y_addr);
         let func_addr = self.fresh_var("global_var_init_func");
         self.func_decl.push(format!("PLV {}();", func_addr));
         self.func_def.push(format!(
@@ -151,24 +255,84 @@ impl CCodeGenerator {
         self.env.push(x, x_addr);
     }
 
-    fn gen_defun(&mut self, name: String, args: Vec<String>, body: Expr) {
+    fn gen_defun(&mut self, name: String, args: Vec<String>, sig: Signature, body: Expr) {
         let funcptr = self
             .env
             .get(&name)
             .unwrap_or_else(|| panic!("Undefined identifier: {}", name))
             .clone();
+        let outer_known = std::mem::take(&mut self.known);
+        let outer_unboxed = std::mem::take(&mut self.unboxed);
+        // Annotated parameters are checked once on entry, so the body may rely on them
+        let mut checks = String::new();
         for (i, arg) in args.iter().enumerate() {
-            self.env.push(arg.clone(), format!("args[{}]", i));
+            let arg_addr = format!("args[{}]", i);
+            if let Some(ty) = sig.param(i) {
+                checks.push_str(&format!(
+                    "__PL_check_type(&{}, {}, \"parameter {} of {}\");\n",
+                    arg_addr,
+                    type_tag(ty),
+                    arg,
+                    name
+                ));
+                self.known.insert(arg_addr.clone(), ty);
+            }
+            self.env.push(arg.clone(), arg_addr);
         }
-        let (body_code, body_addr) = self.gen_expr(body);
+        let values = self.values_returns.get(&funcptr).map(|&(_, n)| n);
+        let self_loop = tail_calls(&body, &name, args.len()).then(|| (funcptr.clone(), args.len()));
+        let body_code = if self_loop.is_some() || values.is_some() {
+            let tail = Tail {
+                self_loop,
+                values,
+                ret: sig.ret.map(|ret| (ret, name.clone())),
+            };
+            // The parameters of a loop are checked again on every iteration
+            let checks = std::mem::take(&mut checks);
+            self.gen_tail_body(body, &tail, checks)
+        } else {
+            let (mut body_code, body_addr) = self.gen_expr(body);
+            if let Some(ret) = sig.ret
+                && self.known.get(&body_addr) != Some(&ret)
+            {
+                body_code.push_str(&format!(
+                    "\n__PL_check_type(&{}, {}, \"return value of {}\");",
+                    body_addr,
+                    type_tag(ret),
+                    name
+                ));
+            }
+            format!("{}\nreturn {};", body_code, self.boxed(&body_addr))
+        };
         for _ in args.iter() {
             self.env.pop();
         }
-        self.func_decl.push(format!("PLV {}(PLV *args);", funcptr));
-        self.func_def.push(format!(
-            "PLV {}(PLV *args){{\n{}\nreturn {};\n}}",
-            funcptr, body_code, body_addr
-        ));
+        self.known = outer_known;
+        self.unboxed = outer_unboxed;
+        match values {
+            // The struct is only boxed when the function is called through its pointer
+            Some(n) => {
+                let values_func = format!("{}__values", funcptr);
+                self.func_decl
+                    .push(format!("PLValues{} {}(PLV *args);", n, values_func));
+                self.func_decl.push(format!("PLV {}(PLV *args);", funcptr));
+                self.func_def.push(format!(
+                    "PLValues{} {}(PLV *args){{\n{}{}\n}}",
+                    n, values_func, checks, body_code
+                ));
+                self.func_def.push(format!(
+                    "PLV {}(PLV *args){{\nPLValues{} values = {}(args);\nreturn __new_TUPLE(values.v, {});\n}}",
+                    funcptr, n, values_func, n
+                ));
+            }
+            None => {
+                self.func_decl.push(format!("PLV {}(PLV *args);", funcptr));
+                self.func_def.push(format!(
+                    "PLV {}(PLV *args){{\n{}{}\n}}",
+                    funcptr, checks, body_code
+                ));
+            }
+        }
     }
 
     fn gen_defclos(&mut self, name: String, freevars: Vec<String>, args: Vec<String>, body: Expr) {
@@ -177,29 +341,66 @@ impl CCodeGenerator {
             .get(&name)
             .unwrap_or_else(|| panic!("Undefined identifier: {}", name))
             .clone();
+        let outer_known = std::mem::take(&mut self.known);
+        let outer_unboxed = std::mem::take(&mut self.unboxed);
         for (i, freevar) in freevars.iter().enumerate() {
             self.env.push(freevar.clone(), format!("freevars[{}]", i));
         }
         for (i, arg) in args.iter().enumerate() {
             self.env.push(arg.clone(), format!("args[{}]", i));
         }
-        let (body_code, body_addr) = self.gen_expr(body);
+        // Within its body, the label of the closure refers to the running closure
+        let mut self_code = String::new();
+        let refers_to_self = body.free_vars(&HashSet::new()).contains(&name);
+        if refers_to_self {
+            let self_addr = self.fresh_var("clos");
+            self_code = format!(
+                "PLV {} = __new_CLOS({}, freevars, {});\n",
+                self_addr,
+                closptr,
+                args.len()
+            );
+            self.env.push(name.clone(), self_addr);
+        }
+        let body_code = if tail_calls(&body, &name, args.len()) {
+            let callee = self.env.get(&name).unwrap().clone();
+            let tail = Tail {
+                self_loop: Some((callee, args.len())),
+                values: None,
+                ret: None,
+            };
+            self.gen_tail_body(body, &tail, String::new())
+        } else {
+            let (body_code, body_addr) = self.gen_expr(body);
+            format!("{}\nreturn {};", body_code, self.boxed(&body_addr))
+        };
+        let body_code = self_code + &body_code;
+        if refers_to_self {
+            self.env.pop();
+        }
         for _ in args.iter() {
             self.env.pop();
         }
         for _ in freevars.iter() {
             self.env.pop();
         }
+        self.known = outer_known;
+        self.unboxed = outer_unboxed;
         self.clos_decl
             .push(format!("PLV {}(PLV *freevars, PLV *args);", closptr));
         self.clos_def.push(format!(
-            "PLV {}(PLV *freevars, PLV *args){{\n{}\nreturn {};\n}}",
-            closptr, body_code, body_addr
+            "PLV {}(PLV *freevars, PLV *args){{\n{}\n}}",
+            closptr, body_code
         ));
     }
 
     fn gen_main_expr(&mut self, expr: Expr) {
-        let (code, addr) = self.gen_expr(expr);
+        let (mut code, mut addr) = self.gen_expr(expr);
+        if self.unboxed.contains(&addr) {
+            let boxed_addr = self.fresh_var("tmp");
+            code.push_str(&format!("\nPLV {} = {};", boxed_addr, self.boxed(&addr)));
+            addr = boxed_addr;
+        }
         self.main_prog.push(code);
         self.main_prog
             .push(format!("__PLV_print(&{});\nputchar('\\n');\n", addr));
@@ -222,7 +423,17 @@ impl CCodeGenerator {
                     let mapped = mapped.clone();
                     if mapped.starts_with("global_func") {
                         let addr = self.fresh_var("f");
-                        let code = format!("PLV {} = __new_FUNCPTR({});", addr, mapped);
+                        let code = format!(
+                            "PLV {} = __new_FUNCPTR({}, {});",
+                            addr,
+                            mapped,
+                            self.runtime_arity(&mapped)
+                        );
+                        (code, addr)
+                    } else if let Some(&n) = self.structs.get(&mapped) {
+                        // Values used as a single value are moved to the heap
+                        let addr = self.fresh_var("tmp");
+                        let code = format!("PLV {} = __new_TUPLE({}.v, {});", addr, mapped, n);
                         (code, addr)
                     } else {
                         ("".to_string(), mapped.clone())
@@ -234,33 +445,85 @@ impl CCodeGenerator {
             Expr::Nil => {
                 let addr = self.fresh_var("tmp");
                 let code = format!("PLV {} = __new_NIL();", addr);
+                self.known.insert(addr.clone(), TypeAnn::Nil);
                 (code, addr)
             }
             Expr::Int(n) => {
                 let addr = self.fresh_var("tmp");
-                let code = format!("PLV {} = __new_INT({});", addr, n);
+                let code = format!("i64 {} = {}LL;", addr, n);
+                self.known.insert(addr.clone(), TypeAnn::Int);
+                self.unboxed.insert(addr.clone());
                 (code, addr)
             }
             Expr::Float(f) => {
                 let addr = self.fresh_var("tmp");
-                let code = format!("PLV {} = __new_FLOAT({});", addr, f);
+                let code = format!("f64 {} = {:?};", addr, f);
+                self.known.insert(addr.clone(), TypeAnn::Float);
+                self.unboxed.insert(addr.clone());
                 (code, addr)
             }
             Expr::Str(s) => {
                 let addr = self.fresh_var("tmp");
-                let code = format!("PLV {} = __new_STR(\"{}\");", addr, s);
+                let code = format!("PLV {} = __new_STR({:?});", addr, s);
+                self.known.insert(addr.clone(), TypeAnn::Str);
                 (code, addr)
             }
             Expr::Bool(b) => {
                 let addr = self.fresh_var("tmp");
-                let code = format!("PLV {} = __new_BOOL({});", addr, if b { 1 } else { 0 });
+                let code = format!("char {} = {};", addr, if b { 1 } else { 0 });
+                self.known.insert(addr.clone(), TypeAnn::Bool);
+                self.unboxed.insert(addr.clone());
                 (code, addr)
             }
             Expr::Form(form) => {
+                if let Some(result) = self.gen_prim_call(&form) {
+                    return result;
+                }
+                if let Some(addr) = self.struct_ref(&form) {
+                    return (String::new(), addr);
+                }
+                // Calls to top-level functions with all their arguments are direct C
+                // calls; anything else is dispatched on its runtime tag by `__PL_funcall`
+                let known_func = match form.first() {
+                    Some(Expr::Id(callee)) => self
+                        .env
+                        .get(callee)
+                        .filter(|func| func.starts_with("global_func"))
+                        .filter(|func| {
+                            self.arities
+                                .get(*func)
+                                .is_none_or(|&arity| arity == form.len() - 1)
+                        })
+                        .cloned(),
+                    _ => None,
+                };
+                // The C builtins read their arguments without checking how many there are
+                if let (Some(func), Some(Expr::Id(callee))) = (&known_func, form.first())
+                    && let Some(builtin) = lookup(callee).filter(|b| b.c_name == func)
+                    && builtin.arity != VARIADIC
+                    && form.len() - 1 != builtin.arity
+                {
+                    panic!(
+                        "Builtin `{}` takes {} argument(s), got {}",
+                        callee,
+                        builtin.arity,
+                        form.len() - 1
+                    );
+                }
+                // Result type of a call to an annotated top-level function
+                let ret = known_func
+                    .as_ref()
+                    .and_then(|func| self.returns.get(func))
+                    .copied();
                 let mut code = String::new();
                 let mut args = Vec::new();
-                for item in form {
-                    let (item_code, item_addr) = self.gen_expr(item);
+                let items = if known_func.is_some() {
+                    &form[1..]
+                } else {
+                    &form[..]
+                };
+                for item in items {
+                    let (item_code, item_addr) = self.gen_expr(item.clone());
                     if !item_code.is_empty() {
                         code.push_str(&format!("{}\n", item_code));
                     }
@@ -268,11 +531,35 @@ impl CCodeGenerator {
                 }
                 let addr = self.fresh_var("tmp");
                 let args_addr = self.fresh_var("args");
-                code.push_str(&format!("PLV {}[{}];\n", args_addr, args.len()));
+                code.push_str(&format!("PLV {}[{}];\n", args_addr, args.len().max(1)));
                 for (i, arg) in args.iter().enumerate() {
-                    code.push_str(&format!("{}[{}] = {};\n", args_addr, i, arg));
+                    code.push_str(&format!("{}[{}] = {};\n", args_addr, i, self.boxed(arg)));
+                }
+                match known_func {
+                    Some(func) if func == "global_func_values" => {
+                        let items = if args.is_empty() { "NULL" } else { &args_addr };
+                        code.push_str(&format!(
+                            "PLV {} = __new_TUPLE({}, {});",
+                            addr,
+                            items,
+                            args.len()
+                        ));
+                    }
+                    Some(func) => {
+                        code.push_str(&format!("PLV {} = {}({});", addr, func, args_addr));
+                    }
+                    None => {
+                        code.push_str(&format!(
+                            "PLV {} = __PL_funcall({}, {});",
+                            addr,
+                            args_addr,
+                            args.len() - 1
+                        ));
+                    }
+                }
+                if let Some(ty) = ret {
+                    self.known.insert(addr.clone(), ty);
                 }
-                code.push_str(&format!("PLV {} = __PL_funcall({});", addr, args_addr));
                 (code, addr)
             }
             Expr::If { cond, then, else_ } => {
@@ -280,19 +567,36 @@ impl CCodeGenerator {
                 let (then_code, then_addr) = self.gen_expr(*then);
                 let (else_code, else_addr) = self.gen_expr(*else_);
                 let addr = self.fresh_var("tmp");
+                let cond_test = self.truth(&cond_addr);
+                // Keep the result unboxed when both branches agree on a scalar type
+                let result_ty = self
+                    .known
+                    .get(&then_addr)
+                    .copied()
+                    .filter(|ty| self.known.get(&else_addr) == Some(ty));
+                let (decl, then_val, else_val) = match result_ty.and_then(scalar_repr) {
+                    Some((c_type, _, _)) => {
+                        self.unboxed.insert(addr.clone());
+                        (c_type, self.scalar(&then_addr), self.scalar(&else_addr))
+                    }
+                    None => ("PLV", self.boxed(&then_addr), self.boxed(&else_addr)),
+                };
                 let mut code = format!("{}\n", cond_code);
-                code.push_str(&format!("PLV {};", addr));
+                code.push_str(&format!("{} {};", decl, addr));
                 code.push_str(&format!(
-                    "if ({}.val.b == 1) {{\n{}\n{} = {};\n}} else {{\n{}\n{}={};\n}}",
-                    cond_addr, then_code, addr, then_addr, else_code, addr, else_addr
+                    "if ({}) {{\n{}\n{} = {};\n}} else {{\n{}\n{}={};\n}}",
+                    cond_test, then_code, addr, then_val, else_code, addr, else_val
                 ));
+                if let Some(ty) = result_ty {
+                    self.known.insert(addr.clone(), ty);
+                }
                 (code, addr)
             }
             Expr::Let { bindings, body } => {
                 let mut code = String::new();
                 assert!(bindings.len() == 1, "Let can only have 1 binding");
                 let (x, e) = bindings[0].clone();
-                let (e_code, e_addr) = self.gen_expr(e);
+                let (e_code, e_addr) = self.gen_binding(e);
                 code.push_str(&format!("{}\n", e_code));
                 self.env.push(x, e_addr.clone());
                 let (body_code, body_addr) = self.gen_expr(*body);
@@ -306,42 +610,489 @@ impl CCodeGenerator {
                 freevars,
                 body,
             } => {
+                let (mut code, clos_addr) = self.gen_closure(&closid, &freevars);
+                self.env.push(name, clos_addr);
+                let (body_code, body_addr) = self.gen_expr(*body);
+                self.env.pop();
+                code.push_str(&format!("{}\n", body_code));
+                (code, body_addr)
+            }
+        }
+    }
+
+    /// Allocate the closure `closid` over the values of `freevars`
+    fn gen_closure(&mut self, closid: &str, freevars: &[String]) -> (String, String) {
+        let mut code = String::new();
+        let clos_addr = self.fresh_var("clos");
+        let closptr_addr = match self.env.get(closid) {
+            Some(addr) => addr.clone(),
+            None => {
+                panic!("Undefined identifier: {}", closid);
+            }
+        };
+        let freevars_addr = self.fresh_var("freevars");
+        code.push_str(&format!(
+            "PLV *{} = malloc(sizeof(PLV) * {});\n",
+            freevars_addr,
+            freevars.len()
+        ));
+        for (i, var) in freevars.iter().enumerate() {
+            let (freevar_code, freevar_addr) = self.gen_expr(Expr::Id(var.clone()));
+            code.push_str(&format!("{}\n", freevar_code));
+            code.push_str(&format!(
+                "{}[{}] = {};\n",
+                freevars_addr,
+                i,
+                self.boxed(&freevar_addr)
+            ));
+        }
+        code.push_str(&format!(
+            "PLV {} = __new_CLOS({}, {}, {});\n",
+            clos_addr,
+            closptr_addr,
+            freevars_addr,
+            self.runtime_arity(&closptr_addr)
+        ));
+        (code, clos_addr)
+    }
+
+    /// Generate the body of a function whose tail positions return as described by
+    /// `tail`, preceded by `checks`. A function that calls itself in tail position is a
+    /// `while` loop, which runs the checks on every iteration. The arguments of a tail
+    /// call replace those of the function, which are first copied out of the caller's array.
+    fn gen_tail_body(&mut self, body: Expr, tail: &Tail, checks: String) -> String {
+        let body_code = self.gen_tail(body, tail);
+        let Some((_, arity)) = tail.self_loop else {
+            return format!("{}{}", checks, body_code);
+        };
+        let mut code = String::new();
+        if arity > 0 {
+            let params = self.fresh_var("params");
+            let args: Vec<String> = (0..arity).map(|i| format!("args[{}]", i)).collect();
+            code.push_str(&format!(
+                "PLV {}[{}] = {{{}}};\nargs = {};\n",
+                params,
+                arity,
+                args.join(", "),
+                params
+            ));
+        }
+        code.push_str(&format!("while (1) {{\n{}{}\n}}", checks, body_code));
+        code
+    }
+
+    /// Generate `expr` in tail position of a function: its value is returned, and a call
+    /// to the function itself starts the next iteration of its loop
+    fn gen_tail(&mut self, expr: Expr, tail: &Tail) -> String {
+        match expr {
+            Expr::If { cond, then, else_ } => {
+                let (cond_code, cond_addr) = self.gen_expr(*cond);
+                let then_code = self.gen_tail(*then, tail);
+                let else_code = self.gen_tail(*else_, tail);
+                format!(
+                    "{}\nif ({}) {{\n{}\n}} else {{\n{}\n}}",
+                    cond_code,
+                    self.truth(&cond_addr),
+                    then_code,
+                    else_code
+                )
+            }
+            Expr::Let { bindings, body } => {
+                assert!(bindings.len() == 1, "Let can only have 1 binding");
+                let (x, e) = bindings[0].clone();
+                let (e_code, e_addr) = self.gen_binding(e);
+                self.env.push(x, e_addr);
+                let body_code = self.gen_tail(*body, tail);
+                self.env.pop();
+                format!("{}\n{}", e_code, body_code)
+            }
+            Expr::LetClos {
+                name,
+                closid,
+                freevars,
+                body,
+            } => {
+                let (code, clos_addr) = self.gen_closure(&closid, &freevars);
+                self.env.push(name, clos_addr);
+                let body_code = self.gen_tail(*body, tail);
+                self.env.pop();
+                format!("{}{}", code, body_code)
+            }
+            Expr::Form(form)
+                if matches!((&tail.self_loop, &form[0]), (Some((callee, arity)), Expr::Id(id))
+                    if form.len() == arity + 1 && self.env.get(id) == Some(callee)) =>
+            {
+                // All the arguments are evaluated before any parameter is replaced
                 let mut code = String::new();
-                let clos_addr = self.fresh_var("clos");
-                let closptr_addr = match self.env.get(&closid) {
-                    Some(addr) => addr.clone(),
-                    None => {
-                        panic!("Undefined identifier: {}", closid);
+                let mut values = Vec::new();
+                for arg in &form[1..] {
+                    let (arg_code, arg_addr) = self.gen_expr(arg.clone());
+                    if !arg_code.is_empty() {
+                        code.push_str(&format!("{}\n", arg_code));
+                    }
+                    values.push(self.boxed(&arg_addr));
+                }
+                if !values.is_empty() {
+                    let next = self.fresh_var("next");
+                    code.push_str(&format!(
+                        "PLV {}[{}] = {{{}}};\n",
+                        next,
+                        values.len(),
+                        values.join(", ")
+                    ));
+                    for i in 0..values.len() {
+                        code.push_str(&format!("args[{}] = {}[{}];\n", i, next, i));
                     }
+                }
+                code.push_str("continue;");
+                code
+            }
+            _ if tail.values.is_some() => {
+                let values = match &expr {
+                    Expr::Form(form) => self.gen_values(form),
+                    _ => None,
                 };
-                let freevars_addr = self.fresh_var("freevars");
-                code.push_str(&format!(
-                    "PLV *{} = malloc(sizeof(PLV) * {});\n",
-                    freevars_addr,
-                    freevars.len()
-                ));
-                for (i, var) in freevars.iter().enumerate() {
-                    let (freevar_code, freevar_addr) = self.gen_expr(Expr::Id(var.clone()));
-                    code.push_str(&format!("{}\n", freevar_code));
-                    code.push_str(&format!("{}[{}] = {};\n", freevars_addr, i, freevar_addr));
+                match values {
+                    Some((code, value, n)) if Some(n) == tail.values => {
+                        format!("{}return {};", code, value)
+                    }
+                    _ => panic!(
+                        "Invalid tail expression for {} values: {}",
+                        tail.values.unwrap(),
+                        expr
+                    ),
+                }
+            }
+            _ => {
+                let (mut code, mut addr) = self.gen_expr(expr);
+                if let Some((ret, name)) = &tail.ret
+                    && self.known.get(&addr) != Some(ret)
+                {
+                    if self.unboxed.contains(&addr) {
+                        let boxed_addr = self.fresh_var("tmp");
+                        code.push_str(&format!("\nPLV {} = {};", boxed_addr, self.boxed(&addr)));
+                        addr = boxed_addr;
+                    }
+                    code.push_str(&format!(
+                        "\n__PL_check_type(&{}, {}, \"return value of {}\");",
+                        addr,
+                        type_tag(*ret),
+                        name
+                    ));
+                }
+                format!("{}\nreturn {};", code, self.boxed(&addr))
+            }
+        }
+    }
+
+    /// Find the top-level functions that can return their values in a `PLValuesN`
+    /// struct: those returning the same number of values from every tail position, either
+    /// by calling `values` or another such function. Tail calls to themselves loop.
+    fn find_values_returns(&mut self, prog: &[Expr]) {
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for expr in prog {
+                let Expr::Defun {
+                    name,
+                    args,
+                    body,
+                    sig,
+                } = expr
+                else {
+                    continue;
+                };
+                let funcptr = self.env.get(name).unwrap().clone();
+                if sig.ret.is_some() || self.values_returns.contains_key(&funcptr) {
+                    continue;
+                }
+                let mut found = Vec::new();
+                let mut shadowed = args.clone();
+                if self.tail_values(body, name, args.len(), &mut shadowed, &mut found)
+                    && let Some(&n) = found.first()
+                    && n > 0
+                    && found.iter().all(|&m| m == n)
+                {
+                    self.values_returns.insert(funcptr, (args.len(), n));
+                    changed = true;
+                }
+            }
+        }
+    }
+
+    /// Collect in `found` the number of values returned by each tail position of `expr`
+    /// in the body of the function `name`, where the names in `shadowed` are local.
+    /// False if a tail position returns anything else.
+    fn tail_values(
+        &self,
+        expr: &Expr,
+        name: &str,
+        arity: usize,
+        shadowed: &mut Vec<String>,
+        found: &mut Vec<usize>,
+    ) -> bool {
+        match expr {
+            Expr::If { then, else_, .. } => {
+                self.tail_values(then, name, arity, shadowed, found)
+                    && self.tail_values(else_, name, arity, shadowed, found)
+            }
+            Expr::Let { bindings, body } => {
+                let len = shadowed.len();
+                shadowed.extend(bindings.iter().map(|(x, _)| x.clone()));
+                let result = self.tail_values(body, name, arity, shadowed, found);
+                shadowed.truncate(len);
+                result
+            }
+            Expr::LetClos { name: x, body, .. } => {
+                shadowed.push(x.clone());
+                let result = self.tail_values(body, name, arity, shadowed, found);
+                shadowed.pop();
+                result
+            }
+            Expr::Form(form) => {
+                let Some(Expr::Id(head)) = form.first() else {
+                    return false;
+                };
+                if shadowed.contains(head) {
+                    return false;
                 }
+                if head == name {
+                    return form.len() == arity + 1;
+                }
+                match self.env.get(head).map(String::as_str) {
+                    Some("global_func_values") => found.push(form.len() - 1),
+                    Some(func) => match self.values_returns.get(func) {
+                        Some(&(func_arity, n)) if func_arity == form.len() - 1 => found.push(n),
+                        _ => return false,
+                    },
+                    None => return false,
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Generate the values of a call to `values` or to a function returning its values
+    /// in a struct: the code computing them, a C expression of type `PLValuesN` and `n`
+    fn gen_values(&mut self, form: &[Expr]) -> Option<(String, String, usize)> {
+        let Some(Expr::Id(head)) = form.first() else {
+            return None;
+        };
+        let func = self.env.get(head)?.clone();
+        let n = if func == "global_func_values" {
+            form.len() - 1
+        } else {
+            match self.values_returns.get(&func) {
+                Some(&(arity, n)) if arity == form.len() - 1 => n,
+                _ => return None,
+            }
+        };
+        if n == 0 {
+            return None;
+        }
+        self.values_types.insert(n);
+        let mut code = String::new();
+        let mut args = Vec::new();
+        for item in &form[1..] {
+            let (item_code, item_addr) = self.gen_expr(item.clone());
+            if !item_code.is_empty() {
+                code.push_str(&format!("{}\n", item_code));
+            }
+            args.push(self.boxed(&item_addr));
+        }
+        let value = if func == "global_func_values" {
+            format!("(PLValues{}){{{{{}}}}}", n, args.join(", "))
+        } else {
+            let args_addr = self.fresh_var("args");
+            code.push_str(&format!("PLV {}[{}];\n", args_addr, args.len().max(1)));
+            for (i, arg) in args.iter().enumerate() {
+                code.push_str(&format!("{}[{}] = {};\n", args_addr, i, arg));
+            }
+            format!("{}__values({})", func, args_addr)
+        };
+        Some((code, value, n))
+    }
+
+    /// Generate the value bound by a `let`, which stays in a `PLValuesN` struct when it
+    /// is a fixed number of values
+    fn gen_binding(&mut self, expr: Expr) -> (String, String) {
+        if let Expr::Form(form) = &expr
+            && let Some((code, value, n)) = self.gen_values(form)
+        {
+            let addr = self.fresh_var("values");
+            self.structs.insert(addr.clone(), n);
+            return (format!("{}PLValues{} {} = {};", code, n, addr, value), addr);
+        }
+        self.gen_expr(expr)
+    }
+
+    /// The C value of `(values-ref v n i)` when `v` is held in a struct of `n` values
+    fn struct_ref(&self, form: &[Expr]) -> Option<String> {
+        let [Expr::Id(callee), Expr::Id(v), Expr::Int(n), Expr::Int(i)] = form else {
+            return None;
+        };
+        if self.env.get(callee)? != "global_func_values_ref" {
+            return None;
+        }
+        let addr = self.env.get(v)?;
+        let len = *self.structs.get(addr)?;
+        (len as i64 == *n && (0..*n).contains(i)).then(|| format!("{}.v[{}]", addr, i))
+    }
+
+    /// Arity of the top-level function, builtin or closure `func` as known to the runtime,
+    /// -1 when it takes any number of arguments
+    fn runtime_arity(&self, func: &str) -> i64 {
+        let arity = match self.arities.get(func) {
+            Some(&arity) => arity,
+            None => builtins()
+                .find(|builtin| builtin.c_name == func)
+                .map_or(VARIADIC, |builtin| builtin.arity),
+        };
+        if arity == VARIADIC { -1 } else { arity as i64 }
+    }
+
+    /// A C condition testing the bool at `addr`
+    fn truth(&self, addr: &str) -> String {
+        if self.unboxed.contains(addr) {
+            addr.to_string()
+        } else {
+            format!("{}.val.b == 1", addr)
+        }
+    }
+
+    /// A `PLV` expression for the value at `addr`, boxing it if it is held unboxed
+    fn boxed(&self, addr: &str) -> String {
+        if self.unboxed.contains(addr) {
+            let (_, _, constructor) = scalar_repr(self.known[addr]).unwrap();
+            format!("{}({})", constructor, addr)
+        } else {
+            addr.to_string()
+        }
+    }
+
+    /// A raw scalar expression for the value at `addr`, whose type must be statically known
+    fn scalar(&self, addr: &str) -> String {
+        if self.unboxed.contains(addr) {
+            addr.to_string()
+        } else {
+            let (_, field, _) = scalar_repr(self.known[addr]).unwrap();
+            format!("{}.val.{}", addr, field)
+        }
+    }
+
+    /// Emit a call to a binary arithmetic/comparison builtin as inline C.
+    ///
+    /// Operands whose types are statically known are used unboxed; the others are
+    /// tag-checked inline. Operands of any other type fall back to the runtime builtin,
+    /// which either handles them (`=`) or reports the type error.
+    fn gen_prim_call(&mut self, form: &[Expr]) -> Option<(String, String)> {
+        let [Expr::Id(callee), lhs, rhs] = form else {
+            return None;
+        };
+        let func = self.env.get(callee)?.clone();
+        let (op, is_cmp) = prim_op(&func)?;
+
+        let mut code = String::new();
+        let (lhs_code, lhs_addr) = self.gen_expr(lhs.clone());
+        let (rhs_code, rhs_addr) = self.gen_expr(rhs.clone());
+        for item_code in [lhs_code, rhs_code] {
+            if !item_code.is_empty() {
+                code.push_str(&format!("{}\n", item_code));
+            }
+        }
+        let operands = [lhs_addr, rhs_addr];
+        let types = operands.clone().map(|addr| self.known.get(&addr).copied());
+
+        // The numeric types the operation may be carried out in, with the tag checks each needs
+        let mut cases = Vec::new();
+        for ty in [TypeAnn::Int, TypeAnn::Float] {
+            if !types.iter().all(|t| t.is_none() || *t == Some(ty)) {
+                continue;
+            }
+            let (_, field, _) = scalar_repr(ty).unwrap();
+            let mut checks = Vec::new();
+            let mut vals = Vec::new();
+            for (addr, t) in operands.iter().zip(types) {
+                if t.is_some() {
+                    vals.push(self.scalar(addr));
+                } else {
+                    let check = format!("{}.type == {}", addr, type_tag(ty));
+                    if !checks.contains(&check) {
+                        checks.push(check);
+                    }
+                    vals.push(format!("{}.val.{}", addr, field));
+                }
+            }
+            let value = match (op, ty) {
+                ("/", TypeAnn::Int) => format!("__PL_idiv({}, {})", vals[0], vals[1]),
+                ("/", _) => format!("__PL_fdiv({}, {})", vals[0], vals[1]),
+                _ => format!("{} {} {}", vals[0], op, vals[1]),
+            };
+            cases.push((checks, ty, value));
+        }
+
+        // Comparisons always yield a bool; arithmetic has a static type once one operand does
+        let result_ty = if is_cmp {
+            Some(TypeAnn::Bool)
+        } else if cases.len() == 1 {
+            Some(cases[0].1)
+        } else {
+            None
+        };
+        let addr = self.fresh_var("tmp");
+        let result_repr = result_ty.and_then(scalar_repr);
+        let wrap = |value: &str, ty: TypeAnn| match result_repr {
+            Some(_) => value.to_string(),
+            None => format!("{}({})", scalar_repr(ty).unwrap().2, value),
+        };
+
+        if let [(checks, ty, value)] = cases.as_slice()
+            && checks.is_empty()
+        {
+            let (c_type, _, _) = result_repr.unwrap();
+            code.push_str(&format!("{} {} = {};", c_type, addr, wrap(value, *ty)));
+        } else {
+            let decl = result_repr.map_or("PLV", |(c_type, _, _)| c_type);
+            code.push_str(&format!("{} {};\n", decl, addr));
+            for (checks, ty, value) in &cases {
                 code.push_str(&format!(
-                    "PLV {} = __new_CLOS({}, {});\n",
-                    clos_addr, closptr_addr, freevars_addr
+                    "if ({}) {{\n{} = {};\n}} else ",
+                    checks.join(" && "),
+                    addr,
+                    wrap(value, *ty)
                 ));
-                self.env.push(name, clos_addr.clone());
-                let (body_code, body_addr) = self.gen_expr(*body);
-                self.env.pop();
-                code.push_str(&format!("{}\n", body_code));
-                (code, body_addr)
             }
+            let args_addr = self.fresh_var("args");
+            let field =
+                result_repr.map_or(String::new(), |(_, field, _)| format!(".val.{}", field));
+            code.push_str(&format!(
+                "{{\nPLV {}[2];\n{}[0] = {};\n{}[1] = {};\n{} = {}({}){};\n}}",
+                args_addr,
+                args_addr,
+                self.boxed(&operands[0]),
+                args_addr,
+                self.boxed(&operands[1]),
+                addr,
+                func,
+                args_addr,
+                field
+            ));
+        }
+        if let Some(ty) = result_ty {
+            self.known.insert(addr.clone(), ty);
+        }
+        if result_repr.is_some() {
+            self.unboxed.insert(addr.clone());
         }
+        Some((code, addr))
     }
 
     /// Assemble the complete C program from all the generated code parts
     fn assemble_program(&self) -> String {
         format!(
             "{}\n\n\
+             // Multiple values types\n{}\n\
              // Global variable declarations\n{}\n\n\
              // Function declarations\n{}\n\n\
              // Closure declarations\n{}\n\n\
@@ -350,7 +1101,11 @@ impl CCodeGenerator {
              // Expressions to evaluate\n{}\n\nreturn 0;\n}}\n\n\
              // Function definitions\n{}\n\n\
              // Closure definitions\n{}",
-            crate::compl::runtime::RUNTIME_C_CODE,
+            crate::compl::runtime::runtime_c_code(),
+            self.values_types
+                .iter()
+                .map(|n| format!("typedef struct {{ PLV v[{}]; }} PLValues{};\n", n, n))
+                .collect::<String>(),
             self.global_var_decl.join("\n"),
             self.func_decl.join("\n"),
             self.clos_decl.join("\n"),
@@ -361,3 +1116,151 @@ impl CCodeGenerator {
         )
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        compl::{CompileOptions, compl_to_c_with},
+        read_string,
+    };
+
+    #[test]
+    fn test_codegen_prim_calls() {
+        let prog = read_string(
+            r#"
+(defun inc ((x : int)) (+ x 1))
+(defun mix (x y) (* x y))
+(< (- 10 3) 2.5)
+(inc (mix 2 3))
+"#,
+        )
+        .unwrap();
+        let options = CompileOptions {
+            inline_threshold: 0,
+            ..CompileOptions::default()
+        };
+        let code = compl_to_c_with(prog, &options).unwrap();
+        let code = code.split("Runtime Code Ends").nth(1).unwrap();
+        // Primitive calls never go through the generic calling convention
+        assert!(!code.contains("__PL_funcall"), "{}", code);
+        // Constant operands are folded
+        assert!(code.contains("i64 tmp_6 = 7LL;"), "{}", code);
+        // Both operands known: plain unboxed arithmetic
+        assert!(code.contains("args[0].val.n + tmp_"), "{}", code);
+        // Unknown operands are tag-checked inline, with the builtin as fallback
+        assert!(
+            code.contains("if (args[0].type == PL_INT && args[1].type == PL_INT)"),
+            "{}",
+            code
+        );
+        assert!(code.contains("global_func_mul(args_"), "{}", code);
+    }
+
+    #[test]
+    fn test_codegen_direct_calls() {
+        let prog = read_string(
+            r#"
+(defun twice (f x) (f (f x)))
+(defun inc (x) (+ x 1))
+(twice inc 1)
+"#,
+        )
+        .unwrap();
+        let options = CompileOptions {
+            inline_threshold: 0,
+            ..CompileOptions::default()
+        };
+        let code = compl_to_c_with(prog, &options).unwrap();
+        let code = code.split("Runtime Code Ends").nth(1).unwrap();
+        // `twice` is known, `f` is not
+        assert!(code.contains("= global_func_0_twice(args_"), "{}", code);
+        assert_eq!(code.matches("__PL_funcall").count(), 2, "{}", code);
+    }
+
+    #[test]
+    fn test_codegen_under_applied_calls() {
+        let prog = read_string(
+            r#"
+(defun add (x y) (+ x y))
+((add 1) 2)
+"#,
+        )
+        .unwrap();
+        let options = CompileOptions {
+            inline_threshold: 0,
+            ..CompileOptions::default()
+        };
+        let code = compl_to_c_with(prog, &options).unwrap();
+        let code = code.split("Runtime Code Ends").nth(1).unwrap();
+        // `add` is called through its pointer, which checks the number of arguments
+        assert!(
+            code.contains("__new_FUNCPTR(global_func_0_add, 2)"),
+            "{}",
+            code
+        );
+        assert!(!code.contains("= global_func_0_add(args_"), "{}", code);
+        assert_eq!(code.matches("__PL_funcall").count(), 2, "{}", code);
+    }
+
+    #[test]
+    fn test_codegen_self_tail_calls() {
+        let prog = read_string(
+            r#"
+(defun sum-to ((n : int)) (let loop ((i 1) (acc 0)) (if (> i n) acc (loop (+ i 1) (+ acc i)))))
+(defun fib (n) (if (< n 2) n (+ (fib (- n 1)) (fib (- n 2)))))
+(sum-to 10)
+"#,
+        )
+        .unwrap();
+        let options = CompileOptions {
+            inline_threshold: 0,
+            ..CompileOptions::default()
+        };
+        let code = compl_to_c_with(prog, &options).unwrap();
+        let code = code.split("Runtime Code Ends").nth(1).unwrap();
+        // The loop becomes a C loop, which captures `n`, rather than a recursive call
+        let (_, loop_def) = code.split_once("(PLV *freevars, PLV *args){").unwrap();
+        assert!(loop_def.contains("while (1) {"), "{}", code);
+        assert!(!loop_def.contains("__PL_funcall"), "{}", code);
+        // Calls that are not in tail position stay recursive
+        assert_eq!(code.matches("while (1)").count(), 1, "{}", code);
+    }
+
+    #[test]
+    fn test_codegen_values_in_structs() {
+        let prog = read_string(
+            r#"
+(defun divmod (a b) (values (quotient a b) (remainder a b)))
+(defun halves (n) (divmod n 2))
+(let-values (((q r) (halves 7))) (+ q r))
+(halves 9)
+"#,
+        )
+        .unwrap();
+        let options = CompileOptions {
+            inline_threshold: 0,
+            ..CompileOptions::default()
+        };
+        let code = compl_to_c_with(prog, &options).unwrap();
+        let code = code.split("Runtime Code Ends").nth(1).unwrap();
+        assert!(
+            code.contains("typedef struct { PLV v[2]; } PLValues2;"),
+            "{}",
+            code
+        );
+        // `halves` passes on the struct of `divmod`, which `let-values` takes apart
+        assert!(
+            code.contains("return global_func_0_divmod__values(args_"),
+            "{}",
+            code
+        );
+        assert!(
+            code.contains("= global_func_1_halves__values(args_"),
+            "{}",
+            code
+        );
+        assert!(code.contains("PLValues2 values_"), "{}", code);
+        // The values are only moved to the heap when `halves` is called by pointer
+        assert_eq!(code.matches("__new_TUPLE").count(), 2, "{}", code);
+    }
+}
@@ -0,0 +1,253 @@
+use std::collections::HashSet;
+
+use super::anormal::a_normalize;
+use crate::Expr;
+use crate::builtins::is_builtin;
+
+/// Variables in scope, innermost last, with their value if it is a known literal
+struct Env {
+    vars: Vec<(String, Option<Expr>)>,
+    /// Names defined at the top level; they shadow the builtins everywhere
+    globals: HashSet<String>,
+}
+
+impl Env {
+    fn get(&self, id: &str) -> Option<&Option<Expr>> {
+        self.vars
+            .iter()
+            .rev()
+            .find(|(name, _)| name == id)
+            .map(|(_, value)| value)
+    }
+
+    /// Whether `id` refers to the builtin of that name
+    fn is_primitive(&self, id: &str) -> bool {
+        is_builtin(id) && self.get(id).is_none() && !self.globals.contains(id)
+    }
+}
+
+fn is_literal(expr: &Expr) -> bool {
+    expr.is_atom() && !matches!(expr, Expr::Id(_))
+}
+
+/// Evaluate a builtin on literal operands, exactly as the interpreter and the C
+/// runtime would. Applications that would fail at runtime (type errors, division by
+/// zero, integer overflow) are left alone.
+fn fold_primitive(op: &str, lhs: &Expr, rhs: &Expr) -> Option<Expr> {
+    match (lhs, rhs) {
+        (Expr::Int(x), Expr::Int(y)) => {
+            let (x, y) = (*x, *y);
+            match op {
+                "+" => x.checked_add(y).map(Expr::Int),
+                "-" => x.checked_sub(y).map(Expr::Int),
+                "*" => x.checked_mul(y).map(Expr::Int),
+                "/" => x.checked_div(y).map(Expr::Int),
+                "=" => Some(Expr::Bool(x == y)),
+                "<" => Some(Expr::Bool(x < y)),
+                "<=" => Some(Expr::Bool(x <= y)),
+                ">" => Some(Expr::Bool(x > y)),
+                ">=" => Some(Expr::Bool(x >= y)),
+                _ => None,
+            }
+        }
+        (Expr::Float(x), Expr::Float(y)) => {
+            let (x, y) = (*x, *y);
+            match op {
+                "+" => Some(Expr::Float(x + y)),
+                "-" => Some(Expr::Float(x - y)),
+                "*" => Some(Expr::Float(x * y)),
+                "/" if y != 0.0 => Some(Expr::Float(x / y)),
+                "=" => Some(Expr::Bool(x == y)),
+                "<" => Some(Expr::Bool(x < y)),
+                "<=" => Some(Expr::Bool(x <= y)),
+                ">" => Some(Expr::Bool(x > y)),
+                ">=" => Some(Expr::Bool(x >= y)),
+                _ => None,
+            }
+        }
+        // Strings are compared by the runtime; every other pair of literals is
+        // equal exactly when the values are
+        (Expr::Str(_), _) | (_, Expr::Str(_)) => None,
+        _ if op == "=" && is_literal(lhs) && is_literal(rhs) => Some(Expr::Bool(lhs == rhs)),
+        _ => None,
+    }
+}
+
+fn fold_expr(expr: Expr, env: &mut Env) -> Expr {
+    match expr {
+        Expr::Nil | Expr::Bool(_) | Expr::Int(_) | Expr::Float(_) | Expr::Str(_) => expr,
+        Expr::Id(id) => match env.get(&id) {
+            Some(Some(value)) => value.clone(),
+            _ => Expr::Id(id),
+        },
+        Expr::Form(form) => {
+            let form: Vec<Expr> = form.into_iter().map(|e| fold_expr(e, env)).collect();
+            if let [Expr::Id(op), lhs, rhs] = form.as_slice()
+                && env.is_primitive(op)
+                && let Some(value) = fold_primitive(op, lhs, rhs)
+            {
+                value
+            } else {
+                Expr::Form(form)
+            }
+        }
+        Expr::Let { bindings, body } => {
+            assert!(
+                bindings.len() == 1,
+                "Constant folding only supports single binding let (needs to be A-normalized)"
+            );
+            let (x, e) = bindings.into_iter().next().unwrap();
+            let e = fold_expr(e, env);
+            if is_literal(&e) {
+                // Propagate the constant instead of binding it
+                env.vars.push((x, Some(e)));
+                let body = fold_expr(*body, env);
+                env.vars.pop();
+                body
+            } else {
+                env.vars.push((x.clone(), None));
+                let body = fold_expr(*body, env);
+                env.vars.pop();
+                Expr::Let {
+                    bindings: vec![(x, e)],
+                    body: Box::new(body),
+                }
+            }
+        }
+        Expr::If { cond, then, else_ } => match fold_expr(*cond, env) {
+            Expr::Bool(true) => fold_expr(*then, env),
+            Expr::Bool(false) => fold_expr(*else_, env),
+            cond => Expr::If {
+                cond: Box::new(cond),
+                then: Box::new(fold_expr(*then, env)),
+                else_: Box::new(fold_expr(*else_, env)),
+            },
+        },
+        Expr::LetFun {
+            name,
+            args,
+            fun_body,
+            expr_body,
+        } => {
+            env.vars.push((name.clone(), None));
+            let expr_body = fold_expr(*expr_body, env);
+            let fun_body = fold_function(&args, *fun_body, env);
+            env.vars.pop();
+            Expr::LetFun {
+                name,
+                args,
+                fun_body: Box::new(fun_body),
+                expr_body: Box::new(expr_body),
+            }
+        }
+        _ => panic!("Invalid Expr for constant folding: {}", expr),
+    }
+}
+
+fn fold_function(args: &[String], body: Expr, env: &mut Env) -> Expr {
+    for arg in args {
+        env.vars.push((arg.clone(), None));
+    }
+    let body = fold_expr(body, env);
+    for _ in args {
+        env.vars.pop();
+    }
+    body
+}
+
+/// Evaluate applications of builtins to literals and `if`s on literal conditions in
+/// an A-normalized program, propagating the resulting constants. The result is
+/// A-normalized again.
+pub fn const_fold(prog: Vec<Expr>) -> Vec<Expr> {
+    let globals = prog
+        .iter()
+        .filter_map(|expr| match expr {
+            Expr::Def { x, .. } => Some(x.clone()),
+            Expr::Defun { name, .. } => Some(name.clone()),
+            _ => None,
+        })
+        .collect();
+    let mut env = Env {
+        vars: Vec::new(),
+        globals,
+    };
+    let prog = prog
+        .into_iter()
+        .map(|expr| match expr {
+            Expr::Def { x, y } => Expr::Def {
+                x,
+                y: Box::new(fold_expr(*y, &mut env)),
+            },
+            Expr::Defun {
+                name,
+                args,
+                body,
+                sig,
+            } => Expr::Defun {
+                body: Box::new(fold_function(&args, *body, &mut env)),
+                name,
+                args,
+                sig,
+            },
+            _ => fold_expr(expr, &mut env),
+        })
+        .collect();
+    a_normalize(prog)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        compl::{
+            anormal::a_normalize, copyprop::copy_prop, knormal::k_normalize, util::NameGenerator,
+        },
+        read_string,
+    };
+
+    use super::*;
+
+    fn fold_string(input: &str) -> Vec<Expr> {
+        let prog = read_string(input).unwrap();
+        let kprog = k_normalize(prog, &mut NameGenerator::new());
+        const_fold(copy_prop(a_normalize(kprog)))
+    }
+
+    #[test]
+    fn constfold_test_arith() {
+        let prog = fold_string(
+            r#"
+(if (> -1 2) (+ 3 4) (* 5 6))
+(let ((x (* 2.5 2.0))) (< x 6.0))
+(= nil nil)
+(defun f (y) (if (= y 0) 1 (+ y (* 2 3))))
+"#,
+        );
+        assert_eq!(prog[0], Expr::Int(30));
+        assert_eq!(prog[1], Expr::Bool(true));
+        assert_eq!(prog[2], Expr::Bool(true));
+        assert_eq!(
+            prog[3].to_string(),
+            "(defun f (y) (let ((%t2 (= y 0))) (if %t2 1 (+ y 6))))"
+        );
+    }
+
+    #[test]
+    fn constfold_test_runtime_errors_kept() {
+        let prog = fold_string(
+            r#"
+(/ 1 0)
+(+ 1 2.0)
+(* 9223372036854775807 2)
+"#,
+        );
+        for expr in prog {
+            assert!(matches!(expr, Expr::Form(_)), "{}", expr);
+        }
+    }
+
+    #[test]
+    fn constfold_test_shadowed_builtin() {
+        let prog = fold_string("(defun f (+) (+ 1 2))");
+        assert_eq!(prog[0].to_string(), "(defun f (+) (+ 1 2))");
+    }
+}
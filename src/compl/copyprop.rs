@@ -1,43 +1,63 @@
 use crate::Expr;
 
+/// Variables in scope: the atom an eliminated variable is a copy of, or `None` for a
+/// variable that is still bound in the output
 #[derive(Debug, Clone, PartialEq)]
-struct Env(Vec<(String, Expr)>);
+struct Env(Vec<(String, Option<Expr>)>);
 impl Env {
     fn new() -> Self {
         Env(Vec::new())
     }
     fn push(&mut self, x: String, e: Expr) {
-        self.0.push((x, e));
+        self.0.push((x, Some(e)));
     }
-    fn get(&self, id: &str) -> Option<&Expr> {
+    fn get_origin(&self, id: &str) -> Expr {
+        // Copies are resolved when they are pushed, so one lookup is enough
         for (name, expr) in self.0.iter().rev() {
             if name == id {
-                return Some(expr);
+                return expr.clone().unwrap_or_else(|| Expr::Id(id.to_string()));
             }
         }
-        None
+        Expr::Id(id.to_string())
     }
-    fn get_origin(&self, id: &str) -> Expr {
-        // println!("get_origin: {}", id);
-        let mut id = id.to_string();
-        loop {
-            if let Some(expr) = self.get(&id) {
-                match expr {
-                    Expr::Id(new_id) => {
-                        id = new_id.clone();
-                    }
-                    _ => return expr.clone(),
-                }
-            } else {
-                return Expr::Id(id);
+    /// Bind `x` in the output. The variables that are copies of an outer `x` would be
+    /// captured, so they are returned to be bound again before `x` is shadowed.
+    fn shadow(&mut self, x: &str) -> Vec<String> {
+        let mut copies = Vec::new();
+        for (i, (y, e)) in self.0.iter().enumerate() {
+            let visible = !self.0[i + 1..].iter().any(|(z, _)| z == y);
+            if visible && *e == Some(Expr::Id(x.to_string())) {
+                copies.push(y.clone());
             }
         }
+        for y in &copies {
+            self.0.push((y.clone(), None));
+        }
+        self.0.push((x.to_string(), None));
+        copies
     }
 }
 
+/// Bind each of `copies` to `x` around `expr`
+fn rebind_copies(copies: Vec<String>, x: &str, expr: Expr) -> Expr {
+    copies.into_iter().rev().fold(expr, |body, y| Expr::Let {
+        bindings: vec![(y, Expr::Id(x.to_string()))],
+        body: Box::new(body),
+    })
+}
+
 pub fn copy_prop(prog: Vec<Expr>) -> Vec<Expr> {
     let mut env = Env::new();
     let mut new_prog = Vec::new();
+    // Define copies of the globals that a definition is about to shadow
+    let shadow = |env: &mut Env, new_prog: &mut Vec<Expr>, x: &str| {
+        for y in env.shadow(x) {
+            new_prog.push(Expr::Def {
+                x: y,
+                y: Box::new(Expr::Id(x.to_string())),
+            });
+        }
+    };
     for expr in prog {
         match expr {
             Expr::Def { x, y } => {
@@ -47,18 +67,30 @@ pub fn copy_prop(prog: Vec<Expr>) -> Vec<Expr> {
                         env.push(x.clone(), new_y.clone());
                     }
                 } else {
+                    shadow(&mut env, &mut new_prog, &x);
                     new_prog.push(Expr::Def {
                         x,
                         y: Box::new(new_y),
                     });
                 }
             }
-            Expr::Defun { name, args, body } => {
-                let new_body = copy_prop_helper(*body, &env);
+            Expr::Defun {
+                name,
+                args,
+                body,
+                sig,
+            } => {
+                shadow(&mut env, &mut new_prog, &name);
+                let mut body_env = env.clone();
+                for arg in &args {
+                    shadow(&mut body_env, &mut new_prog, arg);
+                }
+                let new_body = copy_prop_helper(*body, &body_env);
                 new_prog.push(Expr::Defun {
                     name,
                     args,
                     body: Box::new(new_body),
+                    sig,
                 });
             }
             _ => {
@@ -91,24 +123,21 @@ fn copy_prop_helper(expr: Expr, env: &Env) -> Expr {
                 "Copy propagation only supports single binding let (needs to be A-normalized)"
             );
 
-            let (x, e) = bindings[0].clone();
-            if e == Expr::Id(x.clone()) {
-                // If the binding is a direct copy of itself, we can optimize it away
-                *body
-            } else {
-                let optimized_e = copy_prop_helper(e.clone(), env);
+            let (x, e) = bindings.into_iter().next().unwrap();
+            let optimized_e = copy_prop_helper(e, env);
 
-                if optimized_e.is_atom() {
-                    let mut new_env = env.clone();
-                    new_env.push(x.clone(), optimized_e.clone());
-                    copy_prop_helper(*body, &new_env)
-                } else {
-                    let optimized_body = copy_prop_helper(*body, env);
-                    Expr::Let {
-                        bindings: vec![(x, optimized_e)],
-                        body: Box::new(optimized_body),
-                    }
-                }
+            let mut new_env = env.clone();
+            if optimized_e.is_atom() {
+                new_env.push(x, optimized_e);
+                copy_prop_helper(*body, &new_env)
+            } else {
+                let copies = new_env.shadow(&x);
+                let optimized_body = copy_prop_helper(*body, &new_env);
+                let expr = Expr::Let {
+                    bindings: vec![(x.clone(), optimized_e)],
+                    body: Box::new(optimized_body),
+                };
+                rebind_copies(copies, &x, expr)
             }
         }
         Expr::If { cond, then, else_ } => {
@@ -128,15 +157,30 @@ fn copy_prop_helper(expr: Expr, env: &Env) -> Expr {
             fun_body,
             expr_body,
         } => {
-            let optimized_fun_body = copy_prop_helper(*fun_body, env);
-            let optimized_expr_body = copy_prop_helper(*expr_body, env);
+            // The name is bound in both bodies, the arguments only in the function's
+            let mut body_env = env.clone();
+            let mut copies = vec![(name.clone(), body_env.shadow(&name))];
+            let mut fun_env = body_env.clone();
+            for arg in &args {
+                let arg_copies = fun_env.shadow(arg);
+                for y in &arg_copies {
+                    body_env.0.push((y.clone(), None));
+                }
+                copies.push((arg.clone(), arg_copies));
+            }
+            let optimized_fun_body = copy_prop_helper(*fun_body, &fun_env);
+            let optimized_expr_body = copy_prop_helper(*expr_body, &body_env);
 
-            Expr::LetFun {
+            let expr = Expr::LetFun {
                 name,
                 args,
                 fun_body: Box::new(optimized_fun_body),
                 expr_body: Box::new(optimized_expr_body),
-            }
+            };
+            copies
+                .into_iter()
+                .rev()
+                .fold(expr, |expr, (x, copies)| rebind_copies(copies, &x, expr))
         }
         Expr::Form(exprs) => {
             let optimized_exprs = exprs
@@ -152,15 +196,21 @@ fn copy_prop_helper(expr: Expr, env: &Env) -> Expr {
 #[cfg(test)]
 mod test {
     use crate::{
-        compl::{anormal::a_normalize, closure::closure_convert, knormal::k_normalize}, format_prog, read_file
+        Value,
+        compl::{
+            anormal::a_normalize, closure::closure_convert, knormal::k_normalize,
+            util::NameGenerator,
+        },
+        format_prog,
+        intpt::{create_environment, file::process_string},
+        read_file, read_string,
     };
 
     use super::*;
 
     #[test]
     fn test_copyprop() {
-        let prog = read_file("./examples/factorial.purelisp")
-            .unwrap();
+        let prog = read_file("./examples/factorial.purelisp").unwrap();
         let mut namer = crate::compl::util::NameGenerator::new();
         let kprog = k_normalize(prog.clone(), &mut namer);
         let aprog = a_normalize(kprog.clone());
@@ -172,4 +222,27 @@ mod test {
         println!("copy-propagated:\n{}", format_prog(&cprog));
         println!("closure-converted:\n{}", format_prog(&lprog));
     }
+
+    #[test]
+    fn test_copyprop_shadowed_origin() {
+        // `y` and `b` are copies of variables that are bound again before they are used
+        let source = "(def x 1)\n\
+             (let ((y x)) (let ((x 2)) (+ x y)))\n\
+             (defun f (a) (let ((b a)) (let ((a (+ b 1))) (* a b))))\n\
+             (f 3)\n\
+             (def z x)\n\
+             (def x (+ x 4))\n\
+             (+ x z)";
+        let prog = read_string(source).unwrap();
+        let kprog = k_normalize(prog, &mut NameGenerator::new());
+        let cprog = copy_prop(a_normalize(kprog));
+        let results = process_string(&format_prog(&cprog), &mut create_environment()).unwrap();
+        let values: Vec<_> = cprog
+            .iter()
+            .zip(results)
+            .filter(|(expr, _)| !matches!(expr, Expr::Def { .. } | Expr::Defun { .. }))
+            .map(|(_, value)| value)
+            .collect();
+        assert_eq!(values, [Value::Int(3), Value::Int(12), Value::Int(6)]);
+    }
 }
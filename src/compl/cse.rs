@@ -0,0 +1,198 @@
+use crate::Expr;
+
+/// What is known at a point of an A-normalized expression
+#[derive(Debug, Clone, Default)]
+struct Avail {
+    /// Applications already computed by an enclosing `let`, with the variable holding
+    /// their value. Operands are canonical (see `copies`).
+    forms: Vec<(Vec<Expr>, String)>,
+    /// Variables bound to a copy of another variable, mapped to the original
+    copies: Vec<(String, String)>,
+}
+
+impl Avail {
+    fn canonical(&self, expr: &Expr) -> Expr {
+        match expr {
+            Expr::Id(id) => match self.copies.iter().rev().find(|(x, _)| x == id) {
+                Some((_, origin)) => Expr::Id(origin.clone()),
+                None => expr.clone(),
+            },
+            _ => expr.clone(),
+        }
+    }
+
+    /// Forget everything that mentions `name`, which is about to be rebound
+    fn rebind(&mut self, name: &str) {
+        let id = Expr::Id(name.to_string());
+        self.forms
+            .retain(|(form, var)| var != name && !form.contains(&id));
+        self.copies
+            .retain(|(x, origin)| x != name && origin != name);
+    }
+}
+
+fn cse_expr(expr: Expr, avail: &Avail) -> Expr {
+    match expr {
+        Expr::Let { bindings, body } => {
+            assert!(
+                bindings.len() == 1,
+                "CSE only supports single binding let (needs to be A-normalized)"
+            );
+            let (x, e) = bindings.into_iter().next().unwrap();
+            let mut avail = avail.clone();
+            let e = match e {
+                Expr::Form(form) => {
+                    let key: Vec<Expr> = form.iter().map(|e| avail.canonical(e)).collect();
+                    avail.rebind(&x);
+                    match avail.forms.iter().find(|(f, _)| *f == key) {
+                        // Already computed: reuse the earlier result
+                        Some((_, var)) => {
+                            let var = var.clone();
+                            avail.copies.push((x.clone(), var.clone()));
+                            Expr::Id(var)
+                        }
+                        None => {
+                            if !key.contains(&Expr::Id(x.clone())) {
+                                avail.forms.push((key, x.clone()));
+                            }
+                            Expr::Form(form)
+                        }
+                    }
+                }
+                Expr::Id(id) => {
+                    let origin = avail.canonical(&Expr::Id(id.clone()));
+                    avail.rebind(&x);
+                    if let Expr::Id(origin) = origin
+                        && origin != x
+                    {
+                        avail.copies.push((x.clone(), origin));
+                    }
+                    Expr::Id(id)
+                }
+                e => {
+                    let e = cse_expr(e, &avail);
+                    avail.rebind(&x);
+                    e
+                }
+            };
+            let body = cse_expr(*body, &avail);
+            Expr::Let {
+                bindings: vec![(x, e)],
+                body: Box::new(body),
+            }
+        }
+        Expr::If { cond, then, else_ } => Expr::If {
+            cond,
+            then: Box::new(cse_expr(*then, avail)),
+            else_: Box::new(cse_expr(*else_, avail)),
+        },
+        Expr::LetFun {
+            name,
+            args,
+            fun_body,
+            expr_body,
+        } => {
+            // Function bodies start afresh so that closures capture no extra variables
+            let fun_body = cse_expr(*fun_body, &Avail::default());
+            let mut avail = avail.clone();
+            avail.rebind(&name);
+            let expr_body = cse_expr(*expr_body, &avail);
+            Expr::LetFun {
+                name,
+                args,
+                fun_body: Box::new(fun_body),
+                expr_body: Box::new(expr_body),
+            }
+        }
+        _ => expr,
+    }
+}
+
+/// Share repeated applications in an A-normalized program.
+///
+/// As every function is pure, `(let ((x (f a b))) ... (let ((y (f a b))) ...))`
+/// can bind `y` to `x` instead of calling `f` again. The copies this leaves behind
+/// are removed by running `copy_prop` afterwards.
+pub fn cse(prog: Vec<Expr>) -> Vec<Expr> {
+    prog.into_iter()
+        .map(|expr| match expr {
+            Expr::Def { x, y } => Expr::Def {
+                x,
+                y: Box::new(cse_expr(*y, &Avail::default())),
+            },
+            Expr::Defun {
+                name,
+                args,
+                body,
+                sig,
+            } => Expr::Defun {
+                name,
+                args,
+                body: Box::new(cse_expr(*body, &Avail::default())),
+                sig,
+            },
+            _ => cse_expr(expr, &Avail::default()),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        compl::{
+            anormal::a_normalize, compl_to_ir, copyprop::copy_prop, knormal::k_normalize,
+            util::NameGenerator,
+        },
+        format_prog, read_string,
+    };
+
+    use super::*;
+
+    const PROG: &str = r#"
+(defun f (a b)
+  (if (< a 0)
+      (f b a)
+      (+ (* a b) (- (* a b) (* b a)))))
+(f 2 3)
+"#;
+
+    #[test]
+    fn cse_test_shared_forms() {
+        let prog = read_string(PROG).unwrap();
+        let before = copy_prop(a_normalize(k_normalize(
+            prog.clone(),
+            &mut NameGenerator::new(),
+        )));
+        let after = copy_prop(cse(before.clone()));
+        println!("before:\n{}", format_prog(&before));
+        println!("after:\n{}", format_prog(&after));
+        let before = before[0].to_string();
+        let after = after[0].to_string();
+        assert_eq!(before.matches("(* a b)").count(), 2, "{}", before);
+        assert_eq!(after.matches("(* a b)").count(), 1, "{}", after);
+        // Different operand order is a different application
+        assert_eq!(after.matches("(* b a)").count(), 1, "{}", after);
+
+        let ir = compl_to_ir(prog);
+        println!("compiled:\n{}", ir);
+        assert_eq!(ir.matches("(* a b)").count(), 1, "{}", ir);
+    }
+
+    #[test]
+    fn cse_test_scoping() {
+        // Applications in one branch are not available in the other, and a
+        // rebound operand makes an application different
+        let prog = read_string(
+            r#"
+(defun g (x y)
+  (let ((a (if (< x y) (+ (* x y) 1) (- (* x y) 1))))
+    (let ((b (* x y)))
+      (let ((x (+ x 1))) (+ a (+ b (* x y)))))))
+"#,
+        )
+        .unwrap();
+        let before = copy_prop(a_normalize(k_normalize(prog, &mut NameGenerator::new())));
+        let after = copy_prop(cse(before.clone()));
+        assert_eq!(before, after);
+    }
+}
@@ -0,0 +1,211 @@
+use std::collections::HashSet;
+
+use crate::Expr;
+
+/// Record every name `expr` refers to (ignoring shadowing, which only over-approximates)
+fn collect_refs(expr: &Expr, refs: &mut HashSet<String>) {
+    match expr {
+        Expr::Id(id) => {
+            refs.insert(id.clone());
+        }
+        Expr::Form(form) => form.iter().for_each(|e| collect_refs(e, refs)),
+        Expr::Let { bindings, body } => {
+            bindings.iter().for_each(|(_, e)| collect_refs(e, refs));
+            collect_refs(body, refs);
+        }
+        Expr::If { cond, then, else_ } => {
+            collect_refs(cond, refs);
+            collect_refs(then, refs);
+            collect_refs(else_, refs);
+        }
+        Expr::LetClos {
+            closid,
+            freevars,
+            body,
+            ..
+        } => {
+            refs.insert(closid.clone());
+            refs.extend(freevars.iter().cloned());
+            collect_refs(body, refs);
+        }
+        Expr::Def { y, .. } => collect_refs(y, refs),
+        Expr::Defun { body, .. } | Expr::DefClos { body, .. } => collect_refs(body, refs),
+        Expr::Nil | Expr::Bool(_) | Expr::Int(_) | Expr::Float(_) | Expr::Str(_) => {}
+        _ => panic!("Invalid Expr for dead code elimination: {}", expr),
+    }
+}
+
+fn refers_to(expr: &Expr, name: &str) -> bool {
+    let mut refs = HashSet::new();
+    collect_refs(expr, &mut refs);
+    refs.contains(name)
+}
+
+/// Whether evaluating `expr` can be skipped when its value is unused: it cannot fail
+/// or loop. Calls are never considered pure, as even builtins fail on bad operands.
+fn is_pure(expr: &Expr) -> bool {
+    match expr {
+        Expr::If { then, else_, .. } => is_pure(then) && is_pure(else_),
+        _ => expr.is_atom(),
+    }
+}
+
+fn dce_expr(expr: Expr) -> Expr {
+    match expr {
+        Expr::Let { bindings, body } => {
+            assert!(
+                bindings.len() == 1,
+                "Dead code elimination only supports single binding let (needs to be A-normalized)"
+            );
+            let (x, e) = bindings.into_iter().next().unwrap();
+            let body = dce_expr(*body);
+            if is_pure(&e) && !refers_to(&body, &x) {
+                body
+            } else {
+                Expr::Let {
+                    bindings: vec![(x, dce_expr(e))],
+                    body: Box::new(body),
+                }
+            }
+        }
+        Expr::If { cond, then, else_ } => Expr::If {
+            cond,
+            then: Box::new(dce_expr(*then)),
+            else_: Box::new(dce_expr(*else_)),
+        },
+        Expr::LetClos {
+            name,
+            closid,
+            freevars,
+            body,
+        } => {
+            // Building a closure only captures variables, so an unused one can go
+            let body = dce_expr(*body);
+            if refers_to(&body, &name) {
+                Expr::LetClos {
+                    name,
+                    closid,
+                    freevars,
+                    body: Box::new(body),
+                }
+            } else {
+                body
+            }
+        }
+        _ => expr,
+    }
+}
+
+/// Remove unused pure `let` bindings and closures from a closure-converted program,
+/// then every `defun`/`defclos` that is not reachable from the top-level expressions
+/// and `def`s.
+pub fn dead_code_elim(prog: Vec<Expr>) -> Vec<Expr> {
+    let prog: Vec<Expr> = prog
+        .into_iter()
+        .map(|expr| match expr {
+            Expr::Def { x, y } => Expr::Def {
+                x,
+                y: Box::new(dce_expr(*y)),
+            },
+            Expr::Defun {
+                name,
+                args,
+                body,
+                sig,
+            } => Expr::Defun {
+                name,
+                args,
+                body: Box::new(dce_expr(*body)),
+                sig,
+            },
+            Expr::DefClos {
+                name,
+                freevars,
+                args,
+                body,
+            } => Expr::DefClos {
+                name,
+                freevars,
+                args,
+                body: Box::new(dce_expr(*body)),
+            },
+            _ => dce_expr(expr),
+        })
+        .collect();
+
+    let function_name = |expr: &Expr| match expr {
+        Expr::Defun { name, .. } | Expr::DefClos { name, .. } => Some(name.clone()),
+        _ => None,
+    };
+
+    // Mark the functions reachable from the roots
+    let mut live = HashSet::new();
+    let mut worklist: Vec<&Expr> = prog.iter().filter(|e| function_name(e).is_none()).collect();
+    while let Some(expr) = worklist.pop() {
+        let mut refs = HashSet::new();
+        collect_refs(expr, &mut refs);
+        for def in prog.iter() {
+            if let Some(name) = function_name(def)
+                && refs.contains(&name)
+                && live.insert(name)
+            {
+                worklist.push(def);
+            }
+        }
+    }
+
+    prog.into_iter()
+        .filter(|expr| function_name(expr).is_none_or(|name| live.contains(&name)))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        compl::{
+            anormal::a_normalize, closure::closure_convert, constfold::const_fold,
+            copyprop::copy_prop, knormal::k_normalize, util::NameGenerator,
+        },
+        format_prog, read_string,
+    };
+
+    use super::*;
+
+    #[test]
+    fn dce_test_globals_and_closures() {
+        let mut namer = NameGenerator::new();
+        let prog = read_string(
+            r#"
+(defun unused (x) (unused x))
+(defun used (x) (* x 2))
+(defun helper (x) (used x))
+(let ((f (fn (x) (+ x 1))) (y (if true 1 (undefined)))) (helper 3))
+"#,
+        )
+        .unwrap();
+        let kprog = k_normalize(prog, &mut namer);
+        let cprog = const_fold(copy_prop(a_normalize(kprog)));
+        let converted = closure_convert(cprog, &mut namer);
+        let eliminated = dead_code_elim(converted.clone());
+        println!("closure-converted:\n{}", format_prog(&converted));
+        println!("dead code eliminated:\n{}", format_prog(&eliminated));
+
+        let names: Vec<String> = eliminated
+            .iter()
+            .filter_map(|expr| match expr {
+                Expr::Defun { name, .. } | Expr::DefClos { name, .. } => Some(name.clone()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(names, vec!["used", "helper"]);
+        assert_eq!(eliminated.last().unwrap().to_string(), "(helper 3)");
+    }
+
+    #[test]
+    fn dce_test_impure_kept() {
+        let prog = read_string("(let ((x (+ 1 true))) 2)").unwrap();
+        let kprog = k_normalize(prog, &mut NameGenerator::new());
+        let eliminated = dead_code_elim(a_normalize(kprog));
+        assert!(matches!(eliminated[0], Expr::Let { .. }));
+    }
+}
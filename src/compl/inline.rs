@@ -0,0 +1,421 @@
+use std::collections::HashSet;
+use std::rc::Rc;
+
+use super::anormal::a_normalize;
+use super::util::NameGenerator;
+use crate::Expr;
+
+/// Default maximum size (in IR nodes) of a function body that gets inlined
+pub const DEFAULT_THRESHOLD: usize = 30;
+
+/// A function whose body may be copied to its call sites
+struct Candidate {
+    args: Vec<String>,
+    body: Expr,
+    /// Free identifiers of the body; a call site where one of them is rebound
+    /// does not see the same bindings as the function
+    free: HashSet<String>,
+}
+
+/// Binders in scope at the expression being rewritten, innermost last, each with
+/// the inlinable function it names (if any)
+struct Scope {
+    binders: Vec<(String, Option<Rc<Candidate>>)>,
+    /// Number of top-level binders at the bottom of the stack; they share one scope
+    globals: usize,
+}
+
+impl Scope {
+    fn push(&mut self, name: String, candidate: Option<Rc<Candidate>>) {
+        self.binders.push((name, candidate));
+    }
+
+    fn pop(&mut self) {
+        self.binders.pop();
+    }
+
+    /// The inlinable function `name` refers to, if its body means the same here
+    fn lookup(&self, name: &str) -> Option<Rc<Candidate>> {
+        let mut rebound = Vec::new();
+        for (i, (binder, candidate)) in self.binders.iter().enumerate().rev() {
+            if binder == name {
+                let candidate = candidate.clone()?;
+                // An alias `(let ((name f)) ...)` itself rebinds `name`
+                if candidate.free.contains(name)
+                    || rebound.iter().any(|id| candidate.free.contains(*id))
+                {
+                    return None;
+                }
+                return Some(candidate);
+            }
+            if i >= self.globals {
+                rebound.push(binder.as_str());
+            }
+        }
+        None
+    }
+}
+
+/// Number of IR nodes in `expr`
+fn size(expr: &Expr) -> usize {
+    match expr {
+        Expr::Form(form) => 1 + form.iter().map(size).sum::<usize>(),
+        Expr::Let { bindings, body } => {
+            1 + bindings.iter().map(|(_, e)| size(e)).sum::<usize>() + size(body)
+        }
+        Expr::If { cond, then, else_ } => 1 + size(cond) + size(then) + size(else_),
+        Expr::LetFun {
+            fun_body,
+            expr_body,
+            ..
+        } => 1 + size(fun_body) + size(expr_body),
+        _ => 1,
+    }
+}
+
+/// Collect the free identifiers of a K- or A-normalized expression (builtins included)
+pub(super) fn free_ids(expr: &Expr, bound: &mut Vec<String>, free: &mut HashSet<String>) {
+    match expr {
+        Expr::Id(id) => {
+            if !bound.contains(id) {
+                free.insert(id.clone());
+            }
+        }
+        Expr::Form(form) => form.iter().for_each(|e| free_ids(e, bound, free)),
+        Expr::Let { bindings, body } => {
+            for (_, e) in bindings {
+                free_ids(e, bound, free);
+            }
+            let mark = bound.len();
+            bound.extend(bindings.iter().map(|(x, _)| x.clone()));
+            free_ids(body, bound, free);
+            bound.truncate(mark);
+        }
+        Expr::If { cond, then, else_ } => {
+            free_ids(cond, bound, free);
+            free_ids(then, bound, free);
+            free_ids(else_, bound, free);
+        }
+        Expr::LetFun {
+            name,
+            args,
+            fun_body,
+            expr_body,
+        } => {
+            let mark = bound.len();
+            bound.push(name.clone());
+            free_ids(expr_body, bound, free);
+            bound.extend(args.iter().cloned());
+            free_ids(fun_body, bound, free);
+            bound.truncate(mark);
+        }
+        Expr::Nil | Expr::Bool(_) | Expr::Int(_) | Expr::Float(_) | Expr::Str(_) => {}
+        _ => panic!("Invalid Expr for inlining: {}", expr),
+    }
+}
+
+/// A candidate for `body`, unless the function is recursive or too large
+fn candidate(name: &str, args: &[String], body: &Expr, threshold: usize) -> Option<Rc<Candidate>> {
+    let mut free = HashSet::new();
+    free_ids(body, &mut args.to_vec(), &mut free);
+    if free.contains(name) || size(body) > threshold {
+        return None;
+    }
+    Some(Rc::new(Candidate {
+        args: args.to_vec(),
+        body: body.clone(),
+        free,
+    }))
+}
+
+struct Inliner<'a> {
+    threshold: usize,
+    namer: &'a mut NameGenerator,
+}
+
+impl Inliner<'_> {
+    fn inline_expr(&mut self, expr: Expr, scope: &mut Scope) -> Expr {
+        match expr {
+            Expr::Nil
+            | Expr::Bool(_)
+            | Expr::Int(_)
+            | Expr::Float(_)
+            | Expr::Str(_)
+            | Expr::Id(_) => expr,
+            Expr::Form(form) => {
+                if let Some(Expr::Id(callee)) = form.first()
+                    && let Some(candidate) = scope.lookup(callee)
+                    && candidate.args.len() == form.len() - 1
+                {
+                    let mut subst: Vec<(String, Expr)> = candidate
+                        .args
+                        .iter()
+                        .cloned()
+                        .zip(form[1..].iter().cloned())
+                        .collect();
+                    self.rename(candidate.body.clone(), &mut subst)
+                } else {
+                    Expr::Form(form)
+                }
+            }
+            Expr::Let { bindings, body } => {
+                assert!(
+                    bindings.len() == 1,
+                    "Inlining only supports single binding let (needs to be A-normalized)"
+                );
+                let (x, e) = bindings.into_iter().next().unwrap();
+                let e = self.inline_expr(e, scope);
+                // `(let ((x f)) ...)` makes `x` another name for `f`
+                let alias = match &e {
+                    Expr::Id(id) => scope.lookup(id),
+                    _ => None,
+                };
+                scope.push(x.clone(), alias);
+                let body = self.inline_expr(*body, scope);
+                scope.pop();
+                Expr::Let {
+                    bindings: vec![(x, e)],
+                    body: Box::new(body),
+                }
+            }
+            Expr::If { cond, then, else_ } => Expr::If {
+                cond,
+                then: Box::new(self.inline_expr(*then, scope)),
+                else_: Box::new(self.inline_expr(*else_, scope)),
+            },
+            Expr::LetFun {
+                name,
+                args,
+                fun_body,
+                expr_body,
+            } => {
+                let fun_body = self.inline_function(&name, &args, *fun_body, scope);
+                let candidate = candidate(&name, &args, &fun_body, self.threshold);
+                scope.push(name.clone(), candidate);
+                let expr_body = self.inline_expr(*expr_body, scope);
+                scope.pop();
+                Expr::LetFun {
+                    name,
+                    args,
+                    fun_body: Box::new(fun_body),
+                    expr_body: Box::new(expr_body),
+                }
+            }
+            _ => panic!("Invalid Expr for inlining: {}", expr),
+        }
+    }
+
+    /// Inline calls inside the body of function `name`
+    fn inline_function(
+        &mut self,
+        name: &str,
+        args: &[String],
+        body: Expr,
+        scope: &mut Scope,
+    ) -> Expr {
+        scope.push(name.to_string(), None);
+        for arg in args {
+            scope.push(arg.clone(), None);
+        }
+        let body = self.inline_expr(body, scope);
+        for _ in 0..=args.len() {
+            scope.pop();
+        }
+        body
+    }
+
+    /// Copy `expr` with identifiers replaced according to `subst` and every binder
+    /// given a fresh name, so the copy cannot capture or clash with anything
+    fn rename(&mut self, expr: Expr, subst: &mut Vec<(String, Expr)>) -> Expr {
+        match expr {
+            Expr::Id(id) => subst
+                .iter()
+                .rev()
+                .find(|(x, _)| *x == id)
+                .map(|(_, e)| e.clone())
+                .unwrap_or(Expr::Id(id)),
+            Expr::Nil | Expr::Bool(_) | Expr::Int(_) | Expr::Float(_) | Expr::Str(_) => expr,
+            Expr::Form(form) => {
+                Expr::Form(form.into_iter().map(|e| self.rename(e, subst)).collect())
+            }
+            Expr::Let { bindings, body } => {
+                let (x, e) = bindings.into_iter().next().unwrap();
+                let e = self.rename(e, subst);
+                let new_x = self.namer.next("%t");
+                subst.push((x, Expr::Id(new_x.clone())));
+                let body = self.rename(*body, subst);
+                subst.pop();
+                Expr::Let {
+                    bindings: vec![(new_x, e)],
+                    body: Box::new(body),
+                }
+            }
+            Expr::If { cond, then, else_ } => Expr::If {
+                cond: Box::new(self.rename(*cond, subst)),
+                then: Box::new(self.rename(*then, subst)),
+                else_: Box::new(self.rename(*else_, subst)),
+            },
+            Expr::LetFun {
+                name,
+                args,
+                fun_body,
+                expr_body,
+            } => {
+                let new_name = self.namer.next("%f");
+                let new_args: Vec<String> = args.iter().map(|_| self.namer.next("%t")).collect();
+                subst.push((name, Expr::Id(new_name.clone())));
+                let expr_body = self.rename(*expr_body, subst);
+                for (arg, new_arg) in args.into_iter().zip(new_args.iter()) {
+                    subst.push((arg, Expr::Id(new_arg.clone())));
+                }
+                let fun_body = self.rename(*fun_body, subst);
+                subst.truncate(subst.len() - new_args.len() - 1);
+                Expr::LetFun {
+                    name: new_name,
+                    args: new_args,
+                    fun_body: Box::new(fun_body),
+                    expr_body: Box::new(expr_body),
+                }
+            }
+            _ => panic!("Invalid Expr for inlining: {}", expr),
+        }
+    }
+}
+
+/// Inline calls to small non-recursive functions in an A-normalized program.
+///
+/// Unannotated `defun`s and `letfun`s (including the ones `knormal` makes from `fn`,
+/// so immediately applied lambdas are beta-reduced) whose bodies have at most
+/// `threshold` nodes are copied to every call site with matching arity; the
+/// definitions themselves are kept. The result is A-normalized again.
+pub fn inline(prog: Vec<Expr>, threshold: usize, namer: &mut NameGenerator) -> Vec<Expr> {
+    let mut scope = Scope {
+        binders: Vec::new(),
+        globals: 0,
+    };
+    // Top-level definitions are visible throughout the program
+    for expr in prog.iter() {
+        let (name, candidate) = match expr {
+            Expr::Def { x, .. } => (x, None),
+            Expr::Defun {
+                name,
+                args,
+                body,
+                sig,
+            } => {
+                let candidate = if sig.is_empty() {
+                    candidate(name, args, body, threshold)
+                } else {
+                    // Inlining would skip the checks of the annotations
+                    None
+                };
+                (name, candidate)
+            }
+            _ => continue,
+        };
+        if let Some(other) = scope.binders.iter_mut().find(|(x, _)| x == name) {
+            other.1 = None;
+        } else {
+            scope.push(name.clone(), candidate);
+        }
+    }
+    scope.globals = scope.binders.len();
+
+    let mut inliner = Inliner { threshold, namer };
+    let prog = prog
+        .into_iter()
+        .map(|expr| match expr {
+            Expr::Def { x, y } => Expr::Def {
+                x,
+                y: Box::new(inliner.inline_expr(*y, &mut scope)),
+            },
+            Expr::Defun {
+                name,
+                args,
+                body,
+                sig,
+            } => {
+                let body = inliner.inline_function(&name, &args, *body, &mut scope);
+                Expr::Defun {
+                    name,
+                    args,
+                    body: Box::new(body),
+                    sig,
+                }
+            }
+            _ => inliner.inline_expr(expr, &mut scope),
+        })
+        .collect();
+    a_normalize(prog)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        compl::{anormal::a_normalize, copyprop::copy_prop, knormal::k_normalize},
+        format_prog, read_string,
+    };
+
+    use super::*;
+
+    /// The last expression of the program after inlining and copy propagation
+    fn inline_main(input: &str, threshold: usize) -> String {
+        let mut namer = NameGenerator::new();
+        let prog = read_string(input).unwrap();
+        let kprog = k_normalize(prog, &mut namer);
+        let aprog = a_normalize(kprog);
+        let iprog = inline(aprog.clone(), threshold, &mut namer);
+        println!("a-normalized:\n{}", format_prog(&aprog));
+        println!("inlined:\n{}", format_prog(&iprog));
+        copy_prop(iprog).last().unwrap().to_string()
+    }
+
+    #[test]
+    fn inline_test_defun() {
+        let main = inline_main(
+            r#"
+(defun square (x) (* x x))
+(defun fact (n) (if (= n 0) 1 (* n (fact (- n 1)))))
+(+ (square 3) (fact 5))
+"#,
+            DEFAULT_THRESHOLD,
+        );
+        assert!(main.contains("(* 3 3)"), "{}", main);
+        assert!(!main.contains("square"), "{}", main);
+        // Recursive functions are left alone
+        assert!(main.contains("(fact 5)"), "{}", main);
+
+        let main = inline_main("(defun square (x) (* x x))\n(square 3)", 0);
+        assert!(main.contains("(square 3)"), "{}", main);
+    }
+
+    #[test]
+    fn inline_test_beta_reduction() {
+        let main = inline_main("((fn (x y) (+ x y)) 1 2)", DEFAULT_THRESHOLD);
+        assert!(main.contains("(+ 1 2)"), "{}", main);
+        assert!(!main.contains("(%f0 1 2)"), "{}", main);
+    }
+
+    #[test]
+    fn inline_test_shadowed_free_var() {
+        // The body of `g` refers to the global `y`, which is rebound at the call site
+        let main = inline_main(
+            r#"
+(def y 1)
+(let ((g (fn (x) (+ x y)))) (let ((y 5)) (g 2)))
+"#,
+            DEFAULT_THRESHOLD,
+        );
+        assert!(!main.contains("(+ 2 "), "{}", main);
+    }
+
+    #[test]
+    fn inline_test_alias_shadows_free_var() {
+        // The alias `c` of the lambda rebinds the global `c` its body refers to
+        let main = inline_main(
+            "(def c 5)\n(let ((c (fn (x) (* c -2)))) (c 1))",
+            DEFAULT_THRESHOLD,
+        );
+        assert!(main.contains("(%f0 1)"), "{}", main);
+    }
+}
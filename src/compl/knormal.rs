@@ -1,5 +1,8 @@
+use std::collections::HashSet;
+
 use crate::Expr;
 
+use super::inline::free_ids;
 use super::util::NameGenerator;
 
 fn k_normal(expr: Expr, namer: &mut NameGenerator) -> Expr {
@@ -18,14 +21,7 @@ fn k_normal(expr: Expr, namer: &mut NameGenerator) -> Expr {
                     *e = Expr::Id(temp.clone());
                 }
             }
-            if bindings.is_empty() {
-                Expr::Form(kform)
-            } else {
-                Expr::Let {
-                    bindings,
-                    body: Box::new(Expr::Form(kform)),
-                }
-            }
+            expand_let(bindings, Expr::Form(kform))
         }
         Expr::Let { bindings, body } => {
             let mut new_bindings = Vec::new();
@@ -34,7 +30,7 @@ fn k_normal(expr: Expr, namer: &mut NameGenerator) -> Expr {
             }
             let body = k_normal(*body, namer);
             // println!("Need to expand: {:?}", new_bindings);
-            expand_let(new_bindings, body)
+            expand_parallel_let(new_bindings, body, namer)
         }
         Expr::If { cond, then, else_ } => {
             let cond = k_normal(*cond, namer);
@@ -119,12 +115,18 @@ fn k_normal(expr: Expr, namer: &mut NameGenerator) -> Expr {
                 y: Box::new(k_normal(*y, namer)),
             }
         }
-        Expr::Defun { name, args, body } => {
+        Expr::Defun {
+            name,
+            args,
+            body,
+            sig,
+        } => {
             // Normalize the function body
             Expr::Defun {
                 name,
                 args,
                 body: Box::new(k_normal(*body, namer)),
+                sig,
             }
         }
         Expr::LetFun {
@@ -158,6 +160,34 @@ fn expand_let(bindings: Vec<(String, Expr)>, body: Expr) -> Expr {
     new_body
 }
 
+/// Nest the bindings of a `let` like `expand_let`, keeping them parallel: a variable
+/// that a later binding refers to is first bound to a temporary, and only bound
+/// after every expression is evaluated, so that the later binding still sees the
+/// enclosing variable of that name
+fn expand_parallel_let(
+    bindings: Vec<(String, Expr)>,
+    body: Expr,
+    namer: &mut NameGenerator,
+) -> Expr {
+    let mut evaluated = Vec::new();
+    let mut rebound = Vec::new();
+    for (i, (name, expr)) in bindings.iter().enumerate() {
+        let mut free = HashSet::new();
+        for (_, later) in &bindings[i + 1..] {
+            free_ids(later, &mut Vec::new(), &mut free);
+        }
+        if free.contains(name) {
+            let temp = namer.next("%t");
+            evaluated.push((temp.clone(), expr.clone()));
+            rebound.push((name.clone(), Expr::Id(temp)));
+        } else {
+            evaluated.push((name.clone(), expr.clone()));
+        }
+    }
+    evaluated.extend(rebound);
+    expand_let(evaluated, body)
+}
+
 pub fn k_normalize(prog: Vec<Expr>, namer: &mut NameGenerator) -> Vec<Expr> {
     prog.iter()
         .map(|expr| k_normal(expr.clone(), namer))
@@ -219,4 +249,14 @@ mod test {
         println!("original: {}", pretty_format(&expr));
         println!("k-normalized: {}", pretty_format(&kexpr[0]));
     }
+    #[test]
+    fn knormal_test_parallel_let() {
+        // `b` is bound only once `a` is evaluated against the outer `b`
+        let expr = parse("(let ((a b) (b a)) (- a b))");
+        let kexpr = k_normalize(vec![expr], &mut NameGenerator::new());
+        assert_eq!(
+            kexpr[0].to_string(),
+            "(let ((%t0 b)) (let ((b a)) (let ((a %t0)) (- a b))))"
+        );
+    }
 }
@@ -1,28 +1,388 @@
+use std::fmt;
+
 use crate::Expr;
 
 pub mod anormal;
 pub mod closure;
 pub mod codegen;
+pub mod constfold;
 pub mod copyprop;
+pub mod cse;
+pub mod dce;
+pub mod inline;
 pub mod knormal;
 pub mod runtime;
 pub mod util;
+pub mod verify;
+
+/// Settings of the compiler pipeline
+#[derive(Debug, Clone)]
+pub struct CompileOptions {
+    /// Maximum size of a function body to inline (0 disables inlining)
+    pub inline_threshold: usize,
+    /// Names of the passes to run, in order (`None` for the default pipeline)
+    pub passes: Option<Vec<String>>,
+    /// Passes after which the intermediate program is printed
+    pub dump_after: Vec<String>,
+    /// Check the invariants of each pass's output
+    pub verify: bool,
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        CompileOptions {
+            inline_threshold: inline::DEFAULT_THRESHOLD,
+            passes: None,
+            dump_after: Vec::new(),
+            verify: false,
+        }
+    }
+}
+
+/// The shape of the program between passes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Source,
+    KNormal,
+    ANormal,
+    Closure,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Stage::Source => write!(f, "source"),
+            Stage::KNormal => write!(f, "K-normal"),
+            Stage::ANormal => write!(f, "A-normal"),
+            Stage::Closure => write!(f, "closure-converted"),
+        }
+    }
+}
+
+/// State shared by the passes of one compilation
+pub struct PassContext {
+    pub namer: util::NameGenerator,
+    pub options: CompileOptions,
+}
+
+pub type PassFn = fn(Vec<Expr>, &mut PassContext) -> Vec<Expr>;
+
+/// A registered program transformation
+pub struct Pass {
+    pub name: &'static str,
+    /// Stage the program must be in before the pass
+    pub input: Stage,
+    /// Stage the program is in after the pass
+    pub output: Stage,
+    run: PassFn,
+}
+
+/// Runs a pipeline of passes chosen by name from the registered ones
+pub struct PassManager {
+    passes: Vec<Pass>,
+    pipeline: Vec<&'static str>,
+    dump_after: Vec<&'static str>,
+    verify: bool,
+}
+
+const DEFAULT_PIPELINE: [&str; 9] = [
+    "knormal",
+    "anormal",
+    "inline",
+    "copyprop",
+    "constfold",
+    "cse",
+    "copyprop",
+    "closure",
+    "dce",
+];
+
+impl Default for PassManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PassManager {
+    /// A manager with every pass of the compiler registered and the default pipeline
+    pub fn new() -> Self {
+        let mut manager = PassManager {
+            passes: Vec::new(),
+            pipeline: Vec::new(),
+            dump_after: Vec::new(),
+            verify: false,
+        };
+        manager.register("knormal", Stage::Source, Stage::KNormal, |prog, ctx| {
+            knormal::k_normalize(prog, &mut ctx.namer)
+        });
+        manager.register("anormal", Stage::KNormal, Stage::ANormal, |prog, _| {
+            anormal::a_normalize(prog)
+        });
+        manager.register("inline", Stage::ANormal, Stage::ANormal, |prog, ctx| {
+            inline::inline(prog, ctx.options.inline_threshold, &mut ctx.namer)
+        });
+        manager.register("copyprop", Stage::ANormal, Stage::ANormal, |prog, _| {
+            copyprop::copy_prop(prog)
+        });
+        manager.register("constfold", Stage::ANormal, Stage::ANormal, |prog, _| {
+            constfold::const_fold(prog)
+        });
+        manager.register("cse", Stage::ANormal, Stage::ANormal, |prog, _| {
+            cse::cse(prog)
+        });
+        manager.register("closure", Stage::ANormal, Stage::Closure, |prog, ctx| {
+            closure::closure_convert(prog, &mut ctx.namer)
+        });
+        manager.register("dce", Stage::Closure, Stage::Closure, |prog, _| {
+            dce::dead_code_elim(prog)
+        });
+        manager.pipeline = DEFAULT_PIPELINE.to_vec();
+        manager
+    }
+
+    /// Make a pass available to pipelines under `name`, replacing any pass of that name
+    pub fn register(&mut self, name: &'static str, input: Stage, output: Stage, run: PassFn) {
+        self.passes.retain(|pass| pass.name != name);
+        self.passes.push(Pass {
+            name,
+            input,
+            output,
+            run,
+        });
+    }
+
+    fn get(&self, name: &str) -> Result<&Pass, String> {
+        self.passes
+            .iter()
+            .find(|pass| pass.name == name)
+            .ok_or_else(|| {
+                let names: Vec<&str> = self.passes.iter().map(|pass| pass.name).collect();
+                format!("Unknown pass `{}` (available: {})", name, names.join(", "))
+            })
+    }
+
+    /// Select the passes to run, checking that each one gets the stage it expects
+    pub fn set_pipeline<S: AsRef<str>>(&mut self, names: &[S]) -> Result<(), String> {
+        let mut pipeline = Vec::new();
+        let mut stage = Stage::Source;
+        for name in names {
+            let pass = self.get(name.as_ref())?;
+            if pass.input != stage {
+                return Err(format!(
+                    "Pass `{}` expects a {} program, but it is {} at that point",
+                    pass.name, pass.input, stage
+                ));
+            }
+            stage = pass.output;
+            pipeline.push(pass.name);
+        }
+        self.pipeline = pipeline;
+        Ok(())
+    }
+
+    /// Print the program after every run of pass `name`
+    pub fn dump_after(&mut self, name: &str) -> Result<(), String> {
+        let name = self.get(name)?.name;
+        self.dump_after.push(name);
+        Ok(())
+    }
+
+    pub fn set_verify(&mut self, verify: bool) {
+        self.verify = verify;
+    }
+
+    /// Stage of the program after the whole pipeline
+    pub fn output_stage(&self) -> Stage {
+        self.pipeline
+            .last()
+            .map_or(Stage::Source, |name| self.get(name).unwrap().output)
+    }
+
+    /// Configure a manager according to `options`
+    pub fn from_options(options: &CompileOptions) -> Result<Self, String> {
+        let mut manager = PassManager::new();
+        if let Some(passes) = &options.passes {
+            manager.set_pipeline(passes)?;
+        }
+        for name in &options.dump_after {
+            manager.dump_after(name)?;
+        }
+        manager.set_verify(options.verify);
+        Ok(manager)
+    }
+
+    /// Run the pipeline on `prog`. The output of every pass is verified when asked
+    /// to, and always in debug builds.
+    pub fn run(&self, mut prog: Vec<Expr>, ctx: &mut PassContext) -> Result<Vec<Expr>, String> {
+        let verify = self.verify || cfg!(debug_assertions);
+        let externals = if verify {
+            verify::unbound_identifiers(&prog)
+        } else {
+            Default::default()
+        };
+        for name in &self.pipeline {
+            let pass = self.get(name)?;
+            prog = (pass.run)(prog, ctx);
+            if self.dump_after.contains(name) {
+                println!(";; IR after {}\n{}\n", name, crate::format_prog(&prog));
+            }
+            if verify && let Err(violations) = verify::verify(pass.output, &prog, &externals) {
+                let violations: Vec<String> = violations.iter().map(|v| v.to_string()).collect();
+                return Err(format!(
+                    "Output of pass `{}` is not a valid {} program:\n  {}",
+                    name,
+                    pass.output,
+                    violations.join("\n  ")
+                ));
+            }
+        }
+        Ok(prog)
+    }
+}
+
+fn compl_to_stage(prog: Vec<Expr>, options: &CompileOptions) -> Result<(Vec<Expr>, Stage), String> {
+    let manager = PassManager::from_options(options)?;
+    let prog = crate::stdlib::link(prog);
+    let mut ctx = PassContext {
+        namer: util::NameGenerator::new(),
+        options: options.clone(),
+    };
+    let prog = manager.run(prog, &mut ctx)?;
+    Ok((prog, manager.output_stage()))
+}
+
+/// Run the pipeline up to closure conversion, as for code generation
+pub fn compl_to_closure_with(
+    prog: Vec<Expr>,
+    options: &CompileOptions,
+) -> Result<Vec<Expr>, String> {
+    let (prog, stage) = compl_to_stage(prog, options)?;
+    if stage != Stage::Closure {
+        return Err(format!(
+            "C code generation needs a closure-converted program, but the pipeline leaves it {}",
+            stage
+        ));
+    }
+    Ok(prog)
+}
 
 pub fn compl_to_ir(prog: Vec<Expr>) -> String {
-    let mut namer = util::NameGenerator::new();
-    let prog = knormal::k_normalize(prog, &mut namer);
-    let prog = anormal::a_normalize(prog);
-    let prog = copyprop::copy_prop(prog);
-    let prog = closure::closure_convert(prog, &mut namer);
-    crate::format_prog(&prog)
+    compl_to_ir_with(prog, &CompileOptions::default()).unwrap()
+}
+
+pub fn compl_to_ir_with(prog: Vec<Expr>, options: &CompileOptions) -> Result<String, String> {
+    let (prog, _) = compl_to_stage(prog, options)?;
+    Ok(crate::format_prog(&prog))
 }
 
 /// Generate C code directly from a program file
 pub fn compl_to_c(prog: Vec<Expr>) -> String {
-    let mut namer = util::NameGenerator::new();
-    let prog = knormal::k_normalize(prog, &mut namer);
-    let prog = anormal::a_normalize(prog);
-    let prog = copyprop::copy_prop(prog);
-    let prog = closure::closure_convert(prog, &mut namer);
-    codegen::generate_c_code(prog)
+    compl_to_c_with(prog, &CompileOptions::default()).unwrap()
+}
+
+pub fn compl_to_c_with(prog: Vec<Expr>, options: &CompileOptions) -> Result<String, String> {
+    let prog = compl_to_closure_with(prog, options)?;
+    Ok(codegen::generate_c_code(prog))
+}
+
+/// Generate C code from a closure-converted program, such as one read back from the
+/// output of `compl_to_ir`. No pass is run; the program is only verified.
+pub fn compl_ir_to_c(prog: Vec<Expr>) -> Result<String, String> {
+    if let Err(violations) = verify::verify_closure(&prog, &Default::default()) {
+        let violations: Vec<String> = violations.iter().map(|v| v.to_string()).collect();
+        return Err(format!(
+            "Not a valid {} program:\n  {}",
+            Stage::Closure,
+            violations.join("\n  ")
+        ));
+    }
+    Ok(codegen::generate_c_code(prog))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::read_string;
+
+    #[test]
+    fn test_pass_manager_pipeline() {
+        let prog = read_string("(let ((x (+ 1 2)) (y 3)) (* x y))").unwrap();
+        let options = CompileOptions {
+            passes: Some(vec!["knormal".into(), "anormal".into(), "closure".into()]),
+            verify: true,
+            ..CompileOptions::default()
+        };
+        let ir = compl_to_ir_with(prog.clone(), &options).unwrap();
+        assert!(ir.contains("(+ 1 2)"), "{}", ir);
+        assert!(compl_to_c_with(prog.clone(), &options).is_ok());
+
+        // The default pipeline folds everything
+        assert_eq!(compl_to_ir(prog.clone()).trim(), "9");
+
+        let mut manager = PassManager::new();
+        assert!(manager.set_pipeline(&["anormal"]).is_err());
+        assert!(manager.set_pipeline(&["knormal", "bogus"]).is_err());
+        assert!(manager.dump_after("bogus").is_err());
+        manager.set_pipeline(&["knormal", "anormal"]).unwrap();
+        assert_eq!(manager.output_stage(), Stage::ANormal);
+
+        let options = CompileOptions {
+            passes: Some(vec!["knormal".into()]),
+            ..CompileOptions::default()
+        };
+        assert!(compl_to_c_with(prog, &options).is_err());
+    }
+
+    #[test]
+    fn test_compile_from_ir() {
+        for file in [
+            "./examples/annotated.purelisp",
+            "./examples/boolean.purelisp",
+            "./examples/factorial.purelisp",
+            "./examples/highorder.purelisp",
+            "./examples/recursion.purelisp",
+            "./examples/simple.purelisp",
+            "./examples/sqrt.purelisp",
+        ] {
+            let prog = crate::read_file(file).unwrap();
+            let ir = compl_to_ir(prog.clone());
+            let reparsed = read_string(&ir).unwrap();
+            assert_eq!(crate::format_prog(&reparsed), ir, "{}", file);
+            assert_eq!(
+                compl_ir_to_c(reparsed).unwrap(),
+                compl_to_c(prog),
+                "{}",
+                file
+            );
+        }
+
+        let prog = read_string(
+            r#"
+(defclos c (y) (x) (+ x y))
+(letclos (f (c (y))) (f 1))
+(letclos (g (h ())) 2)
+"#,
+        )
+        .unwrap();
+        let err = compl_ir_to_c(prog).unwrap_err();
+        assert!(err.contains("captured variable `y` is unbound"), "{}", err);
+        assert!(err.contains("`h` is not a closure"), "{}", err);
+    }
+
+    #[test]
+    fn test_pass_manager_verify() {
+        let mut manager = PassManager::new();
+        // A broken pass that reintroduces multi-binding lets
+        manager.register("unnormal", Stage::KNormal, Stage::KNormal, |_, _| {
+            crate::read_string("(let ((x 1) (y 2)) (+ x y))").unwrap()
+        });
+        manager.set_pipeline(&["knormal", "unnormal"]).unwrap();
+        let mut ctx = PassContext {
+            namer: util::NameGenerator::new(),
+            options: CompileOptions::default(),
+        };
+        manager.set_verify(true);
+        let err = manager.run(vec![Expr::Int(1)], &mut ctx).unwrap_err();
+        assert!(err.contains("`unnormal`"), "{}", err);
+    }
 }
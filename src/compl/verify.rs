@@ -0,0 +1,342 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use super::Stage;
+use crate::Expr;
+use crate::builtins::is_builtin;
+
+/// A broken invariant of the program between two passes
+#[derive(Debug, Clone, PartialEq)]
+pub struct Violation {
+    pub message: String,
+    /// The offending sub-expression
+    pub expr: String,
+}
+
+impl Violation {
+    fn new(expr: &Expr, message: String) -> Self {
+        let mut text = expr.to_string();
+        if text.chars().count() > 60 {
+            text = format!("{}...", text.chars().take(57).collect::<String>());
+        }
+        Violation {
+            message,
+            expr: text,
+        }
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}\n    in: {}", self.message, self.expr)
+    }
+}
+
+struct Verifier<'a> {
+    stage: Stage,
+    /// Names defined at the top level, visible everywhere
+    globals: HashSet<String>,
+    /// Closures defined by `defclos`
+    closures: HashSet<String>,
+    /// The `defclos` being checked, whose label refers to the running closure
+    current_closure: Option<String>,
+    /// Identifiers that were already unbound in the source program
+    externals: &'a HashSet<String>,
+    /// Local variables in scope, innermost last
+    scope: Vec<String>,
+    violations: Vec<Violation>,
+    unbound: HashSet<String>,
+}
+
+impl Verifier<'_> {
+    fn fail(&mut self, expr: &Expr, message: impl Into<String>) {
+        self.violations.push(Violation::new(expr, message.into()));
+    }
+
+    fn is_bound(&self, id: &str) -> bool {
+        self.scope.iter().any(|x| x == id)
+            || self.globals.contains(id)
+            || self.current_closure.as_deref() == Some(id)
+            || is_builtin(id)
+            || self.externals.contains(id)
+    }
+
+    fn check_atom(&mut self, expr: &Expr, what: &str) {
+        if !expr.is_atom() {
+            self.fail(expr, format!("{} is not an atom", what));
+        }
+    }
+
+    /// Run `f` with `names` bound
+    fn with_scope<I: IntoIterator<Item = String>>(&mut self, names: I, f: impl FnOnce(&mut Self)) {
+        let mark = self.scope.len();
+        self.scope.extend(names);
+        f(self);
+        self.scope.truncate(mark);
+    }
+
+    /// Check a top-level form
+    fn check_top(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Def { y, .. } => self.check(y),
+            Expr::Defun { args, body, .. } => {
+                self.with_scope(args.iter().cloned(), |v| v.check(body));
+            }
+            Expr::DefClos {
+                name,
+                freevars,
+                args,
+                body,
+            } => {
+                if self.stage != Stage::Closure && self.stage != Stage::Source {
+                    self.fail(expr, "closure before closure conversion");
+                }
+                // A closure body sees only its captured variables and arguments
+                let outer = std::mem::take(&mut self.scope);
+                self.current_closure = Some(name.clone());
+                self.with_scope(freevars.iter().chain(args).cloned(), |v| v.check(body));
+                self.current_closure = None;
+                self.scope = outer;
+            }
+            _ => self.check(expr),
+        }
+    }
+
+    fn check(&mut self, expr: &Expr) {
+        let normalized = self.stage != Stage::Source;
+        match expr {
+            Expr::Nil | Expr::Bool(_) | Expr::Int(_) | Expr::Float(_) | Expr::Str(_) => {}
+            Expr::Id(id) => {
+                if !self.is_bound(id) {
+                    if normalized {
+                        self.fail(expr, format!("unbound identifier `{}`", id));
+                    }
+                    self.unbound.insert(id.clone());
+                }
+            }
+            Expr::Form(form) => {
+                if form.is_empty() && normalized {
+                    self.fail(expr, "empty application");
+                }
+                for item in form {
+                    if normalized {
+                        self.check_atom(item, "operand of an application");
+                    }
+                    self.check(item);
+                }
+            }
+            Expr::Let { bindings, body } => {
+                if normalized && bindings.len() != 1 {
+                    self.fail(expr, "`let` does not have exactly one binding");
+                }
+                for (_, e) in bindings {
+                    if matches!(self.stage, Stage::ANormal | Stage::Closure)
+                        && matches!(e, Expr::Let { .. } | Expr::LetFun { .. })
+                    {
+                        self.fail(e, "nested `let`/`letfun` in the value of a binding");
+                    }
+                    self.check(e);
+                }
+                let names: Vec<String> = bindings.iter().map(|(x, _)| x.clone()).collect();
+                self.with_scope(names, |v| v.check(body));
+            }
+            Expr::If { cond, then, else_ } => {
+                if normalized {
+                    self.check_atom(cond, "condition of `if`");
+                }
+                self.check(cond);
+                self.check(then);
+                self.check(else_);
+            }
+            Expr::LetFun {
+                name,
+                args,
+                fun_body,
+                expr_body,
+            } => {
+                if self.stage == Stage::Closure {
+                    self.fail(expr, "`letfun` left after closure conversion");
+                }
+                self.with_scope(std::iter::once(name.clone()), |v| {
+                    v.with_scope(args.iter().cloned(), |v| v.check(fun_body));
+                    v.check(expr_body);
+                });
+            }
+            Expr::LetClos {
+                name,
+                closid,
+                freevars,
+                body,
+            } => {
+                if self.stage != Stage::Closure && self.stage != Stage::Source {
+                    self.fail(expr, "closure before closure conversion");
+                }
+                if !self.closures.contains(closid) {
+                    self.fail(
+                        expr,
+                        format!("`{}` is not a closure defined by `defclos`", closid),
+                    );
+                }
+                for var in freevars {
+                    if !self.is_bound(var) {
+                        self.fail(expr, format!("captured variable `{}` is unbound", var));
+                    }
+                }
+                self.with_scope(std::iter::once(name.clone()), |v| v.check(body));
+            }
+            Expr::And(exprs) | Expr::Or(exprs) => {
+                if normalized {
+                    self.fail(expr, "`and`/`or` left after K-normalization");
+                }
+                exprs.iter().for_each(|e| self.check(e));
+            }
+            Expr::Not(e) => {
+                if normalized {
+                    self.fail(expr, "`not` left after K-normalization");
+                }
+                self.check(e);
+            }
+            Expr::Fn { args, body } => {
+                if normalized {
+                    self.fail(expr, "`fn` left after K-normalization");
+                }
+                self.with_scope(args.iter().cloned(), |v| v.check(body));
+            }
+            Expr::Def { .. } | Expr::Defun { .. } | Expr::DefClos { .. } => {
+                self.fail(expr, "definition below the top level");
+            }
+        }
+    }
+}
+
+fn run<'a>(stage: Stage, prog: &[Expr], externals: &'a HashSet<String>) -> Verifier<'a> {
+    let mut verifier = Verifier {
+        stage,
+        globals: HashSet::new(),
+        closures: HashSet::new(),
+        current_closure: None,
+        externals,
+        scope: Vec::new(),
+        violations: Vec::new(),
+        unbound: HashSet::new(),
+    };
+    for expr in prog {
+        match expr {
+            Expr::Def { x, .. } => {
+                verifier.globals.insert(x.clone());
+            }
+            Expr::Defun { name, .. } => {
+                verifier.globals.insert(name.clone());
+            }
+            Expr::DefClos { name, .. } => {
+                verifier.closures.insert(name.clone());
+            }
+            _ => {}
+        }
+    }
+    for expr in prog {
+        verifier.check_top(expr);
+    }
+    verifier
+}
+
+/// Identifiers a source program uses without defining them (e.g. interpreter-only
+/// builtins); they are tolerated by `verify`, as passes cannot be blamed for them
+pub fn unbound_identifiers(prog: &[Expr]) -> HashSet<String> {
+    run(Stage::Source, prog, &HashSet::new()).unbound
+}
+
+/// Check that `prog` is a well-formed program of `stage`: the right shape for the
+/// stage, no leftover derived forms and every identifier bound (or in `externals`).
+/// All violations are reported at once.
+pub fn verify(
+    stage: Stage,
+    prog: &[Expr],
+    externals: &HashSet<String>,
+) -> Result<(), Vec<Violation>> {
+    let violations = run(stage, prog, externals).violations;
+    if violations.is_empty() {
+        Ok(())
+    } else {
+        Err(violations)
+    }
+}
+
+pub fn verify_knormal(prog: &[Expr], externals: &HashSet<String>) -> Result<(), Vec<Violation>> {
+    verify(Stage::KNormal, prog, externals)
+}
+
+pub fn verify_anormal(prog: &[Expr], externals: &HashSet<String>) -> Result<(), Vec<Violation>> {
+    verify(Stage::ANormal, prog, externals)
+}
+
+pub fn verify_closure(prog: &[Expr], externals: &HashSet<String>) -> Result<(), Vec<Violation>> {
+    verify(Stage::Closure, prog, externals)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        compl::{
+            anormal::a_normalize, closure::closure_convert, knormal::k_normalize,
+            util::NameGenerator,
+        },
+        read_file, read_string,
+    };
+
+    use super::*;
+
+    #[test]
+    fn verify_test_examples() {
+        for file in [
+            "./examples/annotated.purelisp",
+            "./examples/boolean.purelisp",
+            "./examples/factorial.purelisp",
+            "./examples/highorder.purelisp",
+            "./examples/recursion.purelisp",
+            "./examples/simple.purelisp",
+            "./examples/sqrt.purelisp",
+        ] {
+            let prog = read_file(file).unwrap();
+            let externals = unbound_identifiers(&prog);
+            let mut namer = NameGenerator::new();
+            let kprog = k_normalize(prog, &mut namer);
+            assert_eq!(verify_knormal(&kprog, &externals), Ok(()), "{}", file);
+            let aprog = a_normalize(kprog);
+            assert_eq!(verify_anormal(&aprog, &externals), Ok(()), "{}", file);
+            let cprog = closure_convert(aprog, &mut namer);
+            assert_eq!(verify_closure(&cprog, &externals), Ok(()), "{}", file);
+        }
+    }
+
+    #[test]
+    fn verify_test_violations() {
+        let prog = read_string("(let ((x (and a b)) (y (f (g x)))) (if (h y) x z))").unwrap();
+        let externals = ["f", "g", "h", "a", "b"].map(String::from).into();
+        let violations = verify_anormal(&prog, &externals).unwrap_err();
+        let messages: Vec<&str> = violations.iter().map(|v| v.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "`let` does not have exactly one binding",
+                "`and`/`or` left after K-normalization",
+                "operand of an application is not an atom",
+                "unbound identifier `x`",
+                "condition of `if` is not an atom",
+                "unbound identifier `z`",
+            ]
+        );
+
+        // `letfun` is fine in A-normal form but must be gone after closure conversion
+        let prog = read_string("(let ((x 1)) (letfun (f (y) (+ x y)) (f x)))").unwrap();
+        let externals = HashSet::new();
+        assert!(verify_anormal(&prog, &externals).is_ok());
+        assert!(verify_closure(&prog, &externals).is_err());
+
+        // A closure label is only a value inside its own `defclos`
+        let prog = read_string("(defclos c (y) (x) (c y))\n(c 1)").unwrap();
+        let violations = verify_closure(&prog, &externals).unwrap_err();
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].expr, "c");
+    }
+}
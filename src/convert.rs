@@ -0,0 +1,428 @@
+//! Conversions between Rust types and PureLisp values, used to pass arguments to and
+//! results from native functions, and to exchange data with scripts.
+//!
+//! `IntoValue` and `FromValue` are implemented for the scalar types, `String`, `Vec`
+//! (an empty vector is `nil`, the empty list), `Option` (`None` is `nil`), maps
+//! with string keys and tuples (multiple values); the standard `From` and
+//! `TryFrom` conversions are derived from them (except `TryFrom<Value>` for `Option`,
+//! which the standard library already defines). With the `serde` feature, `to_value`
+//! and `from_value` convert any serializable type.
+//!
+//! ```
+//! use std::collections::HashMap;
+//!
+//! use purelisp::Value;
+//! use purelisp::convert::FromValue;
+//!
+//! let value = Value::from(vec![Some(1), None]);
+//! assert_eq!(value.to_string(), "(1 nil)");
+//! assert_eq!(Vec::<Option<i64>>::try_from(value).unwrap(), [Some(1), None]);
+//! assert_eq!(Option::<i64>::from_value(Value::Nil).unwrap(), None);
+//!
+//! let scores = HashMap::from([("ann".to_string(), 3.5)]);
+//! assert_eq!(Value::from(scores).to_string(), "{ann: 3.5}");
+//! assert!(i64::try_from(Value::Bool(true)).is_err());
+//! ```
+
+#[cfg(feature = "serde")]
+mod serde;
+
+use std::collections::{BTreeMap, HashMap};
+
+use crate::ast::Value;
+use crate::intpt::Error;
+
+#[cfg(feature = "serde")]
+pub use self::serde::{Serde, from_value, to_value};
+
+/// A Rust value that can be turned into a PureLisp value
+pub trait IntoValue {
+    fn into_value(self) -> Value;
+}
+
+/// A Rust value that can be extracted from a PureLisp value
+pub trait FromValue: Sized {
+    fn from_value(value: Value) -> Result<Self, Error>;
+}
+
+impl IntoValue for Value {
+    fn into_value(self) -> Value {
+        self
+    }
+}
+
+impl FromValue for Value {
+    fn from_value(value: Value) -> Result<Self, Error> {
+        Ok(value)
+    }
+}
+
+fn type_error(expected: &'static str, found: &Value) -> Error {
+    Error::Type {
+        expected,
+        found: found.type_name(),
+    }
+}
+
+impl IntoValue for () {
+    fn into_value(self) -> Value {
+        Value::Nil
+    }
+}
+
+impl FromValue for () {
+    fn from_value(value: Value) -> Result<Self, Error> {
+        match value {
+            Value::Nil => Ok(()),
+            _ => Err(type_error("nil", &value)),
+        }
+    }
+}
+
+impl IntoValue for bool {
+    fn into_value(self) -> Value {
+        Value::Bool(self)
+    }
+}
+
+impl FromValue for bool {
+    fn from_value(value: Value) -> Result<Self, Error> {
+        match value {
+            Value::Bool(b) => Ok(b),
+            _ => Err(type_error("bool", &value)),
+        }
+    }
+}
+
+impl IntoValue for i64 {
+    fn into_value(self) -> Value {
+        Value::Int(self)
+    }
+}
+
+impl FromValue for i64 {
+    fn from_value(value: Value) -> Result<Self, Error> {
+        match value {
+            Value::Int(n) => Ok(n),
+            _ => Err(type_error("int", &value)),
+        }
+    }
+}
+
+impl IntoValue for f64 {
+    fn into_value(self) -> Value {
+        Value::Float(self)
+    }
+}
+
+/// Integers are accepted where a float is expected, as in the math builtins
+impl FromValue for f64 {
+    fn from_value(value: Value) -> Result<Self, Error> {
+        match value {
+            Value::Float(x) => Ok(x),
+            Value::Int(n) => Ok(n as f64),
+            _ => Err(type_error("float", &value)),
+        }
+    }
+}
+
+impl IntoValue for String {
+    fn into_value(self) -> Value {
+        Value::Str(self)
+    }
+}
+
+impl IntoValue for &str {
+    fn into_value(self) -> Value {
+        Value::Str(self.to_string())
+    }
+}
+
+impl FromValue for String {
+    fn from_value(value: Value) -> Result<Self, Error> {
+        match value {
+            Value::Str(s) => Ok(s),
+            _ => Err(type_error("string", &value)),
+        }
+    }
+}
+
+impl<T: IntoValue> IntoValue for Option<T> {
+    fn into_value(self) -> Value {
+        match self {
+            Some(value) => value.into_value(),
+            None => Value::Nil,
+        }
+    }
+}
+
+impl<T: FromValue> FromValue for Option<T> {
+    fn from_value(value: Value) -> Result<Self, Error> {
+        match value {
+            Value::Nil => Ok(None),
+            _ => T::from_value(value).map(Some),
+        }
+    }
+}
+
+impl<T: IntoValue> IntoValue for Vec<T> {
+    fn into_value(self) -> Value {
+        Value::list(self.into_iter().map(IntoValue::into_value))
+    }
+}
+
+impl<T: FromValue> FromValue for Vec<T> {
+    fn from_value(value: Value) -> Result<Self, Error> {
+        match value {
+            Value::Nil => Ok(Vec::new()),
+            Value::List(_) => value.items().cloned().map(T::from_value).collect(),
+            _ => Err(type_error("list", &value)),
+        }
+    }
+}
+
+impl<T: IntoValue> IntoValue for HashMap<String, T> {
+    fn into_value(self) -> Value {
+        Value::Map(
+            self.into_iter()
+                .map(|(key, value)| (key, value.into_value()))
+                .collect(),
+        )
+    }
+}
+
+impl<T: FromValue> FromValue for HashMap<String, T> {
+    fn from_value(value: Value) -> Result<Self, Error> {
+        match value {
+            Value::Map(entries) => entries
+                .into_iter()
+                .map(|(key, value)| Ok((key, T::from_value(value)?)))
+                .collect(),
+            _ => Err(type_error("map", &value)),
+        }
+    }
+}
+
+impl<T: IntoValue> IntoValue for BTreeMap<String, T> {
+    fn into_value(self) -> Value {
+        Value::Map(
+            self.into_iter()
+                .map(|(key, value)| (key, value.into_value()))
+                .collect(),
+        )
+    }
+}
+
+impl<T: FromValue> FromValue for BTreeMap<String, T> {
+    fn from_value(value: Value) -> Result<Self, Error> {
+        match value {
+            Value::Map(entries) => entries
+                .into_iter()
+                .map(|(key, value)| Ok((key, T::from_value(value)?)))
+                .collect(),
+            _ => Err(type_error("map", &value)),
+        }
+    }
+}
+
+/// `From` and `TryFrom` in terms of `IntoValue` and `FromValue`
+macro_rules! impl_std_conversions {
+    ($($ty:ty),*) => {
+        $(
+            impl From<$ty> for Value {
+                fn from(value: $ty) -> Self {
+                    value.into_value()
+                }
+            }
+
+            impl TryFrom<Value> for $ty {
+                type Error = Error;
+
+                fn try_from(value: Value) -> Result<Self, Error> {
+                    <$ty>::from_value(value)
+                }
+            }
+        )*
+    };
+}
+
+impl_std_conversions!(bool, i64, f64, String);
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        value.into_value()
+    }
+}
+
+/// `From` and `TryFrom` for containers of any convertible type `T`
+macro_rules! impl_std_generic_conversions {
+    ($($ty:ty),*) => {
+        $(
+            impl<T: IntoValue> From<$ty> for Value {
+                fn from(value: $ty) -> Self {
+                    value.into_value()
+                }
+            }
+
+            impl<T: FromValue> TryFrom<Value> for $ty {
+                type Error = Error;
+
+                fn try_from(value: Value) -> Result<Self, Error> {
+                    Self::from_value(value)
+                }
+            }
+        )*
+    };
+}
+
+impl_std_generic_conversions!(Vec<T>, HashMap<String, T>, BTreeMap<String, T>);
+
+/// Conversions of tuples, which are multiple values
+macro_rules! impl_tuple_conversions {
+    ($($len:literal: ($($item:ident),*)),*) => {
+        $(
+            impl<$($item: IntoValue),*> IntoValue for ($($item,)*) {
+                #[allow(non_snake_case)]
+                fn into_value(self) -> Value {
+                    let ($($item,)*) = self;
+                    Value::Tuple(vec![$($item.into_value()),*])
+                }
+            }
+
+            impl<$($item: FromValue),*> FromValue for ($($item,)*) {
+                fn from_value(value: Value) -> Result<Self, Error> {
+                    match value {
+                        Value::Tuple(items) if items.len() == $len => {
+                            let mut items = items.into_iter();
+                            Ok(($($item::from_value(items.next().unwrap())?,)*))
+                        }
+                        _ => Err(type_error(concat!($len, " values"), &value)),
+                    }
+                }
+            }
+
+            impl<$($item: IntoValue),*> From<($($item,)*)> for Value {
+                fn from(value: ($($item,)*)) -> Self {
+                    value.into_value()
+                }
+            }
+
+            impl<$($item: FromValue),*> TryFrom<Value> for ($($item,)*) {
+                type Error = Error;
+
+                fn try_from(value: Value) -> Result<Self, Error> {
+                    Self::from_value(value)
+                }
+            }
+        )*
+    };
+}
+
+impl_tuple_conversions!(2: (A, B), 3: (A, B, C), 4: (A, B, C, D));
+
+// `Option<Value>` is already `From<Value>`, so options are only extracted by
+// `FromValue`
+impl<T: IntoValue> From<Option<T>> for Value {
+    fn from(value: Option<T>) -> Self {
+        value.into_value()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::intpt::{create_environment, file::process_string};
+
+    #[test]
+    fn test_convert_round_trip() {
+        let nested = vec![
+            HashMap::from([("xs".to_string(), vec![1.5, 2.0])]),
+            HashMap::new(),
+        ];
+        let value = Value::from(nested.clone());
+        assert_eq!(value.to_string(), "({xs: (1.5 2)} {})");
+        assert_eq!(
+            Vec::<HashMap<String, Vec<f64>>>::try_from(value).unwrap(),
+            nested
+        );
+        assert_eq!(String::try_from(Value::from("s")).unwrap(), "s");
+        assert_eq!(f64::from_value(Value::Int(2)).unwrap(), 2.0);
+        assert_eq!(<()>::from_value(().into_value()).unwrap(), ());
+        assert_eq!(Value::from(Vec::<i64>::new()), Value::Nil);
+        assert!(Vec::<i64>::try_from(Value::Nil).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_convert_long_list() {
+        // Lists are chains of cells: comparing and dropping them must not recurse
+        let items: Vec<i64> = (0..100_000).collect();
+        let value = Value::from(items.clone());
+        assert_eq!(value, Value::from(items.clone()));
+        assert_eq!(Vec::<i64>::try_from(value).unwrap(), items);
+    }
+
+    #[test]
+    fn test_convert_errors() {
+        assert_eq!(
+            Vec::<i64>::try_from(Value::list([Value::Int(1), Value::Str("2".into())])),
+            Err(Error::Type {
+                expected: "int",
+                found: "string"
+            })
+        );
+        assert_eq!(
+            BTreeMap::<String, bool>::from_value(Value::Nil),
+            Err(Error::Type {
+                expected: "map",
+                found: "nil"
+            })
+        );
+    }
+
+    #[test]
+    fn test_convert_native_arguments() {
+        let mut env = create_environment();
+        env.set("xs".to_string(), Value::from(vec![3, 1, 2]));
+        env.register_fn("sum", |xs: Vec<i64>| xs.iter().sum::<i64>());
+        env.register_fn("sorted", |mut xs: Vec<i64>| {
+            xs.sort();
+            xs
+        });
+        env.register_fn("get", |map: HashMap<String, i64>, key: String| {
+            map.get(&key).copied()
+        });
+        let results = process_string("(sum xs)\n(sorted xs)", &mut env).unwrap();
+        assert_eq!(results[0], Value::Int(6));
+        assert_eq!(results[1].to_string(), "(1 2 3)");
+
+        env.set(
+            "m".to_string(),
+            Value::from(BTreeMap::from([("a".to_string(), 1)])),
+        );
+        let results = process_string("(get m \"a\")\n(get m \"b\")", &mut env).unwrap();
+        assert_eq!(results, [Value::Int(1), Value::Nil]);
+    }
+
+    #[test]
+    fn test_convert_tuples() {
+        let mut env = create_environment();
+        env.register_fn("split", |s: String, at: i64| {
+            let (head, tail) = s.split_at(at as usize);
+            (head.to_string(), tail.to_string())
+        });
+        let results =
+            process_string("(let-values (((a b) (split \"pure\" 2))) b)", &mut env).unwrap();
+        assert_eq!(results, [Value::from("re")]);
+        let value = process_string("(values 1 true)", &mut env)
+            .unwrap()
+            .remove(0);
+        assert_eq!(<(i64, bool)>::try_from(value.clone()), Ok((1, true)));
+        assert_eq!(
+            <(i64, bool, i64)>::try_from(value),
+            Err(Error::Type {
+                expected: "3 values",
+                found: "values"
+            })
+        );
+    }
+}
@@ -0,0 +1,170 @@
+//! Conversions of any `Serialize`/`Deserialize` type, going through JSON values: structs
+//! and maps become maps, sequences and tuples become lists, `None` and `()` become nil.
+//!
+//! ```
+//! use purelisp::convert::{Serde, from_value, to_value};
+//! use purelisp::intpt::file::process_string;
+//! use purelisp::{Value, create_environment};
+//! use serde::{Deserialize, Serialize};
+//!
+//! #[derive(Serialize, Deserialize, Debug, PartialEq)]
+//! struct Rect {
+//!     w: f64,
+//!     h: f64,
+//! }
+//!
+//! let mut env = create_environment();
+//! env.set("r".to_string(), to_value(&Rect { w: 2.0, h: 1.5 }).unwrap());
+//! env.register_fn("area", |Serde(r): Serde<Rect>| r.w * r.h);
+//! env.register_fn("widen", |Serde(r): Serde<Rect>, dw: f64| Serde(Rect { w: r.w + dw, ..r }));
+//!
+//! let results = process_string("(area r)\n(widen r 1)", &mut env).unwrap();
+//! assert_eq!(results[0], Value::Float(3.0));
+//! let wide: Rect = from_value(results[1].clone()).unwrap();
+//! assert_eq!(wide, Rect { w: 3.0, h: 1.5 });
+//! ```
+
+use ::serde::{Serialize, de::DeserializeOwned};
+use serde_json::{Number, Value as Json};
+
+use super::{FromValue, IntoValue};
+use crate::ast::Value;
+use crate::intpt::Error;
+
+/// Convert a serializable Rust value to a PureLisp value
+pub fn to_value<T: Serialize + ?Sized>(value: &T) -> Result<Value, Error> {
+    let json = serde_json::to_value(value).map_err(|err| Error::native(err.to_string()))?;
+    Ok(from_json(json))
+}
+
+/// Convert a PureLisp value to a deserializable Rust value. Functions can't be
+/// converted.
+pub fn from_value<T: DeserializeOwned>(value: Value) -> Result<T, Error> {
+    serde_json::from_value(to_json(value)?).map_err(|err| Error::native(err.to_string()))
+}
+
+/// A serializable value, converted with `to_value` and `from_value` when passed to or
+/// returned from a native function
+#[derive(Debug, Clone, PartialEq)]
+pub struct Serde<T>(pub T);
+
+impl<T: Serialize> IntoValue for Serde<T> {
+    /// Panics if `T` fails to serialize, e.g. when a map has non-string keys
+    fn into_value(self) -> Value {
+        to_value(&self.0).unwrap_or_else(|err| panic!("{}", err))
+    }
+}
+
+impl<T: DeserializeOwned> FromValue for Serde<T> {
+    fn from_value(value: Value) -> Result<Self, Error> {
+        from_value(value).map(Serde)
+    }
+}
+
+fn from_json(json: Json) -> Value {
+    match json {
+        Json::Null => Value::Nil,
+        Json::Bool(b) => Value::Bool(b),
+        // Integers beyond the range of `i64` are approximated
+        Json::Number(n) => match n.as_i64() {
+            Some(n) => Value::Int(n),
+            None => Value::Float(n.as_f64().unwrap_or(f64::NAN)),
+        },
+        Json::String(s) => Value::Str(s),
+        Json::Array(items) => Value::list(items.into_iter().map(from_json)),
+        Json::Object(entries) => Value::Map(
+            entries
+                .into_iter()
+                .map(|(key, value)| (key, from_json(value)))
+                .collect(),
+        ),
+    }
+}
+
+fn to_json(value: Value) -> Result<Json, Error> {
+    Ok(match value {
+        Value::Nil => Json::Null,
+        Value::Bool(b) => Json::Bool(b),
+        Value::Int(n) => Json::from(n),
+        Value::Float(x) => match Number::from_f64(x) {
+            Some(x) => Json::Number(x),
+            None => return Err(Error::native(format!("cannot convert {} to JSON", x))),
+        },
+        Value::Str(s) => Json::String(s),
+        Value::List(_) => Json::Array(
+            value
+                .items()
+                .cloned()
+                .map(to_json)
+                .collect::<Result<_, _>>()?,
+        ),
+        Value::Tuple(items) => {
+            Json::Array(items.into_iter().map(to_json).collect::<Result<_, _>>()?)
+        }
+        Value::Map(entries) => Json::Object(
+            entries
+                .into_iter()
+                .map(|(key, value)| Ok((key, to_json(value)?)))
+                .collect::<Result<_, Error>>()?,
+        ),
+        Value::Func(_) | Value::Native(_) | Value::Closure { .. } => {
+            return Err(Error::Type {
+                expected: "data",
+                found: "function",
+            });
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use ::serde::{Deserialize, Serialize};
+
+    use super::*;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    enum Shape {
+        Dot,
+        Circle { r: f64 },
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Scene {
+        name: String,
+        shapes: Vec<Shape>,
+        origin: (i64, i64),
+        tag: Option<String>,
+    }
+
+    #[test]
+    fn test_serde_round_trip() {
+        let scene = Scene {
+            name: "s".to_string(),
+            shapes: vec![Shape::Dot, Shape::Circle { r: 0.5 }],
+            origin: (1, -2),
+            tag: None,
+        };
+        let value = to_value(&scene).unwrap();
+        assert_eq!(
+            value.to_string(),
+            "{name: s, origin: (1 -2), shapes: (Dot {Circle: {r: 0.5}}), tag: nil}"
+        );
+        assert_eq!(from_value::<Scene>(value).unwrap(), scene);
+    }
+
+    #[test]
+    fn test_serde_errors() {
+        let value = Value::Map(BTreeMap::from([("name".to_string(), Value::Int(1))]));
+        assert!(from_value::<Scene>(value).is_err());
+        assert!(from_value::<f64>(Value::Float(f64::NAN)).is_err());
+        assert_eq!(
+            from_value::<i64>(Value::Func(|_| Value::Nil)),
+            Err(Error::Type {
+                expected: "data",
+                found: "function"
+            })
+        );
+    }
+}
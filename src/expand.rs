@@ -0,0 +1,686 @@
+//! Macros. A top-level `define-syntax` defines a macro by rewriting rules:
+//!
+//! ```text
+//! (define-syntax swap-args
+//!   (syntax-rules ()
+//!     ((_ f a b) (f b a))))
+//! ```
+//!
+//! Every rule is a pattern and a template. In a pattern, `_` matches anything,
+//! identifiers listed after `syntax-rules` match themselves, other identifiers are
+//! pattern variables, and `p ...` matches any number of `p`. A macro use is replaced
+//! by the template of its first matching rule, the pattern variables substituted;
+//! macros are expanded between reading S-expressions and parsing the special forms,
+//! so the interpreter, the compiler and the type checker never see them.
+//!
+//! Expansion is hygienic. The identifiers introduced by a template are marked; once
+//! a top-level form is expanded, marked identifiers bound by the expansion are
+//! renamed apart (`tmp` becomes `tmp#0`), and the other ones refer to the globals of
+//! that name, so that neither the bindings of a macro nor those around its use
+//! capture identifiers of the other. Local variables of the program that clash with
+//! an identifier introduced by a macro are renamed too.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::ast::{Expr, TypeAnn};
+use crate::compl::util::NameGenerator;
+use crate::parse::advance_parse;
+
+/// Separates an identifier introduced by a template from the number of its expansion
+const MARK: char = '\u{1}';
+
+/// Nested macro uses may not be expanded deeper than this
+const MAX_DEPTH: usize = 256;
+
+/// Identifiers a template can't rebind: they are never marked
+const KEYWORDS: &[&str] = &[
+    "let",
+    "let*",
+    "let-values",
+    "if",
+    "cond",
+    "case",
+    "else",
+    "when",
+    "unless",
+    "do",
+    "and",
+    "or",
+    "not",
+    "fn",
+    "def",
+    "defun",
+    "letfun",
+    "defclos",
+    "letclos",
+    ":",
+    "module",
+    "import",
+    "export",
+];
+
+#[derive(Debug, Clone)]
+struct Macro {
+    literals: Vec<String>,
+    /// Patterns, without the macro keyword, and templates
+    rules: Vec<(Vec<Expr>, Expr)>,
+}
+
+/// Forms matched by a pattern variable, nested once for every `...` following it
+#[derive(Debug, Clone)]
+enum Binding {
+    One(Expr),
+    Many(Vec<Binding>),
+}
+
+/// Expands the macros of a program, top-level form after top-level form; macros are
+/// visible after their definition
+#[derive(Debug, Clone, Default)]
+pub struct Expander {
+    macros: HashMap<String, Macro>,
+    /// Expansions so far, to mark the identifiers of each one apart
+    expansions: usize,
+    /// Identifiers introduced by the templates expanded in the current top-level form
+    introduced: HashSet<String>,
+    namer: NameGenerator,
+}
+
+impl Expander {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether a macro named `name` is defined
+    pub fn is_macro(&self, name: &str) -> bool {
+        self.macros.contains_key(name)
+    }
+
+    /// Parse the top-level S-expression `sexp` after expanding its macros, or record
+    /// the macro it defines and return `None`
+    pub fn expand_toplevel(&mut self, sexp: Expr) -> Option<Expr> {
+        if let Expr::Form(form) = &sexp
+            && form.first() == Some(&Expr::Id("define-syntax".to_string()))
+        {
+            self.define(form);
+            return None;
+        }
+        let expansions = self.expansions;
+        self.introduced.clear();
+        let expr = advance_parse(self.expand(sexp, 0));
+        if self.expansions == expansions {
+            return Some(expr);
+        }
+        Some(self.resolve(expr, &mut Vec::new()))
+    }
+
+    /// Record the macro defined by `(define-syntax name (syntax-rules (literals...) rules...))`
+    fn define(&mut self, form: &[Expr]) {
+        let (name, rules) = match form {
+            [_, Expr::Id(name), Expr::Form(rules)] => (name, rules),
+            _ => panic!("define-syntax must have the form (define-syntax name (syntax-rules ...))"),
+        };
+        let (literals, rules) = match rules.as_slice() {
+            [Expr::Id(keyword), Expr::Form(literals), rules @ ..] if keyword == "syntax-rules" => {
+                (literals, rules)
+            }
+            _ => panic!(
+                "The rules of macro {} must have the form (syntax-rules (literals...) (pattern template)...)",
+                name
+            ),
+        };
+        let literals = literals
+            .iter()
+            .map(|literal| match literal {
+                Expr::Id(id) => id.clone(),
+                _ => panic!("The literals of macro {} must be identifiers", name),
+            })
+            .collect();
+        let rules = rules
+            .iter()
+            .map(|rule| match rule {
+                Expr::Form(rule) => match rule.as_slice() {
+                    [Expr::Form(pattern), template] if !pattern.is_empty() => {
+                        (pattern[1..].to_vec(), template.clone())
+                    }
+                    _ => panic!(
+                        "The rules of macro {} must be (pattern template) pairs",
+                        name
+                    ),
+                },
+                _ => panic!(
+                    "The rules of macro {} must be (pattern template) pairs",
+                    name
+                ),
+            })
+            .collect();
+        self.macros.insert(name.clone(), Macro { literals, rules });
+    }
+
+    /// Expand the macro uses in an S-expression, leaving alone the parts of special
+    /// forms that are not expressions
+    fn expand(&mut self, sexp: Expr, depth: usize) -> Expr {
+        let form = match sexp {
+            Expr::Form(form) => form,
+            _ => return sexp,
+        };
+        let head = match form.first() {
+            Some(Expr::Id(id)) => unmarked(id).to_string(),
+            _ => String::new(),
+        };
+        if let Some(mac) = self.macros.get(&head).cloned() {
+            if depth >= MAX_DEPTH {
+                panic!("Expansion of macro {} is too deep", head);
+            }
+            let expansion = self.apply(&head, &mac, &form);
+            return self.expand(expansion, depth + 1);
+        }
+
+        let mut form = form;
+        match (head.as_str(), form.len()) {
+            ("define-syntax", _) => panic!("define-syntax is only allowed at top level"),
+            ("module" | "import", _) => {}
+            // A named let has its name before the bindings
+            ("let" | "let*" | "let-values", 3) | ("let", 4) => {
+                let last = form.len() - 1;
+                if let Expr::Form(bindings) = &mut form[last - 1] {
+                    for binding in bindings {
+                        if let Expr::Form(pair) = binding
+                            && pair.len() == 2
+                        {
+                            self.expand_all(&mut pair[1..], depth);
+                        }
+                    }
+                }
+                self.expand_all(&mut form[last..], depth);
+            }
+            // The initial values and steps of the variables, and the exit clause
+            ("do", 3) => {
+                if let Expr::Form(specs) = &mut form[1] {
+                    for spec in specs {
+                        if let Expr::Form(spec) = spec
+                            && !spec.is_empty()
+                        {
+                            self.expand_all(&mut spec[1..], depth);
+                        }
+                    }
+                }
+                if let Expr::Form(exit) = &mut form[2] {
+                    self.expand_all(exit, depth);
+                }
+            }
+            // A clause is not a form of its own: its test and expression are
+            ("cond", _) => {
+                for clause in &mut form[1..] {
+                    if let Expr::Form(clause) = clause {
+                        self.expand_all(clause, depth);
+                    }
+                }
+            }
+            // Only the key and the expressions of the clauses, not their literals
+            ("case", 2..) => {
+                self.expand_all(&mut form[1..2], depth);
+                for clause in &mut form[2..] {
+                    if let Expr::Form(clause) = clause
+                        && !clause.is_empty()
+                    {
+                        let last = clause.len() - 1;
+                        self.expand_all(&mut clause[last..], depth);
+                    }
+                }
+            }
+            ("letfun", 3) => {
+                if let Expr::Form(def) = &mut form[1]
+                    && def.len() == 3
+                {
+                    self.expand_all(&mut def[2..], depth);
+                }
+                self.expand_all(&mut form[2..], depth);
+            }
+            // Only the body of a function is an expression
+            ("fn", 3) | ("defun", 4 | 6) | ("defclos", 5) | ("letclos", 3) => {
+                let last = form.len() - 1;
+                self.expand_all(&mut form[last..], depth);
+            }
+            _ => self.expand_all(&mut form, depth),
+        }
+        Expr::Form(form)
+    }
+
+    /// Expand the S-expressions `sexps` in place
+    fn expand_all(&mut self, sexps: &mut [Expr], depth: usize) {
+        for sexp in sexps {
+            *sexp = self.expand(std::mem::replace(sexp, Expr::Nil), depth);
+        }
+    }
+
+    /// Rewrite the use `form` of the macro `name` with its first matching rule
+    fn apply(&mut self, name: &str, mac: &Macro, form: &[Expr]) -> Expr {
+        for (pattern, template) in &mac.rules {
+            let mut bindings = HashMap::new();
+            if match_list(pattern, &form[1..], &mac.literals, &mut bindings) {
+                self.expansions += 1;
+                return self.instantiate(template, &bindings);
+            }
+        }
+        panic!(
+            "No rule of macro {} matches {}",
+            name,
+            Expr::Form(form.to_vec())
+        );
+    }
+
+    /// The template with its pattern variables substituted and its own identifiers
+    /// marked with the current expansion
+    fn instantiate(&mut self, template: &Expr, bindings: &HashMap<String, Binding>) -> Expr {
+        match template {
+            Expr::Id(id) => match bindings.get(id) {
+                Some(Binding::One(sexp)) => sexp.clone(),
+                Some(Binding::Many(_)) => {
+                    panic!(
+                        "Pattern variable {} must be followed by ... in the template",
+                        id
+                    )
+                }
+                None if id == "..." => panic!("Misplaced ... in a macro template"),
+                None if KEYWORDS.contains(&id.as_str()) || TypeAnn::from_name(id).is_some() => {
+                    template.clone()
+                }
+                None => {
+                    self.introduced.insert(id.clone());
+                    Expr::Id(format!("{}{}{}", id, MARK, self.expansions))
+                }
+            },
+            Expr::Form(elements) => {
+                let mut form = Vec::new();
+                let mut i = 0;
+                while i < elements.len() {
+                    let element = &elements[i];
+                    if elements.get(i + 1) != Some(&Expr::Id("...".to_string())) {
+                        form.push(self.instantiate(element, bindings));
+                        i += 1;
+                        continue;
+                    }
+                    // Repeat the element once for every form matched by the pattern
+                    // variables it contains that are followed by `...`
+                    let mut vars = Vec::new();
+                    pattern_vars(element, &[], &mut vars);
+                    let repeated: Vec<(&String, &Vec<Binding>)> = vars
+                        .iter()
+                        .filter_map(|var| match bindings.get(var) {
+                            Some(Binding::Many(items)) => Some((var, items)),
+                            _ => None,
+                        })
+                        .collect();
+                    let count = match repeated.first() {
+                        Some((_, items)) => items.len(),
+                        None => panic!("No pattern variable of a macro template precedes ..."),
+                    };
+                    if repeated.iter().any(|(_, items)| items.len() != count) {
+                        panic!(
+                            "Pattern variables followed by ... match different numbers of forms"
+                        );
+                    }
+                    for k in 0..count {
+                        let mut bindings = bindings.clone();
+                        for (var, items) in &repeated {
+                            bindings.insert(var.to_string(), items[k].clone());
+                        }
+                        form.push(self.instantiate(element, &bindings));
+                    }
+                    i += 2;
+                }
+                Expr::Form(form)
+            }
+            _ => template.clone(),
+        }
+    }
+
+    /// Rename the local variables bound by expansions, and those of the program whose
+    /// names an expansion introduced; unmark the other identifiers
+    fn resolve(&mut self, expr: Expr, scope: &mut Vec<(String, String)>) -> Expr {
+        match expr {
+            Expr::Id(id) => Expr::Id(self.lookup(&id, scope)),
+            Expr::Nil | Expr::Bool(_) | Expr::Int(_) | Expr::Float(_) | Expr::Str(_) => expr,
+            Expr::Form(exprs) => Expr::Form(self.resolve_all(exprs, scope)),
+            Expr::And(exprs) => Expr::And(self.resolve_all(exprs, scope)),
+            Expr::Or(exprs) => Expr::Or(self.resolve_all(exprs, scope)),
+            Expr::Not(expr) => Expr::Not(Box::new(self.resolve(*expr, scope))),
+            Expr::If { cond, then, else_ } => Expr::If {
+                cond: Box::new(self.resolve(*cond, scope)),
+                then: Box::new(self.resolve(*then, scope)),
+                else_: Box::new(self.resolve(*else_, scope)),
+            },
+            // The bindings are evaluated in the enclosing scope
+            Expr::Let { bindings, body } => {
+                let bindings: Vec<(String, Expr)> = bindings
+                    .into_iter()
+                    .map(|(x, expr)| (x, self.resolve(expr, scope)))
+                    .collect();
+                let len = scope.len();
+                let bindings = bindings
+                    .into_iter()
+                    .map(|(x, expr)| (self.bind(&x, scope), expr))
+                    .collect();
+                let body = Box::new(self.resolve(*body, scope));
+                scope.truncate(len);
+                Expr::Let { bindings, body }
+            }
+            Expr::Fn { args, body } => {
+                let len = scope.len();
+                let args = args.iter().map(|arg| self.bind(arg, scope)).collect();
+                let body = Box::new(self.resolve(*body, scope));
+                scope.truncate(len);
+                Expr::Fn { args, body }
+            }
+            Expr::LetFun {
+                name,
+                args,
+                fun_body,
+                expr_body,
+            } => {
+                let len = scope.len();
+                let name = self.bind(&name, scope);
+                let inner = scope.len();
+                let args = args.iter().map(|arg| self.bind(arg, scope)).collect();
+                let fun_body = Box::new(self.resolve(*fun_body, scope));
+                scope.truncate(inner);
+                let expr_body = Box::new(self.resolve(*expr_body, scope));
+                scope.truncate(len);
+                Expr::LetFun {
+                    name,
+                    args,
+                    fun_body,
+                    expr_body,
+                }
+            }
+            // Globals keep their names
+            Expr::Def { x, y } => Expr::Def {
+                x: unmarked(&x).to_string(),
+                y: Box::new(self.resolve(*y, scope)),
+            },
+            Expr::Defun {
+                name,
+                args,
+                body,
+                sig,
+            } => {
+                let len = scope.len();
+                let args = args.iter().map(|arg| self.bind(arg, scope)).collect();
+                let body = Box::new(self.resolve(*body, scope));
+                scope.truncate(len);
+                Expr::Defun {
+                    name: unmarked(&name).to_string(),
+                    args,
+                    body,
+                    sig,
+                }
+            }
+            Expr::DefClos {
+                name,
+                freevars,
+                args,
+                body,
+            } => Expr::DefClos {
+                name,
+                freevars,
+                args,
+                body: Box::new(self.resolve(*body, scope)),
+            },
+            Expr::LetClos {
+                name,
+                closid,
+                freevars,
+                body,
+            } => Expr::LetClos {
+                name,
+                closid,
+                freevars,
+                body: Box::new(self.resolve(*body, scope)),
+            },
+        }
+    }
+
+    fn resolve_all(&mut self, exprs: Vec<Expr>, scope: &mut Vec<(String, String)>) -> Vec<Expr> {
+        exprs
+            .into_iter()
+            .map(|expr| self.resolve(expr, scope))
+            .collect()
+    }
+
+    /// Bring the local variable `x` into scope, and return its new name
+    fn bind(&mut self, x: &str, scope: &mut Vec<(String, String)>) -> String {
+        let name = unmarked(x);
+        let renamed = if name != x || self.introduced.contains(name) {
+            self.namer.next(&format!("{}#", name))
+        } else {
+            x.to_string()
+        };
+        scope.push((x.to_string(), renamed.clone()));
+        renamed
+    }
+
+    /// The name of the variable `id` refers to, innermost first; a global otherwise
+    fn lookup(&self, id: &str, scope: &[(String, String)]) -> String {
+        match scope.iter().rev().find(|(x, _)| x == id) {
+            Some((_, renamed)) => renamed.clone(),
+            None => unmarked(id).to_string(),
+        }
+    }
+}
+
+/// An identifier without the mark of the expansion that introduced it
+fn unmarked(id: &str) -> &str {
+    id.split(MARK).next().unwrap()
+}
+
+/// Match the forms `sexps` against a list of patterns, which may contain a pattern
+/// followed by `...`
+fn match_list(
+    patterns: &[Expr],
+    sexps: &[Expr],
+    literals: &[String],
+    bindings: &mut HashMap<String, Binding>,
+) -> bool {
+    let ellipsis = Expr::Id("...".to_string());
+    let Some(i) = patterns.iter().position(|pattern| *pattern == ellipsis) else {
+        return patterns.len() == sexps.len()
+            && patterns
+                .iter()
+                .zip(sexps)
+                .all(|(pattern, sexp)| match_pattern(pattern, sexp, literals, bindings));
+    };
+    if i == 0 {
+        panic!("... must follow a pattern");
+    }
+    let (before, repeated, after) = (&patterns[..i - 1], &patterns[i - 1], &patterns[i + 1..]);
+    if sexps.len() < before.len() + after.len() {
+        return false;
+    }
+    let rest = sexps.len() - after.len();
+    if !match_list(before, &sexps[..before.len()], literals, bindings)
+        || !match_list(after, &sexps[rest..], literals, bindings)
+    {
+        return false;
+    }
+
+    let mut vars = Vec::new();
+    pattern_vars(repeated, literals, &mut vars);
+    let mut matches: Vec<Vec<Binding>> = vec![Vec::new(); vars.len()];
+    for sexp in &sexps[before.len()..rest] {
+        let mut inner = HashMap::new();
+        if !match_pattern(repeated, sexp, literals, &mut inner) {
+            return false;
+        }
+        for (var, matched) in vars.iter().zip(matches.iter_mut()) {
+            matched.push(inner.remove(var).unwrap());
+        }
+    }
+    for (var, matched) in vars.into_iter().zip(matches) {
+        bindings.insert(var, Binding::Many(matched));
+    }
+    true
+}
+
+fn match_pattern(
+    pattern: &Expr,
+    sexp: &Expr,
+    literals: &[String],
+    bindings: &mut HashMap<String, Binding>,
+) -> bool {
+    match pattern {
+        Expr::Id(id) if id == "_" => true,
+        Expr::Id(id) if literals.contains(id) => {
+            matches!(sexp, Expr::Id(other) if unmarked(other) == id)
+        }
+        Expr::Id(id) => {
+            bindings.insert(id.clone(), Binding::One(sexp.clone()));
+            true
+        }
+        Expr::Form(patterns) => match sexp {
+            Expr::Form(sexps) => match_list(patterns, sexps, literals, bindings),
+            _ => false,
+        },
+        _ => pattern == sexp,
+    }
+}
+
+/// The pattern variables of `pattern`, in order
+fn pattern_vars(pattern: &Expr, literals: &[String], vars: &mut Vec<String>) {
+    match pattern {
+        Expr::Id(id) if id == "_" || id == "..." || literals.contains(id) => {}
+        Expr::Id(id) => vars.push(id.clone()),
+        Expr::Form(patterns) => {
+            for pattern in patterns {
+                pattern_vars(pattern, literals, vars);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::read_string;
+
+    fn expand(source: &str) -> Vec<String> {
+        let prog = read_string(source).unwrap();
+        prog.iter().map(|expr| expr.to_string()).collect()
+    }
+
+    const SWAP: &str = "(define-syntax swap-let
+  (syntax-rules ()
+    ((_ (a b) body) (let ((tmp a)) (let ((a b)) (let ((b tmp)) body))))))";
+
+    #[test]
+    fn expand_test_rules() {
+        let prog = expand(
+            "(define-syntax my-list
+  (syntax-rules ()
+    ((_) nil)
+    ((_ x rest ...) (cons x (my-list rest ...)))))
+(define-syntax for
+  (syntax-rules (in)
+    ((_ x in xs body) (map (fn (x) body) xs))))
+(define-syntax pairs
+  (syntax-rules ()
+    ((_ (k v ...) ...) (my-list (my-list k (+ v ...)) ...))))
+(my-list 1 (my-list) 3)
+(for y in ys (* y 2))
+(pairs (a 1 2) (b 3 4))",
+        );
+        assert_eq!(
+            prog,
+            [
+                "(cons 1 (cons nil (cons 3 nil)))",
+                "(map (fn (y) (* y 2)) ys)",
+                "(cons (cons a (cons (+ 1 2) nil)) (cons (cons b (cons (+ 3 4) nil)) nil))",
+            ]
+        );
+    }
+
+    #[test]
+    fn expand_test_hygiene() {
+        // The `tmp` of the macro doesn't capture the variable of the program, and a
+        // local `cons` of the program doesn't capture the global used by the macro
+        let prog = expand(&format!(
+            "{}
+(let ((x 1) (tmp 2)) (swap-let (x tmp) (- x tmp)))
+(define-syntax push
+  (syntax-rules ()
+    ((_ x xs) (cons x xs))))
+(let ((cons 0)) (push cons nil))
+(defun ok (x) (swap-let (x x) x))",
+            SWAP
+        ));
+        assert_eq!(
+            prog,
+            [
+                "(let ((x 1) (tmp#0 2)) (let ((tmp#1 x)) (let ((x tmp#0)) (let ((tmp#2 tmp#1)) (- x tmp#2)))))",
+                "(let ((cons#3 0)) (cons cons#3 nil))",
+                "(defun ok (x) (let ((tmp#4 x)) (let ((x x)) (let ((x tmp#4)) x))))",
+            ]
+        );
+    }
+
+    #[test]
+    fn expand_test_evaluation() {
+        let mut env = crate::create_environment();
+        let results = crate::intpt::file::process_string(
+            &format!(
+                "{}
+(let ((x 1) (tmp 2)) (swap-let (x tmp) (- x tmp)))",
+                SWAP
+            ),
+            &mut env,
+        )
+        .unwrap();
+        assert_eq!(results, [crate::Value::Int(1)]);
+    }
+
+    #[test]
+    fn expand_test_derived_forms() {
+        // Macro uses in the clauses of cond and case are expanded, but not a case
+        // literal list; `else` in a template is not renamed
+        let prog = expand(
+            "(define-syntax my-not
+  (syntax-rules ()
+    ((_ e) (if e false true))))
+(define-syntax classify
+  (syntax-rules ()
+    ((_ n) (cond ((< n 0) \"negative\") (else (case n ((0) \"zero\") (else \"positive\")))))))
+(cond ((my-not x) (my-not y)))
+(case (my-not x) ((true) (my-not y)))
+(let* ((x 1) (y (my-not x))) y)
+(let-values (((a b) (my-not x))) (my-not a))
+(classify k)",
+        );
+        assert_eq!(
+            prog,
+            [
+                "(if (if x false true) (if y false true) nil)",
+                "(let ((%case0 (if x false true))) (if (or (= %case0 true)) (if y false true) nil))",
+                "(let ((x 1)) (let ((y (if x false true))) y))",
+                "(let ((%values0 (if x false true))) (let ((a (values-ref %values0 2 0)) (b (values-ref %values0 2 1))) (if a false true)))",
+                "(if (< k 0) \"negative\" (let ((%case0 k)) (if (or (= %case0 0)) \"zero\" \"positive\")))",
+            ]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "No rule of macro swap-let matches (swap-let x)")]
+    fn expand_test_no_matching_rule() {
+        expand(&format!("{}\n(swap-let x)", SWAP));
+    }
+
+    #[test]
+    #[should_panic(expected = "Expansion of macro forever is too deep")]
+    fn expand_test_infinite_expansion() {
+        expand("(define-syntax forever (syntax-rules () ((_ x) (+ 1 (forever x)))))\n(forever 0)");
+    }
+
+    #[test]
+    #[should_panic(expected = "define-syntax is only allowed at top level")]
+    fn expand_test_nested_definition() {
+        expand("(let ((x 1)) (define-syntax m (syntax-rules () ((_) x))))");
+    }
+}
@@ -1,4 +1,5 @@
 use crate::Expr;
+use crate::ast::Signature;
 
 pub struct PrettyFormatter {
     indent: usize,
@@ -40,8 +41,8 @@ impl PrettyFormatter {
             Expr::Nil => "nil".to_string(),
             Expr::Bool(b) => b.to_string(),
             Expr::Int(i) => i.to_string(),
-            Expr::Float(f) => f.to_string(),
-            Expr::Str(s) => format!("\"{}\"", s.replace("\"", "\\\"")),
+            Expr::Float(_) => expr.to_string(),
+            Expr::Str(s) => format!("{:?}", s),
             Expr::Id(id) => id.to_string(),
 
             Expr::Form(list) => self.format_form(list, current_indent),
@@ -52,7 +53,12 @@ impl PrettyFormatter {
             Expr::Not(expr) => self.format_not(expr, current_indent),
             Expr::Fn { args, body } => self.format_fn(args, body, current_indent),
             Expr::Def { x, y } => self.format_def(x, y, current_indent),
-            Expr::Defun { name, args, body } => self.format_defun(name, args, body, current_indent),
+            Expr::Defun {
+                name,
+                args,
+                body,
+                sig,
+            } => self.format_defun(name, args, sig, body, current_indent),
             Expr::LetFun {
                 name,
                 args,
@@ -290,6 +296,7 @@ impl PrettyFormatter {
         &self,
         name: &str,
         args: &[String],
+        sig: &Signature,
         body: &Expr,
         current_indent: usize,
     ) -> String {
@@ -297,7 +304,10 @@ impl PrettyFormatter {
         let indent = self.indent_str(current_indent);
         let inner_indent = self.indent_str(next_indent);
 
-        let args_str = format!("({})", args.join(" "));
+        let mut args_str = format!("({})", sig.format_params(args));
+        if let Some(ret) = sig.ret {
+            args_str.push_str(&format!(" : {}", ret));
+        }
 
         // // Try single line format first
         // let single_line = format!(
@@ -391,7 +401,7 @@ impl PrettyFormatter {
 
         let freevar_formatted = freevars
             .iter()
-            .map(|id| format!("{}", id,))
+            .map(|id| id.to_string())
             .collect::<Vec<_>>()
             .join(" ");
 
@@ -426,6 +436,7 @@ mod tests {
     use super::*;
 
     #[test]
+    #[allow(clippy::approx_constant)]
     fn test_format_atoms() {
         let formatter = PrettyFormatter::new();
 
@@ -433,6 +444,7 @@ mod tests {
         assert_eq!(formatter.format(&Expr::Bool(true)), "true");
         assert_eq!(formatter.format(&Expr::Int(42)), "42");
         assert_eq!(formatter.format(&Expr::Float(3.14)), "3.14");
+        assert_eq!(formatter.format(&Expr::Float(2.0)), "2.0");
         assert_eq!(
             formatter.format(&Expr::Str("hello".to_string())),
             "\"hello\""
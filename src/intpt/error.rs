@@ -0,0 +1,91 @@
+use std::fmt;
+use std::time::Duration;
+
+/// Error reported by the interpreter, or by a native function registered by the host
+/// application
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    /// A function was called with the wrong number of arguments
+    Arity {
+        name: String,
+        expected: usize,
+        found: usize,
+    },
+    /// A value does not have the type that was expected
+    Type {
+        expected: &'static str,
+        found: &'static str,
+    },
+    /// Any other failure of a native function, described by its message
+    Native(String),
+    /// A source file could not be read
+    Io(String),
+    /// The source is not a well-formed program
+    Parse(String),
+    /// An imported module could not be found or linked
+    Import(String),
+    /// Evaluation failed, in the top-level form starting on `line` if known
+    Eval {
+        line: Option<usize>,
+        message: String,
+    },
+    /// No global is defined with this name
+    Undefined(String),
+    /// The evaluation took more steps than allowed by `Limits::fuel`
+    OutOfFuel { fuel: u64 },
+    /// Function calls were nested deeper than allowed by `Limits::max_depth`
+    MaxDepth { max_depth: usize },
+    /// The evaluation ran longer than allowed by `Limits::timeout`
+    Timeout { timeout: Duration },
+    /// The evaluation allocated more bytes in total than allowed by
+    /// `Limits::max_allocated`, even if some of them were freed since
+    AllocationExceeded { max_allocated: u64 },
+}
+
+impl Error {
+    pub fn native(message: impl Into<String>) -> Self {
+        Error::Native(message.into())
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Arity {
+                name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{} expects {} argument(s), got {}",
+                name, expected, found
+            ),
+            Error::Type { expected, found } => {
+                write!(f, "Type error: expected {}, found {}", expected, found)
+            }
+            Error::Native(message)
+            | Error::Io(message)
+            | Error::Parse(message)
+            | Error::Import(message) => write!(f, "{}", message),
+            Error::Eval {
+                line: Some(line),
+                message,
+            } => write!(f, "line {}: {}", line, message),
+            Error::Eval {
+                line: None,
+                message,
+            } => write!(f, "{}", message),
+            Error::Undefined(name) => write!(f, "Undefined identifier: {}", name),
+            Error::OutOfFuel { fuel } => write!(f, "Out of fuel after {} steps", fuel),
+            Error::MaxDepth { max_depth } => {
+                write!(f, "Maximum call depth of {} exceeded", max_depth)
+            }
+            Error::Timeout { timeout } => write!(f, "Timed out after {:?}", timeout),
+            Error::AllocationExceeded { max_allocated } => {
+                write!(f, "Allocation limit of {} bytes exceeded", max_allocated)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
@@ -1,222 +1,543 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::panic;
 
-use crate::ast::{Expr, Value};
+use crate::ast::{Expr, Signature, Value};
 use crate::intpt::Env;
 
 pub fn eval(expr: Expr, env: Env) -> Value {
-    // println!("Evaluating: {:?}", expr);
-    // println!("    with Environment: {:?}", env);
-    match expr {
-        Expr::Nil => Value::Nil,
-        Expr::Bool(b) => Value::Bool(b.clone()),
-        Expr::Int(i) => Value::Int(i.clone()),
-        Expr::Float(f) => Value::Float(f.clone()),
-        Expr::Str(s) => Value::Str(s.clone()),
-        Expr::Id(id) => {
-            if let Some(e) = env.get(&id) {
-                e.clone()
-            } else {
-                panic!("Undefined identifier: {}", id);
+    eval_in(expr, env, false)
+}
+
+/// Evaluate `expr` in `env`. `in_call` tells whether `expr` is the body of a call that
+/// entered the meter: a call in tail position then takes the place of that call
+/// instead of nesting in it.
+fn eval_in(mut expr: Expr, mut env: Env, mut in_call: bool) -> Value {
+    // Expressions in tail position (bodies of bindings, branches of if, calls of
+    // closures) are evaluated by looping, so that deeply nested lets and loops written
+    // as tail recursion don't grow the stack
+    let mut entered = false;
+    let value = 'eval: loop {
+        env.meter().step();
+        // println!("Evaluating: {:?}", expr);
+        // println!("    with Environment: {:?}", env);
+        break match expr {
+            Expr::Nil => Value::Nil,
+            Expr::Bool(b) => Value::Bool(b),
+            Expr::Int(i) => Value::Int(i),
+            Expr::Float(f) => Value::Float(f),
+            Expr::Str(s) => {
+                let value = Value::Str(s);
+                env.meter().alloc_value(&value);
+                value
             }
-        }
-        Expr::And(exprs) => {
-            // n-ary short-circuit AND
-            for expr in exprs {
-                let val = eval(expr.clone(), env.clone());
-                match val {
-                    Value::Bool(false) => return Value::Bool(false),
-                    Value::Bool(true) => continue,
-                    _ => panic!("All arguments to 'and' must be boolean"),
+            Expr::Id(id) => {
+                if let Some(e) = env.get(&id) {
+                    e.clone()
+                } else {
+                    panic!("Undefined identifier: {}", id);
                 }
             }
-            Value::Bool(true)
-        }
-        Expr::Or(exprs) => {
-            // n-ary short-circuit OR
-            for expr in exprs {
-                let val = eval(expr.clone(), env.clone());
-                match val {
-                    Value::Bool(true) => return Value::Bool(true),
-                    Value::Bool(false) => continue,
-                    _ => panic!("All arguments to 'or' must be boolean"),
+            Expr::And(exprs) => {
+                // n-ary short-circuit AND
+                for expr in exprs {
+                    let val = eval(expr.clone(), env.clone());
+                    match val {
+                        Value::Bool(false) => break 'eval Value::Bool(false),
+                        Value::Bool(true) => continue,
+                        _ => panic!("All arguments to 'and' must be boolean"),
+                    }
                 }
+                Value::Bool(true)
             }
-            Value::Bool(false)
-        }
-        Expr::Not(expr) => {
-            let val = eval(*expr, env.clone());
-            match val {
-                Value::Bool(b) => Value::Bool(!b),
-                _ => panic!("Argument to 'not' must be boolean"),
+            Expr::Or(exprs) => {
+                // n-ary short-circuit OR
+                for expr in exprs {
+                    let val = eval(expr.clone(), env.clone());
+                    match val {
+                        Value::Bool(true) => break 'eval Value::Bool(true),
+                        Value::Bool(false) => continue,
+                        _ => panic!("All arguments to 'or' must be boolean"),
+                    }
+                }
+                Value::Bool(false)
             }
-        }
-        Expr::Let { bindings, body } => {
-            // Create a new environment by extending the current one
-            let mut new_env = env.clone();
-            let mut new_mappings = std::collections::HashMap::new();
-
-            // Evaluate each binding and add it to the new environment
-            for (id, expr) in bindings {
-                let value = eval(expr, new_env.clone());
-                new_mappings.insert(id, value);
+            Expr::Not(expr) => {
+                let val = eval(*expr, env.clone());
+                match val {
+                    Value::Bool(b) => Value::Bool(!b),
+                    _ => panic!("Argument to 'not' must be boolean"),
+                }
             }
+            Expr::Let { bindings, body } => {
+                // Create a new environment by extending the current one
+                let mut new_env = env.clone();
+                let mut new_mappings = std::collections::HashMap::new();
 
-            new_env.push(new_mappings);
+                // Evaluate each binding and add it to the new environment
+                for (id, expr) in bindings {
+                    let value = eval(expr, new_env.clone());
+                    new_mappings.insert(id, value);
+                }
 
-            // Evaluate the body with the new environment
-            eval(*body, new_env)
-        }
-        Expr::If { cond, then, else_ } => {
-            let cond_val = eval(*cond, env.clone());
-            match cond_val {
-                Value::Bool(true) => eval(*then, env),
-                Value::Bool(false) => eval(*else_, env),
-                _ => panic!("If condition must evaluate to a boolean"),
+                env.meter().alloc_table(&new_mappings);
+                new_env.push(new_mappings);
+
+                // Evaluate the body with the new environment
+                env = new_env;
+                expr = *body;
+                continue;
             }
-        }
-        Expr::Fn { args, body } => {
-            Value::Closure {
-                params: args.clone(),
-                body: *body.clone(),
-                mappings: {
-                    // Create bounded set with function arguments
-                    let mut bounded = std::collections::HashSet::new();
-                    for arg in &args {
-                        bounded.insert(arg.clone());
-                    }
+            Expr::If { cond, then, else_ } => {
+                let cond_val = eval(*cond, env.clone());
+                expr = match cond_val {
+                    Value::Bool(true) => *then,
+                    Value::Bool(false) => *else_,
+                    _ => panic!("If condition must evaluate to a boolean"),
+                };
+                continue;
+            }
+            Expr::Fn { args, body } => {
+                let closure = Value::Closure {
+                    params: args.clone(),
+                    body: *body.clone(),
+                    mappings: {
+                        // Create bounded set with function arguments
+                        let mut bounded = std::collections::HashSet::new();
+                        for arg in &args {
+                            bounded.insert(arg.clone());
+                        }
 
-                    // Get free variable names using the new method
-                    let free_var_names = body.free_vars(&bounded);
+                        // Get free variable names using the new method
+                        let free_var_names = body.free_ids(&bounded);
 
-                    // Build mappings from environment
-                    let mut mappings = HashMap::new();
-                    for var_name in free_var_names {
-                        if let Some(value) = env.get(&var_name) {
-                            mappings.insert(var_name, value.clone());
-                        } else {
-                            panic!("Undefined identifier in closure: {}", var_name);
+                        // Build mappings from environment
+                        let mut mappings = HashMap::new();
+                        for var_name in free_var_names {
+                            if let Some(value) = env.get(&var_name) {
+                                mappings.insert(var_name, value.clone());
+                            } else {
+                                panic!("Undefined identifier in closure: {}", var_name);
+                            }
                         }
-                    }
-                    mappings
-                },
+                        mappings
+                    },
+                    sig: Signature::default(),
+                };
+                env.meter().alloc_value(&closure);
+                closure
             }
-        }
-        Expr::Form(form) => {
-            if form.is_empty() {
-                panic!("Empty form");
-            } else {
-                let vals: Vec<Value> = form.iter().map(|e| eval(e.clone(), env.clone())).collect();
-                let f = &vals[0];
-                match f {
-                    Value::Func(func) => {
-                        let args = vals[1..].to_vec();
-                        // println!("Calling function {:?} on args {:?}", f, args);
-                        func(args)
+            Expr::Form(form) => {
+                if form.is_empty() {
+                    panic!("Empty form");
+                } else {
+                    let mut args: Vec<Value> =
+                        form.iter().map(|e| eval(e.clone(), env.clone())).collect();
+                    let f = args.remove(0);
+                    match &f {
+                        // The result of a call with an annotated return type is still
+                        // to be checked once the call returns
+                        Value::Closure {
+                            params,
+                            body,
+                            mappings,
+                            sig,
+                        } if args.len() == params.len() && sig.ret.is_none() => {
+                            let new_mappings = bind_args(params, mappings, sig, args);
+                            let new_env = call_env(&env, new_mappings);
+                            if !in_call {
+                                env.meter().enter();
+                                (in_call, entered) = (true, true);
+                            }
+                            env = new_env;
+                            expr = body.clone();
+                            continue;
+                        }
+                        _ => apply(&f, args, &env),
                     }
-                    Value::Closure {
-                        params,
-                        body,
-                        mappings,
-                    } => {
-                        let args = vals[1..].to_vec();
-
-                        if args.len() > params.len() {
-                            panic!(
-                                "Too many arguments for function {:?}",
-                                Value::Closure {
-                                    params: params.clone(),
-                                    body: body.clone(),
-                                    mappings: mappings.clone()
-                                }
-                            );
+                }
+            }
+            Expr::Def { .. } => {
+                panic!("Def expression only allowed in top level form");
+            }
+            Expr::Defun { .. } => {
+                panic!("Defun expression only allowed in top level form");
+            }
+            Expr::LetFun {
+                name,
+                args,
+                fun_body,
+                expr_body,
+            } => {
+                // Create a new environment for the letfun expression
+                let mut new_env = env.clone();
+
+                // Create the function closure. Its body defines the function again,
+                // so that it can refer to itself wherever it is called
+                let closure = Value::Closure {
+                    params: args.clone(),
+                    body: Expr::LetFun {
+                        name: name.clone(),
+                        args: args.clone(),
+                        fun_body: fun_body.clone(),
+                        expr_body: fun_body.clone(),
+                    },
+                    mappings: {
+                        // Create bounded set with function name and arguments
+                        let mut bounded = std::collections::HashSet::new();
+                        bounded.insert(name.clone()); // Function can refer to itself
+                        for arg in &args {
+                            bounded.insert(arg.clone());
                         }
 
-                        // Map arguments to parameters
-                        let mut new_mappings = mappings.clone();
-                        for (i, param) in params.iter().enumerate() {
-                            if i < args.len() {
-                                new_mappings.insert(param.clone(), args[i].clone());
+                        // Get free variable names using the new method
+                        let free_var_names = fun_body.free_ids(&bounded);
+
+                        // Build mappings from environment
+                        let mut mappings = HashMap::new();
+                        for var_name in free_var_names {
+                            if let Some(value) = env.get(&var_name) {
+                                mappings.insert(var_name, value.clone());
                             } else {
-                                break;
+                                panic!("Undefined identifier in closure: {}", var_name);
                             }
                         }
+                        mappings
+                    },
+                    sig: Signature::default(),
+                };
 
-                        if args.len() == params.len() {
-                            let mut newenv = env.clone();
-                            newenv.push(new_mappings);
-                            eval(body.clone(), newenv)
-                        } else {
-                            // Partial application
-                            let remaining_params =
-                                params.iter().skip(args.len()).cloned().collect();
-
-                            Value::Closure {
-                                params: remaining_params,
-                                body: body.clone(),
-                                mappings: new_mappings,
+                let mut new_mappings = HashMap::new();
+                new_mappings.insert(name.clone(), closure);
+                env.meter().alloc_table(&new_mappings);
+                new_env.push(new_mappings);
+
+                // Evaluate the body with the function defined
+                env = new_env;
+                expr = *expr_body;
+                continue;
+            }
+            Expr::DefClos { .. } => {
+                panic!("DefClos expression only allowed in top level form");
+            }
+            Expr::LetClos {
+                name,
+                closid,
+                freevars,
+                body,
+            } => {
+                // A `defclos` is a closure over its free variables followed by its
+                // arguments (see `file::evaluate_expressions`): capture the free variables
+                let closure = match env.get_global(&closid) {
+                    Some(Value::Closure {
+                        params,
+                        body: fun_body,
+                        sig,
+                        ..
+                    }) if params.len() >= freevars.len() => {
+                        let (names, params) = params.split_at(freevars.len());
+                        let mut mappings = HashMap::new();
+                        for (name, var) in names.iter().zip(&freevars) {
+                            match env.get(var) {
+                                Some(value) => mappings.insert(name.clone(), value.clone()),
+                                None => panic!("Undefined identifier in closure: {}", var),
+                            };
+                        }
+
+                        // The body refers to the running closure by the label of its
+                        // `defclos`, so rebuild it there from the captured variables
+                        let fun_body = if fun_body.free_vars(&HashSet::new()).contains(&closid) {
+                            Expr::LetClos {
+                                name: closid.clone(),
+                                closid: closid.clone(),
+                                freevars: names.to_vec(),
+                                body: Box::new(fun_body.clone()),
                             }
+                        } else {
+                            fun_body.clone()
+                        };
+
+                        Value::Closure {
+                            params: params.to_vec(),
+                            body: fun_body,
+                            mappings,
+                            sig: sig.clone(),
                         }
                     }
-                    _ => panic!("Type error: {:?}", f),
-                }
+                    _ => panic!("Undefined closure: {}", closid),
+                };
+
+                let table = HashMap::from([(name, closure)]);
+                env.meter().alloc_table(&table);
+                env.push(table);
+                expr = *body;
+                continue;
             }
+        };
+    };
+    if entered {
+        env.meter().leave();
+    }
+    value
+}
+
+/// The variables of a closure called with `args`: those it captured, and its
+/// parameters bound to the arguments, which are checked against their annotations
+fn bind_args(
+    params: &[String],
+    mappings: &HashMap<String, Value>,
+    sig: &Signature,
+    args: Vec<Value>,
+) -> HashMap<String, Value> {
+    for (i, arg) in args.iter().enumerate() {
+        if let Some(ty) = sig.param(i)
+            && !ty.matches(arg)
+        {
+            panic!(
+                "Type error: parameter {} expects {}, found {} {}",
+                params[i],
+                ty,
+                arg.type_name(),
+                arg
+            );
         }
-        Expr::Def { .. } => {
-            panic!("Def expression only allowed in top level form");
-        }
-        Expr::Defun { .. } => {
-            panic!("Defun expression only allowed in top level form");
+    }
+    let mut new_mappings = mappings.clone();
+    new_mappings.extend(params.iter().cloned().zip(args));
+    new_mappings
+}
+
+/// The environment of the body of a call: the globals of `env` and `mappings`, but not
+/// the locals of the caller
+fn call_env(env: &Env, mappings: HashMap<String, Value>) -> Env {
+    let mut new_env = env.globals();
+    env.meter().alloc_table(&mappings);
+    new_env.push(mappings);
+    new_env
+}
+
+/// Apply the function `f` to `args`. Closures are evaluated in the globals of `env`
+/// and their captured variables.
+pub fn apply(f: &Value, args: Vec<Value>, env: &Env) -> Value {
+    match f {
+        Value::Func(func) => {
+            // println!("Calling function {:?} on args {:?}", f, args);
+            let result = func(args);
+            env.meter().alloc_value(&result);
+            result
         }
-        Expr::LetFun {
-            name,
-            args,
-            fun_body,
-            expr_body,
+        Value::Native(native) => match native.call(&args) {
+            Ok(value) => {
+                env.meter().alloc_value(&value);
+                value
+            }
+            // The error is the payload, so that the interpreter reports it as is
+            Err(err) => panic::panic_any(err),
+        },
+        Value::Closure {
+            params,
+            body,
+            mappings,
+            sig,
         } => {
-            // Create a new environment for the letfun expression
-            let mut new_env = env.clone();
-
-            // Create the function closure
-            let closure = Value::Closure {
-                params: args.clone(),
-                body: *fun_body.clone(),
-                mappings: {
-                    // Create bounded set with function name and arguments
-                    let mut bounded = std::collections::HashSet::new();
-                    bounded.insert(name.clone()); // Function can refer to itself
-                    for arg in &args {
-                        bounded.insert(arg.clone());
-                    }
+            if args.len() > params.len() {
+                panic!("Too many arguments for function {:?}", f);
+            }
 
-                    // Get free variable names using the new method
-                    let free_var_names = fun_body.free_vars(&bounded);
+            let applied = args.len();
+            let new_mappings = bind_args(params, mappings, sig, args);
+            if applied == params.len() {
+                env.meter().enter();
+                let result = eval_in(body.clone(), call_env(env, new_mappings), true);
+                env.meter().leave();
+                if let Some(ty) = sig.ret
+                    && !ty.matches(&result)
+                {
+                    panic!(
+                        "Type error: function returns {}, expected {}",
+                        result.type_name(),
+                        ty
+                    );
+                }
+                result
+            } else {
+                // Partial application
+                let remaining_params = params.iter().skip(applied).cloned().collect();
 
-                    // Build mappings from environment
-                    let mut mappings = HashMap::new();
-                    for var_name in free_var_names {
-                        if let Some(value) = env.get(&var_name) {
-                            mappings.insert(var_name, value.clone());
-                        } else {
-                            panic!("Undefined identifier in closure: {}", var_name);
-                        }
-                    }
-                    mappings
-                },
-            };
+                let closure = Value::Closure {
+                    params: remaining_params,
+                    body: body.clone(),
+                    mappings: new_mappings,
+                    sig: sig.skip(applied),
+                };
+                env.meter().alloc_value(&closure);
+                closure
+            }
+        }
+        _ => panic!("Type error: {:?}", f),
+    }
+}
 
-            let mut new_mappings = HashMap::new();
-            new_mappings.insert(name.clone(), closure);
-            new_env.push(new_mappings);
+#[cfg(test)]
+mod test {
+    use crate::compl::{CompileOptions, compl_to_closure_with};
+    use crate::intpt::{create_environment, file::process_string};
+    use crate::{format_prog, read_string};
 
-            // Evaluate the body with the function defined
-            eval(*expr_body, new_env)
+    use super::*;
+
+    /// Values of the top-level expressions that are not definitions
+    fn main_values(prog: &str) -> Vec<Value> {
+        let exprs = read_string(prog).unwrap();
+        let results = process_string(prog, &mut create_environment()).unwrap();
+        exprs
+            .iter()
+            .zip(results)
+            .filter(|(expr, _)| {
+                !matches!(
+                    expr,
+                    Expr::Def { .. } | Expr::Defun { .. } | Expr::DefClos { .. }
+                )
+            })
+            .map(|(_, value)| value)
+            .collect()
+    }
+
+    /// Closures can differ once compiled, so only compare that both are functions
+    fn same_value(a: &Value, b: &Value) -> bool {
+        match (a, b) {
+            (Value::Closure { .. } | Value::Func(_), Value::Closure { .. } | Value::Func(_)) => {
+                true
+            }
+            _ => a == b,
         }
-        Expr::DefClos { .. } => {
-            panic!("DefClos not allowed");
+    }
+
+    /// Evaluate `source` directly and after closure conversion
+    fn check_ir_eval(name: &str, source: &str) {
+        let expected = main_values(source);
+        for options in [
+            CompileOptions::default(),
+            CompileOptions {
+                inline_threshold: 0,
+                ..CompileOptions::default()
+            },
+        ] {
+            let prog = compl_to_closure_with(read_string(source).unwrap(), &options).unwrap();
+            let ir = format_prog(&prog);
+            let actual = main_values(&ir);
+            assert_eq!(expected.len(), actual.len(), "{}:\n{}", name, ir);
+            for (e, a) in expected.iter().zip(&actual) {
+                assert!(same_value(e, a), "{}: {} != {}\n{}", name, e, a, ir);
+            }
         }
-        Expr::LetClos { .. } => {
-            panic!("Clos not allowed");
+    }
+
+    #[test]
+    fn eval_test_ir_examples() {
+        let mut files: Vec<_> = std::fs::read_dir("./examples")
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "purelisp"))
+            .collect();
+        files.sort();
+        assert!(!files.is_empty());
+        for file in files {
+            let source = std::fs::read_to_string(&file).unwrap();
+            check_ir_eval(&file.display().to_string(), &source);
         }
     }
+
+    #[test]
+    fn eval_test_ir_closures() {
+        // Recursive closures refer to themselves by their label, and closures can
+        // capture other closures
+        check_ir_eval(
+            "closures",
+            r#"
+(defun g (k) (letfun (f (x) (if (< x 0) x (f (- x k)))) (f 10)))
+(g 3)
+(defun adder (n) (fn (x) (+ x n)))
+(defun twice (h) (fn (x) (h (h x))))
+((twice (adder 5)) 1)
+(let ((a 2) (b 3)) (letfun (h (x) (if (= x 0) a (+ b (h (- x 1))))) (h 4)))
+"#,
+        );
+    }
+
+    #[test]
+    fn eval_test_lexical_scope() {
+        // Functions see the globals rather than the locals of their caller, and
+        // a local function still refers to itself once it escapes its letfun
+        let values = main_values(
+            r#"
+(def y 1)
+(defun g () y)
+(let ((y 5)) (g))
+(defun mk () (letfun (f (n) (if (= n 0) 0 (f (- n 1)))) f))
+((mk) 3)
+"#,
+        );
+        assert_eq!(values, [Value::Int(1), Value::Int(0)]);
+    }
+
+    #[test]
+    fn eval_test_tail_calls() {
+        // Calls in tail position, to the function itself or to another one, loop
+        // instead of growing the stack
+        let values = main_values(
+            r#"
+(defun count (n acc) (if (= n 0) acc (count (- n 1) (+ acc 1))))
+(count 10000 0)
+(defun even (n) (if (= n 0) true (odd (- n 1))))
+(defun odd (n) (if (= n 0) false (even (- n 1))))
+(even 10001)
+(let loop ((i 0)) (if (< i 10000) (loop (+ i 1)) i))
+"#,
+        );
+        assert_eq!(
+            values,
+            [Value::Int(10000), Value::Bool(false), Value::Int(10000)]
+        );
+    }
+
+    #[test]
+    fn eval_test_annotations() {
+        let mut env = create_environment();
+        let results = process_string(
+            "(defun half ((x : float)) : float (/ x 2.0))\n(half 3.0)",
+            &mut env,
+        )
+        .unwrap();
+        assert_eq!(results[1], Value::Float(1.5));
+    }
+
+    #[test]
+    fn eval_test_parallel_let() {
+        // The bindings of `let` are evaluated in the enclosing environment, those
+        // of `let*` each in the environment of the previous ones
+        let mut env = create_environment();
+        let results = process_string(
+            "(def x 10)
+(let ((x 1) (y x)) y)
+(let* ((x 1) (y x)) y)
+(let ((a 1) (b 2)) (let ((a b) (b a)) (- a b)))",
+            &mut env,
+        )
+        .unwrap();
+        assert_eq!(results[1..], [Value::Int(10), Value::Int(1), Value::Int(1)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "parameter x expects float, found int")]
+    fn eval_test_annotation_param_mismatch() {
+        let mut env = create_environment();
+        process_string("(defun half ((x : float)) (/ x 2.0))\n(half 3)", &mut env).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "function returns int, expected float")]
+    fn eval_test_annotation_return_mismatch() {
+        let mut env = create_environment();
+        process_string("(defun f (x) : float x)\n(f 3)", &mut env).unwrap();
+    }
 }
@@ -1,15 +1,25 @@
 use std::io;
 use std::path::Path;
 
-use crate::ast::{Expr, Value};
+use crate::ast::{Expr, Signature, Value};
 use crate::intpt::Env;
 use crate::intpt::eval::eval;
+use crate::module::Loader;
 // We import read functions from the crate root
 
-/// Processes a multiline Lisp file
+/// Processes a multiline Lisp file, with the modules it imports
 pub fn process_file<P: AsRef<Path>>(file_path: P, env: &mut Env) -> io::Result<Vec<Value>> {
-    // Read and parse expressions from the file
-    let expressions = crate::read_file(file_path)?;
+    process_file_with(file_path, env, &mut Loader::new())
+}
+
+/// Processes a multiline Lisp file, finding the modules it imports with `loader`
+pub fn process_file_with<P: AsRef<Path>>(
+    file_path: P,
+    env: &mut Env,
+    loader: &mut Loader,
+) -> io::Result<Vec<Value>> {
+    // Read and parse expressions from the file, preceded by the imported definitions
+    let expressions = loader.load_file(file_path)?;
 
     // Evaluate all expressions
     evaluate_expressions(expressions, env)
@@ -29,29 +39,54 @@ fn evaluate_expressions(expressions: Vec<Expr>, env: &mut Env) -> io::Result<Vec
     let mut results = Vec::new();
 
     for expr in expressions {
-        match expr {
-            Expr::Def { x, y } => {
-                let value = eval(*y.clone(), env.clone());
-                env.set(x, value.clone());
-                results.push(value);
-            }
-            Expr::Defun { name, args, body } => {
-                // Create a closure for the function
-                let closure = Value::Closure {
-                    params: args,
-                    body: *body,
-                    mappings: std::collections::HashMap::new(),
-                };
-                // Bind the function name to the closure
-                env.set(name, closure.clone());
-                results.push(closure);
-            }
-            _ => {
-                let value = eval(expr, env.clone());
-                results.push(value);
-            }
-        }
+        results.push(evaluate_toplevel(expr, env));
     }
 
     Ok(results)
 }
+
+/// Evaluate a top-level expression, adding the definitions to the global environment
+pub(crate) fn evaluate_toplevel(expr: Expr, env: &mut Env) -> Value {
+    match expr {
+        Expr::Def { x, y } => {
+            let value = eval(*y.clone(), env.clone());
+            env.set(x, value.clone());
+            value
+        }
+        Expr::Defun {
+            name,
+            args,
+            body,
+            sig,
+        } => {
+            // Create a closure for the function
+            let closure = Value::Closure {
+                params: args,
+                body: *body,
+                mappings: std::collections::HashMap::new(),
+                sig,
+            };
+            // Bind the function name to the closure
+            env.set(name, closure.clone());
+            closure
+        }
+        Expr::DefClos {
+            name,
+            freevars,
+            args,
+            body,
+        } => {
+            // Closure code of closure-converted programs: the free variables are
+            // bound by `letclos` like the first arguments of a partial application
+            let closure = Value::Closure {
+                params: freevars.into_iter().chain(args).collect(),
+                body: *body,
+                mappings: std::collections::HashMap::new(),
+                sig: Signature::default(),
+            };
+            env.set(name, closure.clone());
+            closure
+        }
+        _ => eval(expr, env.clone()),
+    }
+}
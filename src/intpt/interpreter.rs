@@ -0,0 +1,570 @@
+use std::cell::Cell;
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::Once;
+
+use crate::ast::Value;
+use crate::convert::{FromValue, IntoValue};
+use crate::expand::Expander;
+use crate::intpt::eval::apply;
+use crate::intpt::file::evaluate_toplevel;
+use crate::intpt::native::NativeFunction;
+use crate::intpt::{Env, Error, Limits, Usage, create_environment};
+use crate::module::Loader;
+use crate::read::read_string_located_with;
+
+/// An interpreter owning its global environment, for host applications: scripts are
+/// loaded into it, and the functions they define can then be called from Rust. Errors
+/// of the script are reported as `Error`s instead of panicking.
+///
+/// ```
+/// use purelisp::intpt::Error;
+/// use purelisp::{Interpreter, Value};
+///
+/// let mut interpreter = Interpreter::new();
+/// interpreter.set_global("rate", 0.25);
+/// interpreter
+///     .load_str("(defun tax (amount) (* amount rate))")
+///     .unwrap();
+///
+/// let tax = interpreter.call("tax", &[Value::Float(80.0)]).unwrap();
+/// assert_eq!(tax, Value::Float(20.0));
+/// assert_eq!(interpreter.eval_str("(tax 4.0)").unwrap(), Value::Float(1.0));
+///
+/// interpreter.load_str("(def total (+ 1 2))").unwrap();
+/// let total: i64 = interpreter.get_global("total").unwrap();
+/// assert_eq!(total, 3);
+///
+/// let error = interpreter.call("tax", &[Value::Str("a".to_string())]);
+/// assert!(matches!(error, Err(Error::Eval { .. })));
+/// let error = interpreter.eval_str("(def x 1)\n(taxes 1.0)").unwrap_err();
+/// assert_eq!(error.to_string(), "line 2: Undefined identifier: taxes");
+/// ```
+#[derive(Debug, Clone)]
+pub struct Interpreter {
+    env: Env,
+    /// Modules imported by the loaded scripts, each linked once
+    loader: Loader,
+    /// Macros defined by the loaded scripts
+    expander: Expander,
+}
+
+impl Interpreter {
+    /// An interpreter with the builtins loaded
+    pub fn new() -> Self {
+        Self::with_env(create_environment())
+    }
+
+    /// An interpreter running in an existing environment
+    pub fn with_env(env: Env) -> Self {
+        Interpreter {
+            env,
+            loader: Loader::new(),
+            expander: Expander::new(),
+        }
+    }
+
+    pub fn env(&self) -> &Env {
+        &self.env
+    }
+
+    pub fn env_mut(&mut self) -> &mut Env {
+        &mut self.env
+    }
+
+    /// Look for imported modules in `dir` too, after the directory of the importing file
+    pub fn add_search_path(&mut self, dir: impl Into<PathBuf>) {
+        self.loader.add_search_path(dir);
+    }
+
+    /// Evaluate every top-level form of a file, whose imports are relative to it
+    pub fn load_file<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Error> {
+        let path = path.as_ref();
+        let source = fs::read_to_string(path)
+            .map_err(|err| Error::Io(format!("{}: {}", path.display(), err)))?;
+        self.eval_source(&source, Some(path)).map(|_| ())
+    }
+
+    /// Evaluate every top-level form of `source`
+    pub fn load_str(&mut self, source: &str) -> Result<(), Error> {
+        self.eval_str(source).map(|_| ())
+    }
+
+    /// Evaluate every top-level form of `source`, returning the value of the last one
+    /// (`nil` if there is none). Nothing is evaluated if `source` doesn't parse or
+    /// its imports fail; if a form fails, the definitions of the forms before it are
+    /// kept. Imports are relative to the current directory.
+    pub fn eval_str(&mut self, source: &str) -> Result<Value, Error> {
+        self.eval_source(source, None)
+    }
+
+    fn eval_source(&mut self, source: &str, file: Option<&Path>) -> Result<Value, Error> {
+        let exprs = match catch_panic(|| read_string_located_with(source, &mut self.expander)) {
+            Ok(Ok(exprs)) => exprs,
+            Ok(Err(err)) => return Err(Error::Parse(err.to_string())),
+            Err(Error::Eval { message, .. }) => return Err(Error::Parse(message)),
+            Err(err) => return Err(err),
+        };
+        // The forms of imported modules are parsed while linking
+        let exprs = match catch_panic(|| self.loader.link(exprs, file)) {
+            Ok(Ok(exprs)) => exprs,
+            Ok(Err(err)) => return Err(Error::Import(err.to_string())),
+            Err(Error::Eval { message, .. }) => return Err(Error::Parse(message)),
+            Err(err) => return Err(err),
+        };
+
+        self.env.meter().start();
+        let mut value = Value::Nil;
+        for (line, expr) in exprs {
+            value = catch_panic(|| evaluate_toplevel(expr, &mut self.env)).map_err(
+                |err| match err {
+                    Error::Eval {
+                        line: None,
+                        message,
+                    } => Error::Eval {
+                        line: Some(line),
+                        message,
+                    },
+                    err => err,
+                },
+            )?;
+        }
+        Ok(value)
+    }
+
+    /// Call the global function `name`. As in PureLisp, passing fewer arguments than
+    /// a function defined by the script expects returns a partial application.
+    pub fn call(&mut self, name: &str, args: &[Value]) -> Result<Value, Error> {
+        let func = match self.env.get_global(self.loader.linked_name(name)) {
+            Some(func @ (Value::Func(_) | Value::Native(_) | Value::Closure { .. })) => {
+                func.clone()
+            }
+            Some(other) => {
+                return Err(Error::Type {
+                    expected: "function",
+                    found: other.type_name(),
+                });
+            }
+            None => return Err(Error::Undefined(name.to_string())),
+        };
+        self.env.meter().start();
+        catch_panic(|| apply(&func, args.to_vec(), &self.env))
+    }
+
+    /// The value of the global `name`, converted to `T`
+    pub fn get_global<T: FromValue>(&self, name: &str) -> Result<T, Error> {
+        match self.env.get_global(self.loader.linked_name(name)) {
+            Some(value) => T::from_value(value.clone()),
+            None => Err(Error::Undefined(name.to_string())),
+        }
+    }
+
+    /// Define or redefine the global `name`
+    pub fn set_global(&mut self, name: &str, value: impl IntoValue) {
+        let name = self.loader.linked_name(name).to_string();
+        self.env.set_global(&name, value.into_value());
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.env.limits()
+    }
+
+    /// Limit the resources used by each subsequent call of `load_file`, `load_str`,
+    /// `eval_str` or `call`
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.env.set_limits(limits);
+    }
+
+    /// Resources used by the last call of `load_file`, `load_str`, `eval_str` or
+    /// `call`
+    pub fn usage(&self) -> Usage {
+        self.env.usage()
+    }
+
+    /// See `Env::register_native`
+    pub fn register_native(
+        &mut self,
+        name: &str,
+        arity: usize,
+        func: impl Fn(&[Value]) -> Result<Value, Error> + 'static,
+    ) {
+        self.env.register_native(name, arity, func);
+    }
+
+    /// See `Env::register_fn`
+    pub fn register_fn<Args, F: NativeFunction<Args>>(&mut self, name: &str, func: F) {
+        self.env.register_fn(name, func);
+    }
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+thread_local! {
+    /// Whether panics are being caught by `catch_panic` on this thread
+    static CATCHING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Run `f`, turning a panic (how the evaluator reports errors) into an error. The
+/// panics caught here are not printed.
+fn catch_panic<T>(f: impl FnOnce() -> T) -> Result<T, Error> {
+    static QUIET_HOOK: Once = Once::new();
+    QUIET_HOOK.call_once(|| {
+        let hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if !CATCHING.get() {
+                hook(info);
+            }
+        }));
+    });
+
+    let catching = CATCHING.replace(true);
+    let result = panic::catch_unwind(AssertUnwindSafe(f));
+    CATCHING.set(catching);
+    result.map_err(|payload| {
+        // Exceeded limits and failed native functions carry their error, other
+        // failures only a message
+        let payload = match payload.downcast::<Error>() {
+            Ok(error) => return *error,
+            Err(payload) => payload,
+        };
+        let message = match payload.downcast::<String>() {
+            Ok(message) => *message,
+            Err(payload) => match payload.downcast_ref::<&str>() {
+                Some(message) => message.to_string(),
+                None => "Unknown error".to_string(),
+            },
+        };
+        Error::Eval {
+            line: None,
+            message,
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    #[test]
+    fn test_interpreter_globals_and_calls() {
+        let mut interpreter = Interpreter::new();
+        interpreter.set_global("xs", vec![1, 2]);
+        interpreter.register_fn("len", |xs: Vec<Value>| xs.len() as i64);
+        interpreter
+            .load_str(
+                "(defun add (x y) (+ x y))\n\
+                 (def n (len xs))\n\
+                 (def inc (add 1))",
+            )
+            .unwrap();
+
+        assert_eq!(interpreter.get_global::<i64>("n"), Ok(2));
+        assert_eq!(
+            interpreter.call("add", &[Value::Int(2), Value::Int(3)]),
+            Ok(Value::Int(5))
+        );
+        assert_eq!(
+            interpreter.call("inc", &[Value::Int(41)]),
+            Ok(Value::Int(42))
+        );
+        assert_eq!(
+            interpreter.call("sqrt", &[Value::Int(4)]),
+            Ok(Value::Float(2.0))
+        );
+        assert_eq!(
+            interpreter.call("len", &[Value::from(vec![3])]),
+            Ok(Value::Int(1))
+        );
+        // Partial application
+        let add2 = interpreter.call("add", &[Value::Int(2)]).unwrap();
+        interpreter.set_global("add2", add2);
+        assert_eq!(interpreter.eval_str("(add2 3)"), Ok(Value::Int(5)));
+        assert_eq!(interpreter.eval_str(""), Ok(Value::Nil));
+
+        // Macros stay defined for later scripts
+        interpreter
+            .load_str("(define-syntax twice (syntax-rules () ((_ e) (add e e))))")
+            .unwrap();
+        assert_eq!(interpreter.eval_str("(twice 21)"), Ok(Value::Int(42)));
+    }
+
+    #[test]
+    fn test_interpreter_errors() {
+        let mut interpreter = Interpreter::new();
+        assert!(matches!(
+            interpreter.load_str("(def x 1"),
+            Err(Error::Parse(_))
+        ));
+        assert!(matches!(
+            interpreter.load_str("(def 1 1)"),
+            Err(Error::Parse(_))
+        ));
+
+        // The forms before a failing one are still evaluated
+        let error = interpreter
+            .load_str("(def a 1)\n\n(def b (mod a 0))\n(def c 3)")
+            .unwrap_err();
+        assert_eq!(
+            error,
+            Error::Eval {
+                line: Some(3),
+                message: "Division by zero".to_string()
+            }
+        );
+        assert_eq!(error.to_string(), "line 3: Division by zero");
+        assert_eq!(interpreter.get_global::<i64>("a"), Ok(1));
+        assert_eq!(
+            interpreter.get_global::<i64>("c"),
+            Err(Error::Undefined("c".to_string()))
+        );
+
+        assert_eq!(
+            interpreter.get_global::<bool>("a"),
+            Err(Error::Type {
+                expected: "bool",
+                found: "int"
+            })
+        );
+        assert_eq!(
+            interpreter.call("f", &[]),
+            Err(Error::Undefined("f".to_string()))
+        );
+        assert_eq!(
+            interpreter.call("a", &[]),
+            Err(Error::Type {
+                expected: "function",
+                found: "int"
+            })
+        );
+        assert!(matches!(
+            interpreter.call("+", &[Value::Int(1), Value::Bool(true)]),
+            Err(Error::Eval { line: None, .. })
+        ));
+        assert!(matches!(
+            interpreter.load_file("examples/missing.purelisp"),
+            Err(Error::Io(_))
+        ));
+    }
+
+    #[test]
+    fn test_interpreter_native_errors() {
+        let mut interpreter = Interpreter::new();
+        interpreter.register_fn("inc", |n: i64| n + 1);
+        interpreter.register_native("fail", 0, |_| Err(Error::Native("no".to_string())));
+        let type_error = Error::Type {
+            expected: "int",
+            found: "string",
+        };
+        assert_eq!(
+            interpreter.call("inc", &[Value::Str("1".into())]),
+            Err(type_error.clone())
+        );
+        assert_eq!(interpreter.eval_str("(inc \"1\")"), Err(type_error));
+        assert_eq!(
+            interpreter.eval_str("(inc)"),
+            Err(Error::Arity {
+                name: "inc".to_string(),
+                expected: 1,
+                found: 0
+            })
+        );
+        assert_eq!(
+            interpreter.call("fail", &[]),
+            Err(Error::Native("no".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_interpreter_load_file() {
+        let mut interpreter = Interpreter::new();
+        interpreter
+            .load_file("examples/recursion.purelisp")
+            .unwrap();
+        assert!(matches!(
+            interpreter.get_global::<Value>("factorial"),
+            Ok(Value::Closure { .. })
+        ));
+        assert_eq!(
+            interpreter.call("factorial", &[Value::Int(5)]),
+            Ok(Value::Int(120))
+        );
+    }
+
+    #[test]
+    fn test_interpreter_modules() {
+        let mut interpreter = Interpreter::new();
+        interpreter
+            .load_file("tests/programs/modules.purelisp")
+            .unwrap();
+        // Imported names are resolved when linking: the globals of a module are
+        // called by their qualified names
+        assert_eq!(
+            interpreter.call("helper", &[Value::Int(1)]),
+            Ok(Value::Int(2))
+        );
+        assert_eq!(
+            interpreter.call("geometry/hypot2", &[Value::Int(3), Value::Int(4)]),
+            Ok(Value::Int(25))
+        );
+        assert_eq!(
+            interpreter.call("numeric/cube", &[Value::Int(3)]),
+            Ok(Value::Int(27))
+        );
+        // The program redefines `max`, which `clamp` still refers to
+        assert_eq!(
+            interpreter.call("max", &[Value::Int(1), Value::Int(2)]),
+            Ok(Value::Int(999))
+        );
+        assert_eq!(
+            interpreter.call("numeric/clamp", &[0, 10, 42].map(Value::Int)),
+            Ok(Value::Int(10))
+        );
+        // Modules already linked are shared by later sources
+        interpreter.add_search_path("tests/programs/modules");
+        assert_eq!(
+            interpreter.eval_str("(import geometry (hypot2))\n(hypot2 1 2)"),
+            Ok(Value::Int(5))
+        );
+        assert_eq!(
+            interpreter.eval_str("(import missing)"),
+            Err(Error::Import(
+                "Module missing.purelisp not found".to_string()
+            ))
+        );
+        assert_eq!(
+            interpreter.eval_str("(import numeric (helper))"),
+            Err(Error::Import(
+                "helper is not exported by numeric".to_string()
+            ))
+        );
+    }
+
+    const FIB: &str = "(defun fib (n) (if (< n 2) n (+ (fib (- n 1)) (fib (- n 2)))))";
+
+    #[test]
+    fn test_interpreter_limits() {
+        let mut interpreter = Interpreter::new();
+        interpreter
+            .load_str("(defun down (n) (if (= n 0) 0 (+ 1 (down (- n 1)))))")
+            .unwrap();
+        interpreter.load_str(FIB).unwrap();
+        interpreter.set_limits(Limits {
+            fuel: Some(10_000),
+            max_depth: Some(20),
+            ..Limits::default()
+        });
+
+        assert_eq!(
+            interpreter.call("down", &[Value::Int(19)]),
+            Ok(Value::Int(19))
+        );
+        assert_eq!(
+            interpreter.call("down", &[Value::Int(1_000_000)]),
+            Err(Error::MaxDepth { max_depth: 20 })
+        );
+        assert_eq!(
+            interpreter.eval_str("(fib 18)"),
+            Err(Error::OutOfFuel { fuel: 10_000 })
+        );
+        // Every evaluation gets the whole budget again
+        assert_eq!(interpreter.eval_str("(fib 10)"), Ok(Value::Int(55)));
+        assert_eq!(
+            interpreter.call("down", &[Value::Int(10)]),
+            Ok(Value::Int(10))
+        );
+    }
+
+    #[test]
+    fn test_interpreter_timeout() {
+        let mut interpreter = Interpreter::new();
+        interpreter.load_str(FIB).unwrap();
+        let timeout = Duration::from_millis(20);
+        interpreter.set_limits(Limits {
+            timeout: Some(timeout),
+            ..Limits::default()
+        });
+        let start = Instant::now();
+        assert_eq!(
+            interpreter.call("fib", &[Value::Int(35)]),
+            Err(Error::Timeout { timeout })
+        );
+        assert!(start.elapsed() < Duration::from_secs(5));
+        interpreter.set_limits(Limits::default());
+        assert_eq!(interpreter.eval_str("(fib 10)"), Ok(Value::Int(55)));
+    }
+
+    #[test]
+    fn test_interpreter_memory() {
+        let mut interpreter = Interpreter::new();
+        // Every closure copies the chain of closures it wraps
+        interpreter
+            .load_str(
+                "(defun wrap (f n) (if (= n 0) f (wrap (fn (x) (f x)) (- n 1))))\n\
+                 (defun id (x) x)",
+            )
+            .unwrap();
+
+        assert_eq!(interpreter.eval_str("((wrap id 5) 1)"), Ok(Value::Int(1)));
+        let usage = interpreter.usage();
+        assert!(usage.steps > 0);
+        // Both `wrap` and the closures call in tail position, so that no call nests
+        assert_eq!(usage.max_depth, 1);
+        let small = usage.allocated;
+        interpreter.eval_str("((wrap id 15) 1)").unwrap();
+        assert!(interpreter.usage().allocated > 3 * small);
+
+        interpreter.set_limits(Limits {
+            max_allocated: Some(small),
+            ..Limits::default()
+        });
+        assert_eq!(interpreter.eval_str("((wrap id 5) 1)"), Ok(Value::Int(1)));
+        assert_eq!(
+            interpreter.eval_str("((wrap id 15) 1)"),
+            Err(Error::AllocationExceeded {
+                max_allocated: small
+            })
+        );
+        assert_eq!(
+            interpreter.call(
+                "wrap",
+                &[Value::Str("x".repeat(small as usize)), Value::Int(0)]
+            ),
+            Err(Error::AllocationExceeded {
+                max_allocated: small
+            })
+        );
+    }
+
+    #[test]
+    fn test_interpreter_memory_of_calls() {
+        let mut interpreter = Interpreter::new();
+        interpreter
+            .load_str(
+                "(defun count (n acc) (if (= n 0) acc (count (- n 1) (+ acc 1))))\n\
+                 (defun grow (xs s n) (if (= n 0) xs (grow (cons s xs) s (- n 1))))",
+            )
+            .unwrap();
+        let max_allocated = 100_000;
+        interpreter.set_limits(Limits {
+            max_allocated: Some(max_allocated),
+            ..Limits::default()
+        });
+        // Calls only pay for their arguments, not for the globals they see
+        assert_eq!(
+            interpreter.call("count", &[Value::Int(50), Value::Int(0)]),
+            Ok(Value::Int(50))
+        );
+        let big = Value::Str("x".repeat(10_000));
+        assert_eq!(
+            interpreter.call("grow", &[Value::Nil, big, Value::Int(20)]),
+            Err(Error::AllocationExceeded { max_allocated })
+        );
+    }
+}
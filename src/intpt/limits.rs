@@ -0,0 +1,192 @@
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::mem::size_of;
+use std::panic;
+use std::time::{Duration, Instant};
+
+use crate::ast::{Cons, Expr, Value};
+use crate::intpt::Error;
+
+/// Limits on the resources a program may use, to run untrusted scripts; `None` is
+/// unlimited. Exceeding a limit stops the evaluation with `Error::OutOfFuel`,
+/// `Error::MaxDepth`, `Error::Timeout` or `Error::AllocationExceeded`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Limits {
+    /// Number of evaluation steps (roughly, of sub-expressions evaluated)
+    pub fuel: Option<u64>,
+    /// Number of nested calls of functions defined in PureLisp; a call in tail position
+    /// takes the place of its caller rather than nesting in it. Every nested call uses native
+    /// stack (a few KB in release builds, tens of KB in debug builds), and running out
+    /// of stack aborts the process: the depth must be limited for deep recursions to
+    /// fail cleanly, and the interpreter may be run on a thread with a larger stack.
+    pub max_depth: Option<usize>,
+    /// Wall-clock time
+    pub timeout: Option<Duration>,
+    /// Number of bytes allocated for values and environments over the whole
+    /// evaluation (see `Usage::allocated`). This is a budget, like the fuel, rather than
+    /// a bound on the memory in use: a long loop fails even if it frees what it
+    /// allocates.
+    pub max_allocated: Option<u64>,
+}
+
+/// Resources used by the last evaluation of an environment
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Usage {
+    /// Number of evaluation steps
+    pub steps: u64,
+    /// Deepest nesting of function calls
+    pub max_depth: usize,
+    /// Approximate number of bytes allocated for values and environments. Memory is
+    /// never given back to this count: values are mostly copied rather than shared (a
+    /// closure copies the variables it captures), so it bounds the memory in use. The
+    /// code of closures is counted as a single node, and a list as its first cell, as
+    /// the rest is shared with the list it was consed onto.
+    pub allocated: u64,
+}
+
+/// The deadline is only checked every this many steps, as reading the clock is slow
+const CLOCK_INTERVAL: u64 = 1024;
+
+/// Resources used by the running program, shared by every copy of an environment
+#[derive(Debug, Default)]
+pub(crate) struct Meter {
+    limits: Cell<Limits>,
+    steps: Cell<u64>,
+    depth: Cell<usize>,
+    max_depth: Cell<usize>,
+    allocated: Cell<u64>,
+    deadline: Cell<Option<Instant>>,
+}
+
+impl Meter {
+    pub fn limits(&self) -> Limits {
+        self.limits.get()
+    }
+
+    pub fn set_limits(&self, limits: Limits) {
+        self.limits.set(limits);
+        self.start();
+    }
+
+    pub fn usage(&self) -> Usage {
+        Usage {
+            steps: self.steps.get(),
+            max_depth: self.max_depth.get(),
+            allocated: self.allocated.get(),
+        }
+    }
+
+    /// Start counting the resources used by a new evaluation
+    pub fn start(&self) {
+        self.steps.set(0);
+        self.depth.set(0);
+        self.max_depth.set(0);
+        self.allocated.set(0);
+        self.deadline.set(
+            self.limits
+                .get()
+                .timeout
+                .map(|timeout| Instant::now() + timeout),
+        );
+    }
+
+    /// Count an evaluation step
+    pub fn step(&self) {
+        let steps = self.steps.get() + 1;
+        self.steps.set(steps);
+        let limits = self.limits.get();
+        if let Some(fuel) = limits.fuel
+            && steps > fuel
+        {
+            exceeded(Error::OutOfFuel { fuel });
+        }
+        if steps.is_multiple_of(CLOCK_INTERVAL)
+            && let (Some(deadline), Some(timeout)) = (self.deadline.get(), limits.timeout)
+            && Instant::now() >= deadline
+        {
+            exceeded(Error::Timeout { timeout });
+        }
+    }
+
+    /// Enter a function call
+    pub fn enter(&self) {
+        let depth = self.depth.get() + 1;
+        if let Some(max_depth) = self.limits.get().max_depth
+            && depth > max_depth
+        {
+            exceeded(Error::MaxDepth { max_depth });
+        }
+        self.depth.set(depth);
+        self.max_depth.set(self.max_depth.get().max(depth));
+    }
+
+    /// Return from a function call
+    pub fn leave(&self) {
+        self.depth.set(self.depth.get() - 1);
+    }
+
+    /// Count `bytes` newly allocated
+    pub fn alloc(&self, bytes: u64) {
+        let allocated = self.allocated.get() + bytes;
+        self.allocated.set(allocated);
+        if let Some(max_allocated) = self.limits.get().max_allocated
+            && allocated > max_allocated
+        {
+            exceeded(Error::AllocationExceeded { max_allocated });
+        }
+    }
+
+    pub fn alloc_value(&self, value: &Value) {
+        self.alloc(value_size(value));
+    }
+
+    pub fn alloc_table(&self, table: &HashMap<String, Value>) {
+        self.alloc(table_size(table));
+    }
+}
+
+/// Approximate number of bytes used by `value` and what it owns
+pub(crate) fn value_size(value: &Value) -> u64 {
+    let owned = match value {
+        Value::Str(s) => s.len() as u64,
+        // The tail was counted when it was made
+        Value::List(cell) => size_of::<Cons>() as u64 + value_size(&cell.head),
+        Value::Tuple(items) => items.iter().map(value_size).sum(),
+        Value::Map(entries) => entries
+            .iter()
+            .map(|(key, value)| string_size(key) + value_size(value))
+            .sum(),
+        Value::Closure {
+            params, mappings, ..
+        } => {
+            params.iter().map(|param| string_size(param)).sum::<u64>()
+                + size_of::<Expr>() as u64
+                + table_size(mappings)
+        }
+        Value::Nil
+        | Value::Bool(_)
+        | Value::Int(_)
+        | Value::Float(_)
+        | Value::Func(_)
+        | Value::Native(_) => 0,
+    };
+    size_of::<Value>() as u64 + owned
+}
+
+/// Approximate number of bytes used by a table of an environment
+pub(crate) fn table_size(table: &HashMap<String, Value>) -> u64 {
+    table
+        .iter()
+        .map(|(key, value)| string_size(key) + value_size(value))
+        .sum()
+}
+
+fn string_size(s: &str) -> u64 {
+    (size_of::<String>() + s.len()) as u64
+}
+
+/// Abort the evaluation: like other runtime errors, an exceeded limit unwinds the
+/// evaluator, with the error as payload so that the interpreter can report it as is
+fn exceeded(error: Error) -> ! {
+    panic::panic_any(error)
+}
@@ -1,31 +1,53 @@
+mod error;
 pub mod eval;
 pub mod file;
+mod interpreter;
+mod limits;
+pub mod native;
 pub mod prelude;
 pub mod repl;
 
 use crate::ast::Value;
+pub use error::Error;
+pub use interpreter::Interpreter;
+use limits::Meter;
+pub use limits::{Limits, Usage};
+use native::{NativeFn, NativeFunction};
 use prelude::load_prelude;
 use std::collections::HashMap;
+use std::rc::Rc;
 
 #[derive(Debug, Clone)]
 pub struct Env {
+    /// The top-level table, shared by the copies of the environment
+    globals: Rc<HashMap<String, Value>>,
+    /// The tables of local bindings, innermost last
     tables: Vec<HashMap<String, Value>>,
+    meter: Rc<Meter>,
 }
 
 impl Env {
+    /// Start a new table of local bindings
     pub fn init(&mut self) {
         self.tables.push(HashMap::new());
     }
 
     pub fn new() -> Self {
-        let mut env = Env { tables: Vec::new() };
-        env.init();
-        env
+        Env {
+            globals: Rc::default(),
+            tables: Vec::new(),
+            meter: Rc::default(),
+        }
     }
 
+    /// Bind `key` in the innermost table
     pub fn set(&mut self, key: String, value: Value) {
-        let table = self.tables.last_mut().unwrap();
-        table.insert(key, value);
+        match self.tables.last_mut() {
+            Some(table) => {
+                table.insert(key, value);
+            }
+            None => self.set_global(&key, value),
+        }
     }
 
     pub fn get(&self, key: &str) -> Option<&Value> {
@@ -34,17 +56,131 @@ impl Env {
                 return Some(value);
             }
         }
-        None
+        self.get_global(key)
+    }
+
+    /// Look `key` up in the top-level table only, ignoring local bindings
+    pub fn get_global(&self, key: &str) -> Option<&Value> {
+        self.globals.get(key)
+    }
+
+    /// Define `key` in the top-level table. The table is only copied if a copy of the
+    /// environment still shares it.
+    pub fn set_global(&mut self, key: &str, value: Value) {
+        Rc::make_mut(&mut self.globals).insert(key.to_string(), value);
+    }
+
+    /// A copy of the environment with only the top-level bindings, which it shares
+    pub fn globals(&self) -> Env {
+        Env {
+            globals: self.globals.clone(),
+            tables: Vec::new(),
+            meter: self.meter.clone(),
+        }
     }
 
     pub fn push(&mut self, map: HashMap<String, Value>) {
         self.tables.push(map);
     }
+
+    pub fn limits(&self) -> Limits {
+        self.meter.limits()
+    }
+
+    /// Limit the resources used by the evaluations in this environment (and its
+    /// copies), counting from now
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.meter.set_limits(limits);
+    }
+
+    /// Resources used since `set_limits`, or since the start of the last evaluation of
+    /// an `Interpreter`
+    pub fn usage(&self) -> Usage {
+        self.meter.usage()
+    }
+
+    pub(crate) fn meter(&self) -> &Meter {
+        &self.meter
+    }
+
+    /// Define the global `name` as a native function taking `arity` arguments. The
+    /// function is only called with exactly `arity` arguments, and may capture state.
+    ///
+    /// ```
+    /// use std::cell::RefCell;
+    /// use std::rc::Rc;
+    ///
+    /// use purelisp::intpt::{Error, file::process_string};
+    /// use purelisp::{Value, create_environment};
+    ///
+    /// let log = Rc::new(RefCell::new(Vec::new()));
+    /// let mut env = create_environment();
+    /// let sink = log.clone();
+    /// env.register_native("log!", 1, move |args| match &args[0] {
+    ///     Value::Str(message) => {
+    ///         sink.borrow_mut().push(message.clone());
+    ///         Ok(Value::Nil)
+    ///     }
+    ///     other => Err(Error::native(format!("cannot log {}", other))),
+    /// });
+    ///
+    /// process_string("(log! \"hello\")", &mut env).unwrap();
+    /// assert_eq!(*log.borrow(), ["hello"]);
+    /// ```
+    pub fn register_native(
+        &mut self,
+        name: &str,
+        arity: usize,
+        func: impl Fn(&[Value]) -> Result<Value, Error> + 'static,
+    ) {
+        let native = NativeFn {
+            name: name.to_string(),
+            arity,
+            func: Rc::new(func),
+        };
+        self.set_global(name, Value::Native(native));
+    }
+
+    /// Define the global `name` as a Rust function or closure, whose arguments and
+    /// result are converted from and to PureLisp values. The function may return an
+    /// `Result` to report errors.
+    ///
+    /// ```
+    /// use purelisp::intpt::{Error, file::process_string};
+    /// use purelisp::{Value, create_environment};
+    ///
+    /// let mut env = create_environment();
+    /// env.register_fn("hypot", |x: f64, y: f64| x.hypot(y));
+    /// env.register_fn("repeat", |s: String, n: i64| {
+    ///     if n < 0 {
+    ///         return Err(Error::native("negative count"));
+    ///     }
+    ///     Ok(s.repeat(n as usize))
+    /// });
+    ///
+    /// let results = process_string("(hypot 3 4.0)\n(repeat \"ab\" 3)", &mut env).unwrap();
+    /// assert_eq!(results, [Value::Float(5.0), Value::Str("ababab".to_string())]);
+    /// ```
+    pub fn register_fn<Args, F: NativeFunction<Args>>(&mut self, name: &str, func: F) {
+        let native = NativeFn {
+            name: name.to_string(),
+            arity: F::ARITY,
+            func: func.into_impl(),
+        };
+        self.set_global(name, Value::Native(native));
+    }
+}
+
+impl Default for Env {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 pub fn create_environment() -> Env {
     let mut env = Env::new();
     load_prelude(&mut env);
+    crate::stdlib::load(&mut env);
     env
 }
 
@@ -0,0 +1,175 @@
+use std::fmt;
+use std::rc::Rc;
+
+use crate::ast::Value;
+use crate::convert::{FromValue, IntoValue};
+use crate::intpt::Error;
+
+/// Implementation of a native function, called with exactly `arity` arguments
+pub type NativeImpl = dyn Fn(&[Value]) -> Result<Value, Error>;
+
+/// A function implemented in Rust by the host application. Unlike the builtins, it
+/// may capture state (a database handle, a logger, ...).
+#[derive(Clone)]
+pub struct NativeFn {
+    pub name: String,
+    pub arity: usize,
+    pub func: Rc<NativeImpl>,
+}
+
+impl NativeFn {
+    /// Call the function, checking the number of arguments first
+    pub fn call(&self, args: &[Value]) -> Result<Value, Error> {
+        if args.len() != self.arity {
+            return Err(Error::Arity {
+                name: self.name.clone(),
+                expected: self.arity,
+                found: args.len(),
+            });
+        }
+        (self.func)(args)
+    }
+}
+
+impl fmt::Debug for NativeFn {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "NativeFn({}/{})", self.name, self.arity)
+    }
+}
+
+// Native functions compare by identity
+impl PartialEq for NativeFn {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.func, &other.func)
+    }
+}
+
+/// The result of a Rust function registered with `Env::register_fn`: a value, or a
+/// `Result` holding a value or an `Error`
+pub trait NativeResult {
+    fn into_result(self) -> Result<Value, Error>;
+}
+
+impl<T: IntoValue> NativeResult for T {
+    fn into_result(self) -> Result<Value, Error> {
+        Ok(self.into_value())
+    }
+}
+
+impl<T: IntoValue> NativeResult for Result<T, Error> {
+    fn into_result(self) -> Result<Value, Error> {
+        self.map(IntoValue::into_value)
+    }
+}
+
+/// A Rust function or closure taking arguments of types `Args` (a tuple) that can be
+/// converted from PureLisp values
+pub trait NativeFunction<Args> {
+    const ARITY: usize;
+
+    fn into_impl(self) -> Rc<NativeImpl>;
+}
+
+macro_rules! one {
+    ($arg:ident) => {
+        1
+    };
+}
+
+macro_rules! impl_native_function {
+    ($($arg:ident),*) => {
+        impl<F, R, $($arg),*> NativeFunction<($($arg,)*)> for F
+        where
+            F: Fn($($arg),*) -> R + 'static,
+            R: NativeResult,
+            $($arg: FromValue,)*
+        {
+            const ARITY: usize = 0 $(+ one!($arg))*;
+
+            #[allow(non_snake_case, unused_mut, unused_variables)]
+            fn into_impl(self) -> Rc<NativeImpl> {
+                Rc::new(move |args: &[Value]| {
+                    let mut args = args.iter().cloned();
+                    $(let $arg = $arg::from_value(args.next().unwrap())?;)*
+                    self($($arg),*).into_result()
+                })
+            }
+        }
+    };
+}
+
+impl_native_function!();
+impl_native_function!(A);
+impl_native_function!(A, B);
+impl_native_function!(A, B, C);
+impl_native_function!(A, B, C, D);
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+    use std::panic::{AssertUnwindSafe, catch_unwind};
+
+    use super::*;
+    use crate::intpt::{create_environment, file::process_string};
+
+    #[test]
+    fn test_native_state_and_higher_order() {
+        let counter = Rc::new(Cell::new(0));
+        let mut env = create_environment();
+        let count = counter.clone();
+        env.register_native("tick", 0, move |_| {
+            count.set(count.get() + 1);
+            Ok(Value::Int(count.get()))
+        });
+        env.register_fn("add3", |x: i64, y: i64, z: i64| x + y + z);
+        let results = process_string(
+            "(tick)\n\
+             (defun twice (f x) (f (f x)))\n\
+             (twice (fn (x) (add3 x x 1)) 1)\n\
+             (let ((g add3)) (g 1 2 (tick)))",
+            &mut env,
+        )
+        .unwrap();
+        assert_eq!(results[0], Value::Int(1));
+        assert_eq!(results[2], Value::Int(7));
+        assert_eq!(results[3], Value::Int(5));
+        assert_eq!(counter.get(), 2);
+    }
+
+    #[test]
+    fn test_native_errors() {
+        let mut env = create_environment();
+        env.register_fn("inc", |x: i64| x + 1);
+        let native = match env.get("inc") {
+            Some(Value::Native(native)) => native.clone(),
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(
+            native.call(&[]),
+            Err(Error::Arity {
+                name: "inc".to_string(),
+                expected: 1,
+                found: 0
+            })
+        );
+        assert_eq!(
+            native.call(&[Value::Bool(true)]),
+            Err(Error::Type {
+                expected: "int",
+                found: "bool"
+            })
+        );
+
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            process_string("(inc 1.5)", &mut env.clone())
+        }));
+        let error = *result.unwrap_err().downcast::<Error>().unwrap();
+        assert_eq!(
+            error,
+            Error::Type {
+                expected: "int",
+                found: "float"
+            }
+        );
+    }
+}
@@ -1,9 +1,9 @@
-mod basics;
-mod math;
-
+use crate::ast::Value;
+use crate::builtins::builtins;
 use crate::intpt::Env;
 
 pub fn load_prelude(env: &mut Env) {
-    basics::load_basics(env);
-    math::load_math(env);
+    for builtin in builtins() {
+        env.set(builtin.name.to_string(), Value::Func(builtin.func));
+    }
 }
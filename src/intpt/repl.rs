@@ -1,6 +1,7 @@
 use rustyline::{DefaultEditor, Result};
 
 use crate::ast::{Expr, Value};
+use crate::expand::Expander;
 use crate::intpt::Env;
 use crate::intpt::eval::eval;
 use crate::parse;
@@ -10,10 +11,8 @@ use std::collections::HashMap;
 pub fn repl(use_history: bool) -> Result<()> {
     let mut rl = DefaultEditor::new()?;
 
-    if use_history {
-        if rl.load_history("history.txt").is_err() {
-            println!("No previous history.");
-        }
+    if use_history && rl.load_history("history.txt").is_err() {
+        println!("No previous history.");
     }
 
     let env = crate::intpt::create_environment();
@@ -25,16 +24,15 @@ pub fn repl(use_history: bool) -> Result<()> {
 pub fn start_repl_with_env(env: Env, use_history: bool) -> Result<()> {
     let mut rl = DefaultEditor::new()?;
 
-    if use_history {
-        if rl.load_history("history.txt").is_err() {
-            println!("No previous history.");
-        }
+    if use_history && rl.load_history("history.txt").is_err() {
+        println!("No previous history.");
     }
 
     start_session(&mut rl, env, use_history)
 }
 
 fn start_session(rl: &mut DefaultEditor, mut env: Env, use_history: bool) -> Result<()> {
+    let mut expander = Expander::new();
     loop {
         let readline = rl.readline("purelisp> ");
         match readline {
@@ -44,7 +42,13 @@ fn start_session(rl: &mut DefaultEditor, mut env: Env, use_history: bool) -> Res
                     rl.save_history("history.txt")?;
                 }
 
-                let expr = parse::parse(&line);
+                let expr = match expander.expand_toplevel(parse::parse_sexp(&line)) {
+                    Some(expr) => expr,
+                    None => {
+                        println!("Macro defined");
+                        continue;
+                    }
+                };
                 // println!("Parsed form: {:?}", expr);
                 // println!("{}", expr);
                 // print!(" -> ");
@@ -53,12 +57,19 @@ fn start_session(rl: &mut DefaultEditor, mut env: Env, use_history: bool) -> Res
                     let value = eval(*y.clone(), env.clone());
                     println!("Evaluation result of {:?}: {:?}", y, value);
                     env.set(x, value);
-                } else if let Expr::Defun { name, args, body } = expr {
+                } else if let Expr::Defun {
+                    name,
+                    args,
+                    body,
+                    sig,
+                } = expr
+                {
                     // Create a closure for the function
                     let closure = Value::Closure {
                         params: args,
                         body: *body,
                         mappings: HashMap::new(),
+                        sig,
                     };
                     // Bind the function name to the closure
                     env.set(name.clone(), closure);
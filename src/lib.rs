@@ -1,10 +1,16 @@
 // PureLisp library interface
 pub mod ast;
+pub mod builtins;
 pub mod compl;
+pub mod convert;
+pub mod expand;
 pub mod formatter;
 pub mod intpt;
+pub mod module;
 pub mod parse;
 pub mod read;
+pub mod stdlib;
+pub mod typeck;
 
 // Re-export the lalrpop module
 use lalrpop_util::lalrpop_mod;
@@ -13,8 +19,10 @@ lalrpop_mod!(pub purelisp);
 // Expose main public API
 pub use ast::{Expr, Value};
 pub use formatter::{PrettyFormatter, format_prog, pretty_format};
-pub use intpt::Env;
 pub use intpt::create_environment;
 pub use intpt::eval::eval;
+pub use intpt::{Env, Interpreter, Limits, Usage};
+pub use module::{Loader, read_program};
 pub use parse::parse;
 pub use read::{read_file, read_string};
+pub use typeck::{CheckReport, TypeError, check_file, check_prog};
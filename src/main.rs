@@ -1,5 +1,5 @@
 use purelisp::read_file;
-use purelisp::{compl, intpt};
+use purelisp::{Loader, compl, format_prog, intpt, typeck};
 
 use std::env;
 use std::fs;
@@ -12,7 +12,11 @@ fn main() {
     let mut next_args = Vec::new();
     let mut is_compile_mode = false;
     let mut is_compile_to_ir = false;
+    let mut is_check_mode = false;
+    let mut is_expand_mode = false;
     let mut output_path = None;
+    let mut options = compl::CompileOptions::default();
+    let mut loader = Loader::new();
 
     // First pass: extract global flags like --history
     let mut i = 1;
@@ -25,6 +29,10 @@ fn main() {
             return;
         } else if arg == "--compile" || arg == "-c" || arg == "compile" {
             is_compile_mode = true;
+        } else if arg == "--check" || arg == "check" {
+            is_check_mode = true;
+        } else if arg == "--expand" || arg == "expand" {
+            is_expand_mode = true;
         } else if arg == "--compile-ir" || arg == "-ir" {
             is_compile_mode = true;
             is_compile_to_ir = true;
@@ -37,6 +45,50 @@ fn main() {
                 print_usage();
                 return;
             }
+        } else if arg == "--inline-threshold" {
+            match args.get(i + 1).and_then(|n| n.parse().ok()) {
+                Some(threshold) => {
+                    options.inline_threshold = threshold;
+                    i += 1;
+                }
+                None => {
+                    println!("Missing or invalid size after --inline-threshold");
+                    print_usage();
+                    return;
+                }
+            }
+        } else if arg == "--passes" || arg.starts_with("--passes=") {
+            match flag_value(&args, &mut i, "--passes") {
+                Some(passes) => options.passes = Some(split_names(&passes)),
+                None => {
+                    println!("Missing pass list after --passes");
+                    print_usage();
+                    return;
+                }
+            }
+        } else if arg == "--dump-after" || arg.starts_with("--dump-after=") {
+            match flag_value(&args, &mut i, "--dump-after") {
+                Some(passes) => options.dump_after.extend(split_names(&passes)),
+                None => {
+                    println!("Missing pass name after --dump-after");
+                    print_usage();
+                    return;
+                }
+            }
+        } else if arg == "-I" || arg == "--path" {
+            match args.get(i + 1) {
+                Some(dir) => {
+                    loader.add_search_path(dir);
+                    i += 1;
+                }
+                None => {
+                    println!("Missing directory after -I/--path");
+                    print_usage();
+                    return;
+                }
+            }
+        } else if arg == "--verify" {
+            options.verify = true;
         } else {
             next_args.push(arg);
         }
@@ -44,7 +96,38 @@ fn main() {
     }
 
     if !next_args.is_empty() {
-        if is_compile_mode {
+        if is_expand_mode {
+            // Expand mode - print the program with its macros expanded
+            let source_path = &next_args[0];
+            match read_file(source_path) {
+                Ok(prog) => println!("{}", format_prog(&prog)),
+                Err(e) => println!("Error reading file: {}", e),
+            }
+        } else if is_check_mode {
+            // Check mode - type check the source file without running it
+            let source_path = &next_args[0];
+            let path = Path::new(source_path);
+
+            if path.exists() {
+                match typeck::check_file(path) {
+                    Ok(report) => {
+                        for (name, scheme) in &report.signatures {
+                            println!("{} : {}", name, scheme);
+                        }
+                        for error in &report.errors {
+                            println!("Type error at {}", error);
+                        }
+                        if !report.is_ok() {
+                            println!("{} type error(s) found", report.errors.len());
+                            std::process::exit(1);
+                        }
+                    }
+                    Err(e) => println!("Error reading file: {}", e),
+                }
+            } else {
+                println!("Source file not found: {}", source_path);
+            }
+        } else if is_compile_mode {
             // Compile mode - compile the source file to binary or C code
             let source_path = &next_args[0];
             let path = Path::new(source_path);
@@ -64,7 +147,7 @@ fn main() {
                     }
                 };
 
-                match compile_file(path, &out_path, is_compile_to_ir) {
+                match compile_file(path, &out_path, is_compile_to_ir, &options, &mut loader) {
                     Ok(()) => println!(
                         "Successfully compiled {} to {}",
                         source_path,
@@ -83,7 +166,7 @@ fn main() {
 
                 if path.exists() {
                     let mut env = intpt::create_environment();
-                    match intpt::file::process_file(path, &mut env) {
+                    match intpt::file::process_file_with(path, &mut env, &mut loader) {
                         Ok(_) => {
                             println!("File loaded successfully.");
                             // Start REPL with the existing environment
@@ -115,7 +198,7 @@ fn main() {
 
             if path.exists() {
                 let mut env = intpt::create_environment();
-                match intpt::file::process_file(path, &mut env) {
+                match intpt::file::process_file_with(path, &mut env, &mut loader) {
                     Ok(res) => {
                         println!("File processed successfully with result: ");
                         for value in res {
@@ -146,6 +229,9 @@ Usage:
   purelisp FILE                                     Execute FILE
   purelisp [--history] -l|--load FILE               Execute FILE then start the REPL
   purelisp compile [-ir] FILE [-o OUTPUT]           Compile FILE to C-code (default) or PureLisp IR
+  purelisp compile FILE.plir [-o OUTPUT]            Compile PureLisp IR to C-code (no passes are run)
+  purelisp check FILE                               Infer types of FILE and report type errors
+  purelisp expand FILE                              Print FILE with its macros expanded
 
 Options:
   -h, --help                Show this help message
@@ -153,22 +239,62 @@ Options:
   -l, --load                Load and execute a file before starting the REPL
   -ir                       Compile a file to PureLisp IR (default is C-code)
   -o, --output FILE         Specify output file for compilation (default is INPUT.plir/c)
+  --inline-threshold N      Inline functions whose body has at most N nodes (default 30, 0 disables)
+  --passes P1,P2,...        Run only the given compiler passes, in order
+                            (knormal, anormal, inline, copyprop, constfold, cse, closure, dce)
+  --dump-after P1,P2,...    Print the IR after each of the given passes
+  --verify                  Check the IR invariants after every pass (always done in debug builds)
+  -I, --path DIR            Also look for imported modules in DIR
 "##);
 }
 
+/// Value of a flag given either as `--flag=value` or as `--flag value`
+fn flag_value(args: &[String], i: &mut usize, flag: &str) -> Option<String> {
+    if let Some(value) = args[*i].strip_prefix(&format!("{}=", flag)) {
+        return Some(value.to_string());
+    }
+    *i += 1;
+    args.get(*i).cloned()
+}
+
+fn split_names(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
 /// Compiles a source file to a binary file
 fn compile_file<P: AsRef<Path>, Q: AsRef<Path>>(
     input_path: P,
     output_path: Q,
     is_compile_to_ir: bool,
+    options: &compl::CompileOptions,
+    loader: &mut Loader,
 ) -> io::Result<()> {
-    let prog = read_file(input_path)?;
-    // Compile the file
-    let compiled_code = if is_compile_to_ir {
-        compl::compl_to_ir(prog)
+    let is_ir_input = input_path
+        .as_ref()
+        .extension()
+        .is_some_and(|ext| ext == "plir");
+    // Imported modules are linked into the program, which compiles to a single C file
+    let prog = if is_ir_input {
+        read_file(input_path)?
     } else {
-        compl::compl_to_c(prog)
+        loader.load_file(input_path)?
     };
+    // Compile the file
+    let compiled_code = if is_ir_input {
+        // IR files are already closure-converted: only generate code
+        if is_compile_to_ir {
+            return Err(io::Error::other("Input file is already PureLisp IR"));
+        }
+        compl::compl_ir_to_c(prog)
+    } else if is_compile_to_ir {
+        compl::compl_to_ir_with(prog, options)
+    } else {
+        compl::compl_to_c_with(prog, options)
+    }
+    .map_err(io::Error::other)?;
     // Write the compiled code to the output file
     let mut file = fs::File::create(output_path)?;
     file.write_all(compiled_code.as_bytes())?;
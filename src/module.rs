@@ -0,0 +1,678 @@
+//! Modules. A file may declare itself a module with `(module name (export f g ...))`
+//! as its first form (without an export list, every global is exported), and import
+//! other modules:
+//!
+//! - `(import "lib/util.purelisp")` imports every export of a file, found relative to
+//!   the importing file or in the search paths;
+//! - `(import util (f g))` imports the given exports of the module in `util.purelisp`,
+//!   and `(import util)` all of them.
+//!
+//! Imported modules are linked into the importing program, dependencies first: the
+//! globals of module `util` are renamed to `util/f`, so that every module has its own
+//! namespace and the program can be interpreted or compiled as a single file.
+//! Exported globals can also be referred to by their qualified name. The top-level
+//! expressions of a module are only evaluated when it is the main program.
+//!
+//! A module may only refer to its own globals, the names it imports, the builtins and
+//! the functions of the standard library. The globals of the main program keep their
+//! names, except those that redefine a builtin or a function of the standard library:
+//! they are qualified too, so that modules still refer to the library.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::ast::Expr;
+use crate::builtins::is_builtin;
+use crate::read::read_string_located;
+
+/// Extension of the file of a module imported by name
+const EXTENSION: &str = "purelisp";
+
+/// Separator between the name of a module and the name of one of its globals
+pub const SEPARATOR: char = '/';
+
+/// Name of a main program that is not read from a file and declares no module
+const MAIN: &str = "main";
+
+/// A module that has been linked
+#[derive(Debug, Clone)]
+struct Module {
+    name: String,
+    path: PathBuf,
+    /// Exported globals, by their name in the module
+    exports: HashSet<String>,
+}
+
+impl Module {
+    fn qualify(&self, name: &str) -> String {
+        format!("{}{}{}", self.name, SEPARATOR, name)
+    }
+}
+
+/// Where to find an imported module
+enum Source {
+    Path(String),
+    Name(String),
+}
+
+struct Import {
+    source: Source,
+    /// Imported names; every export if `None`
+    names: Option<Vec<String>>,
+}
+
+/// Reads programs and links the modules they import
+#[derive(Debug, Clone, Default)]
+pub struct Loader {
+    search_paths: Vec<PathBuf>,
+    /// Modules already linked, by canonical path
+    modules: HashMap<PathBuf, Module>,
+    /// Files being linked, importers first, to detect import cycles
+    loading: Vec<PathBuf>,
+    /// Definitions of the modules linked by the current import
+    linked: Vec<Expr>,
+    /// Globals of the main programs that redefine a builtin or a function of the
+    /// standard library, with the qualified name they are linked as
+    shadowing: HashMap<String, String>,
+}
+
+impl Loader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Look for imported modules in `dir` too, after the directory of the importing file
+    pub fn add_search_path(&mut self, dir: impl Into<PathBuf>) {
+        self.search_paths.push(dir.into());
+    }
+
+    /// Read the program in a file, preceded by the definitions of the modules it imports
+    pub fn load_file<P: AsRef<Path>>(&mut self, path: P) -> io::Result<Vec<Expr>> {
+        let located = self.load_file_located(path)?;
+        Ok(located.into_iter().map(|(_, expr)| expr).collect())
+    }
+
+    /// Like `load_file`, but pairs every expression with the line it starts on; the
+    /// definitions of a module are located at the import that loaded it
+    pub fn load_file_located<P: AsRef<Path>>(&mut self, path: P) -> io::Result<Vec<(usize, Expr)>> {
+        let path = path.as_ref();
+        let source = fs::read_to_string(path)?;
+        self.link(read_string_located(&source)?, Some(path))
+    }
+
+    /// The name the global `name` of the main programs is linked as
+    pub fn linked_name<'a>(&'a self, name: &'a str) -> &'a str {
+        self.shadowing.get(name).map_or(name, String::as_str)
+    }
+
+    /// Link the program `exprs` read from `file` (imports are relative to the current
+    /// directory if it has no file). Modules already linked by this loader are not
+    /// linked again.
+    pub fn link(
+        &mut self,
+        exprs: Vec<(usize, Expr)>,
+        file: Option<&Path>,
+    ) -> io::Result<Vec<(usize, Expr)>> {
+        let file = file.map(fs::canonicalize).transpose()?;
+        if let Some(file) = &file {
+            self.loading.push(file.clone());
+        }
+        let result = self.link_unit(exprs, file.as_deref(), true);
+        if file.is_some() {
+            self.loading.pop();
+        }
+        Ok(result?.1)
+    }
+
+    /// Link the forms of a file (given by its canonical path) or of the main program
+    fn link_unit(
+        &mut self,
+        exprs: Vec<(usize, Expr)>,
+        file: Option<&Path>,
+        is_main: bool,
+    ) -> io::Result<(Module, Vec<(usize, Expr)>)> {
+        let dir = file.and_then(Path::parent).unwrap_or(Path::new("."));
+        let mut declaration = None;
+        let mut body = Vec::new();
+        let mut output = Vec::new();
+        // Names in scope in the unit, and modules whose qualified names may be used
+        let mut scope: HashMap<String, String> = HashMap::new();
+        let mut imported: HashMap<String, Module> = HashMap::new();
+
+        for (i, (line, expr)) in exprs.into_iter().enumerate() {
+            if let Some(decl) = parse_module(&expr)? {
+                if i > 0 {
+                    return Err(invalid("The module declaration must be the first form"));
+                }
+                declaration = Some(decl);
+            } else if let Some(import) = parse_import(&expr)? {
+                let module = self.import(&import.source, dir)?;
+                output.extend(self.linked.drain(..).map(|expr| (line, expr)));
+                let names = match import.names {
+                    Some(names) => names,
+                    None => {
+                        let mut names: Vec<_> = module.exports.iter().cloned().collect();
+                        names.sort();
+                        names
+                    }
+                };
+                for name in names {
+                    if !module.exports.contains(&name) {
+                        return Err(invalid(&format!(
+                            "{} is not exported by {}",
+                            name, module.name
+                        )));
+                    }
+                    let qualified = module.qualify(&name);
+                    match scope.insert(name.clone(), qualified.clone()) {
+                        Some(other) if other != qualified => {
+                            return Err(invalid(&format!(
+                                "{} is imported as both {} and {}",
+                                name, other, qualified
+                            )));
+                        }
+                        _ => {}
+                    }
+                }
+                imported.insert(module.name.clone(), module);
+            } else {
+                body.push((line, expr));
+            }
+        }
+
+        let globals: Vec<String> = body
+            .iter()
+            .filter_map(|(_, expr)| match expr {
+                Expr::Def { x, .. } => Some(x.clone()),
+                Expr::Defun { name, .. } => Some(name.clone()),
+                _ => None,
+            })
+            .collect();
+        let (name, exports) = match declaration {
+            Some((name, exports)) => (name, exports),
+            None => match file.and_then(Path::file_stem) {
+                Some(stem) => (stem.to_string_lossy().into_owned(), None),
+                None => (MAIN.to_string(), None),
+            },
+        };
+        let exports = exports.unwrap_or_else(|| globals.clone());
+        for export in &exports {
+            if !globals.contains(export) {
+                return Err(invalid(&format!(
+                    "{} exports {}, which it does not define",
+                    name, export
+                )));
+            }
+        }
+        let module = Module {
+            name,
+            path: file.map(Path::to_path_buf).unwrap_or_default(),
+            exports: exports.into_iter().collect(),
+        };
+
+        // The globals of a module are qualified, those of the main program are not
+        // unless they redefine the library, and they shadow the imported names
+        for global in globals {
+            let linked = if !is_main {
+                module.qualify(&global)
+            } else if is_library(&global) {
+                let linked = module.qualify(&global);
+                self.shadowing.insert(global.clone(), linked.clone());
+                linked
+            } else {
+                global.clone()
+            };
+            scope.insert(global, linked);
+        }
+        let resolve = |id: &str| -> io::Result<Option<String>> {
+            if let Some(linked) = scope.get(id) {
+                return Ok(Some(linked.clone()));
+            }
+            // A qualified name must refer to an export
+            if let Some((name, global)) = id.split_once(SEPARATOR)
+                && let Some(module) = imported.get(name)
+            {
+                if !module.exports.contains(global) {
+                    return Err(invalid(&format!(
+                        "{} is not exported by {}",
+                        global, module.name
+                    )));
+                }
+                return Ok(None);
+            }
+            // The main program may refer to the globals it defined before, or that the
+            // environment defines; a module only to the library
+            if is_main {
+                Ok(self.shadowing.get(id).cloned())
+            } else if is_library(id) {
+                Ok(None)
+            } else {
+                Err(invalid(&format!(
+                    "{} refers to {}, which it does not define or import",
+                    module.name, id
+                )))
+            }
+        };
+
+        for (line, expr) in body {
+            let expr = match expr {
+                Expr::Def { x, y } => Expr::Def {
+                    x: scope[&x].clone(),
+                    y: Box::new(rename(*y, &mut Vec::new(), &resolve)?),
+                },
+                Expr::Defun {
+                    name,
+                    args,
+                    body,
+                    sig,
+                } => {
+                    let body = rename(*body, &mut args.clone(), &resolve)?;
+                    Expr::Defun {
+                        name: scope[&name].clone(),
+                        args,
+                        body: Box::new(body),
+                        sig,
+                    }
+                }
+                _ if !is_main => continue,
+                expr => rename(expr, &mut Vec::new(), &resolve)?,
+            };
+            output.push((line, expr));
+        }
+        Ok((module, output))
+    }
+
+    /// Link the module imported from `source` unless it already is, and return it
+    fn import(&mut self, source: &Source, dir: &Path) -> io::Result<Module> {
+        let file = match source {
+            Source::Path(path) => PathBuf::from(path),
+            Source::Name(name) => PathBuf::from(format!("{}.{}", name, EXTENSION)),
+        };
+        let path = std::iter::once(dir)
+            .chain(self.search_paths.iter().map(PathBuf::as_path))
+            .map(|dir| dir.join(&file))
+            .find(|path| path.is_file())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("Module {} not found", file.display()),
+                )
+            })?;
+        let path = fs::canonicalize(path)?;
+
+        if let Some(module) = self.modules.get(&path) {
+            return Ok(module.clone());
+        }
+        if let Some(start) = self.loading.iter().position(|file| *file == path) {
+            let cycle: Vec<String> = self.loading[start..]
+                .iter()
+                .chain([&path])
+                .map(|file| file.display().to_string())
+                .collect();
+            return Err(invalid(&format!("Import cycle: {}", cycle.join(" -> "))));
+        }
+
+        let in_module =
+            |err: io::Error| io::Error::new(err.kind(), format!("{}: {}", path.display(), err));
+        let source = fs::read_to_string(&path).map_err(in_module)?;
+        let exprs = read_string_located(&source).map_err(in_module)?;
+        self.loading.push(path.clone());
+        let result = self.link_unit(exprs, Some(&path), false);
+        self.loading.pop();
+        let (module, defs) = result.map_err(in_module)?;
+
+        if let Some(other) = self
+            .modules
+            .values()
+            .find(|other| other.name == module.name)
+        {
+            return Err(invalid(&format!(
+                "Module {} is defined by both {} and {}",
+                module.name,
+                other.path.display(),
+                path.display()
+            )));
+        }
+        self.linked.extend(defs.into_iter().map(|(_, expr)| expr));
+        self.modules.insert(path, module.clone());
+        Ok(module)
+    }
+}
+
+/// Read the program in a file with the modules it imports, found relative to it
+pub fn read_program<P: AsRef<Path>>(path: P) -> io::Result<Vec<Expr>> {
+    Loader::new().load_file(path)
+}
+
+/// Whether `name` is a builtin or a function of the standard library
+fn is_library(name: &str) -> bool {
+    is_builtin(name) || crate::stdlib::defines(name)
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn parse_names(expr: &Expr, what: &str) -> io::Result<Vec<String>> {
+    let invalid_names = || invalid(&format!("The {} must be a list of identifiers", what));
+    match expr {
+        Expr::Form(exprs) => exprs
+            .iter()
+            .map(|expr| match expr {
+                Expr::Id(id) => Ok(id.clone()),
+                _ => Err(invalid_names()),
+            })
+            .collect(),
+        _ => Err(invalid_names()),
+    }
+}
+
+/// The name and exports of `(module name (export f g ...))`
+fn parse_module(expr: &Expr) -> io::Result<Option<(String, Option<Vec<String>>)>> {
+    let form = match expr {
+        Expr::Form(form) if form.first() == Some(&Expr::Id("module".to_string())) => form,
+        _ => return Ok(None),
+    };
+    match &form[1..] {
+        [Expr::Id(name)] => Ok(Some((name.clone(), None))),
+        [Expr::Id(name), Expr::Form(export)]
+            if export.first() == Some(&Expr::Id("export".to_string())) =>
+        {
+            let exports = parse_names(&Expr::Form(export[1..].to_vec()), "exports")?;
+            Ok(Some((name.clone(), Some(exports))))
+        }
+        _ => Err(invalid(
+            "A module declaration must have the form (module name (export names...))",
+        )),
+    }
+}
+
+/// `(import "path" names)` or `(import name names)`, the names being optional
+fn parse_import(expr: &Expr) -> io::Result<Option<Import>> {
+    let form = match expr {
+        Expr::Form(form) if form.first() == Some(&Expr::Id("import".to_string())) => form,
+        _ => return Ok(None),
+    };
+    let source = match form.get(1) {
+        Some(Expr::Str(path)) => Source::Path(path.clone()),
+        Some(Expr::Id(name)) => Source::Name(name.clone()),
+        _ => {
+            return Err(invalid(
+                "An import must have the form (import \"path\") or (import name (names...))",
+            ));
+        }
+    };
+    let names = match &form[2..] {
+        [] => None,
+        [names] => Some(parse_names(names, "imported names")?),
+        _ => return Err(invalid("An import takes at most one list of names")),
+    };
+    Ok(Some(Import { source, names }))
+}
+
+/// Rename the free identifiers of `expr` that are not in `bound` with `resolve`
+/// (`None` keeps an identifier)
+fn rename(
+    expr: Expr,
+    bound: &mut Vec<String>,
+    resolve: &impl Fn(&str) -> io::Result<Option<String>>,
+) -> io::Result<Expr> {
+    let rename_in = |expr: Expr, names: &[String], bound: &mut Vec<String>| {
+        let len = bound.len();
+        bound.extend(names.iter().cloned());
+        let expr = rename(expr, bound, resolve);
+        bound.truncate(len);
+        expr.map(Box::new)
+    };
+    let rename_all = |exprs: Vec<Expr>, bound: &mut Vec<String>| {
+        exprs
+            .into_iter()
+            .map(|expr| rename(expr, bound, resolve))
+            .collect::<io::Result<Vec<_>>>()
+    };
+    Ok(match expr {
+        Expr::Id(id) if !bound.contains(&id) => Expr::Id(resolve(&id)?.unwrap_or(id)),
+        Expr::Nil | Expr::Bool(_) | Expr::Int(_) | Expr::Float(_) | Expr::Str(_) | Expr::Id(_) => {
+            expr
+        }
+        Expr::Form(exprs) => Expr::Form(rename_all(exprs, bound)?),
+        Expr::And(exprs) => Expr::And(rename_all(exprs, bound)?),
+        Expr::Or(exprs) => Expr::Or(rename_all(exprs, bound)?),
+        Expr::Not(expr) => Expr::Not(rename_in(*expr, &[], bound)?),
+        Expr::If { cond, then, else_ } => Expr::If {
+            cond: rename_in(*cond, &[], bound)?,
+            then: rename_in(*then, &[], bound)?,
+            else_: rename_in(*else_, &[], bound)?,
+        },
+        // The bindings are evaluated in the enclosing scope
+        Expr::Let { bindings, body } => {
+            let names: Vec<String> = bindings.iter().map(|(x, _)| x.clone()).collect();
+            let bindings = bindings
+                .into_iter()
+                .map(|(x, expr)| Ok((x, rename(expr, bound, resolve)?)))
+                .collect::<io::Result<_>>()?;
+            Expr::Let {
+                bindings,
+                body: rename_in(*body, &names, bound)?,
+            }
+        }
+        Expr::Fn { args, body } => {
+            let body = rename_in(*body, &args, bound)?;
+            Expr::Fn { args, body }
+        }
+        Expr::LetFun {
+            name,
+            args,
+            fun_body,
+            expr_body,
+        } => {
+            let mut fun_bound = args.clone();
+            fun_bound.push(name.clone());
+            Expr::LetFun {
+                fun_body: rename_in(*fun_body, &fun_bound, bound)?,
+                expr_body: rename_in(*expr_body, std::slice::from_ref(&name), bound)?,
+                name,
+                args,
+            }
+        }
+        // Definitions are only allowed at top level, and closure-converted forms don't
+        // occur in source programs
+        Expr::Def { .. } | Expr::Defun { .. } | Expr::DefClos { .. } | Expr::LetClos { .. } => expr,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Write `files` in a fresh temporary directory and return it
+    fn write_files(test: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("purelisp-{}-{}", std::process::id(), test));
+        let _ = fs::remove_dir_all(&dir);
+        for (file, content) in files {
+            let path = dir.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+        dir
+    }
+
+    fn load(dir: &Path, file: &str) -> io::Result<Vec<String>> {
+        let exprs = Loader::new().load_file(dir.join(file))?;
+        Ok(exprs.iter().map(Expr::to_string).collect())
+    }
+
+    fn load_error(dir: &Path, file: &str) -> String {
+        load(dir, file).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn test_module_namespaces() {
+        let dir = write_files(
+            "namespaces",
+            &[
+                (
+                    "util.purelisp",
+                    "(module util (export inc))\n(defun inc (x) (+ x one))\n(def one 1)\n(inc 0)",
+                ),
+                (
+                    "main.purelisp",
+                    "(import util)\n(def one 2)\n(inc one)\n(util/inc 1)\n(let ((inc 3)) inc)",
+                ),
+            ],
+        );
+        assert_eq!(
+            load(&dir, "main.purelisp").unwrap(),
+            [
+                "(defun util/inc (x) (+ x util/one))",
+                "(def util/one 1)",
+                "(def one 2)",
+                "(util/inc one)",
+                "(util/inc 1)",
+                "(let ((inc 3)) inc)",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_module_library() {
+        let dir = write_files(
+            "library",
+            &[
+                (
+                    "lib.purelisp",
+                    "(module lib (export clamp))\n(defun clamp (lo hi x) (max lo (min hi x)))",
+                ),
+                (
+                    "main.purelisp",
+                    "(import lib)\n(defun max (a b) 999)\n(clamp 0 10 42)\n(max 1 2)",
+                ),
+            ],
+        );
+        // The redefinition of a builtin is qualified, so that modules still refer to
+        // the builtin, and so are the later references of the main programs to it
+        let mut loader = Loader::new();
+        let exprs = loader.load_file(dir.join("main.purelisp")).unwrap();
+        assert_eq!(
+            exprs.iter().map(Expr::to_string).collect::<Vec<_>>(),
+            [
+                "(defun lib/clamp (lo hi x) (max lo (min hi x)))",
+                "(defun main/max (a b) 999)",
+                "(lib/clamp 0 10 42)",
+                "(main/max 1 2)",
+            ]
+        );
+        let exprs = loader
+            .link(read_string_located("(max 3 4)").unwrap(), None)
+            .unwrap();
+        assert_eq!(exprs[0].1.to_string(), "(main/max 3 4)");
+        assert_eq!(loader.linked_name("max"), "main/max");
+    }
+
+    #[test]
+    fn test_module_linked_once() {
+        let dir = write_files(
+            "diamond",
+            &[
+                ("base.purelisp", "(def x 1)"),
+                ("left.purelisp", "(import base)\n(def l x)"),
+                ("right.purelisp", "(import base)\n(def r x)"),
+                ("main.purelisp", "(import left)\n(import right)\n(+ l r)"),
+            ],
+        );
+        assert_eq!(
+            load(&dir, "main.purelisp").unwrap(),
+            [
+                "(def base/x 1)",
+                "(def left/l base/x)",
+                "(def right/r base/x)",
+                "(+ left/l right/r)",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_module_search_paths() {
+        let dir = write_files(
+            "search",
+            &[
+                ("lib/util.purelisp", "(def x 1)"),
+                ("src/main.purelisp", "(import util)\nx"),
+            ],
+        );
+        assert!(load_error(&dir, "src/main.purelisp").contains("Module util.purelisp not found"));
+        let mut loader = Loader::new();
+        loader.add_search_path(dir.join("lib"));
+        let exprs = loader.load_file(dir.join("src/main.purelisp")).unwrap();
+        assert_eq!(exprs.last().unwrap().to_string(), "util/x");
+    }
+
+    #[test]
+    fn test_module_errors() {
+        let dir = write_files(
+            "errors",
+            &[
+                (
+                    "m.purelisp",
+                    "(module m (export f))\n(defun f () (g))\n(defun g () 1)",
+                ),
+                ("n.purelisp", "(module n)\n(def f 2)"),
+                ("a.purelisp", "(import b)\n(def a 1)"),
+                ("b.purelisp", "(import a)\n(def b 1)"),
+                ("private.purelisp", "(import m)\n(m/g)"),
+                ("unexported.purelisp", "(import m (g))"),
+                ("conflict.purelisp", "(import m)\n(import n)"),
+                ("cycle.purelisp", "(import a)"),
+                ("late.purelisp", "(def x 1)\n(module late)"),
+                ("undefined.purelisp", "(module undefined (export f))"),
+                ("import-undefined.purelisp", "(import undefined)"),
+                ("free.purelisp", "(module free)\n(defun f (x) (secret x))"),
+                (
+                    "import-free.purelisp",
+                    "(import free)\n(defun secret (x) (* x 7))",
+                ),
+            ],
+        );
+        assert_eq!(
+            load_error(&dir, "private.purelisp"),
+            "g is not exported by m"
+        );
+        assert_eq!(
+            load_error(&dir, "unexported.purelisp"),
+            "g is not exported by m"
+        );
+        assert_eq!(
+            load_error(&dir, "conflict.purelisp"),
+            "f is imported as both m/f and n/f"
+        );
+        let cycle = load_error(&dir, "cycle.purelisp");
+        assert!(cycle.contains("Import cycle: "), "{}", cycle);
+        assert!(cycle.ends_with("a.purelisp"), "{}", cycle);
+        assert_eq!(
+            load_error(&dir, "late.purelisp"),
+            "The module declaration must be the first form"
+        );
+        assert_eq!(
+            load_error(&dir, "undefined.purelisp"),
+            "undefined exports f, which it does not define"
+        );
+        // Errors in an imported module are prefixed with its path
+        let error = load_error(&dir, "import-undefined.purelisp");
+        assert!(
+            error.ends_with("undefined.purelisp: undefined exports f, which it does not define"),
+            "{}",
+            error
+        );
+        // A module only refers to the names it defines or imports, and to the library
+        let error = load_error(&dir, "import-free.purelisp");
+        assert!(
+            error.ends_with(
+                "free.purelisp: free refers to secret, which it does not define or import"
+            ),
+            "{}",
+            error
+        );
+    }
+}
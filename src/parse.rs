@@ -1,7 +1,269 @@
-use crate::ast::Expr;
+use std::collections::HashSet;
+
+use crate::ast::{Expr, Signature, TypeAnn};
+use crate::compl::util::NameGenerator;
 use crate::purelisp;
 
-fn advance_parse(expr: Expr) -> Expr {
+fn parse_type_ann(expr: &Expr) -> TypeAnn {
+    match expr {
+        Expr::Id(name) => {
+            TypeAnn::from_name(name).unwrap_or_else(|| panic!("Unknown type annotation: {}", name))
+        }
+        _ => panic!("Type annotation must be a type name, found {}", expr),
+    }
+}
+
+/// Contents of a string literal, with the quotes removed and escapes resolved
+pub(crate) fn unquote(lit: &str) -> String {
+    let lit = lit.strip_prefix('"').unwrap_or(lit);
+    let lit = lit.strip_suffix('"').unwrap_or(lit);
+    let mut s = String::new();
+    let mut chars = lit.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            s.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => s.push('\n'),
+            Some('t') => s.push('\t'),
+            Some('r') => s.push('\r'),
+            Some('0') => s.push('\0'),
+            Some(c) => s.push(c),
+            None => s.push('\\'),
+        }
+    }
+    s
+}
+
+fn parse_id_list(expr: &Expr, what: &str) -> Vec<String> {
+    match expr {
+        Expr::Form(list) => list
+            .iter()
+            .map(|e| match e {
+                Expr::Id(id) => id.clone(),
+                _ => panic!("Elements of the {} must be identifiers", what),
+            })
+            .collect(),
+        _ => panic!("The {} must be a list of identifiers", what),
+    }
+}
+
+/// Bindings of a `let*` or a named `let`: a list of `(name expr)` pairs
+fn parse_bindings(expr: &Expr, what: &str) -> Vec<(String, Expr)> {
+    match expr {
+        Expr::Form(list) => list
+            .iter()
+            .map(|binding| match binding {
+                Expr::Form(pair) if pair.len() == 2 => match &pair[0] {
+                    Expr::Id(name) => (name.clone(), pair[1].clone()),
+                    _ => panic!("Variables bound by {} must be identifiers", what),
+                },
+                _ => panic!("Bindings of {} must have the form (name expr)", what),
+            })
+            .collect(),
+        _ => panic!("The bindings of {} must be a list", what),
+    }
+}
+
+/// The expression of an `else` clause, or `None` for any other clause
+fn else_clause<'a>(clause: &'a [Expr], what: &str) -> Option<&'a Expr> {
+    match clause {
+        [Expr::Id(id), expr] if id == "else" => Some(expr),
+        [Expr::Id(id), ..] if id == "else" => {
+            panic!("The else clause of {} must have the form (else expr)", what)
+        }
+        _ => None,
+    }
+}
+
+/// Desugar `(cond (test expr) ... (else expr))` into nested ifs. Without an `else`
+/// clause the value is nil when no test holds.
+fn desugar_cond(clauses: &[Expr]) -> Expr {
+    let Some((last, rest)) = clauses.split_last() else {
+        return Expr::Nil;
+    };
+    let clause = match last {
+        Expr::Form(clause) => clause,
+        _ => panic!("Clauses of cond must have the form (test expr)"),
+    };
+    let mut result = match (else_clause(clause, "cond"), clause.as_slice()) {
+        (Some(expr), _) => expr.clone(),
+        (None, [test, expr]) => Expr::If {
+            cond: Box::new(test.clone()),
+            then: Box::new(expr.clone()),
+            else_: Box::new(Expr::Nil),
+        },
+        _ => panic!("Clauses of cond must have the form (test expr)"),
+    };
+    for clause in rest.iter().rev() {
+        match clause {
+            Expr::Form(clause) if else_clause(clause, "cond").is_some() => {
+                panic!("The else clause must be the last clause of cond")
+            }
+            Expr::Form(clause) if clause.len() == 2 => {
+                result = Expr::If {
+                    cond: Box::new(clause[0].clone()),
+                    then: Box::new(clause[1].clone()),
+                    else_: Box::new(result),
+                };
+            }
+            _ => panic!("Clauses of cond must have the form (test expr)"),
+        }
+    }
+    result
+}
+
+/// Names starting with `prefix` that are not free in any of `scope`, so that binding
+/// them around `scope` cannot capture a variable of the program
+fn fresh_names(prefix: &str, scope: &[&Expr]) -> impl Iterator<Item = String> + use<> {
+    let free: HashSet<String> = scope
+        .iter()
+        .flat_map(|expr| expr.free_ids(&HashSet::new()))
+        .collect();
+    let (prefix, mut namer) = (prefix.to_string(), NameGenerator::new());
+    std::iter::repeat_with(move || namer.next(&prefix)).filter(move |name| !free.contains(name))
+}
+
+/// Desugar `(case key ((lit ...) expr) ... (else expr))`: the key is evaluated once
+/// and compared with `=` to the literals of each clause in turn
+fn desugar_case(key: &Expr, clauses: &[Expr]) -> Expr {
+    let scope: Vec<&Expr> = clauses.iter().collect();
+    let name = fresh_names("%case", &scope).next().unwrap();
+    let var = Expr::Id(name.clone());
+    let mut result = Expr::Nil;
+    for (i, clause) in clauses.iter().enumerate().rev() {
+        let clause = match clause {
+            Expr::Form(clause) => clause,
+            _ => panic!("Clauses of case must have the form ((literal ...) expr)"),
+        };
+        if let Some(expr) = else_clause(clause, "case") {
+            if i != clauses.len() - 1 {
+                panic!("The else clause must be the last clause of case");
+            }
+            result = expr.clone();
+            continue;
+        }
+        let (literals, expr) = match clause.as_slice() {
+            [Expr::Form(literals), expr] => (literals, expr),
+            _ => panic!("Clauses of case must have the form ((literal ...) expr)"),
+        };
+        let tests: Vec<Expr> = literals
+            .iter()
+            .map(|literal| match literal {
+                Expr::Nil | Expr::Bool(_) | Expr::Int(_) | Expr::Float(_) | Expr::Str(_) => {
+                    Expr::Form(vec![
+                        Expr::Id("=".to_string()),
+                        var.clone(),
+                        literal.clone(),
+                    ])
+                }
+                _ => panic!("Case clauses can only match literals, found {}", literal),
+            })
+            .collect();
+        result = Expr::If {
+            cond: Box::new(Expr::Or(tests)),
+            then: Box::new(expr.clone()),
+            else_: Box::new(result),
+        };
+    }
+    Expr::Let {
+        bindings: vec![(name, key.clone())],
+        body: Box::new(result),
+    }
+}
+
+/// Desugar `(let-values (((var ...) expr) ...) body)`: each expression is bound to a
+/// temporary, whose values are then bound to the variables with `values-ref`. Like
+/// `let`, the bindings are parallel.
+fn desugar_let_values(bindings: &Expr, body: Expr) -> Expr {
+    let bindings = match bindings {
+        Expr::Form(bindings) => bindings,
+        _ => panic!("The bindings of let-values must be a list"),
+    };
+    let mut fresh = fresh_names("%values", &[&body]);
+    let mut temps = Vec::new();
+    let mut vars = Vec::new();
+    for binding in bindings {
+        let (names, expr) = match binding {
+            Expr::Form(pair) if pair.len() == 2 => (&pair[0], &pair[1]),
+            _ => panic!("Bindings of let-values must have the form ((name ...) expr)"),
+        };
+        let names = parse_id_list(names, "let-values variables");
+        let temp = fresh.next().unwrap();
+        for (j, name) in names.iter().enumerate() {
+            let value_ref = Expr::Form(vec![
+                Expr::Id("values-ref".to_string()),
+                Expr::Id(temp.clone()),
+                Expr::Int(names.len() as i64),
+                Expr::Int(j as i64),
+            ]);
+            vars.push((name.clone(), value_ref));
+        }
+        temps.push((temp, expr.clone()));
+    }
+    Expr::Let {
+        bindings: temps,
+        body: Box::new(Expr::Let {
+            bindings: vars,
+            body: Box::new(body),
+        }),
+    }
+}
+
+/// A loop `name` over `vars`: the function `name` with the body `body`, called once
+/// on `inits`
+fn loop_fun(name: &str, vars: Vec<String>, inits: Vec<Expr>, body: Expr) -> Expr {
+    let call = std::iter::once(Expr::Id(name.to_string())).chain(inits);
+    Expr::LetFun {
+        name: name.to_string(),
+        args: vars,
+        fun_body: Box::new(body),
+        expr_body: Box::new(Expr::Form(call.collect())),
+    }
+}
+
+/// Desugar `(do ((var init step) ...) (test result))` into a loop that stops with the
+/// value of `result` once `test` holds, and otherwise starts again with each variable
+/// bound to the value of its step. A variable without a step keeps its value.
+fn desugar_do(specs: &Expr, exit: &Expr) -> Expr {
+    let specs = match specs {
+        Expr::Form(specs) => specs,
+        _ => panic!("The variables of do must be a list"),
+    };
+    // The variables, their initial values and steps, and the exit clause are all in
+    // the scope of the loop
+    let scope: Vec<&Expr> = specs.iter().chain([exit]).collect();
+    let name = fresh_names("%do", &scope).next().unwrap();
+    let (mut vars, mut inits, mut steps) = (Vec::new(), Vec::new(), Vec::new());
+    for spec in specs {
+        match spec {
+            Expr::Form(spec) if spec.len() == 2 || spec.len() == 3 => match &spec[0] {
+                Expr::Id(var) => {
+                    vars.push(var.clone());
+                    inits.push(spec[1].clone());
+                    steps.push(spec.get(2).cloned().unwrap_or_else(|| spec[0].clone()));
+                }
+                _ => panic!("Variables bound by do must be identifiers"),
+            },
+            _ => panic!("Variables of do must have the form (name init step)"),
+        }
+    }
+    let (test, result) = match exit {
+        Expr::Form(exit) if exit.len() == 2 => (exit[0].clone(), exit[1].clone()),
+        _ => panic!("The exit clause of do must have the form (test expr)"),
+    };
+    let next = std::iter::once(Expr::Id(name.clone())).chain(steps);
+    let body = Expr::If {
+        cond: Box::new(test),
+        then: Box::new(result),
+        else_: Box::new(Expr::Form(next.collect())),
+    };
+    loop_fun(&name, vars, inits, body)
+}
+
+/// Recognize the special forms of an S-expression
+pub(crate) fn advance_parse(expr: Expr) -> Expr {
     match expr {
         Expr::Form(form) => {
             // Transform each element in the form recursively
@@ -9,149 +271,254 @@ fn advance_parse(expr: Expr) -> Expr {
                 form.iter().map(|e| advance_parse(e.clone())).collect();
 
             // Check if this is a special form
-            if !transformed_form.is_empty() {
-                if let Expr::Id(id) = &transformed_form[0] {
-                    if id == "let" && transformed_form.len() == 3 {
-                        // Transform let expression
-                        let mut bindings = Vec::new();
-
-                        // Check that the second element is a list of bindings
-                        if let Expr::Form(binding_list) = &transformed_form[1] {
-                            // Process bindings (each binding is a 2-element list)
-                            for binding in binding_list {
-                                if let Expr::Form(pair) = binding {
-                                    if pair.len() == 2 {
-                                        if let Expr::Id(var_name) = &pair[0] {
-                                            bindings.push((var_name.clone(), pair[1].clone()));
-                                        }
-                                    }
-                                }
+            if let Some(Expr::Id(id)) = transformed_form.first() {
+                if id == "let" && transformed_form.len() == 3 {
+                    // Transform let expression
+                    let mut bindings = Vec::new();
+
+                    // Check that the second element is a list of bindings
+                    if let Expr::Form(binding_list) = &transformed_form[1] {
+                        // Process bindings (each binding is a 2-element list)
+                        for binding in binding_list {
+                            if let Expr::Form(pair) = binding
+                                && pair.len() == 2
+                                && let Expr::Id(var_name) = &pair[0]
+                            {
+                                bindings.push((var_name.clone(), pair[1].clone()));
                             }
                         }
+                    }
 
-                        // The 3rd element is the body expression
-                        let body = Box::new(transformed_form[2].clone());
+                    // The 3rd element is the body expression
+                    let body = Box::new(transformed_form[2].clone());
 
-                        return Expr::Let { bindings, body };
-                    } else if id == "if" && transformed_form.len() == 4 {
-                        // Transform if expression
-                        let cond = Box::new(transformed_form[1].clone());
-                        let then = Box::new(transformed_form[2].clone());
-                        let else_ = Box::new(transformed_form[3].clone());
-
-                        return Expr::If { cond, then, else_ };
-                    } else if id == "and" {
-                        // Transform n-ary and expression
-                        let exprs = transformed_form[1..].to_vec();
-                        return Expr::And(exprs);
-                    } else if id == "or" {
-                        // Transform n-ary or expression
-                        let exprs = transformed_form[1..].to_vec();
-                        return Expr::Or(exprs);
-                    } else if id == "not" && transformed_form.len() == 2 {
-                        // Transform not expression
-                        let expr = Box::new(transformed_form[1].clone());
-                        return Expr::Not(expr);
-                    } else if id == "fn" && transformed_form.len() == 3 {
-                        // Transform fn (lambda function) expression
-                        let mut args = Vec::new();
+                    return Expr::Let { bindings, body };
+                } else if id == "let" && transformed_form.len() == 4 {
+                    // Transform named let expression: (let name ((var init) ...) body)
+                    // is a loop, which the body starts again by calling `name`
+                    if let Expr::Id(name) = &transformed_form[1] {
+                        let (vars, inits) = parse_bindings(&transformed_form[2], "named let")
+                            .into_iter()
+                            .unzip();
+                        return loop_fun(name, vars, inits, transformed_form[3].clone());
+                    } else {
+                        panic!("The name of a named let must be an identifier");
+                    }
+                } else if id == "if" && transformed_form.len() == 4 {
+                    // Transform if expression
+                    let cond = Box::new(transformed_form[1].clone());
+                    let then = Box::new(transformed_form[2].clone());
+                    let else_ = Box::new(transformed_form[3].clone());
 
-                        // Check that the second element is a list of argument names
-                        if let Expr::Form(arg_list) = &transformed_form[1] {
-                            // Extract argument names
-                            for arg in arg_list {
-                                if let Expr::Id(arg_name) = arg {
-                                    args.push(arg_name.clone());
-                                }
+                    return Expr::If { cond, then, else_ };
+                } else if id == "and" {
+                    // Transform n-ary and expression
+                    let exprs = transformed_form[1..].to_vec();
+                    return Expr::And(exprs);
+                } else if id == "or" {
+                    // Transform n-ary or expression
+                    let exprs = transformed_form[1..].to_vec();
+                    return Expr::Or(exprs);
+                } else if id == "not" && transformed_form.len() == 2 {
+                    // Transform not expression
+                    let expr = Box::new(transformed_form[1].clone());
+                    return Expr::Not(expr);
+                } else if id == "fn" && transformed_form.len() == 3 {
+                    // Transform fn (lambda function) expression
+                    let mut args = Vec::new();
+
+                    // Check that the second element is a list of argument names
+                    if let Expr::Form(arg_list) = &transformed_form[1] {
+                        // Extract argument names
+                        for arg in arg_list {
+                            if let Expr::Id(arg_name) = arg {
+                                args.push(arg_name.clone());
                             }
                         }
+                    }
 
-                        // The third element is the body expression
-                        let body = Box::new(transformed_form[2].clone());
+                    // The third element is the body expression
+                    let body = Box::new(transformed_form[2].clone());
 
-                        return Expr::Fn { args, body };
-                    } else if id == "def" && transformed_form.len() == 3 {
-                        // Transform def expression
-                        if let Expr::Id(x) = &transformed_form[1] {
-                            let y = Box::new(transformed_form[2].clone());
-                            return Expr::Def { x: x.clone(), y };
-                        } else {
-                            panic!("First argument to def must be an identifier");
-                        }
-                    } else if id == "defun" && transformed_form.len() == 4 {
-                        // Transform defun expression
-                        if let Expr::Id(func_name) = &transformed_form[1] {
-                            let mut args = Vec::new();
-
-                            // Check that the third element is a list of argument names
-                            if let Expr::Form(arg_list) = &transformed_form[2] {
-                                // Extract argument names
-                                for arg in arg_list {
-                                    if let Expr::Id(arg_name) = arg {
+                    return Expr::Fn { args, body };
+                } else if id == "def" && transformed_form.len() == 3 {
+                    // Transform def expression
+                    if let Expr::Id(x) = &transformed_form[1] {
+                        let y = Box::new(transformed_form[2].clone());
+                        return Expr::Def { x: x.clone(), y };
+                    } else {
+                        panic!("First argument to def must be an identifier");
+                    }
+                } else if id == "defun"
+                    && (transformed_form.len() == 4 || transformed_form.len() == 6)
+                {
+                    // Transform defun expression, optionally annotated:
+                    // (defun f ((x : int) y) : float body)
+                    if let Expr::Id(func_name) = &transformed_form[1] {
+                        let mut args = Vec::new();
+                        let mut sig = Signature::default();
+
+                        // Check that the third element is a list of argument names
+                        if let Expr::Form(arg_list) = &transformed_form[2] {
+                            // Extract argument names and their annotations
+                            for arg in arg_list {
+                                match arg {
+                                    Expr::Id(arg_name) => {
                                         args.push(arg_name.clone());
-                                    } else {
-                                        panic!("Arguments to defun must be identifiers");
+                                        sig.params.push(None);
                                     }
+                                    Expr::Form(annotated) => match annotated.as_slice() {
+                                        [Expr::Id(arg_name), Expr::Id(colon), ty]
+                                            if colon == ":" =>
+                                        {
+                                            args.push(arg_name.clone());
+                                            sig.params.push(Some(parse_type_ann(ty)));
+                                        }
+                                        _ => panic!(
+                                            "Annotated defun argument must have the form (name : type)"
+                                        ),
+                                    },
+                                    _ => panic!("Arguments to defun must be identifiers"),
                                 }
-                            } else {
-                                panic!("Second argument to defun must be a list of argument names");
                             }
+                        } else {
+                            panic!("Second argument to defun must be a list of argument names");
+                        }
 
-                            let body = Box::new(transformed_form[3].clone());
+                        // Canonical form: no parameter annotations at all is an empty list
+                        if sig.params.iter().all(|p| p.is_none()) {
+                            sig.params.clear();
+                        }
 
-                            return Expr::Defun {
-                                name: func_name.clone(),
-                                args,
-                                body,
-                            };
-                        } else {
-                            panic!("First argument to defun must be an identifier");
+                        if transformed_form.len() == 6 {
+                            if transformed_form[3] != Expr::Id(":".to_string()) {
+                                panic!("Return type of defun must be written as `: type`");
+                            }
+                            sig.ret = Some(parse_type_ann(&transformed_form[4]));
                         }
-                    } else if id == "letfun" && transformed_form.len() == 3 {
-                        // Transform letfun expression
-                        if let Expr::Form(func_def) = &transformed_form[1] {
-                            if func_def.len() == 3 {
-                                if let Expr::Id(func_name) = &func_def[0] {
-                                    let mut args = Vec::new();
-
-                                    // Check that the second element is a list of argument names
-                                    if let Expr::Form(arg_list) = &func_def[1] {
-                                        // Extract argument names
-                                        for arg in arg_list {
-                                            if let Expr::Id(arg_name) = arg {
-                                                args.push(arg_name.clone());
-                                            } else {
-                                                panic!("Arguments to letfun must be identifiers");
-                                            }
-                                        }
-                                    } else {
-                                        panic!(
-                                            "Second element in letfun function definition must be a list of argument names"
-                                        );
-                                    }
 
-                                    let fun_body = Box::new(func_def[2].clone());
-                                    let expr_body = Box::new(transformed_form[2].clone());
+                        let body = Box::new(transformed_form.last().unwrap().clone());
+
+                        return Expr::Defun {
+                            name: func_name.clone(),
+                            args,
+                            body,
+                            sig,
+                        };
+                    } else {
+                        panic!("First argument to defun must be an identifier");
+                    }
+                } else if id == "letfun" && transformed_form.len() == 3 {
+                    // Transform letfun expression
+                    if let Expr::Form(func_def) = &transformed_form[1] {
+                        if func_def.len() == 3 {
+                            if let Expr::Id(func_name) = &func_def[0] {
+                                let mut args = Vec::new();
 
-                                    return Expr::LetFun {
-                                        name: func_name.clone(),
-                                        args,
-                                        fun_body,
-                                        expr_body,
-                                    };
+                                // Check that the second element is a list of argument names
+                                if let Expr::Form(arg_list) = &func_def[1] {
+                                    // Extract argument names
+                                    for arg in arg_list {
+                                        if let Expr::Id(arg_name) = arg {
+                                            args.push(arg_name.clone());
+                                        } else {
+                                            panic!("Arguments to letfun must be identifiers");
+                                        }
+                                    }
                                 } else {
                                     panic!(
-                                        "First element in letfun function definition must be an identifier"
+                                        "Second element in letfun function definition must be a list of argument names"
                                     );
                                 }
+
+                                let fun_body = Box::new(func_def[2].clone());
+                                let expr_body = Box::new(transformed_form[2].clone());
+
+                                return Expr::LetFun {
+                                    name: func_name.clone(),
+                                    args,
+                                    fun_body,
+                                    expr_body,
+                                };
                             } else {
                                 panic!(
-                                    "letfun function definition must have three elements: name, args list, and body"
+                                    "First element in letfun function definition must be an identifier"
                                 );
                             }
                         } else {
-                            panic!("First argument to letfun must be a function definition");
+                            panic!(
+                                "letfun function definition must have three elements: name, args list, and body"
+                            );
                         }
+                    } else {
+                        panic!("First argument to letfun must be a function definition");
+                    }
+                } else if id == "let*" && transformed_form.len() == 3 {
+                    // Transform let* expression: each binding sees the previous ones
+                    let bindings = parse_bindings(&transformed_form[1], "let*");
+                    let body = transformed_form[2].clone();
+                    return bindings
+                        .into_iter()
+                        .rev()
+                        .fold(body, |body, binding| Expr::Let {
+                            bindings: vec![binding],
+                            body: Box::new(body),
+                        });
+                } else if (id == "when" || id == "unless") && transformed_form.len() == 3 {
+                    // Transform when/unless expression, which is nil when it does not run
+                    let cond = Box::new(transformed_form[1].clone());
+                    let body = Box::new(transformed_form[2].clone());
+                    let (then, else_) = if id == "when" {
+                        (body, Box::new(Expr::Nil))
+                    } else {
+                        (Box::new(Expr::Nil), body)
+                    };
+                    return Expr::If { cond, then, else_ };
+                } else if id == "let-values" && transformed_form.len() == 3 {
+                    return desugar_let_values(&transformed_form[1], transformed_form[2].clone());
+                } else if id == "do" && transformed_form.len() == 3 {
+                    return desugar_do(&transformed_form[1], &transformed_form[2]);
+                } else if id == "cond" {
+                    return desugar_cond(&transformed_form[1..]);
+                } else if id == "case" && transformed_form.len() >= 2 {
+                    return desugar_case(&transformed_form[1], &transformed_form[2..]);
+                }
+            }
+
+            // Closure-converted IR, as printed by `purelisp compile -ir`
+            if let Some(Expr::Id(id)) = transformed_form.first() {
+                if id == "defclos" && transformed_form.len() == 5 {
+                    // Transform defclos expression: (defclos name (freevars...) (args...) body)
+                    if let Expr::Id(name) = &transformed_form[1] {
+                        let freevars =
+                            parse_id_list(&transformed_form[2], "defclos free variables");
+                        let args = parse_id_list(&transformed_form[3], "defclos arguments");
+                        let body = Box::new(transformed_form[4].clone());
+                        return Expr::DefClos {
+                            name: name.clone(),
+                            freevars,
+                            args,
+                            body,
+                        };
+                    } else {
+                        panic!("First argument to defclos must be an identifier");
+                    }
+                } else if id == "letclos" && transformed_form.len() == 3 {
+                    // Transform letclos expression: (letclos (name (closid (freevars...))) body)
+                    if let Expr::Form(binding) = &transformed_form[1]
+                        && let [Expr::Id(name), Expr::Form(closure)] = binding.as_slice()
+                        && let [Expr::Id(closid), freevars] = closure.as_slice()
+                    {
+                        let freevars = parse_id_list(freevars, "letclos free variables");
+                        let body = Box::new(transformed_form[2].clone());
+                        return Expr::LetClos {
+                            name: name.clone(),
+                            closid: closid.clone(),
+                            freevars,
+                            body,
+                        };
+                    } else {
+                        panic!("letclos binding must have the form (name (closid (freevars...)))");
                     }
                 }
             }
@@ -163,9 +530,165 @@ fn advance_parse(expr: Expr) -> Expr {
     }
 }
 
-pub fn parse(input: &str) -> Expr {
-    match purelisp::ExprParser::new().parse(&input) {
-        Ok(expr) => advance_parse(expr),
+/// Parse a single S-expression, without recognizing the special forms
+pub fn parse_sexp(input: &str) -> Expr {
+    match purelisp::ExprParser::new().parse(input) {
+        Ok(expr) => expr,
         Err(_) => panic!("Parse error"),
     }
 }
+
+pub fn parse(input: &str) -> Expr {
+    advance_parse(parse_sexp(input))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pretty_format;
+
+    #[test]
+    fn parse_test_annotated_defun() {
+        let expr = parse("(defun f ((x : int) y) : float (g x y))");
+        match &expr {
+            Expr::Defun { args, sig, .. } => {
+                assert_eq!(args, &vec!["x".to_string(), "y".to_string()]);
+                assert_eq!(sig.params, vec![Some(TypeAnn::Int), None]);
+                assert_eq!(sig.ret, Some(TypeAnn::Float));
+            }
+            _ => panic!("expected defun, got {}", expr),
+        }
+        assert_eq!(parse(&pretty_format(&expr)), expr);
+        assert_eq!(parse(&expr.to_string()), expr);
+    }
+
+    #[test]
+    fn parse_test_unannotated_defun() {
+        let expr = parse("(defun f (x y) (g x y))");
+        match &expr {
+            Expr::Defun { sig, .. } => assert!(sig.is_empty() && sig.params.is_empty()),
+            _ => panic!("expected defun, got {}", expr),
+        }
+    }
+
+    #[test]
+    fn parse_test_string_literal() {
+        let expr = parse(r#""say \"hi\"\n""#);
+        assert_eq!(expr, Expr::Str("say \"hi\"\n".to_string()));
+        // Printing a literal quotes and escapes it again
+        assert_eq!(parse(&expr.to_string()), expr);
+    }
+
+    #[test]
+    fn parse_test_derived_forms() {
+        let cases = [
+            (
+                "(cond ((< n 0) -1) ((= n 0) 0) (else 1))",
+                "(if (< n 0) -1 (if (= n 0) 0 1))",
+            ),
+            ("(cond ((p x) 1))", "(if (p x) 1 nil)"),
+            ("(cond)", "nil"),
+            (
+                "(case (f x) ((1 2) \"small\") ((\"a\") 0) (else \"other\"))",
+                "(let ((%case0 (f x))) (if (or (= %case0 1) (= %case0 2)) \"small\" (if (or (= %case0 \"a\")) 0 \"other\")))",
+            ),
+            (
+                "(case x ((true) 1))",
+                "(let ((%case0 x)) (if (or (= %case0 true)) 1 nil))",
+            ),
+            (
+                "(let* ((x 1) (y (+ x 1))) (* x y))",
+                "(let ((x 1)) (let ((y (+ x 1))) (* x y)))",
+            ),
+            ("(when (p x) 1)", "(if (p x) 1 nil)"),
+            ("(unless (p x) 1)", "(if (p x) nil 1)"),
+        ];
+        for (source, desugared) in cases {
+            assert_eq!(parse(source), parse(desugared), "{}", source);
+        }
+    }
+
+    #[test]
+    fn parse_test_case_hygiene() {
+        // The key is bound to a name that the clauses do not refer to
+        let cases = [
+            (
+                "(let ((%case 5)) (case 1 ((1) %case) (else 0)))",
+                "(let ((%case 5)) (let ((%case0 1)) (if (or (= %case0 1)) %case 0)))",
+            ),
+            (
+                "(case k ((1) (f %case0 %case1)))",
+                "(let ((%case2 k)) (if (or (= %case2 1)) (f %case0 %case1) nil))",
+            ),
+        ];
+        for (source, desugared) in cases {
+            assert_eq!(parse(source), parse(desugared), "{}", source);
+        }
+    }
+
+    #[test]
+    fn parse_test_loops() {
+        let cases = [
+            (
+                "(let loop ((i 0) (acc 1)) (if (= i n) acc (loop (+ i 1) (* acc 2))))",
+                "(letfun (loop (i acc) (if (= i n) acc (loop (+ i 1) (* acc 2)))) (loop 0 1))",
+            ),
+            (
+                "(do ((i 0 (+ i 1)) (acc nil (cons i acc)) (n 3)) ((= i n) acc))",
+                "(letfun (%do0 (i acc n) (if (= i n) acc (%do0 (+ i 1) (cons i acc) n))) (%do0 0 nil 3))",
+            ),
+            // The loop is named after none of the variables the loop refers to
+            (
+                "(let ((%do0 9)) (do ((i 0 (+ i 1))) ((= i 2) %do0)))",
+                "(let ((%do0 9)) (letfun (%do1 (i) (if (= i 2) %do0 (%do1 (+ i 1)))) (%do1 0)))",
+            ),
+            (
+                "(do ((%do0 0 (+ %do0 1))) ((= %do0 2) 0))",
+                "(letfun (%do1 (%do0) (if (= %do0 2) 0 (%do1 (+ %do0 1)))) (%do1 0))",
+            ),
+        ];
+        for (source, desugared) in cases {
+            assert_eq!(parse(source), parse(desugared), "{}", source);
+        }
+    }
+
+    #[test]
+    fn parse_test_let_values() {
+        assert_eq!(
+            parse("(let-values (((q r) (divmod a b)) ((x) y)) (+ q r))"),
+            parse(
+                "(let ((%values0 (divmod a b)) (%values1 y))
+                   (let ((q (values-ref %values0 2 0)) (r (values-ref %values0 2 1))
+                         (x (values-ref %values1 1 0)))
+                     (+ q r)))"
+            )
+        );
+        // The temporaries are named after none of the variables the body refers to
+        assert_eq!(
+            parse("(let-values (((a) (f)) ((b) (g))) (+ a %values0))"),
+            parse(
+                "(let ((%values1 (f)) (%values2 (g)))
+                   (let ((a (values-ref %values1 1 0)) (b (values-ref %values2 1 0)))
+                     (+ a %values0)))"
+            )
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "The exit clause of do must have the form (test expr)")]
+    fn parse_test_do_without_result() {
+        parse("(do ((i 0 (+ i 1))) ((= i 3)))");
+    }
+
+    #[test]
+    #[should_panic(expected = "The else clause must be the last clause of cond")]
+    fn parse_test_cond_else_not_last() {
+        parse("(cond (else 1) ((p x) 2))");
+    }
+
+    #[test]
+    #[should_panic(expected = "Case clauses can only match literals, found y")]
+    fn parse_test_case_non_literal() {
+        parse("(case x ((y) 1))");
+    }
+}
@@ -3,16 +3,34 @@ use std::io::{self, Read};
 use std::path::Path;
 
 use crate::ast::Expr;
+use crate::expand::Expander;
 use crate::parse;
 
 /// Reads a multiline string and parses it into a vector of expressions
 pub fn read_string(content: &str) -> io::Result<Vec<Expr>> {
+    let located = read_string_located(content)?;
+    Ok(located.into_iter().map(|(_, expr)| expr).collect())
+}
+
+/// Like `read_string`, but pairs every top-level expression with the
+/// (1-based) line number it starts on
+pub fn read_string_located(content: &str) -> io::Result<Vec<(usize, Expr)>> {
+    read_string_located_with(content, &mut Expander::new())
+}
+
+/// Like `read_string_located`, expanding the macros defined in the content or
+/// earlier with `expander`
+pub fn read_string_located_with(
+    content: &str,
+    expander: &mut Expander,
+) -> io::Result<Vec<(usize, Expr)>> {
     let mut expressions = Vec::new();
     let mut buffer = String::new();
     let mut paren_count = 0;
+    let mut start_line = 0;
 
     // Process the content line by line
-    for line in content.lines() {
+    for (line_no, line) in content.lines().enumerate() {
         let mut trimmed = line.trim();
 
         // Skip empty lines
@@ -39,13 +57,18 @@ pub fn read_string(content: &str) -> io::Result<Vec<Expr>> {
         }
 
         // Append the current line to the buffer
+        if buffer.is_empty() {
+            start_line = line_no + 1;
+        }
         buffer.push_str(trimmed);
         buffer.push(' ');
 
         // If we have a complete expression, parse it
         if paren_count == 0 && !buffer.trim().is_empty() {
-            let expr = parse::parse(&buffer);
-            expressions.push(expr);
+            let sexp = parse::parse_sexp(&buffer);
+            if let Some(expr) = expander.expand_toplevel(sexp) {
+                expressions.push((start_line, expr));
+            }
 
             // Reset the buffer and paren count for the next expression
             buffer.clear();
@@ -63,8 +86,10 @@ pub fn read_string(content: &str) -> io::Result<Vec<Expr>> {
     
     // Handle any remaining content in the buffer
     if !buffer.trim().is_empty() {
-        let expr = parse::parse(&buffer);
-        expressions.push(expr);
+        let sexp = parse::parse_sexp(&buffer);
+        if let Some(expr) = expander.expand_toplevel(sexp) {
+            expressions.push((start_line, expr));
+        }
     }
 
     Ok(expressions)
@@ -80,3 +105,12 @@ pub fn read_file<P: AsRef<Path>>(file_path: P) -> io::Result<Vec<Expr>> {
     // Process the file contents
     read_string(&contents)
 }
+
+/// Reads a file and parses it into a vector of expressions with line numbers
+pub fn read_file_located<P: AsRef<Path>>(file_path: P) -> io::Result<Vec<(usize, Expr)>> {
+    let mut file = fs::File::open(file_path)?;
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+
+    read_string_located(&contents)
+}
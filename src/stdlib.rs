@@ -0,0 +1,143 @@
+//! The standard library, written in PureLisp in `std/` and bundled into the binary.
+//! The interpreter loads all of it into every new environment; the compiler and the
+//! type checker link in the definitions a program refers to. Its functions are
+//! ordinary globals, which a program may redefine.
+
+use std::collections::HashSet;
+use std::sync::OnceLock;
+
+use crate::ast::Expr;
+use crate::intpt::Env;
+use crate::intpt::file::evaluate_toplevel;
+use crate::read::read_string;
+
+/// Sources of the standard library, by file name. A definition only refers to the
+/// definitions before it.
+pub const SOURCES: &[(&str, &str)] = &[
+    ("list.purelisp", include_str!("../std/list.purelisp")),
+    (
+        "function.purelisp",
+        include_str!("../std/function.purelisp"),
+    ),
+];
+
+/// Definitions of the standard library, in order
+pub fn definitions() -> &'static [Expr] {
+    static DEFINITIONS: OnceLock<Vec<Expr>> = OnceLock::new();
+    DEFINITIONS.get_or_init(|| {
+        SOURCES
+            .iter()
+            .flat_map(|(file, source)| {
+                read_string(source).unwrap_or_else(|err| panic!("std/{}: {}", file, err))
+            })
+            .collect()
+    })
+}
+
+fn def_name(expr: &Expr) -> Option<&str> {
+    match expr {
+        Expr::Def { x, .. } => Some(x),
+        Expr::Defun { name, .. } => Some(name),
+        _ => None,
+    }
+}
+
+/// Whether the standard library defines `name`
+pub fn defines(name: &str) -> bool {
+    definitions()
+        .iter()
+        .any(|expr| def_name(expr) == Some(name))
+}
+
+/// Define every function of the standard library in `env`
+pub fn load(env: &mut Env) {
+    for expr in definitions() {
+        evaluate_toplevel(expr.clone(), env);
+    }
+}
+
+/// The definitions of the standard library that `prog` refers to, directly or through
+/// other definitions, and does not define itself
+pub fn referenced<'a>(prog: impl IntoIterator<Item = &'a Expr>) -> Vec<Expr> {
+    let mut defined = HashSet::new();
+    let mut ids = Vec::new();
+    for expr in prog {
+        defined.extend(def_name(expr));
+        crate::typeck::collect_ids(expr, &mut ids);
+    }
+    let mut used: HashSet<&str> = ids.into_iter().collect();
+
+    // Definitions only depend on earlier ones, so a backward pass finds them all
+    let mut linked = Vec::new();
+    for expr in definitions().iter().rev() {
+        let name = def_name(expr).unwrap();
+        if used.contains(name) && !defined.contains(name) {
+            let mut ids = Vec::new();
+            crate::typeck::collect_ids(expr, &mut ids);
+            used.extend(ids);
+            linked.push(expr.clone());
+        }
+    }
+    linked.reverse();
+    linked
+}
+
+/// `prog` preceded by the definitions of the standard library it refers to
+pub fn link(prog: Vec<Expr>) -> Vec<Expr> {
+    let mut linked = referenced(&prog);
+    linked.extend(prog);
+    linked
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::intpt::create_environment;
+    use crate::intpt::file::process_string;
+
+    fn names(prog: &[Expr]) -> Vec<&str> {
+        prog.iter().filter_map(def_name).collect()
+    }
+
+    #[test]
+    fn test_stdlib_link() {
+        // `reverse` needs `foldl`; the program defines its own `map`
+        let prog = read_string("(defun map (f x) (f x))\n(reverse (map (fn (x) (cons x nil)) 1))")
+            .unwrap();
+        let linked = link(prog);
+        assert_eq!(names(&linked), ["foldl", "reverse", "map"]);
+        assert_eq!(link(read_string("(+ 1 2)").unwrap()).len(), 1);
+    }
+
+    #[test]
+    fn test_stdlib_interpreter() {
+        let mut env = create_environment();
+        for expr in definitions() {
+            assert!(env.get(def_name(expr).unwrap()).is_some());
+        }
+        let results = process_string(
+            "(def xs (range 0 4))\n\
+             (foldr (fn (x acc) (+ (* 10 acc) x)) 0 xs)\n\
+             (length (append xs (reverse xs)))",
+            &mut env,
+        )
+        .unwrap();
+        assert_eq!(results[1], crate::Value::Int(3210));
+        assert_eq!(results[2], crate::Value::Int(8));
+    }
+
+    #[test]
+    fn test_stdlib_long_lists() {
+        // The list functions loop in constant stack, far beyond the depth of recursion
+        // the interpreter allows in debug builds
+        let results = process_string(
+            "(def xs (range 0 1000))\n\
+             (length (map (fn (x) (* x 2)) (filter (fn (x) (> x 10)) xs)))\n\
+             (foldr + 0 (append xs xs))",
+            &mut create_environment(),
+        )
+        .unwrap();
+        assert_eq!(results[1], crate::Value::Int(989));
+        assert_eq!(results[2], crate::Value::Int(999_000));
+    }
+}
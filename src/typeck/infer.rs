@@ -0,0 +1,433 @@
+use std::collections::{HashMap, HashSet};
+
+use super::TypeError;
+use super::types::{Scheme, TyVar, Type, TypePrinter};
+use crate::Expr;
+use crate::ast::Signature;
+
+/// Typing environment: a stack of bindings, innermost last
+#[derive(Debug, Clone)]
+pub(crate) struct TypeEnv(Vec<(String, Scheme)>);
+
+impl TypeEnv {
+    pub(crate) fn new() -> Self {
+        TypeEnv(Vec::new())
+    }
+
+    pub(crate) fn push(&mut self, name: String, scheme: Scheme) {
+        self.0.push((name, scheme));
+    }
+
+    pub(crate) fn get(&self, name: &str) -> Option<&Scheme> {
+        for (id, scheme) in self.0.iter().rev() {
+            if id == name {
+                return Some(scheme);
+            }
+        }
+        None
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub(crate) fn truncate(&mut self, len: usize) {
+        self.0.truncate(len);
+    }
+}
+
+enum UnifyError {
+    Mismatch,
+    Arity,
+    Occurs,
+    NotNumeric(Type),
+}
+
+pub(crate) struct Inferer {
+    bindings: Vec<Option<Type>>,
+    num: HashSet<TyVar>,
+}
+
+impl Inferer {
+    pub(crate) fn new() -> Self {
+        Inferer {
+            bindings: Vec::new(),
+            num: HashSet::new(),
+        }
+    }
+
+    pub(crate) fn fresh(&mut self) -> Type {
+        self.bindings.push(None);
+        Type::Var(self.bindings.len() - 1)
+    }
+
+    /// A fresh variable that may only be bound to `int` or `float`
+    pub(crate) fn fresh_num(&mut self) -> Type {
+        let ty = self.fresh();
+        if let Type::Var(v) = ty {
+            self.num.insert(v);
+        }
+        ty
+    }
+
+    /// Follow variable bindings until reaching an unbound variable or a constructor
+    fn resolve(&self, ty: &Type) -> Type {
+        let mut ty = ty.clone();
+        while let Type::Var(v) = ty {
+            match &self.bindings[v] {
+                Some(bound) => ty = bound.clone(),
+                None => break,
+            }
+        }
+        ty
+    }
+
+    /// Apply the current substitution everywhere inside `ty`
+    pub(crate) fn zonk(&self, ty: &Type) -> Type {
+        match self.resolve(ty) {
+            Type::List(item) => Type::list(self.zonk(&item)),
+            Type::Fun(params, ret) => Type::Fun(
+                params.iter().map(|p| self.zonk(p)).collect(),
+                Box::new(self.zonk(&ret)),
+            ),
+            Type::Values(items) => Type::Values(items.iter().map(|item| self.zonk(item)).collect()),
+            other => other,
+        }
+    }
+
+    fn occurs(&self, v: TyVar, ty: &Type) -> bool {
+        match self.resolve(ty) {
+            Type::Var(w) => v == w,
+            Type::List(item) => self.occurs(v, &item),
+            Type::Fun(params, ret) => {
+                params.iter().any(|p| self.occurs(v, p)) || self.occurs(v, &ret)
+            }
+            Type::Values(items) => items.iter().any(|item| self.occurs(v, item)),
+            _ => false,
+        }
+    }
+
+    fn bind(&mut self, v: TyVar, ty: Type) -> Result<(), UnifyError> {
+        if self.occurs(v, &ty) {
+            return Err(UnifyError::Occurs);
+        }
+        if self.num.contains(&v) {
+            match &ty {
+                Type::Int | Type::Float => {}
+                Type::Var(w) => {
+                    self.num.insert(*w);
+                }
+                _ => return Err(UnifyError::NotNumeric(self.zonk(&ty))),
+            }
+        }
+        self.bindings[v] = Some(ty);
+        Ok(())
+    }
+
+    fn unify(&mut self, a: &Type, b: &Type) -> Result<(), UnifyError> {
+        match (self.resolve(a), self.resolve(b)) {
+            (Type::Var(x), Type::Var(y)) if x == y => Ok(()),
+            (Type::Var(x), ty) | (ty, Type::Var(x)) => self.bind(x, ty),
+            (Type::List(x), Type::List(y)) => self.unify(&x, &y),
+            (Type::Nil, Type::List(_)) | (Type::List(_), Type::Nil) => Ok(()),
+            (Type::Fun(p1, r1), Type::Fun(p2, r2)) => {
+                if p1.len() != p2.len() {
+                    return Err(UnifyError::Arity);
+                }
+                for (x, y) in p1.iter().zip(p2.iter()) {
+                    self.unify(x, y)?;
+                }
+                self.unify(&r1, &r2)
+            }
+            (Type::Values(xs), Type::Values(ys)) => {
+                if xs.len() != ys.len() {
+                    return Err(UnifyError::Mismatch);
+                }
+                for (x, y) in xs.iter().zip(ys.iter()) {
+                    self.unify(x, y)?;
+                }
+                Ok(())
+            }
+            (x, y) if x == y => Ok(()),
+            _ => Err(UnifyError::Mismatch),
+        }
+    }
+
+    /// Unify `found` with `expected`, describing a failure in terms of `what` and `expr`
+    pub(crate) fn expect(
+        &mut self,
+        expected: &Type,
+        found: &Type,
+        expr: &Expr,
+        what: &str,
+    ) -> Result<(), TypeError> {
+        let before = (self.zonk(expected), self.zonk(found));
+        self.unify(expected, found).map_err(|err| {
+            let mut printer = TypePrinter::new();
+            let expected = printer.print(&before.0);
+            let found = printer.print(&before.1);
+            let message = match err {
+                UnifyError::Mismatch => format!("{}: expected {}, found {}", what, expected, found),
+                UnifyError::Arity => format!(
+                    "{}: expected {}, found {} (different number of arguments)",
+                    what, expected, found
+                ),
+                UnifyError::Occurs => {
+                    format!(
+                        "{}: infinite type when unifying {} with {}",
+                        what, expected, found
+                    )
+                }
+                UnifyError::NotNumeric(ty) => {
+                    format!("{}: expected a number, found {}", what, printer.print(&ty))
+                }
+            };
+            TypeError::new(expr, message)
+        })
+    }
+
+    pub(crate) fn instantiate(&mut self, scheme: &Scheme) -> Type {
+        let mut mapping = HashMap::new();
+        for v in &scheme.vars {
+            let fresh = if scheme.num.contains(v) {
+                self.fresh_num()
+            } else {
+                self.fresh()
+            };
+            mapping.insert(*v, fresh);
+        }
+        scheme.ty.substitute(&mapping)
+    }
+
+    /// Quantify over the variables of `ty` that are not free in `env`
+    pub(crate) fn generalize(&self, env: &TypeEnv, ty: &Type) -> Scheme {
+        let ty = self.zonk(ty);
+        let mut env_vars = HashSet::new();
+        for (_, scheme) in env.0.iter() {
+            for v in self.zonk(&scheme.ty).free_vars() {
+                if !scheme.vars.contains(&v) {
+                    env_vars.insert(v);
+                }
+            }
+        }
+        let vars: Vec<TyVar> = ty
+            .free_vars()
+            .into_iter()
+            .filter(|v| !env_vars.contains(v))
+            .collect();
+        let num = vars
+            .iter()
+            .copied()
+            .filter(|v| self.num.contains(v))
+            .collect();
+        Scheme { vars, num, ty }
+    }
+
+    /// Infer the type of a (non top-level) expression
+    pub(crate) fn infer(&mut self, expr: &Expr, env: &mut TypeEnv) -> Result<Type, TypeError> {
+        match expr {
+            Expr::Nil => Ok(Type::Nil),
+            Expr::Bool(_) => Ok(Type::Bool),
+            Expr::Int(_) => Ok(Type::Int),
+            Expr::Float(_) => Ok(Type::Float),
+            Expr::Str(_) => Ok(Type::Str),
+            Expr::Id(id) => match env.get(id) {
+                Some(scheme) => {
+                    let scheme = scheme.clone();
+                    Ok(self.instantiate(&scheme))
+                }
+                None => Err(TypeError::new(expr, format!("unbound identifier `{}`", id))),
+            },
+            Expr::Form(form) => {
+                if form.is_empty() {
+                    return Err(TypeError::new(expr, "empty form".to_string()));
+                }
+                let callee = &form[0];
+                if let Some(ty) = self.infer_values(form, env)? {
+                    return Ok(ty);
+                }
+                let callee_ty = self.infer(callee, env)?;
+                let mut arg_tys = Vec::new();
+                for arg in &form[1..] {
+                    arg_tys.push(self.infer(arg, env)?);
+                }
+                match self.resolve(&callee_ty) {
+                    Type::Fun(params, ret) => {
+                        if arg_tys.len() > params.len() {
+                            return Err(TypeError::new(
+                                expr,
+                                format!(
+                                    "`{}` expects {} argument(s), but {} were given",
+                                    callee,
+                                    params.len(),
+                                    arg_tys.len()
+                                ),
+                            ));
+                        }
+                        for (i, (param, arg)) in params.iter().zip(arg_tys.iter()).enumerate() {
+                            self.expect(
+                                param,
+                                arg,
+                                &form[i + 1],
+                                &format!("argument {} of `{}`", i + 1, callee),
+                            )?;
+                        }
+                        if arg_tys.len() == params.len() {
+                            Ok(*ret)
+                        } else {
+                            // Partial application yields a function of the remaining parameters
+                            Ok(Type::Fun(params[arg_tys.len()..].to_vec(), ret))
+                        }
+                    }
+                    Type::Var(_) => {
+                        let ret = self.fresh();
+                        let fun_ty = Type::fun(arg_tys, ret.clone());
+                        self.expect(&callee_ty, &fun_ty, expr, "application")?;
+                        Ok(ret)
+                    }
+                    other => Err(TypeError::new(
+                        expr,
+                        format!("`{}` is not a function, it has type {}", callee, other),
+                    )),
+                }
+            }
+            Expr::Let { bindings, body } => {
+                // Bindings are evaluated in the enclosing scope (parallel let)
+                let mut schemes = Vec::new();
+                for (name, e) in bindings {
+                    let ty = self.infer(e, env)?;
+                    schemes.push((name.clone(), self.generalize(env, &ty)));
+                }
+                let mark = env.len();
+                for (name, scheme) in schemes {
+                    env.push(name, scheme);
+                }
+                let body_ty = self.infer(body, env);
+                env.truncate(mark);
+                body_ty
+            }
+            Expr::If { cond, then, else_ } => {
+                let cond_ty = self.infer(cond, env)?;
+                self.expect(&Type::Bool, &cond_ty, cond, "condition of `if`")?;
+                let then_ty = self.infer(then, env)?;
+                let else_ty = self.infer(else_, env)?;
+                self.expect(&then_ty, &else_ty, else_, "branches of `if`")?;
+                // A `nil` branch may be the empty list of the other one
+                if self.resolve(&then_ty) == Type::Nil {
+                    Ok(else_ty)
+                } else {
+                    Ok(then_ty)
+                }
+            }
+            Expr::And(exprs) | Expr::Or(exprs) => {
+                let op = if matches!(expr, Expr::And(_)) {
+                    "and"
+                } else {
+                    "or"
+                };
+                for e in exprs {
+                    let ty = self.infer(e, env)?;
+                    self.expect(&Type::Bool, &ty, e, &format!("operand of `{}`", op))?;
+                }
+                Ok(Type::Bool)
+            }
+            Expr::Not(e) => {
+                let ty = self.infer(e, env)?;
+                self.expect(&Type::Bool, &ty, e, "operand of `not`")?;
+                Ok(Type::Bool)
+            }
+            Expr::Fn { args, body } => self.infer_function(args, &Signature::default(), body, env),
+            Expr::LetFun {
+                name,
+                args,
+                fun_body,
+                expr_body,
+            } => {
+                // The function is monomorphic inside its own body
+                let mark = env.len();
+                let fun_var = self.fresh();
+                env.push(name.clone(), Scheme::mono(fun_var.clone()));
+                let fun_ty = self.infer_function(args, &Signature::default(), fun_body, env);
+                env.truncate(mark);
+                let fun_ty = fun_ty?;
+                self.expect(
+                    &fun_var,
+                    &fun_ty,
+                    expr,
+                    &format!("recursive use of `{}`", name),
+                )?;
+
+                let scheme = self.generalize(env, &fun_var);
+                env.push(name.clone(), scheme);
+                let body_ty = self.infer(expr_body, env);
+                env.truncate(mark);
+                body_ty
+            }
+            Expr::Def { .. } | Expr::Defun { .. } => Err(TypeError::new(
+                expr,
+                "definitions are only allowed at the top level".to_string(),
+            )),
+            Expr::DefClos { .. } | Expr::LetClos { .. } => Err(TypeError::new(
+                expr,
+                "closure-converted IR cannot be type checked".to_string(),
+            )),
+        }
+    }
+
+    /// Type of a call to `values`, which returns its arguments, or to `values-ref` with
+    /// literal counts, which takes one of `n` values apart. Neither has a function type
+    /// in the prelude, so they are only recognized where no local shadows them.
+    fn infer_values(
+        &mut self,
+        form: &[Expr],
+        env: &mut TypeEnv,
+    ) -> Result<Option<Type>, TypeError> {
+        match form {
+            [Expr::Id(callee), args @ ..] if callee == "values" && env.get(callee).is_none() => {
+                let mut items = Vec::new();
+                for arg in args {
+                    items.push(self.infer(arg, env)?);
+                }
+                Ok(Some(Type::Values(items)))
+            }
+            [Expr::Id(callee), v, Expr::Int(n), Expr::Int(i)]
+                if callee == "values-ref" && env.get(callee).is_none() && (0..*n).contains(i) =>
+            {
+                let v_ty = self.infer(v, env)?;
+                let items: Vec<Type> = (0..*n).map(|_| self.fresh()).collect();
+                let item = items[*i as usize].clone();
+                self.expect(&Type::Values(items), &v_ty, v, "argument 1 of `values-ref`")?;
+                Ok(Some(item))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Infer the type of a function with the given parameters and body,
+    /// constrained by the annotations in `sig`
+    pub(crate) fn infer_function(
+        &mut self,
+        args: &[String],
+        sig: &Signature,
+        body: &Expr,
+        env: &mut TypeEnv,
+    ) -> Result<Type, TypeError> {
+        let mark = env.len();
+        let mut params = Vec::new();
+        for (i, arg) in args.iter().enumerate() {
+            let ty = match sig.param(i) {
+                Some(ann) => Type::from(ann),
+                None => self.fresh(),
+            };
+            env.push(arg.clone(), Scheme::mono(ty.clone()));
+            params.push(ty);
+        }
+        let body_ty = self.infer(body, env);
+        env.truncate(mark);
+        let body_ty = body_ty?;
+        if let Some(ret) = sig.ret {
+            self.expect(&Type::from(ret), &body_ty, body, "annotated return type")?;
+        }
+        Ok(Type::fun(params, body_ty))
+    }
+}
@@ -0,0 +1,434 @@
+mod infer;
+mod prelude;
+pub mod types;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+use crate::Expr;
+use infer::{Inferer, TypeEnv};
+use types::{Scheme, Type};
+
+/// A type error, located at the top-level form it was found in
+#[derive(Debug, Clone, PartialEq)]
+pub struct TypeError {
+    /// Line on which the enclosing top-level form starts, if known
+    pub line: Option<usize>,
+    /// The offending sub-expression
+    pub expr: String,
+    pub message: String,
+}
+
+impl TypeError {
+    fn new(expr: &Expr, message: String) -> Self {
+        let mut text = expr.to_string();
+        if text.chars().count() > 60 {
+            text = format!("{}...", text.chars().take(57).collect::<String>());
+        }
+        TypeError {
+            line: None,
+            expr: text,
+            message,
+        }
+    }
+}
+
+impl fmt::Display for TypeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(line) = self.line {
+            write!(f, "line {}: ", line)?;
+        }
+        write!(f, "{}\n    in: {}", self.message, self.expr)
+    }
+}
+
+/// Result of type checking a program
+#[derive(Debug, Clone, Default)]
+pub struct CheckReport {
+    /// Inferred type of every top-level definition that checked successfully, in source order
+    pub signatures: Vec<(String, Scheme)>,
+    pub errors: Vec<TypeError>,
+}
+
+impl CheckReport {
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+fn def_name(expr: &Expr) -> Option<&str> {
+    match expr {
+        Expr::Def { x, .. } => Some(x),
+        Expr::Defun { name, .. } => Some(name),
+        _ => None,
+    }
+}
+
+/// Every identifier mentioned in `expr` (an over-approximation of its free variables)
+pub(crate) fn collect_ids<'a>(expr: &'a Expr, ids: &mut Vec<&'a str>) {
+    match expr {
+        Expr::Id(id) => ids.push(id),
+        Expr::Form(exprs) | Expr::And(exprs) | Expr::Or(exprs) => {
+            exprs.iter().for_each(|e| collect_ids(e, ids));
+        }
+        Expr::Let { bindings, body } => {
+            bindings.iter().for_each(|(_, e)| collect_ids(e, ids));
+            collect_ids(body, ids);
+        }
+        Expr::If { cond, then, else_ } => {
+            collect_ids(cond, ids);
+            collect_ids(then, ids);
+            collect_ids(else_, ids);
+        }
+        Expr::Not(e) => collect_ids(e, ids),
+        Expr::Fn { body, .. }
+        | Expr::Defun { body, .. }
+        | Expr::DefClos { body, .. }
+        | Expr::LetClos { body, .. } => collect_ids(body, ids),
+        Expr::Def { y, .. } => collect_ids(y, ids),
+        Expr::LetFun {
+            fun_body,
+            expr_body,
+            ..
+        } => {
+            collect_ids(fun_body, ids);
+            collect_ids(expr_body, ids);
+        }
+        Expr::Nil | Expr::Bool(_) | Expr::Int(_) | Expr::Float(_) | Expr::Str(_) => {}
+    }
+}
+
+/// Tarjan's algorithm; components come out dependencies-first
+struct SccFinder<'a> {
+    deps: &'a [Vec<usize>],
+    index: Vec<Option<usize>>,
+    low: Vec<usize>,
+    on_stack: Vec<bool>,
+    stack: Vec<usize>,
+    counter: usize,
+    sccs: Vec<Vec<usize>>,
+}
+
+impl<'a> SccFinder<'a> {
+    fn run(deps: &'a [Vec<usize>]) -> Vec<Vec<usize>> {
+        let n = deps.len();
+        let mut finder = SccFinder {
+            deps,
+            index: vec![None; n],
+            low: vec![0; n],
+            on_stack: vec![false; n],
+            stack: Vec::new(),
+            counter: 0,
+            sccs: Vec::new(),
+        };
+        for v in 0..n {
+            if finder.index[v].is_none() {
+                finder.visit(v);
+            }
+        }
+        finder.sccs
+    }
+
+    fn visit(&mut self, v: usize) {
+        self.index[v] = Some(self.counter);
+        self.low[v] = self.counter;
+        self.counter += 1;
+        self.stack.push(v);
+        self.on_stack[v] = true;
+
+        for &w in &self.deps[v] {
+            match self.index[w] {
+                None => {
+                    self.visit(w);
+                    self.low[v] = self.low[v].min(self.low[w]);
+                }
+                Some(w_index) if self.on_stack[w] => {
+                    self.low[v] = self.low[v].min(w_index);
+                }
+                _ => {}
+            }
+        }
+
+        if Some(self.low[v]) == self.index[v] {
+            let mut scc = Vec::new();
+            while let Some(w) = self.stack.pop() {
+                self.on_stack[w] = false;
+                scc.push(w);
+                if w == v {
+                    break;
+                }
+            }
+            scc.sort();
+            self.sccs.push(scc);
+        }
+    }
+}
+
+/// Infer the type of a top-level definition's value
+fn infer_def(inf: &mut Inferer, expr: &Expr, env: &mut TypeEnv) -> Result<Type, TypeError> {
+    match expr {
+        Expr::Def { y, .. } => inf.infer(y, env),
+        Expr::Defun {
+            args, body, sig, ..
+        } => inf.infer_function(args, sig, body, env),
+        _ => unreachable!(),
+    }
+}
+
+/// Type check a program whose top-level forms are paired with their line numbers.
+///
+/// Top-level definitions are visible throughout the program (as in the compiler);
+/// mutually recursive groups are inferred together and then generalized.
+pub fn check_prog(prog: &[(usize, Expr)]) -> CheckReport {
+    // The definitions of the standard library the program uses are checked with it,
+    // but not reported
+    let library = crate::stdlib::referenced(prog.iter().map(|(_, expr)| expr));
+    let prog: Vec<(usize, Expr)> = library
+        .iter()
+        .map(|expr| (0, expr.clone()))
+        .chain(prog.iter().cloned())
+        .collect();
+    let prog = &prog[..];
+    let mut inf = Inferer::new();
+    let mut env = prelude::prelude_env(&mut inf);
+    let mut report = CheckReport::default();
+
+    // Nodes of the dependency graph are the top-level definitions
+    let defs: Vec<usize> = (0..prog.len())
+        .filter(|&i| def_name(&prog[i].1).is_some())
+        .collect();
+    let mut node_of: HashMap<&str, usize> = HashMap::new();
+    for (node, &i) in defs.iter().enumerate() {
+        node_of.insert(def_name(&prog[i].1).unwrap(), node);
+    }
+    let deps: Vec<Vec<usize>> = defs
+        .iter()
+        .map(|&i| {
+            let mut ids = Vec::new();
+            collect_ids(&prog[i].1, &mut ids);
+            let mut nodes: Vec<usize> = ids
+                .iter()
+                .filter_map(|id| node_of.get(id))
+                .copied()
+                .collect();
+            nodes.sort();
+            nodes.dedup();
+            nodes
+        })
+        .collect();
+
+    let mut schemes: Vec<Option<Scheme>> = vec![None; defs.len()];
+    for scc in SccFinder::run(&deps) {
+        let mark = env.len();
+        let vars: Vec<Type> = scc.iter().map(|_| inf.fresh()).collect();
+        for (&node, var) in scc.iter().zip(vars.iter()) {
+            let name = def_name(&prog[defs[node]].1).unwrap();
+            env.push(name.to_string(), Scheme::mono(var.clone()));
+        }
+
+        let mut ok = vec![true; scc.len()];
+        for (k, &node) in scc.iter().enumerate() {
+            let (line, expr) = &prog[defs[node]];
+            let result = infer_def(&mut inf, expr, &mut env).and_then(|ty| {
+                let name = def_name(expr).unwrap();
+                inf.expect(&vars[k], &ty, expr, &format!("definition of `{}`", name))
+            });
+            if let Err(mut err) = result {
+                err.line = Some(*line);
+                report.errors.push(err);
+                ok[k] = false;
+            }
+        }
+
+        env.truncate(mark);
+        for (k, &node) in scc.iter().enumerate() {
+            let name = def_name(&prog[defs[node]].1).unwrap();
+            let scheme = inf.generalize(&env, &vars[k]);
+            env.push(name.to_string(), scheme.clone());
+            if ok[k] {
+                schemes[node] = Some(scheme);
+            }
+        }
+    }
+
+    for (node, &i) in defs.iter().enumerate() {
+        if i < library.len() {
+            continue;
+        }
+        if let Some(scheme) = &schemes[node] {
+            let name = def_name(&prog[i].1).unwrap();
+            report.signatures.push((name.to_string(), scheme.clone()));
+        }
+    }
+
+    for (line, expr) in prog {
+        if def_name(expr).is_some() {
+            continue;
+        }
+        let mark = env.len();
+        if let Err(mut err) = inf.infer(expr, &mut env) {
+            err.line = Some(*line);
+            report.errors.push(err);
+        }
+        env.truncate(mark);
+    }
+
+    report.errors.sort_by_key(|err| err.line);
+    report
+}
+
+/// Type check a program given as source text
+pub fn check_string(content: &str) -> io::Result<CheckReport> {
+    let prog = crate::read::read_string_located(content)?;
+    Ok(check_prog(&prog))
+}
+
+/// Type check a program file
+pub fn check_file<P: AsRef<Path>>(file_path: P) -> io::Result<CheckReport> {
+    // The definitions of imported modules are located at their import
+    let prog = crate::module::Loader::new().load_file_located(file_path)?;
+    Ok(check_prog(&prog))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn signature(report: &CheckReport, name: &str) -> String {
+        report
+            .signatures
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, scheme)| scheme.to_string())
+            .unwrap_or_else(|| panic!("no signature for {}", name))
+    }
+
+    #[test]
+    fn typeck_test_examples() {
+        for file in [
+            "./examples/boolean.purelisp",
+            "./examples/highorder.purelisp",
+            "./examples/recursion.purelisp",
+            "./examples/simple.purelisp",
+            "./examples/sqrt.purelisp",
+        ] {
+            let report = check_file(file).unwrap();
+            assert!(report.is_ok(), "{}: {:?}", file, report.errors);
+        }
+
+        // Self-application `(maker maker)` has no simple type
+        let report = check_file("./examples/factorial.purelisp").unwrap();
+        assert_eq!(report.errors.len(), 1);
+        assert!(report.errors[0].message.contains("infinite type"));
+    }
+
+    #[test]
+    fn typeck_test_signatures() {
+        let report = check_file("./examples/recursion.purelisp").unwrap();
+        assert_eq!(signature(&report, "factorial"), "(int) -> int");
+        assert_eq!(signature(&report, "is-odd"), "(int) -> bool");
+
+        let report = check_file("./examples/highorder.purelisp").unwrap();
+        assert_eq!(
+            signature(&report, "apply-n-times"),
+            "(('a) -> 'a, int, 'a) -> 'a"
+        );
+
+        let report = check_string("(defun double (x) (+ x x))").unwrap();
+        assert_eq!(signature(&report, "double"), "num 'a => ('a) -> 'a");
+    }
+
+    #[test]
+    fn typeck_test_let_polymorphism() {
+        let report = check_string(
+            r#"
+(defun id (x) x)
+(let ((f (fn (x) x))) (if (f true) (f 1) (id 2)))
+"#,
+        )
+        .unwrap();
+        assert!(report.is_ok(), "{:?}", report.errors);
+        assert_eq!(signature(&report, "id"), "('a) -> 'a");
+    }
+
+    #[test]
+    fn typeck_test_lists() {
+        let report = check_string(
+            r#"
+(defun sum (xs) (foldl + 0.0 xs))
+(defun singletons (xs) (map (fn (x) (cons x nil)) xs))
+(def evens (filter (fn (x) (= (mod x 2) 0)) (range 0 10)))
+(head 1)
+(+ 1 (head (map (fn (x) true) evens)))
+"#,
+        )
+        .unwrap();
+        assert_eq!(signature(&report, "sum"), "(list float) -> float");
+        assert_eq!(
+            signature(&report, "singletons"),
+            "(list 'a) -> list (list 'a)"
+        );
+        assert_eq!(signature(&report, "evens"), "list int");
+        // Only the definitions of the program are reported
+        assert_eq!(report.signatures.len(), 3);
+        let lines: Vec<Option<usize>> = report.errors.iter().map(|e| e.line).collect();
+        assert_eq!(lines, vec![Some(5), Some(6)]);
+    }
+
+    #[test]
+    fn typeck_test_values() {
+        let report = check_string(
+            r#"
+(defun divmod (a b) (values (quotient a b) (remainder a b)))
+(defun swap (p) (let-values (((a b) p)) (values b a)))
+(let-values (((q r) (divmod 7 2))) (+ q 1.5))
+(swap (values 1 2 3))
+"#,
+        )
+        .unwrap();
+        assert_eq!(signature(&report, "divmod"), "(int, int) -> values int int");
+        assert_eq!(signature(&report, "swap"), "(values 'a 'b) -> values 'b 'a");
+        let lines: Vec<Option<usize>> = report.errors.iter().map(|e| e.line).collect();
+        assert_eq!(lines, vec![Some(4), Some(5)]);
+    }
+
+    #[test]
+    fn typeck_test_annotations() {
+        let report = check_string(
+            r#"
+(defun half ((x : float)) : float (/ x 2.0))
+(defun id ((x : int)) x)
+(half 3)
+"#,
+        )
+        .unwrap();
+        assert_eq!(signature(&report, "half"), "(float) -> float");
+        assert_eq!(signature(&report, "id"), "(int) -> int");
+        assert_eq!(report.errors.len(), 1);
+        assert_eq!(report.errors[0].line, Some(4));
+
+        let report = check_string("(defun f ((x : int)) : float (+ x 1))").unwrap();
+        assert_eq!(report.errors.len(), 1);
+        assert!(report.errors[0].message.contains("annotated return type"));
+    }
+
+    #[test]
+    fn typeck_test_errors() {
+        let report = check_string(
+            r#"
+(defun f (x) (+ x 1))
+
+(f "one")
+(if 1 2 3)
+(+ 1 2.0)
+(undefined 1)
+"#,
+        )
+        .unwrap();
+        let lines: Vec<Option<usize>> = report.errors.iter().map(|e| e.line).collect();
+        assert_eq!(lines, vec![Some(4), Some(5), Some(6), Some(7)]);
+        assert_eq!(signature(&report, "f"), "(int) -> int");
+    }
+}
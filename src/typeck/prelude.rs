@@ -0,0 +1,90 @@
+use super::infer::{Inferer, TypeEnv};
+use super::types::{Scheme, Type};
+
+/// Build a scheme with one quantified variable `'a` (numeric when `num` is set)
+fn poly(inf: &mut Inferer, num: bool, make: impl Fn(Type) -> Type) -> Scheme {
+    let var = inf.fresh();
+    let v = match var {
+        Type::Var(v) => v,
+        _ => unreachable!(),
+    };
+    Scheme {
+        vars: vec![v],
+        num: if num { vec![v] } else { Vec::new() },
+        ty: make(var),
+    }
+}
+
+/// Types of the builtins loaded by the interpreter prelude
+pub(crate) fn prelude_env(inf: &mut Inferer) -> TypeEnv {
+    let mut env = TypeEnv::new();
+
+    for op in ["+", "-", "*", "/"] {
+        let scheme = poly(inf, true, |a| Type::fun(vec![a.clone(), a.clone()], a));
+        env.push(op.to_string(), scheme);
+    }
+    for op in ["<", "<=", ">", ">="] {
+        let scheme = poly(inf, true, |a| Type::fun(vec![a.clone(), a], Type::Bool));
+        env.push(op.to_string(), scheme);
+    }
+    let scheme = poly(inf, false, |a| Type::fun(vec![a.clone(), a], Type::Bool));
+    env.push("=".to_string(), scheme);
+
+    // math
+    for op in ["sq", "abs", "floor", "ceil", "round"] {
+        let scheme = poly(inf, true, |a| Type::fun(vec![a.clone()], a));
+        env.push(op.to_string(), scheme);
+    }
+    for op in ["sqrt", "exp", "log", "sin", "cos"] {
+        let scheme = poly(inf, true, |a| Type::fun(vec![a], Type::Float));
+        env.push(op.to_string(), scheme);
+    }
+    for op in ["pow", "min", "max"] {
+        let scheme = poly(inf, true, |a| Type::fun(vec![a.clone(), a.clone()], a));
+        env.push(op.to_string(), scheme);
+    }
+    for op in ["mod", "quotient", "remainder"] {
+        let scheme = Scheme::mono(Type::fun(vec![Type::Int, Type::Int], Type::Int));
+        env.push(op.to_string(), scheme);
+    }
+
+    // lists
+    let scheme = poly(inf, false, |a| {
+        Type::fun(vec![a.clone(), Type::list(a.clone())], Type::list(a))
+    });
+    env.push("cons".to_string(), scheme);
+    let scheme = poly(inf, false, |a| Type::fun(vec![Type::list(a.clone())], a));
+    env.push("head".to_string(), scheme);
+    let scheme = poly(inf, false, |a| {
+        Type::fun(vec![Type::list(a.clone())], Type::list(a))
+    });
+    env.push("tail".to_string(), scheme);
+    let scheme = poly(inf, false, |a| Type::fun(vec![Type::list(a)], Type::Bool));
+    env.push("empty?".to_string(), scheme);
+
+    env
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::builtins::builtins;
+
+    #[test]
+    fn test_prelude_types_every_builtin() {
+        let mut inf = Inferer::new();
+        let env = prelude_env(&mut inf);
+        for builtin in builtins() {
+            // Typed by `infer` from their arguments
+            if ["values", "values-ref"].contains(&builtin.name) {
+                continue;
+            }
+            let scheme = env.get(builtin.name);
+            assert!(
+                matches!(scheme, Some(Scheme { ty: Type::Fun(params, _), .. }) if params.len() == builtin.arity),
+                "{}",
+                builtin.name
+            );
+        }
+    }
+}
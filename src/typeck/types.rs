@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::ast::TypeAnn;
+
+pub type TyVar = usize;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Type {
+    Nil,
+    Bool,
+    Int,
+    Float,
+    Str,
+    /// A list; `nil`, the empty list, is also accepted wherever a list is expected
+    List(Box<Type>),
+    Fun(Vec<Type>, Box<Type>),
+    /// Multiple values, returned by `values`
+    Values(Vec<Type>),
+    Var(TyVar),
+}
+
+/// A type quantified over `vars`. Variables listed in `num` may only be
+/// instantiated to `int` or `float` (the overloaded arithmetic builtins).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Scheme {
+    pub vars: Vec<TyVar>,
+    pub num: Vec<TyVar>,
+    pub ty: Type,
+}
+
+impl Type {
+    pub fn fun(params: Vec<Type>, ret: Type) -> Type {
+        Type::Fun(params, Box::new(ret))
+    }
+
+    pub fn list(item: Type) -> Type {
+        Type::List(Box::new(item))
+    }
+
+    /// Collect type variables occurring in this type, in order of first appearance
+    pub fn free_vars(&self) -> Vec<TyVar> {
+        let mut vars = Vec::new();
+        self.collect_vars(&mut vars);
+        vars
+    }
+
+    fn collect_vars(&self, vars: &mut Vec<TyVar>) {
+        match self {
+            Type::Var(v) => {
+                if !vars.contains(v) {
+                    vars.push(*v);
+                }
+            }
+            Type::List(item) => item.collect_vars(vars),
+            Type::Fun(params, ret) => {
+                for param in params {
+                    param.collect_vars(vars);
+                }
+                ret.collect_vars(vars);
+            }
+            Type::Values(items) => {
+                for item in items {
+                    item.collect_vars(vars);
+                }
+            }
+            Type::Nil | Type::Bool | Type::Int | Type::Float | Type::Str => {}
+        }
+    }
+
+    /// Replace type variables according to `mapping`
+    pub fn substitute(&self, mapping: &HashMap<TyVar, Type>) -> Type {
+        match self {
+            Type::Var(v) => mapping.get(v).cloned().unwrap_or(Type::Var(*v)),
+            Type::List(item) => Type::list(item.substitute(mapping)),
+            Type::Fun(params, ret) => Type::Fun(
+                params.iter().map(|p| p.substitute(mapping)).collect(),
+                Box::new(ret.substitute(mapping)),
+            ),
+            Type::Values(items) => {
+                Type::Values(items.iter().map(|item| item.substitute(mapping)).collect())
+            }
+            _ => self.clone(),
+        }
+    }
+}
+
+impl From<TypeAnn> for Type {
+    fn from(ann: TypeAnn) -> Self {
+        match ann {
+            TypeAnn::Nil => Type::Nil,
+            TypeAnn::Bool => Type::Bool,
+            TypeAnn::Int => Type::Int,
+            TypeAnn::Float => Type::Float,
+            TypeAnn::Str => Type::Str,
+        }
+    }
+}
+
+impl Scheme {
+    /// A scheme that quantifies over nothing
+    pub fn mono(ty: Type) -> Self {
+        Scheme {
+            vars: Vec::new(),
+            num: Vec::new(),
+            ty,
+        }
+    }
+}
+
+/// Renders types with variables renamed to 'a, 'b, ... in order of appearance
+pub(crate) struct TypePrinter {
+    names: HashMap<TyVar, String>,
+}
+
+impl TypePrinter {
+    pub(crate) fn new() -> Self {
+        TypePrinter {
+            names: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn var_name(&mut self, v: TyVar) -> String {
+        let next = self.names.len();
+        self.names
+            .entry(v)
+            .or_insert_with(|| {
+                let letter = (b'a' + (next % 26) as u8) as char;
+                if next < 26 {
+                    format!("'{}", letter)
+                } else {
+                    format!("'{}{}", letter, next / 26)
+                }
+            })
+            .clone()
+    }
+
+    pub(crate) fn print(&mut self, ty: &Type) -> String {
+        match ty {
+            Type::Nil => "nil".to_string(),
+            Type::Bool => "bool".to_string(),
+            Type::Int => "int".to_string(),
+            Type::Float => "float".to_string(),
+            Type::Str => "string".to_string(),
+            Type::Var(v) => self.var_name(*v),
+            Type::List(item) => format!("list {}", self.print_arg(item)),
+            Type::Fun(params, ret) => {
+                let params: Vec<String> = params.iter().map(|p| self.print(p)).collect();
+                format!("({}) -> {}", params.join(", "), self.print(ret))
+            }
+            Type::Values(items) => {
+                let mut printed = "values".to_string();
+                for item in items {
+                    printed.push(' ');
+                    printed.push_str(&self.print_arg(item));
+                }
+                printed
+            }
+        }
+    }
+
+    /// Print the type of a list element or value, in parentheses unless it is atomic
+    fn print_arg(&mut self, ty: &Type) -> String {
+        match ty {
+            Type::Fun(..) | Type::List(_) | Type::Values(_) => format!("({})", self.print(ty)),
+            _ => self.print(ty),
+        }
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", TypePrinter::new().print(self))
+    }
+}
+
+impl fmt::Display for Scheme {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut printer = TypePrinter::new();
+        let body = printer.print(&self.ty);
+        let constraints: Vec<String> = self
+            .ty
+            .free_vars()
+            .into_iter()
+            .filter(|v| self.num.contains(v))
+            .map(|v| format!("num {}", printer.var_name(v)))
+            .collect();
+        if constraints.is_empty() {
+            write!(f, "{}", body)
+        } else {
+            write!(f, "{} => {}", constraints.join(", "), body)
+        }
+    }
+}
@@ -0,0 +1,281 @@
+//! Generator of random well-scoped, well-typed PureLisp programs for property tests
+//!
+//! Programs only use integers, floats, booleans and unary integer functions, and are
+//! built so that they always terminate without errors: there is no division, one
+//! operand of a multiplication is a small literal, and recursive functions are
+//! counting loops over a small literal.
+
+use proptest::prelude::*;
+
+use purelisp::Expr;
+use purelisp::ast::Signature;
+
+/// Maximum nesting depth of generated expressions
+const MAX_DEPTH: u32 = 4;
+
+/// Names of generated variables. The pool is small so that bindings shadow each other.
+const NAMES: [&str; 6] = ["a", "b", "c", "x", "y", "z"];
+/// Names of generated functions
+const FUN_NAMES: [&str; 3] = ["f", "g", "h"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Ty {
+    Int,
+    Float,
+    Bool,
+    /// A function from int to int
+    Fun,
+}
+
+/// Variables in scope with their types, innermost last. Variables without a type
+/// only shadow outer ones and are never used.
+#[derive(Debug, Clone, Default)]
+pub struct Scope(Vec<(String, Option<Ty>)>);
+
+impl Scope {
+    fn with(&self, name: &str, ty: Ty) -> Scope {
+        let mut scope = self.clone();
+        scope.0.push((name.to_string(), Some(ty)));
+        scope
+    }
+
+    fn hiding(&self, name: &str) -> Scope {
+        let mut scope = self.clone();
+        scope.0.push((name.to_string(), None));
+        scope
+    }
+
+    /// Visible variables of type `ty`, not shadowed by a later binding
+    fn vars(&self, ty: Ty) -> Vec<String> {
+        let mut vars: Vec<String> = Vec::new();
+        for (i, (name, t)) in self.0.iter().enumerate() {
+            let shadowed = self.0[i + 1..].iter().any(|(other, _)| other == name);
+            if *t == Some(ty) && !shadowed {
+                vars.push(name.clone());
+            }
+        }
+        vars
+    }
+}
+
+fn id(name: &str) -> Expr {
+    Expr::Id(name.to_string())
+}
+
+fn call(f: Expr, args: Vec<Expr>) -> Expr {
+    Expr::Form(std::iter::once(f).chain(args).collect())
+}
+
+fn literal(ty: Ty) -> BoxedStrategy<Expr> {
+    match ty {
+        Ty::Int => (-100i64..100).prop_map(Expr::Int).boxed(),
+        // Quarters print and read back exactly
+        Ty::Float => (-40i64..40)
+            .prop_map(|n| Expr::Float(n as f64 / 4.0))
+            .boxed(),
+        Ty::Bool => any::<bool>().prop_map(Expr::Bool).boxed(),
+        Ty::Fun => {
+            let scope = Scope::default();
+            prop::sample::select(&NAMES[..])
+                .prop_flat_map(move |x| {
+                    leaf(Ty::Int, &scope.with(x, Ty::Int)).prop_map(move |body| Expr::Fn {
+                        args: vec![x.to_string()],
+                        body: Box::new(body),
+                    })
+                })
+                .boxed()
+        }
+    }
+}
+
+fn leaf(ty: Ty, scope: &Scope) -> BoxedStrategy<Expr> {
+    let vars = scope.vars(ty);
+    if vars.is_empty() {
+        literal(ty)
+    } else {
+        prop_oneof![literal(ty), prop::sample::select(vars).prop_map(Expr::Id)].boxed()
+    }
+}
+
+/// `(let ((x e)) body)` binding a random variable of a random type
+fn let_expr(ty: Ty, scope: Scope, depth: u32) -> BoxedStrategy<Expr> {
+    (
+        prop::sample::select(&NAMES[..]),
+        prop::sample::select(&[Ty::Int, Ty::Float, Ty::Bool, Ty::Fun][..]),
+    )
+        .prop_flat_map(move |(x, bound_ty)| {
+            (
+                expr(bound_ty, scope.clone(), depth - 1),
+                expr(ty, scope.with(x, bound_ty), depth - 1),
+            )
+                .prop_map(move |(value, body)| Expr::Let {
+                    bindings: vec![(x.to_string(), value)],
+                    body: Box::new(body),
+                })
+        })
+        .boxed()
+}
+
+/// A counting loop `(letfun (f (n acc) (if (<= n 0) acc (f (- n 1) (+ acc step)))) (f k init))`
+/// with `k` below 5
+fn loop_expr(scope: Scope, depth: u32) -> BoxedStrategy<Expr> {
+    (
+        prop::sample::select(&FUN_NAMES[..]),
+        prop::sample::select(&NAMES[..]),
+        prop::sample::select(&NAMES[..]),
+        0i64..5,
+    )
+        .prop_filter("distinct loop variables", |(_, n, acc, _)| n != acc)
+        .prop_flat_map(move |(f, n, acc, count)| {
+            // The step can't see the accumulator, so that it only grows by addition
+            let body_scope = scope.hiding(f).hiding(acc).with(n, Ty::Int);
+            (
+                expr(Ty::Int, body_scope, depth - 1),
+                expr(Ty::Int, scope.hiding(f), depth - 1),
+            )
+                .prop_map(move |(step, init)| Expr::LetFun {
+                    name: f.to_string(),
+                    args: vec![n.to_string(), acc.to_string()],
+                    fun_body: Box::new(Expr::If {
+                        cond: Box::new(call(id("<="), vec![id(n), Expr::Int(0)])),
+                        then: Box::new(id(acc)),
+                        else_: Box::new(call(
+                            id(f),
+                            vec![
+                                call(id("-"), vec![id(n), Expr::Int(1)]),
+                                call(id("+"), vec![id(acc), step]),
+                            ],
+                        )),
+                    }),
+                    expr_body: Box::new(call(id(f), vec![Expr::Int(count), init])),
+                })
+        })
+        .boxed()
+}
+
+/// A random expression of type `ty` using only variables of `scope`
+pub fn expr(ty: Ty, scope: Scope, depth: u32) -> BoxedStrategy<Expr> {
+    if depth == 0 {
+        return leaf(ty, &scope);
+    }
+    let sub = |ty| expr(ty, scope.clone(), depth - 1);
+    let if_expr = (sub(Ty::Bool), sub(ty), sub(ty)).prop_map(|(cond, then, else_)| Expr::If {
+        cond: Box::new(cond),
+        then: Box::new(then),
+        else_: Box::new(else_),
+    });
+    let common = prop_oneof![
+        2 => leaf(ty, &scope),
+        1 => if_expr,
+        1 => let_expr(ty, scope.clone(), depth),
+    ];
+    match ty {
+        Ty::Int | Ty::Float => {
+            let small = match ty {
+                Ty::Int => (-10i64..10).prop_map(Expr::Int).boxed(),
+                _ => (-8i64..8).prop_map(|n| Expr::Float(n as f64 / 2.0)).boxed(),
+            };
+            let arith = (prop::sample::select(&["+", "-"][..]), sub(ty), sub(ty))
+                .prop_map(|(op, a, b)| call(id(op), vec![a, b]));
+            let mul = (sub(ty), small).prop_map(|(a, k)| call(id("*"), vec![a, k]));
+            if ty == Ty::Float {
+                return prop_oneof![3 => common, 2 => arith, 1 => mul].boxed();
+            }
+            let apply = (sub(Ty::Fun), sub(Ty::Int)).prop_map(|(f, a)| call(f, vec![a]));
+            prop_oneof![
+                3 => common,
+                2 => arith,
+                1 => mul,
+                1 => apply,
+                1 => loop_expr(scope.clone(), depth),
+            ]
+            .boxed()
+        }
+        Ty::Bool => {
+            let compare = (
+                prop::sample::select(&["<", "<=", ">", ">=", "="][..]),
+                prop::sample::select(&[Ty::Int, Ty::Float][..]),
+            )
+                .prop_flat_map({
+                    let scope = scope.clone();
+                    move |(op, ty)| {
+                        (
+                            expr(ty, scope.clone(), depth - 1),
+                            expr(ty, scope.clone(), depth - 1),
+                        )
+                            .prop_map(move |(a, b)| call(id(op), vec![a, b]))
+                    }
+                });
+            let logic = prop_oneof![
+                prop::collection::vec(sub(Ty::Bool), 0..3).prop_map(Expr::And),
+                prop::collection::vec(sub(Ty::Bool), 0..3).prop_map(Expr::Or),
+                sub(Ty::Bool).prop_map(|b| Expr::Not(Box::new(b))),
+            ];
+            prop_oneof![2 => common, 2 => compare, 1 => logic].boxed()
+        }
+        Ty::Fun => {
+            let lambda = prop::sample::select(&NAMES[..]).prop_flat_map({
+                let scope = scope.clone();
+                move |x| {
+                    expr(Ty::Int, scope.with(x, Ty::Int), depth - 1).prop_map(move |body| {
+                        Expr::Fn {
+                            args: vec![x.to_string()],
+                            body: Box::new(body),
+                        }
+                    })
+                }
+            });
+            prop_oneof![2 => common, 1 => lambda].boxed()
+        }
+    }
+}
+
+/// A random program: a few global definitions followed by expressions to print
+pub fn program() -> BoxedStrategy<Vec<Expr>> {
+    // Globals are never redefined: each definition takes the next name of a pool
+    let defs = (
+        prop::collection::vec((any::<bool>(), prop::sample::select(&NAMES[..])), 0..4),
+        Just(FUN_NAMES.to_vec()).prop_shuffle(),
+        Just(NAMES.to_vec()).prop_shuffle(),
+    );
+    defs.prop_flat_map(|(defs, fun_names, names)| {
+        // Each definition sees the globals defined before it
+        let mut scope = Scope::default();
+        let mut strategies = Vec::new();
+        let (mut fun_names, mut names) = (fun_names.into_iter(), names.into_iter());
+        for (is_defun, x) in defs {
+            if is_defun {
+                let f = fun_names.next().unwrap();
+                strategies.push(
+                    expr(Ty::Int, scope.with(x, Ty::Int), MAX_DEPTH - 1)
+                        .prop_map(move |body| Expr::Defun {
+                            name: f.to_string(),
+                            args: vec![x.to_string()],
+                            body: Box::new(body),
+                            sig: Signature::default(),
+                        })
+                        .boxed(),
+                );
+                scope = scope.with(f, Ty::Fun);
+            } else {
+                let x = names.next().unwrap();
+                strategies.push(
+                    expr(Ty::Int, scope.clone(), MAX_DEPTH - 1)
+                        .prop_map(move |value| Expr::Def {
+                            x: x.to_string(),
+                            y: Box::new(value),
+                        })
+                        .boxed(),
+                );
+                scope = scope.with(x, Ty::Int);
+            }
+        }
+        let main = prop::sample::select(&[Ty::Int, Ty::Float, Ty::Bool][..])
+            .prop_flat_map(move |ty| expr(ty, scope.clone(), MAX_DEPTH));
+        (strategies, prop::collection::vec(main, 1..4)).prop_map(|(mut prog, main)| {
+            prog.extend(main);
+            prog
+        })
+    })
+    .boxed()
+}
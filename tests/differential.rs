@@ -0,0 +1,165 @@
+//! Differential tests: every program under `examples/` and `tests/programs/` must
+//! print the same values when interpreted and when compiled to C
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+use purelisp::compl::compl_to_c;
+use purelisp::intpt::file::process_file;
+use purelisp::{Expr, create_environment, read_program};
+
+/// Output of the interpreter: the value of each top-level expression that is not a
+/// definition, one per line, as printed by the compiled program's `main`
+fn interpret(path: &Path) -> String {
+    let exprs = read_program(path).unwrap();
+    let values = process_file(path, &mut create_environment()).unwrap();
+    let mut output = String::new();
+    for (expr, value) in exprs.iter().zip(values) {
+        if !matches!(
+            expr,
+            Expr::Def { .. } | Expr::Defun { .. } | Expr::DefClos { .. }
+        ) {
+            output.push_str(&format!("{}\n", value));
+        }
+    }
+    output
+}
+
+/// Output of the program compiled with `compl_to_c` and built with the local `cc`
+fn compile_and_run(path: &Path) -> String {
+    let run = run_compiled(path);
+    assert!(
+        run.status.success(),
+        "{}: compiled program failed:\n{}",
+        path.display(),
+        String::from_utf8_lossy(&run.stderr)
+    );
+    String::from_utf8(run.stdout).unwrap()
+}
+
+/// Compile the program with `compl_to_c`, build it with the local `cc` and run it
+fn run_compiled(path: &Path) -> Output {
+    let c_code = compl_to_c(read_program(path).unwrap());
+    let stem = path.file_stem().unwrap().to_string_lossy();
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("differential");
+    fs::create_dir_all(&dir).unwrap();
+    let c_path = dir.join(format!("{}.c", stem));
+    let bin_path = dir.join(stem.as_ref());
+    fs::write(&c_path, c_code).unwrap();
+
+    let cc = Command::new("cc")
+        .arg(&c_path)
+        .arg("-o")
+        .arg(&bin_path)
+        .arg("-lm")
+        .output()
+        .expect("failed to run cc");
+    assert!(
+        cc.status.success(),
+        "{}: cc failed:\n{}",
+        path.display(),
+        String::from_utf8_lossy(&cc.stderr)
+    );
+
+    Command::new(&bin_path).output().unwrap()
+}
+
+fn programs(dir: &str) -> Vec<PathBuf> {
+    let mut files: Vec<_> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "purelisp"))
+        .collect();
+    files.sort();
+    assert!(!files.is_empty(), "no programs in {}", dir);
+    files
+}
+
+fn has_cc() -> bool {
+    Command::new("cc").arg("--version").output().is_ok()
+}
+
+fn check_dir(dir: &str) {
+    if !has_cc() {
+        eprintln!("skipping differential tests: no C compiler `cc` found");
+        return;
+    }
+    let mut failures = Vec::new();
+    for path in programs(dir) {
+        let expected = interpret(&path);
+        let actual = compile_and_run(&path);
+        if expected != actual {
+            failures.push(format!(
+                "{}:\n--- interpreter\n{}--- compiled\n{}",
+                path.display(),
+                expected,
+                actual
+            ));
+        }
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
+
+#[test]
+fn differential_examples() {
+    check_dir("examples");
+}
+
+#[test]
+fn differential_programs() {
+    check_dir("tests/programs");
+}
+
+/// Loops the compiler turns into C loops run in constant stack space, far beyond the
+/// depth of recursion the interpreter (or a recursive C function) would allow
+#[test]
+fn compiled_loops_are_iterative() {
+    if !has_cc() {
+        eprintln!("skipping differential tests: no C compiler `cc` found");
+        return;
+    }
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("differential");
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("deep_loops.purelisp");
+    fs::write(
+        &path,
+        "(defun count (n) (let loop ((i 0) (acc 0)) (if (= i n) acc (loop (+ i 1) (+ acc 2)))))
+(count 10000000)
+(defun steps (n k) (do ((i 0 (+ i k)) (acc 0 (+ acc 1))) ((>= i n) acc)))
+(steps 30000000 3)
+(defun adder (k) (let loop ((i 0) (f (fn (x) x))) (if (= i k) (f 1) (loop (+ i 1) f))))
+(adder 10000000)
+(length (map (fn (x) (* x 2)) (filter (fn (x) (> x 10)) (range 0 100000))))
+(foldr + 0 (range 0 100000))
+",
+    )
+    .unwrap();
+    assert_eq!(
+        compile_and_run(&path),
+        "20000000\n10000000\n1\n99989\n4999950000\n"
+    );
+}
+
+/// Integer overflow in `pow` stops the compiled program, as it stops the interpreter
+#[test]
+fn compiled_pow_overflow() {
+    if !has_cc() {
+        eprintln!("skipping differential tests: no C compiler `cc` found");
+        return;
+    }
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("differential");
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("pow_overflow.purelisp");
+    fs::write(&path, "(pow -2 63)\n(pow 2 100)\n").unwrap();
+    let run = run_compiled(&path);
+    assert!(!run.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&run.stdout),
+        "-9223372036854775808\n"
+    );
+    assert_eq!(
+        String::from_utf8_lossy(&run.stderr),
+        "Error: Integer overflow\n"
+    );
+}
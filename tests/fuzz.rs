@@ -0,0 +1,74 @@
+//! Property tests on random programs: the formatter output reads back to the same
+//! program, and every prefix of the compiler pipeline preserves what it prints
+
+mod common;
+
+use proptest::prelude::*;
+
+use purelisp::compl::{CompileOptions, compl_to_ir_with};
+use purelisp::intpt::file::process_string;
+use purelisp::{Expr, Value, create_environment, format_prog, parse, pretty_format, read_string};
+
+/// The default pipeline of the compiler
+const PIPELINE: [&str; 9] = [
+    "knormal",
+    "anormal",
+    "inline",
+    "copyprop",
+    "constfold",
+    "cse",
+    "copyprop",
+    "closure",
+    "dce",
+];
+
+/// Values of the top-level expressions of `source` that are not definitions
+fn main_values(source: &str) -> Vec<Value> {
+    let exprs = read_string(source).unwrap();
+    let values = process_string(source, &mut create_environment()).unwrap();
+    exprs
+        .iter()
+        .zip(values)
+        .filter(|(expr, _)| {
+            !matches!(
+                expr,
+                Expr::Def { .. } | Expr::Defun { .. } | Expr::DefClos { .. }
+            )
+        })
+        .map(|(_, value)| value)
+        .collect()
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(128))]
+
+    #[test]
+    fn pretty_format_reparses(prog in common::program()) {
+        for expr in &prog {
+            prop_assert_eq!(&parse(&pretty_format(expr)), expr);
+        }
+        prop_assert_eq!(read_string(&format_prog(&prog)).unwrap(), prog);
+    }
+
+    #[test]
+    fn passes_preserve_semantics(prog in common::program()) {
+        let source = format_prog(&prog);
+        let expected = main_values(&source);
+        for n in 1..=PIPELINE.len() {
+            let options = CompileOptions {
+                passes: Some(PIPELINE[..n].iter().map(|name| name.to_string()).collect()),
+                verify: true,
+                ..CompileOptions::default()
+            };
+            let ir = compl_to_ir_with(prog.clone(), &options).unwrap();
+            prop_assert_eq!(
+                &main_values(&ir),
+                &expected,
+                "after {}:\n{}\nsource:\n{}",
+                PIPELINE[n - 1],
+                ir,
+                source
+            );
+        }
+    }
+}